@@ -0,0 +1,94 @@
+//! `--analyze`: a quality-control summary of a directory of recordings,
+//! using the same level measurements as the silence check without deleting
+//! anything.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::convert;
+use crate::error::BlackboxError;
+use crate::info::RecordingInfo;
+use crate::meter;
+use crate::silence;
+
+/// One recording's format, levels, and whether it counts as silent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileAnalysis {
+    pub path: PathBuf,
+    pub info: RecordingInfo,
+    /// Whether the RMS level, of the whole file or of the first and last
+    /// `window_secs` if set, is below the threshold.
+    pub silent: bool,
+}
+
+/// Analyzes every `.wav` file in `dir`, in name order, against the silence
+/// `threshold`. Files that can't be read are reported and skipped.
+pub fn analyze_directory(dir: &Path, threshold: f64, window_secs: Option<f64>) -> Result<Vec<FileAnalysis>, BlackboxError> {
+    let mut analyses = Vec::new();
+    for path in convert::wav_files(dir)? {
+        let analysis = RecordingInfo::from_path(&path).and_then(|info| {
+            let silent = match window_secs {
+                Some(_) => silence::is_silent(&path.to_string_lossy(), threshold, window_secs)?,
+                None => info.rms < threshold,
+            };
+            Ok(FileAnalysis { path: path.clone(), info, silent })
+        });
+        match analysis {
+            Ok(analysis) => analyses.push(analysis),
+            Err(e) => eprintln!("Failed to analyze {}: {}", path.display(), e),
+        }
+    }
+    Ok(analyses)
+}
+
+/// Prints a line per recording in `dir` and a summary, judging silence by
+/// `threshold` and `SILENCE_CHECK_SECONDS`.
+pub fn run_analyze(dir: &Path, threshold: f64, config: &Config) -> Result<(), BlackboxError> {
+    let analyses = analyze_directory(dir, threshold, config.silence_check_seconds)?;
+    for analysis in &analyses {
+        println!("{}  {:.1} s  RMS {} dBFS  peak {} dBFS  {}",
+                 analysis.path.display(),
+                 analysis.info.duration().as_secs_f64(),
+                 meter::format_dbfs(analysis.info.rms),
+                 meter::format_dbfs(analysis.info.peak),
+                 if analysis.silent { "silent" } else { "ok" });
+    }
+    let silent = analyses.iter().filter(|analysis| analysis.silent).count();
+    println!("{} recording(s) analyzed, {} silent below {} dBFS", analyses.len(), silent, meter::format_dbfs(threshold));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use tempfile::tempdir;
+
+    fn write_wav(path: &Path, samples: &[i16]) {
+        let spec = wav_spec(1, 8000);
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_analyze_directory() {
+        let temp_dir = tempdir().unwrap();
+        write_wav(&temp_dir.path().join("a-loud.wav"), &[8000, -8000].repeat(4000));
+        write_wav(&temp_dir.path().join("b-quiet.wav"), &[10, -10].repeat(4000));
+        std::fs::write(temp_dir.path().join("c-broken.wav"), b"not audio").unwrap();
+
+        let analyses = analyze_directory(temp_dir.path(), 0.001, None).unwrap();
+        let verdicts: Vec<(String, bool)> = analyses.iter()
+            .map(|a| (a.path.file_name().unwrap().to_string_lossy().into_owned(), a.silent))
+            .collect();
+        assert_eq!(verdicts, vec![("a-loud.wav".to_string(), false), ("b-quiet.wav".to_string(), true)]);
+        assert_eq!(analyses[0].info.frames, 8000);
+        assert!((analyses[0].info.peak - 8000.0 / 32768.0).abs() < 1e-12);
+
+        // Nothing is deleted
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+    }
+}
@@ -0,0 +1,666 @@
+//! Where input audio comes from. `CpalBackend` records real devices through
+//! cpal; tests substitute `MockBackend`, which plays a prepared buffer
+//! through the same capture path, so a whole recording runs without
+//! hardware. `SOURCE=tone` swaps in `tone::ToneBackend`, which generates a
+//! test signal in real time.
+
+use chrono::{DateTime, Local, TimeDelta};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::SampleFormat;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::capture::Capture;
+use crate::error::BlackboxError;
+use crate::monitor::{self, MonitorBuffer};
+use crate::throttle::{ThrottledLogger, WARNING_INTERVAL};
+
+/// Which input to record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputSelector {
+    /// The default input device.
+    Default,
+    /// Whatever the machine is playing.
+    Loopback,
+    /// The input device with this name.
+    Named(String),
+}
+
+/// Keeps audio flowing until dropped.
+///
+/// Streams are `Send`, so an `AudioRecorder` holding them can be moved to or
+/// shared with another thread. cpal's own streams aren't, so `CpalBackend`
+/// keeps each one on a thread of its own behind a `StreamThread`.
+pub trait ActiveStream: Send {}
+
+/// The name of the thread holding an input stream, which only waits for it
+/// to be stopped. The callback that receives the audio runs on cpal's own
+/// thread, which keeps whatever name the audio API gives it, and the audio
+/// is written on `capture::WRITER_THREAD_NAME`.
+pub(crate) const INPUT_THREAD_NAME: &str = "blackbox-input";
+/// The name of the thread holding a monitor output stream.
+const MONITOR_THREAD_NAME: &str = "blackbox-monitor";
+
+/// Owns a cpal stream on the thread that built it, which is the only thread
+/// that touches it. Dropping this stops the stream and waits for the thread.
+struct StreamThread {
+    /// Dropped to tell the thread to drop the stream.
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl StreamThread {
+    /// Runs `start` on a new thread called `name` and keeps the stream it
+    /// returns alive there, handing back whatever else `start` returned.
+    fn spawn<S, T, F>(name: &str, start: F) -> Result<(Self, T), BlackboxError>
+    where
+        S: 'static,
+        T: Send + 'static,
+        F: FnOnce() -> Result<(S, T), BlackboxError> + Send + 'static,
+    {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let thread = thread::Builder::new().name(name.to_string()).spawn(move || match start() {
+            Ok((stream, value)) => {
+                let _ = started_tx.send(Ok(value));
+                // Returns once `stop` is dropped
+                let _ = stop_rx.recv();
+                drop(stream);
+            },
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+            },
+        })?;
+        match started_rx.recv() {
+            Ok(Ok(value)) => Ok((StreamThread { stop: Some(stop_tx), thread: Some(thread) }, value)),
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            },
+            Err(_) => Err(BlackboxError::Stream("The audio stream thread panicked".to_string())),
+        }
+    }
+}
+
+impl Drop for StreamThread {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ActiveStream for StreamThread {}
+
+/// A running monitor output and the buffer it plays from.
+pub type MonitorOutput = (Box<dyn ActiveStream>, Arc<Mutex<MonitorBuffer>>);
+
+pub trait AudioBackend: Send {
+    fn open_input(&self, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError>;
+
+    /// Starts playing the audio pushed into the returned buffer on the output
+    /// device called `name`.
+    fn start_monitor(&self, name: &str, sample_rate: u32, channels: usize)
+        -> Result<MonitorOutput, BlackboxError>;
+}
+
+/// An input opened for recording, but not started yet.
+pub trait InputDevice: Send {
+    fn name(&self) -> String;
+    fn sample_rate(&self) -> u32;
+    /// How many channels each frame the device delivers has.
+    fn channels(&self) -> usize;
+    /// The name of each of the device's channels. cpal doesn't pass on the
+    /// names some platforms give channels, so every channel gets the
+    /// generic one unless a backend knows better.
+    fn channel_labels(&self) -> Vec<String> {
+        generic_channel_labels(self.channels())
+    }
+    /// Records at `sample_rate` instead of the device's default rate, or
+    /// fails if the device can't.
+    fn use_sample_rate(&mut self, sample_rate: u32) -> Result<(), BlackboxError>;
+    /// Switches to a config with at least `needed` channels if the current
+    /// one has fewer, keeping the current config with a warning if the
+    /// device has none.
+    fn cover_channels(&mut self, needed: usize);
+    /// Switches to the first of `formats`, named as in `PREFERRED_FORMATS`,
+    /// that the device offers at its current rate and channel count, keeping
+    /// the current config if it offers none of them.
+    fn prefer_formats(&mut self, formats: &[String]);
+    /// Starts feeding the device's audio to `capture`.
+    fn start(self: Box<Self>, capture: &Arc<Mutex<Capture>>) -> Result<Box<dyn ActiveStream>, BlackboxError>;
+}
+
+/// Real devices on one cpal host.
+pub struct CpalBackend {
+    host: cpal::Host,
+}
+
+impl CpalBackend {
+    /// Opens the host named by `name`. See `select_host`.
+    pub fn new(host_name: &str) -> Result<Self, BlackboxError> {
+        Ok(CpalBackend { host: select_host(host_name)? })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn open_input(&self, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError> {
+        let device = match selector {
+            InputSelector::Default => default_input_device(&self.host)?,
+            InputSelector::Loopback => loopback_device(&self.host)?,
+            InputSelector::Named(name) => find_input_device(&self.host, name)?,
+        };
+        // WASAPI records an output device in loopback mode, in its output format
+        let loopback = *selector == InputSelector::Loopback && cfg!(target_os = "windows");
+        let config = if loopback {
+            device.default_output_config()
+        } else {
+            device.default_input_config()
+        }.map_err(|e| BlackboxError::Stream(format!("Failed to get default input stream config: {}", e)))?;
+        Ok(Box::new(CpalInput { device, config, loopback }))
+    }
+
+    fn start_monitor(&self, name: &str, sample_rate: u32, channels: usize)
+        -> Result<MonitorOutput, BlackboxError> {
+        let device = monitor::find_output_device(&self.host, name)?;
+        let (stream, buffer) = StreamThread::spawn(MONITOR_THREAD_NAME, move || monitor::start_monitor(device, sample_rate, channels))?;
+        Ok((Box::new(stream), buffer))
+    }
+}
+
+struct CpalInput {
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    /// Recording an output device through WASAPI loopback.
+    loopback: bool,
+}
+
+impl InputDevice for CpalInput {
+    fn name(&self) -> String {
+        self.device.name().unwrap_or_default()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate().0
+    }
+
+    fn channels(&self) -> usize {
+        self.config.channels() as usize
+    }
+
+    fn use_sample_rate(&mut self, sample_rate: u32) -> Result<(), BlackboxError> {
+        let ranges: Vec<cpal::SupportedStreamConfigRange> = if self.loopback {
+            self.device.supported_output_configs().map(|ranges| ranges.collect())
+        } else {
+            self.device.supported_input_configs().map(|ranges| ranges.collect())
+        }.map_err(|e| BlackboxError::Stream(format!("Failed to list supported stream configs: {}", e)))?;
+
+        // Prefer formats we can convert, then the default's channel count and format
+        let default = &self.config;
+        let best = ranges.into_iter()
+            .filter_map(|range| range.try_with_sample_rate(cpal::SampleRate(sample_rate)))
+            .max_by_key(|config| (is_convertible(config.sample_format()),
+                                  config.channels() == default.channels(),
+                                  config.sample_format() == default.sample_format(),
+                                  config.channels()));
+        match best {
+            Some(config) => {
+                self.config = config;
+                Ok(())
+            },
+            None => Err(BlackboxError::Config(format!("{} can't record at {} Hz", self.name(), sample_rate))),
+        }
+    }
+
+    fn cover_channels(&mut self, needed: usize) {
+        if self.channels() >= needed {
+            return;
+        }
+        let ranges: Vec<cpal::SupportedStreamConfigRange> = match self.loopback {
+            true => self.device.supported_output_configs().map(|ranges| ranges.collect()),
+            false => self.device.supported_input_configs().map(|ranges| ranges.collect()),
+        }.unwrap_or_default();
+        match select_input_config(&self.config, &ranges, needed) {
+            Some(config) => {
+                status!("Switching {} to {} channels to cover channel {}", self.name(), config.channels(), needed - 1);
+                self.config = config;
+            },
+            None => eprintln!("Warning: {} has no config with {} channels, recording its default of {}",
+                              self.name(), needed, self.channels()),
+        }
+    }
+
+    fn prefer_formats(&mut self, formats: &[String]) {
+        let formats: Vec<SampleFormat> = formats.iter().filter_map(|name| sample_format_named(name)).collect();
+        let ranges: Vec<cpal::SupportedStreamConfigRange> = match self.loopback {
+            true => self.device.supported_output_configs().map(|ranges| ranges.collect()),
+            false => self.device.supported_input_configs().map(|ranges| ranges.collect()),
+        }.unwrap_or_default();
+        match select_preferred_format(&self.config, &ranges, &formats) {
+            Some(config) => {
+                if config.sample_format() != self.config.sample_format() {
+                    status!("Switching {} to {} samples", self.name(), config.sample_format());
+                }
+                self.config = config;
+            },
+            None => eprintln!("Warning: {} offers none of the preferred formats, recording {} samples",
+                              self.name(), self.config.sample_format()),
+        }
+    }
+
+    fn start(self: Box<Self>, capture: &Arc<Mutex<Capture>>) -> Result<Box<dyn ActiveStream>, BlackboxError> {
+        status!("Default input stream config: {:?}", self.config);
+        let capture = Arc::clone(capture);
+        let (stream, ()) = StreamThread::spawn(INPUT_THREAD_NAME, move || {
+            let stream = build_input_stream(&self.device, self.config, &capture)?;
+            stream.play().map_err(|e| BlackboxError::Stream(format!("Failed to play stream: {}", e)))?;
+            Ok((stream, ()))
+        })?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// When `MockBackend`'s first frame was captured, by the timestamps it
+/// gives with each batch.
+#[cfg(test)]
+pub const MOCK_CAPTURE_START: &str = "2024-06-26T09:05:01.123456+00:00";
+
+/// Plays `samples`, interleaved with `channels` channels, as if a device
+/// had delivered them. `start` feeds the whole buffer in callback-sized
+/// batches before returning, so a recording's contents don't depend on how
+/// long it runs. Each batch is timestamped as if the first were captured at
+/// `MOCK_CAPTURE_START`.
+#[cfg(test)]
+pub struct MockBackend {
+    pub sample_rate: u32,
+    /// Rates besides `sample_rate` the device can be switched to.
+    pub other_sample_rates: Vec<u32>,
+    pub channels: usize,
+    pub samples: Vec<i16>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    /// A device delivering `samples`, interleaved over `channels`, at
+    /// `sample_rate` only.
+    pub fn new(sample_rate: u32, channels: usize, samples: Vec<i16>) -> Self {
+        MockBackend { sample_rate, other_sample_rates: Vec::new(), channels, samples }
+    }
+}
+
+#[cfg(test)]
+impl AudioBackend for MockBackend {
+    fn open_input(&self, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError> {
+        let name = match selector {
+            InputSelector::Named(name) => name.clone(),
+            _ => "Mock Input".to_string(),
+        };
+        Ok(Box::new(MockInput {
+            name,
+            sample_rate: self.sample_rate,
+            other_sample_rates: self.other_sample_rates.clone(),
+            channels: self.channels,
+            samples: self.samples.clone(),
+        }))
+    }
+
+    fn start_monitor(&self, name: &str, _sample_rate: u32, _channels: usize)
+        -> Result<MonitorOutput, BlackboxError> {
+        Err(BlackboxError::DeviceNotFound(format!("No output device named {}", name)))
+    }
+}
+
+#[cfg(test)]
+struct MockInput {
+    name: String,
+    sample_rate: u32,
+    other_sample_rates: Vec<u32>,
+    channels: usize,
+    samples: Vec<i16>,
+}
+
+#[cfg(test)]
+struct MockStream;
+
+#[cfg(test)]
+impl ActiveStream for MockStream {}
+
+#[cfg(test)]
+impl InputDevice for MockInput {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn use_sample_rate(&mut self, sample_rate: u32) -> Result<(), BlackboxError> {
+        if sample_rate != self.sample_rate && !self.other_sample_rates.contains(&sample_rate) {
+            return Err(BlackboxError::Config(format!("{} can't record at {} Hz", self.name, sample_rate)));
+        }
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    fn cover_channels(&mut self, _needed: usize) {}
+
+    fn prefer_formats(&mut self, _formats: &[String]) {}
+
+    fn start(self: Box<Self>, capture: &Arc<Mutex<Capture>>) -> Result<Box<dyn ActiveStream>, BlackboxError> {
+        let start = DateTime::parse_from_rfc3339(MOCK_CAPTURE_START).unwrap().with_timezone(&Local);
+        for (i, batch) in self.samples.chunks(512 * self.channels).enumerate() {
+            let mut capture = capture.lock().unwrap();
+            capture.set_capture_time(start + TimeDelta::nanoseconds(i as i64 * 512 * 1_000_000_000 / self.sample_rate as i64));
+            capture.process(batch, |s| (s as i32) << 16);
+            capture.wait_for_writer();
+        }
+        Ok(Box::new(MockStream))
+    }
+}
+
+/// `Channel 0` to `Channel {channels - 1}`, numbered as in AUDIO_CHANNELS.
+pub fn generic_channel_labels(channels: usize) -> Vec<String> {
+    (0..channels).map(|channel| format!("Channel {}", channel)).collect()
+}
+
+/// The config among `ranges` best suited to recording channels below
+/// `needed`, if any has that many: preferring formats we can convert, then
+/// `current`'s sample rate, then the fewest channels, then `current`'s format.
+pub fn select_input_config(current: &cpal::SupportedStreamConfig, ranges: &[cpal::SupportedStreamConfigRange],
+                           needed: usize) -> Option<cpal::SupportedStreamConfig> {
+    ranges.iter()
+        .filter(|range| range.channels() as usize >= needed)
+        .map(|range| range.try_with_sample_rate(current.sample_rate())
+            .unwrap_or_else(|| range.with_max_sample_rate()))
+        .max_by_key(|config| (is_convertible(config.sample_format()),
+                              config.sample_rate() == current.sample_rate(),
+                              std::cmp::Reverse(config.channels()),
+                              config.sample_format() == current.sample_format()))
+}
+
+/// The config among `ranges` with `current`'s channel count and sample rate
+/// whose format comes first in `preferred`, if any is offered.
+pub fn select_preferred_format(current: &cpal::SupportedStreamConfig, ranges: &[cpal::SupportedStreamConfigRange],
+                               preferred: &[SampleFormat]) -> Option<cpal::SupportedStreamConfig> {
+    preferred.iter().find_map(|&format| {
+        ranges.iter()
+            .filter(|range| range.channels() == current.channels() && range.sample_format() == format)
+            .find_map(|range| range.try_with_sample_rate(current.sample_rate()))
+    })
+}
+
+/// The sample format called `name` in `PREFERRED_FORMATS`.
+fn sample_format_named(name: &str) -> Option<SampleFormat> {
+    match name {
+        "f32" => Some(SampleFormat::F32),
+        "i16" => Some(SampleFormat::I16),
+        "i32" => Some(SampleFormat::I32),
+        "u16" => Some(SampleFormat::U16),
+        _ => None,
+    }
+}
+
+/// Whether `build_input_stream` can convert samples of `format`.
+fn is_convertible(format: SampleFormat) -> bool {
+    matches!(format, SampleFormat::F32 | SampleFormat::I16 | SampleFormat::I32 | SampleFormat::U16)
+}
+
+/// Reports errors on the input stream, summarizing them when they repeat.
+fn stream_error_handler() -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let mut log = ThrottledLogger::new("stream errors", WARNING_INTERVAL);
+    move |err| log.warn(|| format!("An error occurred on the input audio stream: {}", err))
+}
+
+/// When the first frame of a callback's buffer was captured: the clock now,
+/// less how long the device says the buffer took to reach us.
+fn capture_time(info: &cpal::InputCallbackInfo) -> DateTime<Local> {
+    let timestamp = info.timestamp();
+    let latency = timestamp.callback.duration_since(&timestamp.capture).unwrap_or_default();
+    Local::now() - TimeDelta::from_std(latency).unwrap_or_default()
+}
+
+/// Builds (but doesn't start) an input stream that feeds `capture`,
+/// converting whatever sample format the device delivers.
+fn build_input_stream(device: &cpal::Device, input_config: cpal::SupportedStreamConfig,
+                                 capture: &Arc<Mutex<Capture>>) -> Result<cpal::Stream, BlackboxError> {
+    match input_config.sample_format() {
+        SampleFormat::F32 => {
+            let capture_clone = Arc::clone(capture);
+            device.build_input_stream(
+                &input_config.into(),
+                move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                    let mut capture = capture_clone.lock().unwrap();
+                    capture.set_capture_time(capture_time(info));
+                    capture.process_f32(data);
+                },
+                stream_error_handler(),
+                None, // No specific latency requirement
+            )
+        },
+        SampleFormat::I16 => {
+            let capture_clone = Arc::clone(capture);
+            device.build_input_stream(
+                &input_config.into(),
+                move |data: &[i16], info: &cpal::InputCallbackInfo| {
+                    let mut capture = capture_clone.lock().unwrap();
+                    capture.set_capture_time(capture_time(info));
+                    capture.process(data, |s| (s as i32) << 16);
+                },
+                stream_error_handler(),
+                None, // No specific latency requirement
+            )
+        },
+        SampleFormat::I32 => {
+            // WASAPI in exclusive mode commonly delivers 32-bit integers
+            let capture_clone = Arc::clone(capture);
+            device.build_input_stream(
+                &input_config.into(),
+                move |data: &[i32], info: &cpal::InputCallbackInfo| {
+                    let mut capture = capture_clone.lock().unwrap();
+                    capture.set_capture_time(capture_time(info));
+                    capture.process(data, |s| s);
+                },
+                stream_error_handler(),
+                None, // No specific latency requirement
+            )
+        },
+        SampleFormat::U16 => {
+            let capture_clone = Arc::clone(capture);
+            device.build_input_stream(
+                &input_config.into(),
+                move |data: &[u16], info: &cpal::InputCallbackInfo| {
+                    let mut capture = capture_clone.lock().unwrap();
+                    capture.set_capture_time(capture_time(info));
+                    capture.process(data, |s| ((s as i32) - 32768) << 16);
+                },
+                stream_error_handler(),
+                None, // No specific latency requirement
+            )
+        },
+        format => return Err(BlackboxError::Stream(format!("Unsupported sample format: {:?}", format))),
+    }.map_err(|e| BlackboxError::Stream(format!("Failed to build input stream: {}", e)))
+}
+
+/// The default input device of `host`.
+fn default_input_device(host: &cpal::Host) -> Result<cpal::Device, BlackboxError> {
+    host.default_input_device().ok_or_else(|| {
+        // Windows hides every microphone from apps that privacy settings block
+        let hint = if cfg!(target_os = "windows") {
+            " (check that microphone access is allowed for desktop apps in Privacy settings)"
+        } else {
+            ""
+        };
+        BlackboxError::DeviceNotFound(format!("No input device available{}", hint))
+    })
+}
+
+/// The device carrying what the machine is playing: the default output
+/// device itself under WASAPI, which cpal then records in loopback mode, or
+/// the monitor source PulseAudio and PipeWire list as an input on Linux.
+fn loopback_device(host: &cpal::Host) -> Result<cpal::Device, BlackboxError> {
+    if cfg!(target_os = "windows") {
+        return host.default_output_device()
+            .ok_or_else(|| BlackboxError::DeviceNotFound("No output device to record in loopback mode".to_string()));
+    }
+    if !cfg!(target_os = "linux") {
+        return Err(BlackboxError::Config("Loopback capture is not supported on this platform; \
+                                          route the output to a virtual input device and record that instead".to_string()));
+    }
+    let devices: Vec<cpal::Device> = host.input_devices()
+        .map_err(|e| BlackboxError::DeviceNotFound(format!("Failed to list input devices: {}", e)))?
+        .collect();
+    let names: Vec<String> = devices.iter().map(|device| device.name().unwrap_or_default()).collect();
+    let index = find_monitor_source(&names)?;
+    Ok(devices.into_iter().nth(index).unwrap())
+}
+
+/// The position of the first monitor source among input device `names`.
+fn find_monitor_source(names: &[String]) -> Result<usize, BlackboxError> {
+    names.iter()
+        .position(|name| name.to_lowercase().contains("monitor"))
+        .ok_or_else(|| BlackboxError::DeviceNotFound(format!(
+            "No monitor source to record in loopback mode (input devices: {})", names.join(", "))))
+}
+
+/// Finds the input device called `name` on `host`.
+fn find_input_device(host: &cpal::Host, name: &str) -> Result<cpal::Device, BlackboxError> {
+    let devices = host.input_devices()
+        .map_err(|e| BlackboxError::DeviceNotFound(format!("Failed to list input devices: {}", e)))?;
+    devices.into_iter()
+        .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+        .ok_or_else(|| BlackboxError::DeviceNotFound(format!("Input device not found: {}", name)))
+}
+
+/// Returns the cpal host named by `name` (e.g. "alsa" or "jack"), or the
+/// platform default for "auto". Never falls back silently: asking for a host
+/// that isn't compiled in or can't be opened is an error.
+fn select_host(name: &str) -> Result<cpal::Host, BlackboxError> {
+    if name.eq_ignore_ascii_case("auto") {
+        return Ok(cpal::default_host());
+    }
+    let id = cpal::ALL_HOSTS.iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let compiled: Vec<&str> = cpal::ALL_HOSTS.iter().map(|id| id.name()).collect();
+            BlackboxError::Config(format!("Audio host {} is not supported by this build (available: {})",
+                                          name, compiled.join(", ")))
+        })?;
+    cpal::host_from_id(*id)
+        .map_err(|e| BlackboxError::DeviceNotFound(format!("Audio host {} is unavailable: {}", id.name(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_input_config_covers_requested_channels() {
+        let range = |channels, max_rate, format| cpal::SupportedStreamConfigRange::new(
+            channels, cpal::SampleRate(8000), cpal::SampleRate(max_rate), cpal::SupportedBufferSize::Unknown, format);
+        let default = range(1, 48000, SampleFormat::I16).with_sample_rate(cpal::SampleRate(48000));
+        let ranges = [
+            range(1, 48000, SampleFormat::I16),
+            range(8, 48000, SampleFormat::I16),
+            range(2, 44100, SampleFormat::I16),
+            range(2, 48000, SampleFormat::F32),
+            range(4, 48000, SampleFormat::I16),
+        ];
+
+        // Two channels at the default's rate, even in another format, over
+        // a rate change or more channels than needed
+        let config = select_input_config(&default, &ranges, 2).unwrap();
+        assert_eq!((config.channels(), config.sample_rate().0, config.sample_format()), (2, 48000, SampleFormat::F32));
+
+        let config = select_input_config(&default, &ranges, 3).unwrap();
+        assert_eq!((config.channels(), config.sample_rate().0), (4, 48000));
+
+        assert!(select_input_config(&default, &ranges, 9).is_none());
+    }
+
+    #[test]
+    fn test_select_preferred_format() {
+        let range = |channels, format| cpal::SupportedStreamConfigRange::new(
+            channels, cpal::SampleRate(8000), cpal::SampleRate(48000), cpal::SupportedBufferSize::Unknown, format);
+        // A device offering U16 by default, and F32 and I16 too
+        let default = range(2, SampleFormat::U16).with_sample_rate(cpal::SampleRate(48000));
+        let ranges = [
+            range(2, SampleFormat::U16),
+            range(2, SampleFormat::I16),
+            range(4, SampleFormat::F32),
+            range(2, SampleFormat::F32),
+        ];
+
+        let preferred = ["f32", "i16", "u16"].map(|name| sample_format_named(name).unwrap());
+        let config = select_preferred_format(&default, &ranges, &preferred).unwrap();
+        assert_eq!((config.channels(), config.sample_rate().0, config.sample_format()), (2, 48000, SampleFormat::F32));
+
+        let config = select_preferred_format(&default, &ranges[..2], &preferred).unwrap();
+        assert_eq!(config.sample_format(), SampleFormat::I16);
+
+        assert!(select_preferred_format(&default, &ranges, &[SampleFormat::I32]).is_none());
+    }
+
+    #[test]
+    fn test_stream_holder_thread_is_named() {
+        let (stream, name) = StreamThread::spawn(INPUT_THREAD_NAME, || {
+            Ok(((), thread::current().name().map(str::to_string)))
+        }).unwrap();
+        assert_eq!(name.as_deref(), Some("blackbox-input"));
+        drop(stream);
+
+        let failed = StreamThread::spawn::<(), (), _>(INPUT_THREAD_NAME, || {
+            Err(BlackboxError::Stream("no stream".to_string()))
+        });
+        assert!(matches!(failed, Err(BlackboxError::Stream(_))));
+    }
+
+    #[test]
+    fn test_select_unknown_host_is_an_error() {
+        match select_host("nonexistent") {
+            Err(BlackboxError::Config(msg)) => {
+                assert!(msg.contains("nonexistent"));
+                assert!(msg.contains("available:"));
+            },
+            _ => panic!("expected a config error"),
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_wasapi_devices_can_be_listed() {
+        let host = select_host("wasapi").unwrap();
+        for device in host.input_devices().unwrap() {
+            assert!(device.name().is_ok());
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_wasapi_is_windows_only() {
+        assert!(matches!(select_host("wasapi"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_loopback_without_monitor_source_is_an_error() {
+        let names = vec!["default".to_string(), "Monitor of Built-in Audio Analog Stereo".to_string()];
+        assert_eq!(find_monitor_source(&names).unwrap(), 1);
+
+        match find_monitor_source(&["default".to_string(), "hw:0,0".to_string()]) {
+            Err(BlackboxError::DeviceNotFound(msg)) => assert!(msg.contains("loopback")),
+            _ => panic!("expected a device error"),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_loopback_is_unsupported_on_macos() {
+        assert!(matches!(loopback_device(&cpal::default_host()), Err(BlackboxError::Config(_))));
+    }
+}
@@ -0,0 +1,150 @@
+//! `--benchmark`: how many channels this machine can record without
+//! falling behind.
+//!
+//! Synthesized audio is pushed through the same capture and WAV writing path
+//! a recording uses, into a scratch file in the output directory, and timed.
+//! No audio device is involved, so the result measures the CPU and disk.
+
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::capture::{Capture, INTERMEDIATE_BUFFER_SIZE};
+use crate::config::Config;
+use crate::error::BlackboxError;
+use crate::processor::WavProcessor;
+
+/// The rate the benchmark synthesizes audio at.
+pub const BENCHMARK_SAMPLE_RATE: u32 = 48000;
+
+/// How much faster than real time a channel count must be written to count
+/// as sustainable, leaving room for everything else the machine is doing.
+const REQUIRED_HEADROOM: f64 = 2.0;
+
+/// How much audio is written at each channel count, in seconds.
+const SECONDS_PER_STEP: f64 = 2.0;
+
+/// The most channels tried.
+const MAX_CHANNELS: usize = 256;
+
+/// How fast one channel count was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkResult {
+    pub channels: usize,
+    /// Seconds of audio written per second of wall-clock time.
+    pub realtime_factor: f64,
+}
+
+impl BenchmarkResult {
+    pub fn is_sustainable(&self) -> bool {
+        self.realtime_factor >= REQUIRED_HEADROOM
+    }
+}
+
+/// Writes `seconds` of synthesized audio with `channels` channels to a
+/// scratch file in `dir`, deletes it, and returns how many times faster than
+/// real time that went.
+pub fn measure(dir: &Path, channels: usize, sample_rate: u32, seconds: f64,
+               buffer_bytes: usize) -> Result<f64, BlackboxError> {
+    let path = dir.join(".blackbox-benchmark.wav");
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    // One device callback's worth of noise, fed over and over
+    let frames_per_callback = INTERMEDIATE_BUFFER_SIZE;
+    let callback: Vec<i16> = (0..frames_per_callback * channels)
+        .map(|i| ((i as u32).wrapping_mul(2_654_435_761) >> 16) as i16)
+        .collect();
+    let callbacks = (seconds * sample_rate as f64 / frames_per_callback as f64).ceil() as usize;
+    let selected: Vec<usize> = (0..channels).collect();
+
+    let started = Instant::now();
+    let result = (|| {
+        let processor = WavProcessor::create(path.to_str().unwrap_or_default(), spec, buffer_bytes)?;
+        let mut capture = Capture::new(&selected, channels, Box::new(processor), false);
+        for _ in 0..callbacks {
+            capture.process(&callback, |s| (s as i32) << 16);
+        }
+        capture.finish()
+    })();
+    let elapsed = started.elapsed().as_secs_f64();
+    let _ = fs::remove_file(&path);
+    result?;
+
+    let audio_seconds = (callbacks * frames_per_callback) as f64 / sample_rate as f64;
+    Ok(audio_seconds / elapsed.max(f64::EPSILON))
+}
+
+/// Measures 1, 2, 4, ... channels up to `max_channels`, stopping after the
+/// first count that isn't sustainable.
+pub fn run_steps(dir: &Path, sample_rate: u32, seconds: f64, max_channels: usize,
+                 buffer_bytes: usize) -> Result<Vec<BenchmarkResult>, BlackboxError> {
+    let mut results = Vec::new();
+    let mut channels = 1;
+    while channels <= max_channels {
+        let realtime_factor = measure(dir, channels, sample_rate, seconds, buffer_bytes)?;
+        let result = BenchmarkResult { channels, realtime_factor };
+        let sustainable = result.is_sustainable();
+        results.push(result);
+        if !sustainable {
+            break;
+        }
+        channels *= 2;
+    }
+    Ok(results)
+}
+
+/// The most channels among `results` that were sustainable.
+pub fn recommended_channels(results: &[BenchmarkResult]) -> Option<usize> {
+    results.iter().filter(|r| r.is_sustainable()).map(|r| r.channels).max()
+}
+
+/// Runs the benchmark into the configured output directory and prints a
+/// table of the results and a recommendation.
+pub fn run_benchmark(config: &Config) -> Result<(), BlackboxError> {
+    fs::create_dir_all(&config.output_dir)?;
+    println!("Writing {} Hz 16-bit audio to {}", BENCHMARK_SAMPLE_RATE, config.output_dir.display());
+    println!("{:>8}  {:>10}  {:>8}", "Channels", "x realtime", "MB/s");
+    let results = run_steps(&config.output_dir, BENCHMARK_SAMPLE_RATE, SECONDS_PER_STEP, MAX_CHANNELS,
+                            config.write_buffer_bytes)?;
+    for result in &results {
+        let megabytes_per_second = result.realtime_factor * result.channels as f64
+            * BENCHMARK_SAMPLE_RATE as f64 * 2.0 / 1_000_000.0;
+        println!("{:>8}  {:>10.1}  {:>8.1}{}", result.channels, result.realtime_factor, megabytes_per_second,
+                 if result.is_sustainable() { "" } else { "  (too slow)" });
+    }
+    match recommended_channels(&results) {
+        Some(channels) if channels == MAX_CHANNELS => {
+            println!("Recommendation: {} channels or more can be recorded at {} Hz", channels, BENCHMARK_SAMPLE_RATE)
+        },
+        Some(channels) => {
+            println!("Recommendation: record at most {} channels at {} Hz", channels, BENCHMARK_SAMPLE_RATE)
+        },
+        None => println!("Recommendation: this machine can't keep up with even one channel at {} Hz",
+                         BENCHMARK_SAMPLE_RATE),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_benchmark_steps() {
+        let temp_dir = tempdir().unwrap();
+        let results = run_steps(temp_dir.path(), 8000, 0.1, 4, 65536).unwrap();
+
+        let channels: Vec<usize> = results.iter().map(|r| r.channels).collect();
+        assert!(channels.starts_with(&[1]));
+        assert!(channels.windows(2).all(|w| w[1] == w[0] * 2));
+        // A trivial load is far faster than real time
+        assert!(results[0].realtime_factor > 1.0);
+        assert!(recommended_channels(&results).unwrap_or(0) <= 4);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,990 @@
+//! The path from device buffers to the recording: channel selection,
+//! batching, and the observers that watch the signal on its way through.
+
+use chrono::{DateTime, Local, TimeDelta};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::dither::Dither;
+use crate::error::BlackboxError;
+use crate::metadata::Marker;
+use crate::monitor::MonitorBuffer;
+use crate::processor::{full_scale, AudioProcessor};
+use crate::throttle::{ThrottledLogger, WARNING_INTERVAL};
+
+/// How far above the lowest real-time priority the audio thread asks to run,
+/// leaving room above it for the audio server's own threads.
+#[cfg(unix)]
+const REALTIME_PRIORITY_OFFSET: i32 = 10;
+
+/// How many samples are batched up before being handed to the processor,
+/// unless `batch_samples` says otherwise.
+pub const INTERMEDIATE_BUFFER_SIZE: usize = 512;
+
+/// The name of the thread that writes a recording's files, for `top -H` and
+/// debuggers.
+pub const WRITER_THREAD_NAME: &str = "blackbox-writer";
+
+/// How many seconds of audio can queue up for the writer thread, e.g. while
+/// it retries creating a file, before batches are dropped.
+pub const WRITER_QUEUE_SECONDS: u64 = 5;
+
+/// Everything the input stream callback needs to turn device buffers into
+/// the recording: the selected channels, the processor, and the observers
+/// that watch the signal on its way through.
+pub struct Capture {
+    channels: Vec<usize>,
+    total_channels: usize,
+    debug: bool,
+    writer: Option<Writer>,
+    /// Batches the writer thread can hold, or `None` to write on the
+    /// calling thread.
+    writer_queue: Option<usize>,
+    buffer: Vec<i32>,
+    /// Samples to batch up before each write to the processor.
+    batch_samples: usize,
+    /// Bits per sample the recording is kept at.
+    bits_per_sample: u16,
+    pub dead_monitor: DeadChannelMonitor,
+    pub drift_monitor: DriftMonitor,
+    pub monitor: Option<Arc<Mutex<MonitorBuffer>>>,
+    /// Dithers float input down to `bits_per_sample` instead of truncating it.
+    pub dither: Option<Dither>,
+    monitor_frame: Vec<f32>,
+    /// The largest absolute sample on each channel since `take_peaks`.
+    peaks: Vec<u32>,
+    /// Non-finite float samples replaced with silence.
+    bad_samples: u64,
+    /// Batches that failed to write, or were dropped because the writer
+    /// thread had fallen behind.
+    write_errors: Arc<AtomicU64>,
+    /// Reports batches dropped because the writer thread's queue was full.
+    dropped_log: ThrottledLogger,
+    /// Reports device buffers that end partway through a frame.
+    short_frame_log: ThrottledLogger,
+    /// Stop recording once `write_errors` passes this.
+    max_write_errors: Option<u64>,
+    /// Set when the recording was stopped for too many write errors.
+    aborted: Arc<AtomicBool>,
+    frames: u64,
+    pub markers: Vec<Marker>,
+    /// Metering the input without writing it, until `start_writing`.
+    armed: bool,
+    /// Frames still to drop from the start of the stream.
+    warmup_frames: u64,
+    /// Ask for real-time scheduling for the thread delivering the audio, on
+    /// the first callback, and for the writer thread as it starts.
+    realtime_priority: bool,
+    priority_requested: bool,
+    /// When the first frame of the buffer being handed to `process` was
+    /// captured, as reported by the device.
+    capture_time: Option<DateTime<Local>>,
+    /// When the first recorded frame was captured.
+    start_time: Option<DateTime<Local>>,
+}
+
+impl Capture {
+    pub fn new(channels: &[usize], total_channels: usize, processor: Box<dyn AudioProcessor>, debug: bool) -> Self {
+        let mut capture = Capture::armed(channels, total_channels, debug);
+        capture.writer = Some(Writer::Inline(capture.batch_writer(processor)));
+        capture.armed = false;
+        capture
+    }
+
+    /// A capture that meters and monitors the input but writes nothing until
+    /// `start_writing` gives it a processor.
+    pub fn armed(channels: &[usize], total_channels: usize, debug: bool) -> Self {
+        Capture {
+            channels: channels.to_vec(),
+            total_channels,
+            debug,
+            writer: None,
+            writer_queue: None,
+            buffer: Vec::with_capacity(INTERMEDIATE_BUFFER_SIZE),
+            batch_samples: INTERMEDIATE_BUFFER_SIZE,
+            bits_per_sample: 16,
+            dead_monitor: DeadChannelMonitor::new(channels, 0, 0),
+            drift_monitor: DriftMonitor::new(0, 0, false),
+            monitor: None,
+            dither: None,
+            monitor_frame: Vec::with_capacity(channels.len()),
+            peaks: vec![0; channels.len()],
+            bad_samples: 0,
+            write_errors: Arc::new(AtomicU64::new(0)),
+            dropped_log: ThrottledLogger::new("dropped batches", WARNING_INTERVAL),
+            short_frame_log: ThrottledLogger::new("short frames", WARNING_INTERVAL),
+            max_write_errors: None,
+            aborted: Arc::new(AtomicBool::new(false)),
+            frames: 0,
+            markers: Vec::new(),
+            armed: true,
+            warmup_frames: 0,
+            realtime_priority: false,
+            priority_requested: false,
+            capture_time: None,
+            start_time: None,
+        }
+    }
+
+    fn batch_writer(&self, processor: Box<dyn AudioProcessor>) -> BatchWriter {
+        BatchWriter {
+            processor: Some(processor),
+            write_errors: Arc::clone(&self.write_errors),
+            write_error_log: ThrottledLogger::new("write errors", WARNING_INTERVAL),
+            max_write_errors: self.max_write_errors,
+            aborted: Arc::clone(&self.aborted),
+            finished: Vec::new(),
+        }
+    }
+
+    /// Starts writing to `processor`, counting frames and markers from here.
+    pub fn start_writing(&mut self, processor: Box<dyn AudioProcessor>) -> Result<(), BlackboxError> {
+        let writer = self.batch_writer(processor);
+        self.writer = Some(match self.writer_queue {
+            Some(batches) => Writer::Thread(WriterThread::spawn(writer, batches, self.realtime_priority)?),
+            None => Writer::Inline(writer),
+        });
+        self.armed = false;
+        self.frames = 0;
+        self.markers.clear();
+        self.buffer.clear();
+        self.start_time = None;
+        Ok(())
+    }
+
+    /// Hands samples to the processor in batches of at least `batch_samples`
+    /// rather than `INTERMEDIATE_BUFFER_SIZE`.
+    pub fn with_batch_samples(mut self, batch_samples: usize) -> Self {
+        self.batch_samples = batch_samples;
+        self.buffer = Vec::with_capacity(batch_samples);
+        self
+    }
+
+    /// Records `bits_per_sample` samples, 16 or 24, rather than 16-bit ones.
+    /// Devices that deliver fewer bits are padded out.
+    pub fn with_bits_per_sample(mut self, bits_per_sample: u16) -> Self {
+        self.bits_per_sample = bits_per_sample;
+        self
+    }
+
+    /// Asks for real-time scheduling for the threads receiving and writing
+    /// the audio, so they keep up when the machine is busy. Set it before
+    /// `with_writer_thread`.
+    pub fn with_realtime_priority(mut self, realtime_priority: bool) -> Self {
+        self.realtime_priority = realtime_priority;
+        self
+    }
+
+    /// Drops the first `frames` frames the device delivers, before they are
+    /// metered, monitored or written.
+    pub fn with_warmup_discard(mut self, frames: u64) -> Self {
+        self.warmup_frames = frames;
+        self
+    }
+
+    /// Gives up on the recording once more than `max` batches have failed to
+    /// write: what was written is finalized and `aborted` is set.
+    pub fn with_max_write_errors(mut self, max: Option<u64>, aborted: Arc<AtomicBool>) -> Self {
+        self.max_write_errors = max;
+        self.aborted = aborted;
+        if let Some(Writer::Inline(ref mut writer)) = self.writer {
+            writer.max_write_errors = max;
+            writer.aborted = Arc::clone(&self.aborted);
+        }
+        self
+    }
+
+    /// Writes on a thread of its own, named `WRITER_THREAD_NAME`, rather than
+    /// the one calling `process`, so that creating and retrying files at a
+    /// rotation, pruning old recordings and flushing to disk never hold up
+    /// the device. Up to `queue_batches` batches wait for it; while the
+    /// queue is full, further batches are dropped and counted as write
+    /// errors.
+    pub fn with_writer_thread(mut self, queue_batches: usize) -> Result<Self, BlackboxError> {
+        self.writer_queue = Some(queue_batches.max(1));
+        if let Some(Writer::Inline(writer)) = self.writer.take() {
+            self.writer = Some(Writer::Thread(WriterThread::spawn(writer, queue_batches.max(1), self.realtime_priority)?));
+        }
+        Ok(self)
+    }
+
+    /// Whether samples are being written: there is a processor and the
+    /// recording hasn't been aborted.
+    fn writing(&self) -> bool {
+        match self.writer {
+            Some(Writer::Inline(ref writer)) => writer.processor.is_some(),
+            Some(Writer::Thread(_)) => !self.aborted.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    /// Waits until the writer thread has written every batch queued so far.
+    #[cfg(test)]
+    pub fn wait_for_writer(&self) {
+        if let Some(Writer::Thread(ref thread)) = self.writer {
+            while thread.pending.load(Ordering::Relaxed) > 0 && !thread.thread.is_finished() {
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+    }
+
+    /// How many frames have been recorded.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// When the first recorded frame was captured: from the device's own
+    /// timestamp if it gave one with the buffer, otherwise from the clock as
+    /// the buffer arrived. `None` until a frame is recorded.
+    pub fn start_time(&self) -> Option<DateTime<Local>> {
+        self.start_time
+    }
+
+    /// Tells the next call to `process` when the first frame of its buffer
+    /// was captured, so the recording's start can be placed to the sample.
+    pub fn set_capture_time(&mut self, time: DateTime<Local>) {
+        self.capture_time = Some(time);
+    }
+
+    /// Marks the current position in the recording and returns it in frames.
+    pub fn add_marker(&mut self, label: &str) -> u64 {
+        self.markers.push(Marker { position: self.frames, label: label.to_string() });
+        self.frames
+    }
+
+    /// Handles one interleaved buffer from the device. `to_i32` converts a
+    /// device sample to a 32-bit value, which keeps its top
+    /// `bits_per_sample` bits.
+    pub fn process<T: Copy>(&mut self, data: &[T], mut to_i32: impl FnMut(T) -> i32) {
+        let shift = 32 - self.bits_per_sample;
+        self.process_samples(data, |s| to_i32(s) >> shift);
+    }
+
+    /// `process`, with `to_sample` converting straight to `bits_per_sample`.
+    fn process_samples<T: Copy>(&mut self, data: &[T], mut to_sample: impl FnMut(T) -> i32) {
+        if self.debug {
+            status!("Received data with length: {}", data.len());
+        }
+        if self.realtime_priority && !self.priority_requested {
+            self.priority_requested = true;
+            match raise_thread_priority() {
+                Ok(()) => status!("Running the audio thread at real-time priority"),
+                Err(e) => eprintln!("Warning: couldn't give the audio thread real-time priority: {}", e),
+            }
+        }
+        let monitor = self.monitor.clone();
+        let mut monitor_lock = monitor.as_ref().map(|m| m.lock().unwrap());
+        let scale = full_scale(self.bits_per_sample) as f32;
+        let correction = self.drift_monitor.observe((data.len() / self.total_channels) as u64, Instant::now());
+        let capture_time = self.capture_time.take();
+        if self.writing() || self.armed {
+            let mut skip = 0;
+            match correction {
+                DriftCorrection::Insert(frames) if !self.armed => {
+                    for _ in 0..frames {
+                        self.buffer.extend(std::iter::repeat_n(0, self.channels.len()));
+                        self.frames += 1;
+                        if self.buffer.len() >= self.batch_samples {
+                            self.write_batch();
+                        }
+                    }
+                },
+                DriftCorrection::Drop(frames) => skip = frames as usize,
+                _ => {},
+            }
+            for (index, frame) in data.chunks(self.total_channels).enumerate().skip(skip) {
+                if !self.writing() && !self.armed {
+                    break;
+                }
+                if self.warmup_frames > 0 {
+                    self.warmup_frames -= 1;
+                    continue;
+                }
+                if frame.len() == self.total_channels {
+                    let start = self.buffer.len();
+                    self.buffer.extend(self.channels.iter().map(|&channel| to_sample(frame[channel])));
+                    let selected = &self.buffer[start..];
+                    self.dead_monitor.observe(selected);
+                    for (peak, &sample) in self.peaks.iter_mut().zip(selected) {
+                        *peak = (*peak).max(sample.unsigned_abs());
+                    }
+                    if let Some(ref mut monitor) = monitor_lock {
+                        self.monitor_frame.clear();
+                        self.monitor_frame.extend(selected.iter().map(|&s| s as f32 / scale));
+                        monitor.push_frame(&self.monitor_frame);
+                    }
+                    if self.armed {
+                        self.buffer.truncate(start);
+                        continue;
+                    }
+                    if self.start_time.is_none() {
+                        let sample_rate = self.drift_monitor.sample_rate().max(1) as i64;
+                        let offset = TimeDelta::nanoseconds(index as i64 * 1_000_000_000 / sample_rate);
+                        self.start_time = Some(capture_time.unwrap_or_else(Local::now) + offset);
+                    }
+                    self.frames += 1;
+                    if self.buffer.len() >= self.batch_samples {
+                        self.write_batch();
+                    }
+                } else {
+                    let total_channels = self.total_channels;
+                    self.short_frame_log.warn(|| format!("Buffer too small: expected {} channels, found {}",
+                                                         total_channels, frame.len()));
+                }
+            }
+        }
+    }
+
+    /// Hands the batched samples to the writer: written straight away, or
+    /// queued for the writer thread. A batch the queue has no room for is
+    /// dropped and counted as a write error.
+    fn write_batch(&mut self) {
+        match self.writer {
+            Some(Writer::Inline(ref mut writer)) => writer.write(&self.buffer),
+            Some(Writer::Thread(ref thread)) => match thread.batches.try_send(std::mem::take(&mut self.buffer)) {
+                Ok(()) => {
+                    thread.pending.fetch_add(1, Ordering::Relaxed);
+                    // Emptied batches come back from the writer, so this
+                    // only allocates until there are enough in circulation
+                    self.buffer = thread.spare.try_recv().unwrap_or_else(|_| Vec::with_capacity(self.batch_samples));
+                },
+                Err(TrySendError::Full(batch)) | Err(TrySendError::Disconnected(batch)) => {
+                    self.buffer = batch;
+                    self.write_errors.fetch_add(1, Ordering::Relaxed);
+                    self.dropped_log.warn(|| "Warning: the writer has fallen behind, dropping audio".to_string());
+                },
+            },
+            None => {},
+        }
+        self.buffer.clear();
+    }
+
+    /// How many batches have failed to write.
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// Handles one interleaved buffer of float samples, dithered down to
+    /// `bits_per_sample` if `dither` is set and truncated otherwise. NaN and
+    /// infinite samples, which a faulty driver can deliver, are recorded as
+    /// silence and counted in `bad_samples`.
+    pub fn process_f32(&mut self, data: &[f32]) {
+        let mut bad_samples = 0;
+        let mut dither = self.dither.take();
+        let bits_per_sample = self.bits_per_sample;
+        let max = full_scale(bits_per_sample) as f64;
+        self.process_samples(data, |s| match (s.is_finite(), dither.as_mut()) {
+            (false, _) => {
+                bad_samples += 1;
+                0
+            },
+            (true, Some(dither)) => dither.quantize(s, bits_per_sample),
+            (true, None) => (s as f64 * max).clamp(-max - 1.0, max) as i32,
+        });
+        self.dither = dither;
+        self.bad_samples += bad_samples;
+    }
+
+    /// How many NaN or infinite samples have been replaced with silence.
+    pub fn bad_samples(&self) -> u64 {
+        self.bad_samples
+    }
+
+    /// The peak level of each channel since the last call, as a fraction of
+    /// full scale, resetting them for the next.
+    pub fn take_peaks(&mut self) -> Vec<f64> {
+        let scale = full_scale(self.bits_per_sample) as f64 + 1.0;
+        self.peaks.iter_mut()
+            .map(|peak| std::mem::take(peak) as f64 / scale)
+            .collect()
+    }
+
+    /// Writes out whatever is still buffered, finalizes the processor, and
+    /// returns the paths of the files it wrote, waiting for the writer
+    /// thread to catch up first.
+    pub fn finish(&mut self) -> Result<Vec<String>, BlackboxError> {
+        for log in [&mut self.dropped_log, &mut self.short_frame_log, &mut self.drift_monitor.drift_log] {
+            if let Some(summary) = log.flush() {
+                eprintln!("{}", summary);
+            }
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        match self.writer.take() {
+            Some(Writer::Inline(mut writer)) => writer.finish(&rest),
+            Some(Writer::Thread(thread)) => thread.finish(rest),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Where `Capture` sends its batches.
+enum Writer {
+    /// Written by the thread calling `process`.
+    Inline(BatchWriter),
+    Thread(WriterThread),
+}
+
+/// Writes batches to the processor, counting the ones that fail and
+/// aborting the recording once there are too many.
+struct BatchWriter {
+    processor: Option<Box<dyn AudioProcessor>>,
+    write_errors: Arc<AtomicU64>,
+    write_error_log: ThrottledLogger,
+    max_write_errors: Option<u64>,
+    aborted: Arc<AtomicBool>,
+    /// Files finalized when the recording was aborted, for `finish`.
+    finished: Vec<String>,
+}
+
+impl BatchWriter {
+    fn write(&mut self, samples: &[i32]) {
+        let Some(ref mut processor) = self.processor else {
+            return;
+        };
+        if let Err(e) = processor.write_samples(samples) {
+            self.write_error_log.warn(|| format!("Failed to write samples: {:?}", e));
+            self.write_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        // Batches dropped before reaching the writer count as well
+        let write_errors = self.write_errors.load(Ordering::Relaxed);
+        if self.max_write_errors.is_some_and(|max| write_errors > max) {
+            eprintln!("Fatal: {} write errors, stopping the recording", write_errors);
+            match processor.finalize() {
+                Ok(files) => self.finished = files,
+                Err(e) => eprintln!("Failed to finalize the recording: {}", e),
+            }
+            self.processor = None;
+            self.aborted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes `rest` and finalizes the processor, returning its files, or
+    /// the files finalized when the recording was aborted.
+    fn finish(&mut self, rest: &[i32]) -> Result<Vec<String>, BlackboxError> {
+        if let Some(summary) = self.write_error_log.flush() {
+            eprintln!("{}", summary);
+        }
+        match self.processor.take() {
+            Some(mut processor) => {
+                if !rest.is_empty() {
+                    processor.write_samples(rest)?;
+                }
+                processor.finalize()
+            },
+            None => Ok(std::mem::take(&mut self.finished)),
+        }
+    }
+}
+
+/// A `BatchWriter` running on a thread of its own, fed through a bounded
+/// queue so the audio callback never waits on the disk.
+struct WriterThread {
+    batches: SyncSender<Vec<i32>>,
+    /// Emptied batches handed back for reuse.
+    spare: Receiver<Vec<i32>>,
+    /// Batches queued but not yet written.
+    pending: Arc<AtomicUsize>,
+    thread: JoinHandle<Result<Vec<String>, BlackboxError>>,
+}
+
+impl WriterThread {
+    fn spawn(mut writer: BatchWriter, queue_batches: usize, realtime_priority: bool) -> Result<Self, BlackboxError> {
+        let (batches, queued) = mpsc::sync_channel::<Vec<i32>>(queue_batches);
+        let (returned, spare) = mpsc::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let written = Arc::clone(&pending);
+        let thread = thread::Builder::new()
+            .name(WRITER_THREAD_NAME.to_string())
+            .spawn(move || {
+                if realtime_priority {
+                    match raise_thread_priority() {
+                        Ok(()) => status!("Running the writer thread at real-time priority"),
+                        Err(e) => eprintln!("Warning: couldn't give the writer thread real-time priority: {}", e),
+                    }
+                }
+                for mut batch in queued {
+                    writer.write(&batch);
+                    written.fetch_sub(1, Ordering::Relaxed);
+                    batch.clear();
+                    let _ = returned.send(batch);
+                }
+                writer.finish(&[])
+            })?;
+        Ok(WriterThread { batches, spare, pending, thread })
+    }
+
+    /// Queues `rest`, waiting for room if need be, then waits for the
+    /// thread to write everything and finalize.
+    fn finish(self, rest: Vec<i32>) -> Result<Vec<String>, BlackboxError> {
+        if !rest.is_empty() {
+            let _ = self.batches.send(rest);
+        }
+        drop(self.batches);
+        self.thread.join()
+            .unwrap_or_else(|_| Err(BlackboxError::Stream("The writer thread panicked".to_string())))
+    }
+}
+
+/// Switches the calling thread to real-time (`SCHED_FIFO`) scheduling, which
+/// usually needs root or an `rtprio` limit.
+#[cfg(unix)]
+pub fn raise_thread_priority() -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) } + REALTIME_PRIORITY_OFFSET,
+    };
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn raise_thread_priority() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "real-time priority is not available on this platform"))
+}
+
+/// Tracks how long each recorded channel has been flat-zero so that a pulled
+/// cable is reported while the recording is still running. Time is counted in
+/// frames rather than wall-clock time, so the timeout follows the audio itself.
+pub struct DeadChannelMonitor {
+    channels: Vec<usize>,
+    timeout_secs: u64,
+    timeout_frames: u64,
+    zero_frames: Vec<u64>,
+    warned: Vec<bool>,
+}
+
+impl DeadChannelMonitor {
+    /// A `timeout_secs` of zero disables the monitor.
+    pub fn new(channels: &[usize], timeout_secs: u64, sample_rate: u32) -> Self {
+        DeadChannelMonitor {
+            channels: channels.to_vec(),
+            timeout_secs,
+            timeout_frames: timeout_secs * sample_rate as u64,
+            zero_frames: vec![0; channels.len()],
+            warned: vec![false; channels.len()],
+        }
+    }
+
+    /// Feeds one frame, holding one sample per monitored channel in order.
+    pub fn observe(&mut self, samples: &[i32]) {
+        if self.timeout_frames == 0 {
+            return;
+        }
+        for (i, &sample) in samples.iter().enumerate().take(self.channels.len()) {
+            if sample != 0 {
+                if self.warned[i] {
+                    eprintln!("Channel {} is receiving signal again", self.channels[i]);
+                }
+                self.zero_frames[i] = 0;
+                self.warned[i] = false;
+                continue;
+            }
+            self.zero_frames[i] += 1;
+            if !self.warned[i] && self.zero_frames[i] >= self.timeout_frames {
+                eprintln!("Warning: channel {} has been silent for {} seconds; check the input connection",
+                          self.channels[i], self.timeout_secs);
+                self.warned[i] = true;
+            }
+        }
+    }
+
+    pub fn dead_channels(&self) -> Vec<usize> {
+        self.channels.iter()
+            .zip(&self.warned)
+            .filter(|(_, &warned)| warned)
+            .map(|(&channel, _)| channel)
+            .collect()
+    }
+}
+
+/// How `DriftMonitor` asks for the recording to be brought back in line
+/// with the clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriftCorrection {
+    None,
+    /// Insert this many frames of silence before the buffer, for audio the
+    /// device dropped.
+    Insert(u64),
+    /// Drop this many frames from the start of the buffer.
+    Drop(u64),
+}
+
+/// Compares the frames a device delivers with how many the wall clock says
+/// it should have, to catch dropped buffers (xruns) and devices running at
+/// a slightly wrong rate, which would leave a long recording out of sync.
+/// Each time the two are further apart than the threshold, it's counted and
+/// reported, and counting starts again from there.
+pub struct DriftMonitor {
+    sample_rate: u32,
+    threshold_frames: u64,
+    resync: bool,
+    start: Option<Instant>,
+    frames_since_start: u64,
+    xruns: u64,
+    /// Frames still to drop, or insert if negative, to make up for drift
+    /// already found. At most a buffer's worth is corrected per buffer, so a
+    /// long stall doesn't stop the callback to write minutes of silence.
+    owed: i64,
+    drift_log: ThrottledLogger,
+}
+
+impl DriftMonitor {
+    /// A `threshold_ms` of zero disables the monitor. With `resync` set,
+    /// each drift found is corrected by inserting or dropping frames.
+    pub fn new(sample_rate: u32, threshold_ms: u64, resync: bool) -> Self {
+        DriftMonitor {
+            sample_rate,
+            threshold_frames: threshold_ms * sample_rate as u64 / 1000,
+            resync,
+            start: None,
+            frames_since_start: 0,
+            xruns: 0,
+            owed: 0,
+            drift_log: ThrottledLogger::new("drift warnings", WARNING_INTERVAL),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Feeds the size of a buffer that arrived at `now`.
+    pub fn observe(&mut self, frames: u64, now: Instant) -> DriftCorrection {
+        if self.threshold_frames == 0 {
+            return DriftCorrection::None;
+        }
+        // The first buffer only marks the start; its audio was captured before it
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some(now);
+                return DriftCorrection::None;
+            },
+        };
+        self.frames_since_start += frames;
+        let expected = (now.duration_since(start).as_secs_f64() * self.sample_rate as f64) as i64;
+        let drift = self.frames_since_start as i64 - expected;
+        if drift.unsigned_abs() >= self.threshold_frames {
+            self.xruns += 1;
+            let sample_rate = self.sample_rate as u64;
+            self.drift_log.warn(|| format!("Warning: input is {} ms {} the clock; {}",
+                                           drift.unsigned_abs() * 1000 / sample_rate,
+                                           if drift < 0 { "behind" } else { "ahead of" },
+                                           if drift < 0 { "audio may have been dropped" }
+                                           else { "the device may be running fast" }));
+            self.start = Some(now);
+            self.frames_since_start = 0;
+            if self.resync {
+                self.owed += drift;
+            }
+        }
+
+        let correction = self.owed.unsigned_abs().min(frames);
+        match self.owed.signum() {
+            -1 => {
+                self.owed += correction as i64;
+                DriftCorrection::Insert(correction)
+            },
+            1 => {
+                self.owed -= correction as i64;
+                DriftCorrection::Drop(correction)
+            },
+            _ => DriftCorrection::None,
+        }
+    }
+
+    /// How many times the input has drifted past the threshold.
+    pub fn xruns(&self) -> u64 {
+        self.xruns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{wav_spec, Collect, Discard, FailingWriter, ThreadNames, WriteSizes};
+    use crate::processor::WavProcessor;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dead_channel_monitor() {
+        let sample_rate = 100;
+        let mut monitor = DeadChannelMonitor::new(&[0, 1], 2, sample_rate);
+
+        // Channel 1 is always zero; channel 0 carries signal.
+        for _ in 0..(2 * sample_rate - 1) {
+            monitor.observe(&[1000, 0]);
+        }
+        assert!(monitor.dead_channels().is_empty());
+
+        monitor.observe(&[1000, 0]);
+        assert_eq!(monitor.dead_channels(), vec![1]);
+
+        // Any nonzero sample resets the channel.
+        monitor.observe(&[1000, 5]);
+        assert!(monitor.dead_channels().is_empty());
+    }
+
+    #[test]
+    fn test_drift_monitor() {
+        let start = Instant::now();
+        let at = |ms: u64| start + std::time::Duration::from_millis(ms);
+        let mut monitor = DriftMonitor::new(48000, 5, true);
+
+        // 480 frames every 10 ms is right on time
+        for i in 0..100 {
+            assert_eq!(monitor.observe(480, at(i * 10)), DriftCorrection::None);
+        }
+        assert_eq!(monitor.xruns(), 0);
+
+        // A buffer goes missing
+        assert_eq!(monitor.observe(480, at(1010)), DriftCorrection::Insert(480));
+        assert_eq!(monitor.xruns(), 1);
+        assert_eq!(monitor.observe(480, at(1020)), DriftCorrection::None);
+
+        // And one arrives twice as big as it should
+        assert_eq!(monitor.observe(960, at(1030)), DriftCorrection::Drop(480));
+        assert_eq!(monitor.xruns(), 2);
+
+        // Without resync, drift is only counted
+        let mut monitor = DriftMonitor::new(48000, 5, false);
+        monitor.observe(480, at(0));
+        assert_eq!(monitor.observe(480, at(20)), DriftCorrection::None);
+        assert_eq!(monitor.xruns(), 1);
+
+        // A stall of a minute is made up a buffer at a time
+        let mut monitor = DriftMonitor::new(48000, 5, true);
+        monitor.observe(480, at(0));
+        assert_eq!(monitor.observe(480, at(60_010)), DriftCorrection::Insert(480));
+        let mut inserted = 480;
+        let mut now = 60_010;
+        loop {
+            now += 10;
+            match monitor.observe(480, at(now)) {
+                DriftCorrection::Insert(frames) => {
+                    assert_eq!(frames, 480);
+                    inserted += frames;
+                },
+                correction => {
+                    assert_eq!(correction, DriftCorrection::None);
+                    break;
+                },
+            }
+        }
+        assert_eq!(inserted, 60_000 * 48);
+        assert_eq!(monitor.xruns(), 1);
+
+        let mut disabled = DriftMonitor::new(48000, 0, true);
+        disabled.observe(480, at(0));
+        assert_eq!(disabled.observe(480, at(1000)), DriftCorrection::None);
+    }
+
+    #[test]
+    fn test_dead_channel_monitor_disabled() {
+        let mut monitor = DeadChannelMonitor::new(&[0, 1], 0, 100);
+        for _ in 0..1000 {
+            monitor.observe(&[0, 0]);
+        }
+        assert!(monitor.dead_channels().is_empty());
+    }
+
+    #[test]
+    fn test_batch_samples() {
+        let write_batches = |batch_samples: Option<usize>| {
+            let sizes = Arc::new(Mutex::new(Vec::new()));
+            let mut capture = Capture::new(&[0, 1], 2, Box::new(WriteSizes(Arc::clone(&sizes))), false);
+            if let Some(batch_samples) = batch_samples {
+                capture = capture.with_batch_samples(batch_samples);
+            }
+            capture.process(&[0i16; 2048], |s| (s as i32) << 16);
+            let sizes = sizes.lock().unwrap().clone();
+            sizes
+        };
+        assert_eq!(write_batches(None), vec![INTERMEDIATE_BUFFER_SIZE; 4]);
+        assert_eq!(write_batches(Some(128)), vec![128; 16]);
+        // Batches hold whole frames
+        assert_eq!(write_batches(Some(1000))[0], 1000);
+        assert_eq!(write_batches(Some(999))[0], 1000);
+    }
+
+    #[test]
+    fn test_non_finite_samples_become_silence() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(Collect(Arc::clone(&written))), false)
+            .with_batch_samples(2);
+        capture.process_f32(&[f32::NAN, 0.5, f32::INFINITY, f32::NEG_INFINITY, -0.5, 1.0]);
+        capture.finish().unwrap();
+
+        assert_eq!(*written.lock().unwrap(), vec![0, 16383, 0, 0, -16383, 32767]);
+        assert_eq!(capture.bad_samples(), 3);
+    }
+
+    #[test]
+    fn test_24_bit_capture_keeps_the_low_bits() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(Collect(Arc::clone(&written))), false)
+            .with_bits_per_sample(24);
+        capture.process(&[0x1234_5678i32, -0x1234_5678], |s| s);
+        capture.process(&[100i16, -100], |s| (s as i32) << 16);
+        capture.process_f32(&[0.5, -1.5]);
+        capture.finish().unwrap();
+
+        assert_eq!(*written.lock().unwrap(), vec![0x12_3456, -0x12_3457, 100 << 8, -100 << 8, 4194303, -8388608]);
+    }
+
+    #[test]
+    fn test_too_many_write_errors_abort_the_recording() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(FailingWriter), false)
+            .with_batch_samples(2)
+            .with_max_write_errors(Some(2), Arc::clone(&aborted));
+
+        capture.process(&[1i16; 4], |s| (s as i32) << 16);
+        assert_eq!(capture.write_errors(), 2);
+        assert!(!aborted.load(Ordering::Relaxed));
+
+        capture.process(&[1i16; 4], |s| (s as i32) << 16);
+        assert_eq!(capture.write_errors(), 3);
+        assert!(aborted.load(Ordering::Relaxed));
+        assert_eq!(capture.finish().unwrap(), vec!["failing.wav"]);
+    }
+
+    #[test]
+    fn test_writer_thread_writes_and_finalizes() {
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(ThreadNames(Box::new(Discard), Arc::clone(&names))), false)
+            .with_batch_samples(2)
+            .with_writer_thread(8)
+            .unwrap();
+        capture.process(&[1i16; 10], |s| (s as i32) << 16);
+        capture.finish().unwrap();
+
+        // Five batches, and the finalize that flushes to disk
+        let names = names.lock().unwrap();
+        assert_eq!(names.len(), 6);
+        assert!(names.iter().all(|name| name.as_deref() == Some(WRITER_THREAD_NAME)));
+    }
+
+    #[test]
+    fn test_capture_feeds_monitor() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("monitored.wav");
+
+        let spec = wav_spec(2, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+
+        // Four device channels, recording channels 1 and 2
+        let mut capture = Capture::new(&[1, 2], 4, Box::new(processor), false);
+        let monitor = Arc::new(Mutex::new(MonitorBuffer::new(2, 48000, 48000)));
+        capture.monitor = Some(Arc::clone(&monitor));
+
+        let data: Vec<i16> = (0..40).collect();
+        capture.process(&data, |s| (s as i32) << 16);
+        capture.finish().unwrap();
+
+        assert_eq!(monitor.lock().unwrap().len_frames(), 10);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(&samples[..4], &[1, 2, 5, 6]);
+    }
+
+    #[test]
+    fn test_channels_recorded_in_configured_order() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("ordered.wav");
+
+        let spec = wav_spec(3, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+        let mut capture = Capture::new(&[2, 0, 1], 3, Box::new(processor), false);
+
+        // Device channel n carries 10 * n + frame
+        let data: Vec<i16> = (0..4).flat_map(|frame| (0..3).map(move |ch| 10 * ch + frame)).collect();
+        capture.process(&data, |s| (s as i32) << 16);
+        capture.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(&samples[..6], &[20, 0, 10, 21, 1, 11]);
+    }
+
+    #[test]
+    fn test_markers_track_frame_position() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("markers.wav");
+
+        let spec = wav_spec(2, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(processor), false);
+
+        capture.process(&[0i16; 200], |s| (s as i32) << 16);
+        assert_eq!(capture.add_marker("first"), 100);
+        capture.process(&[0i16; 100], |s| (s as i32) << 16);
+        assert_eq!(capture.add_marker("second"), 150);
+        capture.finish().unwrap();
+
+        let positions: Vec<u64> = capture.markers.iter().map(|m| m.position).collect();
+        assert_eq!(positions, vec![100, 150]);
+    }
+
+    #[test]
+    fn test_frames_count_past_u32() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("long.wav");
+
+        let spec = wav_spec(1, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+        let mut capture = Capture::new(&[0], 1, Box::new(processor), false);
+        // About 24.8 hours in at 48 kHz
+        capture.frames = u32::MAX as u64 - 10;
+
+        capture.process(&[0i16; 100], |s| (s as i32) << 16);
+        assert_eq!(capture.frames(), u32::MAX as u64 + 90);
+        assert_eq!(capture.add_marker("next day"), u32::MAX as u64 + 90);
+        capture.finish().unwrap();
+    }
+
+    #[test]
+    fn test_peaks_reset_when_taken() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("peaks.wav");
+
+        let spec = wav_spec(2, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+        let mut capture = Capture::new(&[1, 0], 3, Box::new(processor), false);
+
+        capture.process(&[100i16, -16384, 7, -32768, 0, 7], |s| (s as i32) << 16);
+        assert_eq!(capture.take_peaks(), vec![0.5, 1.0]);
+        assert_eq!(capture.take_peaks(), vec![0.0, 0.0]);
+        capture.finish().unwrap();
+    }
+
+    #[test]
+    fn test_finish_returns_files_on_disk() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("finished.wav");
+
+        let spec = wav_spec(2, 48000);
+        let processor = WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap();
+        let mut capture = Capture::new(&[0, 1], 2, Box::new(processor), false);
+        capture.process(&[1i16; 2000], |s| (s as i32) << 16);
+
+        let files = capture.finish().unwrap();
+
+        let on_disk: Vec<String> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+            .map(|path| path.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(files, on_disk);
+
+        // Finishing twice doesn't report the files again
+        assert!(capture.finish().unwrap().is_empty());
+    }
+}
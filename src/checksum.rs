@@ -0,0 +1,137 @@
+//! SHA-256 checksums of finished recordings, for showing later that a file
+//! hasn't changed since it was recorded.
+//!
+//! Each checksum is written next to its file as `{file}.sha256`, in the
+//! format `sha256sum` writes, so a copy can be checked with `sha256sum -c`
+//! as well as with `--verify`.
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::error::BlackboxError;
+
+/// Appended to a file's name for its checksum.
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// The SHA-256 hash of the file at `path`, as lowercase hex.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hash = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hash.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()),
+            n => hash.update(&buffer[..n]),
+        }
+    }
+}
+
+/// Where the checksum of the file at `path` is kept.
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(CHECKSUM_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Hashes the file at `path` and writes the checksum next to it, returning
+/// where it was written.
+pub fn write_checksum(path: &Path) -> io::Result<PathBuf> {
+    let hash = sha256_file(path)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let checksum = checksum_path(path);
+    fs::write(&checksum, format!("{}  {}\n", hash, file_name))?;
+    Ok(checksum)
+}
+
+/// Deletes the checksum of the file at `path`, if it has one.
+pub fn remove_checksum(path: &Path) {
+    match fs::remove_file(checksum_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Failed to delete the checksum of {}: {}", path.display(), e);
+        },
+        _ => {},
+    }
+}
+
+/// Whether the file at `path` still matches its stored checksum.
+pub fn verify_checksum(path: &Path) -> Result<bool, BlackboxError> {
+    let stored = fs::read_to_string(checksum_path(path))?;
+    let stored = stored.split_whitespace().next()
+        .ok_or_else(|| BlackboxError::Corrupt(format!("Empty checksum for {}", path.display())))?;
+    Ok(sha256_file(path)? == stored.to_ascii_lowercase())
+}
+
+/// Checks every file in `dir` that has a checksum against it, printing each
+/// result and a summary. Returns how many files failed.
+pub fn run_verify(dir: &Path) -> Result<usize, BlackboxError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.to_string_lossy();
+        if let Some(file) = name.strip_suffix(CHECKSUM_SUFFIX) {
+            files.push(PathBuf::from(file));
+        }
+    }
+    files.sort();
+
+    let mut failed = 0;
+    for file in &files {
+        match verify_checksum(file) {
+            Ok(true) => println!("OK {}", file.display()),
+            Ok(false) => {
+                println!("FAILED {}", file.display());
+                failed += 1;
+            },
+            Err(e) => {
+                eprintln!("Failed to verify {}: {}", file.display(), e);
+                failed += 1;
+            },
+        }
+    }
+    println!("{} of {} file(s) match their checksums in {}", files.len() - failed, files.len(), dir.display());
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sha256_known_values() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("known.bin");
+        for (contents, hash) in [
+            (&b""[..], "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+             "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(sha256_file(&path).unwrap(), hash);
+        }
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("2024-06-26-09-05.wav");
+        fs::write(&path, vec![7u8; 100_000]).unwrap();
+
+        let checksum = write_checksum(&path).unwrap();
+        assert!(fs::read_to_string(&checksum).unwrap().ends_with("  2024-06-26-09-05.wav\n"));
+        assert!(verify_checksum(&path).unwrap());
+        assert_eq!(run_verify(temp_dir.path()).unwrap(), 0);
+
+        let mut contents = fs::read(&path).unwrap();
+        contents[5000] ^= 1;
+        fs::write(&path, contents).unwrap();
+        assert!(!verify_checksum(&path).unwrap());
+        assert_eq!(run_verify(temp_dir.path()).unwrap(), 1);
+
+        remove_checksum(&path);
+        assert!(!checksum.exists());
+    }
+}
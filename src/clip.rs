@@ -0,0 +1,141 @@
+//! `DETECT_CLIPPING`: counting the samples of each channel at full scale,
+//! so a recording that clipped can be flagged.
+
+use crate::envelope::file_channels;
+use crate::error::BlackboxError;
+use crate::events::EventLog;
+use crate::processor::{full_scale, AudioProcessor};
+
+/// Whether a sample at `bits_per_sample`, widened to `i32`, is at or beyond
+/// full scale. Float input over 1.0 saturates to full scale on conversion,
+/// so it counts.
+pub fn is_clipped(sample: i32, bits_per_sample: u16) -> bool {
+    let max = full_scale(bits_per_sample);
+    sample >= max || sample < -max
+}
+
+/// How many samples of each channel have clipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipCounter {
+    counts: Vec<u64>,
+    frames: u64,
+    bits_per_sample: u16,
+}
+
+impl ClipCounter {
+    pub fn new(channels: usize, bits_per_sample: u16) -> Self {
+        ClipCounter { counts: vec![0; channels], frames: 0, bits_per_sample }
+    }
+
+    /// Counts the clipped samples in interleaved audio.
+    pub fn add(&mut self, samples: &[i32]) {
+        for frame in samples.chunks_exact(self.counts.len()) {
+            for (count, &sample) in self.counts.iter_mut().zip(frame) {
+                *count += is_clipped(sample, self.bits_per_sample) as u64;
+            }
+            self.frames += 1;
+        }
+    }
+
+    /// The clipped samples of the channel at `position`.
+    pub fn count(&self, position: usize) -> u64 {
+        self.counts[position]
+    }
+
+    /// The clipped samples of the channel at `position` as a percentage of
+    /// its samples.
+    pub fn percent(&self, position: usize) -> f64 {
+        match self.frames {
+            0 => 0.0,
+            frames => self.counts[position] as f64 * 100.0 / frames as f64,
+        }
+    }
+}
+
+/// Passes the audio on to `inner` while counting each channel's clipped
+/// samples, and reports them as each file is finalized: an event for every
+/// channel that clipped, and a warning when more than `warn_percent` of a
+/// channel's samples did.
+pub struct ClipProcessor {
+    inner: Box<dyn AudioProcessor>,
+    counter: ClipCounter,
+    /// The device channel at each position, for naming it.
+    channels: Vec<usize>,
+    /// Each file and the positions of the channels it holds.
+    outputs: Vec<(String, Vec<usize>)>,
+    warn_percent: f64,
+    events: EventLog,
+}
+
+impl ClipProcessor {
+    /// `files` are the files `inner` writes: one holding every channel, or
+    /// one per channel, in order.
+    pub fn new(inner: Box<dyn AudioProcessor>, channels: &[usize], bits_per_sample: u16, files: &[String],
+               warn_percent: f64, events: EventLog) -> Self {
+        ClipProcessor {
+            inner,
+            counter: ClipCounter::new(channels.len(), bits_per_sample),
+            channels: channels.to_vec(),
+            outputs: file_channels(files, channels.len()),
+            warn_percent,
+            events,
+        }
+    }
+}
+
+impl AudioProcessor for ClipProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.counter.add(samples);
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let files = self.inner.finalize()?;
+        for (file, positions) in &self.outputs {
+            for &i in positions {
+                let (count, percent) = (self.counter.count(i), self.counter.percent(i));
+                if count == 0 {
+                    continue;
+                }
+                let detail = format!("channel {}: {} samples ({:.3}%)", self.channels[i], count, percent);
+                if percent > self.warn_percent {
+                    eprintln!("Warning: {} clipped on {}", file, detail);
+                }
+                self.events.log("clipped", file, &detail);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Discard;
+    use crate::capture::Capture;
+    use std::fs;
+
+    #[test]
+    fn test_clipping_is_counted_per_channel() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("events.jsonl");
+        let files = vec!["a-ch1.wav".to_string(), "a-ch2.wav".to_string()];
+        let processor = ClipProcessor::new(Box::new(Discard), &[1, 2], 16, &files, 5.0, EventLog::new(&log_path));
+        let mut capture = Capture::new(&[1, 2], 3, Box::new(processor), false);
+
+        // Channel 1 goes over full scale on a tenth of its samples, channel 2
+        // stays just under it
+        let samples: Vec<f32> = (0..1000).flat_map(|i| [0.0, if i % 10 == 0 { 1.5 } else { 0.5 }, 0.99]).collect();
+        capture.process_f32(&samples);
+        capture.finish().unwrap();
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains("\"path\":\"a-ch1.wav\"") && log.contains("channel 1: 100 samples (10.000%)"));
+
+        let mut counter = ClipCounter::new(2, 16);
+        counter.add(&[32767, 0, -32768, 32766]);
+        assert_eq!((counter.count(0), counter.count(1)), (2, 0));
+        assert_eq!(counter.percent(0), 100.0);
+    }
+}
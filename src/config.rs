@@ -0,0 +1,1313 @@
+//! Recorder settings, resolved from built-in defaults, environment variables
+//! and command-line flags, in increasing order of precedence. Each value
+//! remembers where it came from so `--print-config` can explain it.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::error::BlackboxError;
+use crate::schedule::{self, ScheduleWindow};
+
+pub const DEFAULT_CHANNELS: &str = "1,2";
+pub const DEFAULT_ON_CHANNEL_MISMATCH: &str = "error";
+pub const DEFAULT_DEBUG: &str = "false";
+pub const DEFAULT_DURATION: &str = "10";
+pub const DEFAULT_DEAD_CHANNEL_TIMEOUT: &str = "0";
+pub const DEFAULT_DRIFT_THRESHOLD_MS: &str = "0";
+pub const DEFAULT_RESYNC: &str = "false";
+pub const DEFAULT_WRITE_BUFFER_BYTES: &str = "65536";
+pub const DEFAULT_BATCH_SAMPLES: &str = "512";
+pub const DEFAULT_FLUSH_INTERVAL_SECS: &str = "0";
+pub const DEFAULT_WARMUP_DISCARD_MS: &str = "0";
+pub const DEFAULT_AUTO_REPAIR: &str = "false";
+pub const DEFAULT_VERIFY_ON_FINALIZE: &str = "false";
+pub const DEFAULT_WRITE_CHECKSUM: &str = "false";
+pub const DEFAULT_OUTPUT_DIR: &str = ".";
+pub const DEFAULT_TIMESTAMP_TZ: &str = "local";
+pub const DEFAULT_OUTPUT_MODE: &str = "file";
+pub const DEFAULT_SPLIT_NAMING: &str = "channel";
+pub const DEFAULT_SPLIT_INDEX_WIDTH: &str = "2";
+pub const DEFAULT_OUTPUT_FORMAT: &str = "wav";
+pub const DEFAULT_BIT_DEPTH: &str = "16";
+pub const DEFAULT_WAVPACK_COMMAND: &str = "wavpack";
+pub const DEFAULT_PROXY_BITRATE: &str = "128";
+pub const DEFAULT_PROXY_COMMAND: &str = "ffmpeg";
+pub const DEFAULT_COMPRESS_FINALIZED: &str = "none";
+pub const DEFAULT_COMPRESS_KEEP_ORIGINAL: &str = "false";
+pub const DEFAULT_AUDIO_HOST: &str = "auto";
+pub const DEFAULT_SILENCE_THRESHOLD: &str = "0";
+pub const DEFAULT_SILENCE_ACTION: &str = "delete";
+pub const DEFAULT_SPLIT_SILENCE_POLICY: &str = "per_channel";
+pub const DEFAULT_MIN_DISK_SPACE_MB: &str = "0";
+pub const DEFAULT_ROTATE_SECONDS: &str = "0";
+pub const DEFAULT_ROTATE_ALIGN: &str = "false";
+pub const DEFAULT_DAILY_ROTATION: &str = "false";
+pub const DEFAULT_WRITE_INFO_TAGS: &str = "false";
+pub const DEFAULT_MONO_TO_STEREO: &str = "false";
+pub const DEFAULT_LEVEL_ENVELOPE: &str = "false";
+pub const DEFAULT_DETECT_CLIPPING: &str = "false";
+pub const DEFAULT_CLIP_WARN_PERCENT: &str = "0";
+pub const DEFAULT_TIMESTAMP_TRACK: &str = "false";
+pub const DEFAULT_TIMESTAMP_TRACK_SECS: &str = "1";
+pub const DEFAULT_SAMPLE_ACCURATE_START: &str = "false";
+pub const DEFAULT_WRITE_BEXT: &str = "false";
+pub const DEFAULT_MEASURE_LOUDNESS: &str = "false";
+pub const DEFAULT_SHOW_LEVELS: &str = "false";
+pub const DEFAULT_FADE_MS: &str = "0";
+pub const DEFAULT_DITHER: &str = "false";
+pub const DEFAULT_REALTIME_PRIORITY: &str = "false";
+pub const DEFAULT_SYNC_TONE: &str = "false";
+pub const DEFAULT_SYNC_TONE_HZ: &str = "1000";
+pub const DEFAULT_SYNC_TONE_MS: &str = "500";
+pub const DEFAULT_FILE_CREATE_ATTEMPTS: &str = "3";
+pub const DEFAULT_FILE_CREATE_RETRY_MS: &str = "100";
+pub const DEFAULT_FADE_SCOPE: &str = "file";
+pub const DEFAULT_EVENT_LOG: &str = "false";
+pub const DEFAULT_START_ARMED: &str = "false";
+pub const DEFAULT_RESTART_ON_START: &str = "false";
+pub const DEFAULT_CAPTURE_MODE: &str = "input";
+pub const DEFAULT_SOURCE: &str = "device";
+pub const DEFAULT_TONE_SIGNAL: &str = "sine";
+pub const DEFAULT_TONE_HZ: &str = "440";
+pub const DEFAULT_WAIT_FOR_DEVICE: &str = "false";
+pub const DEFAULT_DEVICE_POLL_MS: &str = "1000";
+
+/// The length of a day, which `DAILY_ROTATION` rotates on.
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Where a setting's effective value came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Default,
+    Env,
+    Cli,
+    /// Changed after loading, with `Config::set_field`.
+    Set,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Env => write!(f, "env"),
+            Source::Cli => write!(f, "cli"),
+            Source::Set => write!(f, "set"),
+        }
+    }
+}
+
+/// What a recording is written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// One WAV file holding the recorded channels.
+    File,
+    /// Raw PCM on stdout.
+    Stdout,
+    /// One mono WAV file per recorded channel.
+    Split,
+}
+
+/// The format finished recordings are kept in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Wav,
+    /// Re-encoded losslessly with the `wavpack` command once finished.
+    WavPack,
+}
+
+/// Each format `OUTPUT_FORMAT` accepts in this build, by name.
+const OUTPUT_FORMATS: &[(&str, OutputFormat)] = &[
+    ("wav", OutputFormat::Wav),
+    ("wavpack", OutputFormat::WavPack),
+];
+
+impl OutputFormat {
+    /// The format named `name` in `OUTPUT_FORMAT`, if this build supports it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        OUTPUT_FORMATS.iter().find(|&&(format_name, _)| format_name == name).map(|&(_, format)| format)
+    }
+}
+
+/// General-purpose compression for finished files, for smaller uploads
+/// where an audio codec isn't an option.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FileCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FileCompression {
+    /// The extension added to a compressed file, e.g. `take.wav.zst`.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            FileCompression::None => None,
+            FileCompression::Gzip => Some("gz"),
+            FileCompression::Zstd => Some("zst"),
+        }
+    }
+
+    /// The tool that compresses and decompresses it.
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            FileCompression::None => None,
+            FileCompression::Gzip => Some("gzip"),
+            FileCompression::Zstd => Some("zstd"),
+        }
+    }
+
+    /// The compression of a file named with its extension, if any.
+    pub fn from_path(path: &str) -> FileCompression {
+        match path.rsplit_once('.') {
+            Some((_, "gz")) => FileCompression::Gzip,
+            Some((_, "zst")) => FileCompression::Zstd,
+            _ => FileCompression::None,
+        }
+    }
+}
+
+/// The names `OUTPUT_FORMAT` accepts in this build. WavPack also needs the
+/// `wavpack` encoder installed when recordings finish.
+pub fn supported_output_formats() -> Vec<&'static str> {
+    OUTPUT_FORMATS.iter().map(|&(name, _)| name).collect()
+}
+
+/// Whether `OUTPUT_FORMAT` accepts `name` in this build.
+pub fn is_format_supported(name: &str) -> bool {
+    OutputFormat::from_name(name).is_some()
+}
+
+/// Which boundaries `FADE_MS` fades at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FadeScope {
+    /// The start and end of every file, including at each rotation.
+    File,
+    /// Only the start and end of the whole recording.
+    Session,
+}
+
+/// What the default device records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureMode {
+    /// The default input device, such as a microphone or interface.
+    Input,
+    /// Whatever the machine is playing.
+    Loopback,
+}
+
+/// Where the recorded audio comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AudioSource {
+    /// Audio devices.
+    #[default]
+    Device,
+    /// A generated test signal, for trying out the rest of the pipeline
+    /// without any input.
+    Tone,
+}
+
+/// The test signal recorded with `AudioSource::Tone`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ToneSignal {
+    #[default]
+    Sine,
+    /// A sine sweeping up from 20 Hz to half the sample rate, repeating.
+    Sweep,
+    /// White noise.
+    Noise,
+}
+
+/// What happens when a configured channel doesn't exist on the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelMismatch {
+    /// Refuse to start recording.
+    Error,
+    /// Record the channels that do exist.
+    Skip,
+    /// Like `Skip`, with a warning naming the missing channels.
+    Warn,
+}
+
+/// What happens to a recording found to be silent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SilenceAction {
+    #[default]
+    Delete,
+    /// Only report it, for trying out a threshold.
+    Report,
+}
+
+/// Which files of a split recording are deleted as silent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SplitSilencePolicy {
+    /// Each channel's file on its own.
+    #[default]
+    PerChannel,
+    /// A segment's files only when every one of them is silent, so a
+    /// recording never loses some of its channels.
+    AllOrNothing,
+}
+
+/// How the files of a split recording are told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SplitNaming {
+    /// `-ch{n}`, after the device channel.
+    #[default]
+    Channel,
+    /// `_input{nn}`, numbered from 1 in recording order and zero-padded.
+    Index,
+    /// `-{label}`, after the channel's `CHANNEL_LABELS` entry, or `-ch{n}`
+    /// for a channel without one.
+    Label,
+}
+
+/// Which time zone file name timestamps are in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimestampZone {
+    #[default]
+    Local,
+    /// UTC, marked with a `Z` after the time.
+    Utc,
+}
+
+/// What a key bound in `CONTROL_KEYS` does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlAction {
+    /// Drop a numbered marker.
+    Marker,
+    /// Stop the recording.
+    Stop,
+    /// Start writing a recording armed with `START_ARMED`.
+    Start,
+}
+
+/// One input device to record and the channels to take from it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSpec {
+    pub name: String,
+    pub channels: Vec<usize>,
+}
+
+/// The device channels summed to each side of the stereo monitor mix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorMix {
+    pub left: Vec<usize>,
+    pub right: Vec<usize>,
+}
+
+/// Where a recording was made, in decimal degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6},{:.6}", self.latitude, self.longitude)
+    }
+}
+
+struct Setting {
+    name: &'static str,
+    value: Option<String>,
+    source: Source,
+}
+
+pub struct Config {
+    /// Empty when `all_channels` is set.
+    pub channels: Vec<usize>,
+    /// Record every channel the device has, from `AUDIO_CHANNELS=all`.
+    pub all_channels: bool,
+    pub channel_labels: Vec<String>,
+    pub on_channel_mismatch: ChannelMismatch,
+    pub input_devices: Vec<DeviceSpec>,
+    pub capture_mode: CaptureMode,
+    pub source: AudioSource,
+    pub tone_signal: ToneSignal,
+    pub tone_hz: u32,
+    /// Keep looking for a missing input device instead of failing.
+    pub wait_for_device: bool,
+    pub device_poll_ms: u64,
+    pub sample_rate: Option<u32>,
+    /// Sample formats to record in, most wanted first, e.g. "f32".
+    pub preferred_formats: Vec<String>,
+    pub location: Option<Location>,
+    /// A file holding the location, read each time recording starts.
+    pub location_file: Option<PathBuf>,
+    pub debug: bool,
+    pub record_duration: u64,
+    pub rotate_seconds: u64,
+    /// Shorten the first file so the rest start on clock boundaries.
+    pub rotate_align: bool,
+    /// Also start a new file at each midnight, so no file spans two days.
+    pub daily_rotation: bool,
+    /// Keep only this many of a rotating recording's latest files on disk.
+    pub circular_files: Option<usize>,
+    pub fade_ms: u64,
+    pub fade_scope: FadeScope,
+    pub dither: bool,
+    /// Ask for real-time scheduling for the thread writing the audio.
+    pub realtime_priority: bool,
+    /// Write a tone at the start of each file.
+    pub sync_tone: bool,
+    pub sync_tone_hz: u32,
+    pub sync_tone_ms: u64,
+    /// The device channel to put the tone on, or every channel if unset.
+    pub sync_tone_channel: Option<usize>,
+    pub dead_channel_timeout: u64,
+    pub drift_threshold_ms: u64,
+    pub resync: bool,
+    /// Audio dropped from the start of each device's stream.
+    pub warmup_discard_ms: u64,
+    pub write_buffer_bytes: usize,
+    pub batch_samples: usize,
+    pub flush_interval_secs: u64,
+    pub auto_repair: bool,
+    /// Check each finalized file holds all its audio, setting aside any that don't.
+    pub verify_on_finalize: bool,
+    /// Write a SHA-256 checksum next to each finished file.
+    pub write_checksum: bool,
+    pub file_create_attempts: u32,
+    pub file_create_retry_ms: u64,
+    /// Stop the recording once more than this many writes have failed.
+    pub max_write_errors: Option<u64>,
+    pub output_dir: PathBuf,
+    pub backup_dir: Option<PathBuf>,
+    /// Permissions given to each finished file and its checksum.
+    pub file_mode: Option<u32>,
+    /// The group, by name or number, finished files are handed to.
+    pub file_group: Option<String>,
+    /// A named pipe to also stream the recording to as a WAV.
+    pub fifo_path: Option<PathBuf>,
+    /// A file kept up to date with the recording's progress as JSON.
+    pub status_file: Option<PathBuf>,
+    pub timestamp_tz: TimestampZone,
+    pub output_mode: OutputMode,
+    /// Write a single recorded channel as a stereo file in `file` mode.
+    pub mono_to_stereo: bool,
+    /// Write the per-second peak and RMS of each recorded file beside it.
+    pub level_envelope: bool,
+    /// Count each file's samples at full scale and report the channels that clipped.
+    pub detect_clipping: bool,
+    /// Warn when more than this percentage of a channel's samples clipped.
+    pub clip_warn_percent: f64,
+    /// Write when each stretch of every recorded file was captured beside it.
+    pub timestamp_track: bool,
+    /// Seconds of audio between the timestamp track's lines.
+    pub timestamp_track_secs: u64,
+    /// Write when the first sample of every recorded file was captured beside it.
+    pub sample_accurate_start: bool,
+    /// Write a Broadcast Wave `bext` chunk, with the start as its TimeReference.
+    pub write_bext: bool,
+    pub split_naming: SplitNaming,
+    pub split_index_width: usize,
+    /// Bits per sample of the files written in `split` mode.
+    pub split_bit_depth: u16,
+    /// Bits per sample of the single file written in `file` mode.
+    pub single_bit_depth: u16,
+    /// Also write a stereo mix of these channels next to each recording.
+    pub monitor_mix: Option<MonitorMix>,
+    /// The recorded channels summed onto each channel of the monitor output.
+    pub monitor_downmix: Option<Vec<Vec<usize>>>,
+    pub output_format: OutputFormat,
+    /// Compress each finished file whole, e.g. to `take.wav.zst`.
+    pub compress_finalized: FileCompression,
+    /// Keep each file as well as its compressed copy.
+    pub compress_keep_original: bool,
+    /// Run in place of `gzip` or `zstd`, taking the same arguments.
+    pub compress_command: Option<String>,
+    pub wavpack_command: String,
+    /// Extension of a lossy copy to encode alongside each recording, e.g. `mp3`.
+    pub proxy_format: Option<String>,
+    pub proxy_bitrate: u32,
+    pub proxy_command: String,
+    /// Run on each finished recording, with `{path}` replaced by its path.
+    pub post_command: Option<String>,
+    pub audio_host: String,
+    pub monitor_output: Option<String>,
+    pub silence_threshold: f64,
+    pub silence_action: SilenceAction,
+    pub split_silence_policy: SplitSilencePolicy,
+    pub silence_check_seconds: Option<f64>,
+    pub min_recording_secs: Option<f64>,
+    pub channel_thresholds: HashMap<usize, f64>,
+    pub max_recordings: Option<usize>,
+    pub min_disk_space_mb: u64,
+    pub write_info_tags: bool,
+    pub measure_loudness: bool,
+    pub show_levels: bool,
+    pub control_keys: HashMap<String, ControlAction>,
+    /// Open the devices and meter them, but only record once started.
+    pub start_armed: bool,
+    /// A start command while recording finishes the recording and starts a
+    /// new one, instead of being ignored.
+    pub restart_on_start: bool,
+    /// Record only during these windows, waiting in between.
+    pub schedule: Vec<ScheduleWindow>,
+    pub event_log: bool,
+    settings: Vec<Setting>,
+}
+
+/// Resolves raw setting strings and records their provenance.
+struct Loader<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    settings: Vec<Setting>,
+}
+
+impl Loader<'_> {
+    fn get(&mut self, name: &'static str, default: &str) -> String {
+        self.get_optional(name, Some(default)).unwrap()
+    }
+
+    fn get_optional(&mut self, name: &'static str, default: Option<&str>) -> Option<String> {
+        let (value, source) = match (self.env)(name) {
+            Some(value) => (Some(value), Source::Env),
+            None => (default.map(str::to_string), Source::Default),
+        };
+        self.settings.push(Setting { name, value: value.clone(), source });
+        value
+    }
+
+    fn override_cli(&mut self, name: &'static str, value: &str) -> String {
+        if let Some(setting) = self.settings.iter_mut().find(|s| s.name == name) {
+            setting.value = Some(value.to_string());
+            setting.source = Source::Cli;
+        }
+        value.to_string()
+    }
+}
+
+/// The built-in defaults, ignoring the environment and arguments, unlike
+/// `Config::load`.
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_sources(&|_| None, &[]).expect("the built-in defaults are valid")
+    }
+}
+
+impl Config {
+    /// The channels to record from a device with `total_channels`.
+    pub fn channels_for(&self, total_channels: usize) -> Vec<usize> {
+        match self.all_channels {
+            true => (0..total_channels).collect(),
+            false => self.channels.clone(),
+        }
+    }
+
+    /// Bits per sample the recording is captured and written at: the depth
+    /// of the current mode's files, or 16 for stdout.
+    pub fn bits_per_sample(&self) -> u16 {
+        match self.output_mode {
+            OutputMode::File => self.single_bit_depth,
+            OutputMode::Split => self.split_bit_depth,
+            OutputMode::Stdout => 16,
+        }
+    }
+
+    /// Loads the configuration from the process environment and arguments.
+    pub fn load() -> Result<Config, BlackboxError> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        Config::from_sources(&|name| env::var(name).ok(), &args)
+    }
+
+    /// The value of the setting `name`, such as `SILENCE_THRESHOLD` or
+    /// `silence_threshold`, as it was given, or `None` if it is unset or
+    /// there is no such setting.
+    pub fn get_field(&self, name: &str) -> Option<String> {
+        let name = name.to_ascii_uppercase();
+        self.settings.iter().find(|s| s.name == name).and_then(|s| s.value.clone())
+    }
+
+    /// Changes the setting `name`, named as for `get_field`, to `value`. The
+    /// whole config is loaded again with the new value, so it is checked
+    /// exactly as it would be at startup, against the other settings too;
+    /// if it fails, the config is left as it was.
+    pub fn set_field(&mut self, name: &str, value: &str) -> Result<(), BlackboxError> {
+        let name = name.to_ascii_uppercase();
+        if !self.settings.iter().any(|s| s.name == name) {
+            return Err(BlackboxError::Config(format!("Unknown setting: {}", name)));
+        }
+        let env = |setting: &str| match setting == name {
+            true => Some(value.to_string()),
+            false => self.settings.iter()
+                .find(|s| s.name == setting && s.source != Source::Default)
+                .and_then(|s| s.value.clone()),
+        };
+        let mut config = Config::from_sources(&env, &[])?;
+        for setting in &mut config.settings {
+            setting.source = match setting.name == name {
+                true => Source::Set,
+                false => self.settings.iter().find(|s| s.name == setting.name).map_or(setting.source, |s| s.source),
+            };
+        }
+        *self = config;
+        Ok(())
+    }
+
+    pub fn from_sources(env: &dyn Fn(&str) -> Option<String>, args: &[String]) -> Result<Config, BlackboxError> {
+        let mut loader = Loader { env, settings: Vec::new() };
+
+        let channels = loader.get("AUDIO_CHANNELS", DEFAULT_CHANNELS);
+        let all_channels = channels.trim().eq_ignore_ascii_case("all");
+        let channels = if all_channels { Vec::new() } else { parse_channel_string(&channels)? };
+
+        let wait_for_device: bool = parse(&loader.get("WAIT_FOR_DEVICE", DEFAULT_WAIT_FOR_DEVICE), "wait for device flag")?;
+
+        let device_poll_ms: u64 = parse(&loader.get("DEVICE_POLL_MS", DEFAULT_DEVICE_POLL_MS), "device poll interval")?;
+
+        let channel_labels: Vec<String> = match loader.get_optional("CHANNEL_LABELS", None) {
+            Some(value) => value.split(',').map(|label| label.trim().to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        let on_channel_mismatch = match loader.get("ON_CHANNEL_MISMATCH", DEFAULT_ON_CHANNEL_MISMATCH).as_str() {
+            "error" => ChannelMismatch::Error,
+            "skip" => ChannelMismatch::Skip,
+            "warn" => ChannelMismatch::Warn,
+            other => return Err(BlackboxError::Config(format!("Invalid channel mismatch policy: {}", other))),
+        };
+
+        let input_devices = match loader.get_optional("INPUT_DEVICES", None) {
+            Some(value) => parse_input_devices(&value)?,
+            None => Vec::new(),
+        };
+
+        let capture_mode = match loader.get("CAPTURE_MODE", DEFAULT_CAPTURE_MODE).as_str() {
+            "input" => CaptureMode::Input,
+            "loopback" => CaptureMode::Loopback,
+            other => return Err(BlackboxError::Config(format!("Invalid capture mode: {}", other))),
+        };
+        if capture_mode == CaptureMode::Loopback && !input_devices.is_empty() {
+            return Err(BlackboxError::Config("CAPTURE_MODE=loopback can't be combined with INPUT_DEVICES".to_string()));
+        }
+
+        let source = match loader.get("SOURCE", DEFAULT_SOURCE).as_str() {
+            "device" => AudioSource::Device,
+            "tone" => AudioSource::Tone,
+            other => return Err(BlackboxError::Config(format!("Invalid source: {}", other))),
+        };
+        let tone_signal = match loader.get("TONE_SIGNAL", DEFAULT_TONE_SIGNAL).as_str() {
+            "sine" => ToneSignal::Sine,
+            "sweep" => ToneSignal::Sweep,
+            "noise" => ToneSignal::Noise,
+            other => return Err(BlackboxError::Config(format!("Invalid tone signal: {}", other))),
+        };
+        let tone_hz: u32 = parse(&loader.get("TONE_HZ", DEFAULT_TONE_HZ), "tone frequency")?;
+        if tone_hz == 0 {
+            return Err(BlackboxError::Config(format!("Invalid tone frequency: {}", tone_hz)));
+        }
+
+        let location = match loader.get_optional("LOCATION", None) {
+            Some(value) => Some(parse_location(&value)?),
+            None => None,
+        };
+        let location_file = loader.get_optional("LOCATION_FILE", None).map(PathBuf::from);
+        if location.is_some() && location_file.is_some() {
+            return Err(BlackboxError::Config("LOCATION and LOCATION_FILE can't both be set".to_string()));
+        }
+
+        let sample_rate = match loader.get_optional("SAMPLE_RATE", None) {
+            Some(value) => Some(parse::<u32>(&value, "sample rate")?),
+            None => None,
+        };
+
+        let preferred_formats = match loader.get_optional("PREFERRED_FORMATS", None) {
+            Some(value) => parse_preferred_formats(&value)?,
+            None => Vec::new(),
+        };
+
+        let debug: bool = parse(&loader.get("DEBUG", DEFAULT_DEBUG), "debug flag")?;
+
+        let record_duration: u64 = parse(&loader.get("RECORD_DURATION", DEFAULT_DURATION), "record duration")?;
+
+        let rotate_seconds: u64 = parse(&loader.get("ROTATE_SECONDS", DEFAULT_ROTATE_SECONDS), "rotation interval")?;
+
+        let rotate_align: bool = parse(&loader.get("ROTATE_ALIGN", DEFAULT_ROTATE_ALIGN), "rotation alignment flag")?;
+
+        let daily_rotation: bool = parse(&loader.get("DAILY_ROTATION", DEFAULT_DAILY_ROTATION), "daily rotation flag")?;
+        if daily_rotation && rotate_seconds > 0 && !SECONDS_PER_DAY.is_multiple_of(rotate_seconds) {
+            return Err(BlackboxError::Config(
+                format!("DAILY_ROTATION needs a ROTATE_SECONDS that divides a day evenly, not {}", rotate_seconds)));
+        }
+
+        let circular_files = match loader.get_optional("CIRCULAR_FILES", None) {
+            Some(value) => Some(parse::<usize>(&value, "number of circular files")?),
+            None => None,
+        };
+        match circular_files {
+            Some(0) => return Err(BlackboxError::Config("CIRCULAR_FILES must be at least 1".to_string())),
+            Some(_) if rotate_seconds == 0 && !daily_rotation => {
+                return Err(BlackboxError::Config("CIRCULAR_FILES needs ROTATE_SECONDS or DAILY_ROTATION".to_string()));
+            },
+            _ => {},
+        }
+
+        let fade_ms: u64 = parse(&loader.get("FADE_MS", DEFAULT_FADE_MS), "fade length")?;
+
+        let fade_scope = match loader.get("FADE_SCOPE", DEFAULT_FADE_SCOPE).as_str() {
+            "file" => FadeScope::File,
+            "session" => FadeScope::Session,
+            other => return Err(BlackboxError::Config(format!("Invalid fade scope: {}", other))),
+        };
+
+        let dither: bool = parse(&loader.get("DITHER", DEFAULT_DITHER), "dither flag")?;
+
+        let realtime_priority: bool = parse(&loader.get("REALTIME_PRIORITY", DEFAULT_REALTIME_PRIORITY),
+                                            "real-time priority flag")?;
+
+        let sync_tone: bool = parse(&loader.get("SYNC_TONE", DEFAULT_SYNC_TONE), "sync tone flag")?;
+        let sync_tone_hz: u32 = parse(&loader.get("SYNC_TONE_HZ", DEFAULT_SYNC_TONE_HZ), "sync tone frequency")?;
+        if sync_tone_hz == 0 {
+            return Err(BlackboxError::Config(format!("Invalid sync tone frequency: {}", sync_tone_hz)));
+        }
+        let sync_tone_ms: u64 = parse(&loader.get("SYNC_TONE_MS", DEFAULT_SYNC_TONE_MS), "sync tone length")?;
+        let sync_tone_channel: Option<usize> = match loader.get_optional("SYNC_TONE_CHANNEL", None) {
+            Some(value) => Some(parse(&value, "sync tone channel")?),
+            None => None,
+        };
+
+        let dead_channel_timeout: u64 = parse(&loader.get("DEAD_CHANNEL_TIMEOUT", DEFAULT_DEAD_CHANNEL_TIMEOUT),
+                                              "dead channel timeout")?;
+
+        let drift_threshold_ms: u64 = parse(&loader.get("DRIFT_THRESHOLD_MS", DEFAULT_DRIFT_THRESHOLD_MS),
+                                            "drift threshold")?;
+
+        let resync: bool = parse(&loader.get("RESYNC", DEFAULT_RESYNC), "resync flag")?;
+
+        let write_buffer_bytes: usize = parse(&loader.get("WRITE_BUFFER_BYTES", DEFAULT_WRITE_BUFFER_BYTES),
+                                              "write buffer size")?;
+
+        let batch_samples: usize = parse(&loader.get("BATCH_SAMPLES", DEFAULT_BATCH_SAMPLES), "batch size")?;
+        if !(16..=1 << 20).contains(&batch_samples) {
+            return Err(BlackboxError::Config(format!("Invalid batch size: {} (must be 16 to 1048576 samples)", batch_samples)));
+        }
+
+        let warmup_discard_ms: u64 = parse(&loader.get("WARMUP_DISCARD_MS", DEFAULT_WARMUP_DISCARD_MS),
+                                           "warm-up discard")?;
+
+        let flush_interval_secs: u64 = parse(&loader.get("FLUSH_INTERVAL_SECS", DEFAULT_FLUSH_INTERVAL_SECS),
+                                             "flush interval")?;
+
+        let auto_repair: bool = parse(&loader.get("AUTO_REPAIR", DEFAULT_AUTO_REPAIR), "auto repair flag")?;
+
+        let verify_on_finalize: bool = parse(&loader.get("VERIFY_ON_FINALIZE", DEFAULT_VERIFY_ON_FINALIZE),
+                                             "verify on finalize flag")?;
+
+        let write_checksum: bool = parse(&loader.get("WRITE_CHECKSUM", DEFAULT_WRITE_CHECKSUM), "checksum flag")?;
+
+        let file_create_attempts: u32 = parse(&loader.get("FILE_CREATE_ATTEMPTS", DEFAULT_FILE_CREATE_ATTEMPTS),
+                                              "number of file create attempts")?;
+
+        let file_create_retry_ms: u64 = parse(&loader.get("FILE_CREATE_RETRY_MS", DEFAULT_FILE_CREATE_RETRY_MS),
+                                              "file create retry delay")?;
+
+        let max_write_errors = match loader.get_optional("MAX_WRITE_ERRORS", None) {
+            Some(value) => Some(parse::<u64>(&value, "maximum write errors")?),
+            None => None,
+        };
+
+        let output_dir = PathBuf::from(loader.get("OUTPUT_DIR", DEFAULT_OUTPUT_DIR));
+
+        let backup_dir = loader.get_optional("BACKUP_DIR", None).map(PathBuf::from);
+
+        let file_mode = match loader.get_optional("FILE_MODE", None) {
+            Some(value) => Some(parse_file_mode(&value)?),
+            None => None,
+        };
+        let file_group = loader.get_optional("FILE_GROUP", None);
+        if (file_mode.is_some() || file_group.is_some()) && !cfg!(unix) {
+            return Err(BlackboxError::Config("FILE_MODE and FILE_GROUP are not supported on this platform".to_string()));
+        }
+
+        let fifo_path = loader.get_optional("FIFO_PATH", None).map(PathBuf::from);
+        if fifo_path.is_some() && !cfg!(unix) {
+            return Err(BlackboxError::Config("FIFO_PATH is not supported on this platform".to_string()));
+        }
+
+        let status_file = loader.get_optional("STATUS_FILE", None).map(PathBuf::from);
+
+        let timestamp_tz = match loader.get("TIMESTAMP_TZ", DEFAULT_TIMESTAMP_TZ).as_str() {
+            "local" => TimestampZone::Local,
+            "utc" => TimestampZone::Utc,
+            other => return Err(BlackboxError::Config(format!("Invalid timestamp time zone: {}", other))),
+        };
+
+        let mut output_mode = loader.get("OUTPUT_MODE", DEFAULT_OUTPUT_MODE);
+        if args.iter().any(|arg| arg == "--stdout") {
+            output_mode = loader.override_cli("OUTPUT_MODE", "stdout");
+        }
+        let output_mode = match output_mode.as_str() {
+            "file" => OutputMode::File,
+            "stdout" => OutputMode::Stdout,
+            "split" => OutputMode::Split,
+            other => return Err(BlackboxError::Config(format!("Invalid output mode: {}", other))),
+        };
+
+        let mono_to_stereo: bool = parse(&loader.get("MONO_TO_STEREO", DEFAULT_MONO_TO_STEREO), "mono to stereo flag")?;
+        let level_envelope: bool = parse(&loader.get("LEVEL_ENVELOPE", DEFAULT_LEVEL_ENVELOPE), "level envelope flag")?;
+        let detect_clipping: bool = parse(&loader.get("DETECT_CLIPPING", DEFAULT_DETECT_CLIPPING), "detect clipping flag")?;
+        let clip_warn_percent: f64 = parse(&loader.get("CLIP_WARN_PERCENT", DEFAULT_CLIP_WARN_PERCENT), "clip warning percentage")?;
+        if !(0.0..=100.0).contains(&clip_warn_percent) {
+            return Err(BlackboxError::Config(format!("Invalid clip warning percentage: {} (must be 0 to 100)", clip_warn_percent)));
+        }
+        let timestamp_track: bool = parse(&loader.get("TIMESTAMP_TRACK", DEFAULT_TIMESTAMP_TRACK), "timestamp track flag")?;
+        let timestamp_track_secs: u64 = parse(&loader.get("TIMESTAMP_TRACK_SECS", DEFAULT_TIMESTAMP_TRACK_SECS),
+                                              "timestamp track interval")?;
+        if timestamp_track_secs == 0 {
+            return Err(BlackboxError::Config("TIMESTAMP_TRACK_SECS must be at least 1".to_string()));
+        }
+        let sample_accurate_start: bool = parse(&loader.get("SAMPLE_ACCURATE_START", DEFAULT_SAMPLE_ACCURATE_START),
+                                                "sample-accurate start flag")?;
+        let write_bext: bool = parse(&loader.get("WRITE_BEXT", DEFAULT_WRITE_BEXT), "bext flag")?;
+
+        let split_naming = match loader.get("SPLIT_NAMING", DEFAULT_SPLIT_NAMING).as_str() {
+            "channel" => SplitNaming::Channel,
+            "index" => SplitNaming::Index,
+            "label" => SplitNaming::Label,
+            other => return Err(BlackboxError::Config(format!("Invalid split naming: {}", other))),
+        };
+
+        let split_index_width: usize = parse(&loader.get("SPLIT_INDEX_WIDTH", DEFAULT_SPLIT_INDEX_WIDTH),
+                                             "split index width")?;
+
+        // Each mode's depth falls back to BIT_DEPTH
+        let bit_depth = parse_bit_depth(&loader.get("BIT_DEPTH", DEFAULT_BIT_DEPTH))?;
+        let split_bit_depth = match loader.get_optional("SPLIT_BIT_DEPTH", None) {
+            Some(value) => parse_bit_depth(&value)?,
+            None => bit_depth,
+        };
+        let single_bit_depth = match loader.get_optional("SINGLE_BIT_DEPTH", None) {
+            Some(value) => parse_bit_depth(&value)?,
+            None => bit_depth,
+        };
+
+        let monitor_mix = match loader.get_optional("MONITOR_MIX", None) {
+            Some(value) => Some(parse_monitor_mix(&value)?),
+            None => None,
+        };
+        if monitor_mix.is_some() && output_mode == OutputMode::Stdout {
+            return Err(BlackboxError::Config("MONITOR_MIX can't be used with stdout output".to_string()));
+        }
+
+        let output_format = loader.get("OUTPUT_FORMAT", DEFAULT_OUTPUT_FORMAT);
+        let output_format = OutputFormat::from_name(&output_format).ok_or_else(|| {
+            BlackboxError::Config(format!("Unsupported output format: {} (supported: {})",
+                                          output_format, supported_output_formats().join(", ")))
+        })?;
+
+        let wavpack_command = loader.get("WAVPACK_COMMAND", DEFAULT_WAVPACK_COMMAND);
+
+        let compress_finalized = match loader.get("COMPRESS_FINALIZED", DEFAULT_COMPRESS_FINALIZED).as_str() {
+            "none" => FileCompression::None,
+            "gzip" => FileCompression::Gzip,
+            "zstd" => FileCompression::Zstd,
+            other => return Err(BlackboxError::Config(format!("Invalid compression: {} (must be none, gzip or zstd)", other))),
+        };
+        let compress_keep_original: bool = parse(&loader.get("COMPRESS_KEEP_ORIGINAL", DEFAULT_COMPRESS_KEEP_ORIGINAL),
+                                                 "keep original flag")?;
+        let compress_command = loader.get_optional("COMPRESS_COMMAND", None);
+
+        let proxy_format = loader.get_optional("PROXY_FORMAT", None);
+        if let Some(format) = &proxy_format {
+            if format.is_empty() || format == "wav" || !format.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(BlackboxError::Config(format!("Invalid proxy format: {}", format)));
+            }
+        }
+
+        let proxy_bitrate: u32 = parse(&loader.get("PROXY_BITRATE", DEFAULT_PROXY_BITRATE), "proxy bitrate")?;
+
+        let proxy_command = loader.get("PROXY_COMMAND", DEFAULT_PROXY_COMMAND);
+
+        let post_command = loader.get_optional("POST_COMMAND", None).filter(|command| !command.trim().is_empty());
+
+        let audio_host = loader.get("AUDIO_HOST", DEFAULT_AUDIO_HOST);
+
+        let monitor_output = loader.get_optional("MONITOR_OUTPUT", None);
+
+        let monitor_downmix = match loader.get_optional("MONITOR_DOWNMIX", None) {
+            Some(value) => Some(parse_monitor_downmix(&value)?),
+            None => None,
+        };
+        if monitor_downmix.is_some() && monitor_output.is_none() {
+            return Err(BlackboxError::Config("MONITOR_DOWNMIX needs MONITOR_OUTPUT".to_string()));
+        }
+
+        let silence_threshold: f64 = parse(&loader.get("SILENCE_THRESHOLD", DEFAULT_SILENCE_THRESHOLD),
+                                           "silence threshold")?;
+
+        let silence_action = match loader.get("SILENCE_ACTION", DEFAULT_SILENCE_ACTION).as_str() {
+            "delete" => SilenceAction::Delete,
+            "report" => SilenceAction::Report,
+            other => return Err(BlackboxError::Config(format!("Invalid silence action: {}", other))),
+        };
+
+        let split_silence_policy = match loader.get("SPLIT_SILENCE_POLICY", DEFAULT_SPLIT_SILENCE_POLICY).as_str() {
+            "per_channel" => SplitSilencePolicy::PerChannel,
+            "all_or_nothing" => SplitSilencePolicy::AllOrNothing,
+            other => return Err(BlackboxError::Config(format!("Invalid split silence policy: {}", other))),
+        };
+
+        let channel_thresholds = match loader.get_optional("CHANNEL_THRESHOLDS", None) {
+            Some(value) => parse_channel_thresholds(&value)?,
+            None => HashMap::new(),
+        };
+
+        let silence_check_seconds = match loader.get_optional("SILENCE_CHECK_SECONDS", None) {
+            Some(value) => Some(parse::<f64>(&value, "silence check window")?),
+            None => None,
+        };
+
+        let min_recording_secs = match loader.get_optional("MIN_RECORDING_SECS", None) {
+            Some(value) => Some(parse::<f64>(&value, "minimum recording length")?),
+            None => None,
+        };
+
+        let max_recordings = match loader.get_optional("MAX_RECORDINGS", None) {
+            Some(value) => Some(parse::<usize>(&value, "maximum number of recordings")?),
+            None => None,
+        };
+
+        let min_disk_space_mb: u64 = parse(&loader.get("MIN_DISK_SPACE_MB", DEFAULT_MIN_DISK_SPACE_MB),
+                                           "minimum disk space")?;
+
+        let write_info_tags: bool = parse(&loader.get("WRITE_INFO_TAGS", DEFAULT_WRITE_INFO_TAGS),
+                                          "info tags flag")?;
+
+        let measure_loudness: bool = parse(&loader.get("MEASURE_LOUDNESS", DEFAULT_MEASURE_LOUDNESS),
+                                           "loudness flag")?;
+
+        let show_levels: bool = parse(&loader.get("SHOW_LEVELS", DEFAULT_SHOW_LEVELS), "show levels flag")?;
+
+        let control_keys = match loader.get_optional("CONTROL_KEYS", None) {
+            Some(value) => parse_control_keys(&value)?,
+            None => HashMap::new(),
+        };
+
+        let start_armed: bool = parse(&loader.get("START_ARMED", DEFAULT_START_ARMED), "start armed flag")?;
+        if start_armed && !control_keys.values().any(|&action| action == ControlAction::Start) {
+            return Err(BlackboxError::Config("START_ARMED needs a key bound to start in CONTROL_KEYS".to_string()));
+        }
+
+        let restart_on_start: bool = parse(&loader.get("RESTART_ON_START", DEFAULT_RESTART_ON_START),
+                                           "restart on start flag")?;
+
+        let schedule = match loader.get_optional("SCHEDULE", None) {
+            Some(value) => schedule::parse_schedule(&value)?,
+            None => Vec::new(),
+        };
+        if !schedule.is_empty() && start_armed {
+            return Err(BlackboxError::Config("SCHEDULE can't be combined with START_ARMED".to_string()));
+        }
+
+        let event_log: bool = parse(&loader.get("EVENT_LOG", DEFAULT_EVENT_LOG), "event log flag")?;
+
+        Ok(Config {
+            channels,
+            all_channels,
+            channel_labels,
+            on_channel_mismatch,
+            input_devices,
+            capture_mode,
+            source,
+            tone_signal,
+            tone_hz,
+            wait_for_device,
+            device_poll_ms,
+            sample_rate,
+            preferred_formats,
+            location,
+            location_file,
+            debug,
+            record_duration,
+            rotate_seconds,
+            rotate_align,
+            daily_rotation,
+            circular_files,
+            fade_ms,
+            fade_scope,
+            dither,
+            realtime_priority,
+            sync_tone,
+            sync_tone_hz,
+            sync_tone_ms,
+            sync_tone_channel,
+            dead_channel_timeout,
+            drift_threshold_ms,
+            resync,
+            write_buffer_bytes,
+            batch_samples,
+            warmup_discard_ms,
+            flush_interval_secs,
+            auto_repair,
+            verify_on_finalize,
+            write_checksum,
+            file_create_attempts,
+            file_create_retry_ms,
+            max_write_errors,
+            output_dir,
+            backup_dir,
+            file_mode,
+            file_group,
+            fifo_path,
+            status_file,
+            timestamp_tz,
+            output_mode,
+            mono_to_stereo,
+            level_envelope,
+            detect_clipping,
+            clip_warn_percent,
+            timestamp_track,
+            timestamp_track_secs,
+            sample_accurate_start,
+            write_bext,
+            split_naming,
+            split_index_width,
+            split_bit_depth,
+            single_bit_depth,
+            monitor_mix,
+            output_format,
+            compress_finalized,
+            compress_keep_original,
+            compress_command,
+            wavpack_command,
+            proxy_format,
+            proxy_bitrate,
+            proxy_command,
+            post_command,
+            audio_host,
+            monitor_output,
+            monitor_downmix,
+            silence_threshold,
+            silence_action,
+            split_silence_policy,
+            silence_check_seconds,
+            min_recording_secs,
+            channel_thresholds,
+            max_recordings,
+            min_disk_space_mb,
+            write_info_tags,
+            measure_loudness,
+            show_levels,
+            control_keys,
+            start_armed,
+            restart_on_start,
+            schedule,
+            event_log,
+            settings: loader.settings,
+        })
+    }
+
+    /// One `NAME=value (source)` line per setting, in load order.
+    pub fn describe(&self) -> String {
+        self.settings.iter()
+            .map(|s| format!("{}={} ({})\n", s.name, s.value.as_deref().unwrap_or("(unset)"), s.source))
+            .collect()
+    }
+}
+
+/// Parses a comma-separated list of channel indexes such as "0,1". The order
+/// is kept as written, since it is the order the channels are recorded in.
+pub fn parse_channel_string(value: &str) -> Result<Vec<usize>, BlackboxError> {
+    if value.trim().is_empty() {
+        return Err(BlackboxError::Config("No channels selected".to_string()));
+    }
+    let mut channels: Vec<usize> = Vec::new();
+    for s in value.split(',') {
+        let channel = |s: &str| s.trim().parse::<usize>()
+            .map_err(|_| BlackboxError::Config(format!("Invalid channel number: {}", s)));
+        match s.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (channel(first)?, channel(last)?);
+                if first > last {
+                    return Err(BlackboxError::Config(format!("Invalid channel range: {}", s)));
+                }
+                channels.extend(first..=last);
+            },
+            None => channels.push(channel(s)?),
+        }
+    }
+    for (i, channel) in channels.iter().enumerate() {
+        if channels[..i].contains(channel) {
+            return Err(BlackboxError::Config(format!("Channel {} is listed more than once", channel)));
+        }
+    }
+    Ok(channels)
+}
+
+/// The channel string selecting all `total_channels` of a device, e.g.
+/// "0-7" for an eight-channel interface.
+pub fn all_channels(total_channels: usize) -> String {
+    match total_channels {
+        0 | 1 => "0".to_string(),
+        _ => format!("0-{}", total_channels - 1),
+    }
+}
+
+/// Parses a WAV bit depth, which must be 16 or 24.
+fn parse_bit_depth(value: &str) -> Result<u16, BlackboxError> {
+    match value.trim() {
+        "16" => Ok(16),
+        "24" => Ok(24),
+        other => Err(BlackboxError::Config(format!("Invalid bit depth: {} (must be 16 or 24)", other))),
+    }
+}
+
+/// Parses a list of devices and their channels such as
+/// "Scarlett 18i20=0,1;MOTU 828=2,3".
+pub fn parse_input_devices(value: &str) -> Result<Vec<DeviceSpec>, BlackboxError> {
+    value.split(';')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (name, channels) = entry.rsplit_once('=')
+                .ok_or_else(|| BlackboxError::Config(format!("Invalid input device: {}", entry)))?;
+            Ok(DeviceSpec { name: name.trim().to_string(), channels: parse_channel_string(channels)? })
+        })
+        .collect()
+}
+
+/// Parses a list of sample formats in order of preference, such as
+/// "f32,i16,u16".
+pub fn parse_preferred_formats(value: &str) -> Result<Vec<String>, BlackboxError> {
+    value.split(',')
+        .map(|name| match name.trim().to_ascii_lowercase().as_str() {
+            name @ ("f32" | "i16" | "i32" | "u16") => Ok(name.to_string()),
+            _ => Err(BlackboxError::Config(format!("Invalid sample format: {} (supported: f32, i16, i32, u16)",
+                                                   name.trim()))),
+        })
+        .collect()
+}
+
+/// Parses Unix permissions written in octal, such as "640" or "0o640".
+pub fn parse_file_mode(value: &str) -> Result<u32, BlackboxError> {
+    let digits = value.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(BlackboxError::Config(format!("Invalid file mode: {} (must be octal, e.g. 640)", value))),
+    }
+}
+
+/// Parses a monitor mix such as "0,1:2-3", the channels for the left side
+/// before the colon and those for the right after it.
+pub fn parse_monitor_mix(value: &str) -> Result<MonitorMix, BlackboxError> {
+    let (left, right) = value.split_once(':')
+        .ok_or_else(|| BlackboxError::Config(format!("Invalid monitor mix: {}", value)))?;
+    Ok(MonitorMix { left: parse_channel_string(left)?, right: parse_channel_string(right)? })
+}
+
+/// Parses the channels summed onto each monitor output channel, separated by
+/// colons, such as "0,1:2-3" for a stereo output.
+pub fn parse_monitor_downmix(value: &str) -> Result<Vec<Vec<usize>>, BlackboxError> {
+    value.split(':').map(parse_channel_string).collect()
+}
+
+/// Parses per-channel silence thresholds such as "0:0.01,3:0.002".
+pub fn parse_channel_thresholds(value: &str) -> Result<HashMap<usize, f64>, BlackboxError> {
+    value.split(',')
+        .map(|pair| {
+            let (channel, threshold) = pair.split_once(':')
+                .ok_or_else(|| BlackboxError::Config(format!("Invalid channel threshold: {}", pair)))?;
+            Ok((parse(channel.trim(), "channel number")?, parse(threshold.trim(), "silence threshold")?))
+        })
+        .collect()
+}
+
+/// Parses key bindings such as "q=stop,m=marker,s=start".
+pub fn parse_control_keys(value: &str) -> Result<HashMap<String, ControlAction>, BlackboxError> {
+    value.split(',')
+        .map(|pair| {
+            let invalid = || BlackboxError::Config(format!("Invalid control key: {}", pair));
+            let (key, action) = pair.split_once('=').ok_or_else(invalid)?;
+            let action = match action.trim() {
+                "marker" => ControlAction::Marker,
+                "stop" => ControlAction::Stop,
+                "start" => ControlAction::Start,
+                _ => return Err(invalid()),
+            };
+            match key.trim() {
+                "" => Err(invalid()),
+                key => Ok((key.to_string(), action)),
+            }
+        })
+        .collect()
+}
+
+/// Parses a latitude and longitude in decimal degrees such as
+/// "51.5007,-0.1246".
+pub fn parse_location(value: &str) -> Result<Location, BlackboxError> {
+    let invalid = || BlackboxError::Config(format!("Invalid location: {}", value));
+    let (latitude, longitude) = value.split_once(',').ok_or_else(invalid)?;
+    let latitude: f64 = latitude.trim().parse().map_err(|_| invalid())?;
+    let longitude: f64 = longitude.trim().parse().map_err(|_| invalid())?;
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(invalid());
+    }
+    Ok(Location { latitude, longitude })
+}
+
+fn parse<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, BlackboxError> {
+    value.parse().map_err(|_| BlackboxError::Config(format!("Invalid {}: {}", what, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::env_from;
+
+    #[test]
+    fn test_provenance() {
+        let env = env_from(&[
+            ("DEBUG", "true"),
+            ("OUTPUT_MODE", "file"),
+        ]);
+        let config = Config::from_sources(&env, &["--stdout".to_string()]).unwrap();
+
+        assert!(config.debug);
+        assert_eq!(config.output_mode, OutputMode::Stdout);
+
+        let description = config.describe();
+        assert!(description.contains("DEBUG=true (env)\n"));
+        assert!(description.contains("AUDIO_CHANNELS=1,2 (default)\n"));
+        assert!(description.contains("OUTPUT_MODE=stdout (cli)\n"));
+        assert!(description.contains("MONITOR_OUTPUT=(unset) (default)\n"));
+    }
+
+    #[test]
+    fn test_get_and_set_field() {
+        let mut config = Config::from_sources(&|_| None, &["--stdout".to_string()]).unwrap();
+        assert_eq!(config.get_field("silence_threshold").as_deref(), Some(DEFAULT_SILENCE_THRESHOLD));
+        assert_eq!(config.get_field("MONITOR_OUTPUT"), None);
+
+        config.set_field("silence_threshold", "0.002").unwrap();
+        assert_eq!(config.silence_threshold, 0.002);
+        assert_eq!(config.get_field("SILENCE_THRESHOLD").as_deref(), Some("0.002"));
+        assert!(config.describe().contains("SILENCE_THRESHOLD=0.002 (set)\n"));
+        // The rest keeps its value and where it came from
+        assert_eq!(config.output_mode, OutputMode::Stdout);
+        assert!(config.describe().contains("OUTPUT_MODE=stdout (cli)\n"));
+
+        assert!(matches!(config.set_field("silence_threshold", "quiet"), Err(BlackboxError::Config(_))));
+        assert_eq!(config.silence_threshold, 0.002);
+        assert!(matches!(config.set_field("volume", "11"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_channel_string() {
+        assert_eq!(parse_channel_string("3, 4").unwrap(), vec![3, 4]);
+        assert!(matches!(parse_channel_string("1,x"), Err(BlackboxError::Config(_))));
+        assert!(matches!(parse_channel_string(" "), Err(BlackboxError::Config(msg)) if msg == "No channels selected"));
+
+        let env = env_from(&[("AUDIO_CHANNELS", "")]);
+        assert!(matches!(Config::from_sources(&env, &[]), Err(BlackboxError::Config(msg)) if msg == "No channels selected"));
+    }
+
+    #[test]
+    fn test_channels_keep_their_order() {
+        assert_eq!(parse_channel_string("2,0,1").unwrap(), vec![2, 0, 1]);
+        assert!(matches!(parse_channel_string("1,2,1"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_channel_ranges() {
+        assert_eq!(parse_channel_string("4,0-2").unwrap(), vec![4, 0, 1, 2]);
+        assert!(matches!(parse_channel_string("3-1"), Err(BlackboxError::Config(_))));
+        assert!(matches!(parse_channel_string("0-2,1"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_file_mode() {
+        assert_eq!(parse_file_mode("640").unwrap(), 0o640);
+        assert_eq!(parse_file_mode("0o2775").unwrap(), 0o2775);
+        for mode in ["rw-r-----", "789", "17777"] {
+            assert!(matches!(parse_file_mode(mode), Err(BlackboxError::Config(_))), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_clip_warn_percent() {
+        let env = env_from(&[("CLIP_WARN_PERCENT", "150")]);
+        assert!(matches!(Config::from_sources(&env, &[]), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_channel_thresholds() {
+        assert_eq!(parse_channel_thresholds("0:0.01, 3:0.5").unwrap(), HashMap::from([(0, 0.01), (3, 0.5)]));
+        assert!(matches!(parse_channel_thresholds("0=0.01"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_input_devices() {
+        assert_eq!(parse_input_devices("Scarlett 18i20=0,1; MOTU=2").unwrap(), vec![
+            DeviceSpec { name: "Scarlett 18i20".to_string(), channels: vec![0, 1] },
+            DeviceSpec { name: "MOTU".to_string(), channels: vec![2] },
+        ]);
+        assert!(matches!(parse_input_devices("Scarlett"), Err(BlackboxError::Config(_))));
+        assert!(matches!(parse_input_devices("MOTU="), Err(BlackboxError::Config(msg)) if msg == "No channels selected"));
+
+        let env = env_from(&[
+            ("CAPTURE_MODE", "loopback"),
+            ("INPUT_DEVICES", "MOTU=0"),
+        ]);
+        assert!(matches!(Config::from_sources(&env, &[]), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_control_keys() {
+        assert_eq!(parse_control_keys("q=stop, m = marker").unwrap(), HashMap::from([
+            ("q".to_string(), ControlAction::Stop),
+            ("m".to_string(), ControlAction::Marker),
+        ]));
+        assert!(matches!(parse_control_keys("q=quit"), Err(BlackboxError::Config(_))));
+        assert!(matches!(parse_control_keys("=stop"), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!(parse_location("51.5007, -0.1246").unwrap(), Location { latitude: 51.5007, longitude: -0.1246 });
+        for location in ["51.5007", "north,west", "91,0", "0,-181", "NaN,0"] {
+            assert!(matches!(parse_location(location), Err(BlackboxError::Config(_))), "{}", location);
+        }
+    }
+
+    #[test]
+    fn test_batch_samples() {
+        for batch_samples in ["8", "2000000"] {
+            let env = env_from(&[("BATCH_SAMPLES", batch_samples)]);
+            assert!(matches!(Config::from_sources(&env, &[]), Err(BlackboxError::Config(_))));
+        }
+    }
+
+    #[test]
+    fn test_record_duration() {
+        let env = env_from(&[("RECORD_DURATION", "soon")]);
+        match Config::from_sources(&env, &[]) {
+            Err(BlackboxError::Config(msg)) => assert_eq!(msg, "Invalid record duration: soon"),
+            _ => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn test_supported_output_formats() {
+        assert!(supported_output_formats().contains(&"wav"));
+        assert!(supported_output_formats().iter().all(|format| is_format_supported(format)));
+        assert!(!is_format_supported("mp4"));
+
+        let env = env_from(&[("OUTPUT_FORMAT", "mp4")]);
+        assert!(matches!(Config::from_sources(&env, &[]),
+                         Err(BlackboxError::Config(msg)) if msg == "Unsupported output format: mp4 (supported: wav, wavpack)"));
+    }
+
+    #[test]
+    fn test_all_channels() {
+        assert_eq!(all_channels(8), "0-7");
+        assert_eq!(all_channels(1), "0");
+        assert_eq!(parse_channel_string(&all_channels(8)).unwrap(), (0..8).collect::<Vec<usize>>());
+
+        let config = Config::from_sources(&env_from(&[("AUDIO_CHANNELS", "All")]), &[]).unwrap();
+        assert!(config.all_channels);
+        assert_eq!(config.channels_for(3), vec![0, 1, 2]);
+    }
+}
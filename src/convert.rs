@@ -0,0 +1,176 @@
+//! `--convert`: encoding existing WAV recordings to another format.
+//!
+//! Uses the same encoders as finished recordings do, so `wavpack` goes
+//! through `WAVPACK_COMMAND` and anything else is treated as a proxy
+//! extension for `PROXY_COMMAND`. Each converted file is written next to its
+//! WAV, which is kept. Only WAV files can be converted: a recording already
+//! in WavPack has to be unpacked with `wvunpack` first.
+//!
+//! `--decompress` undoes `COMPRESS_FINALIZED` the same way, writing each
+//! file's original next to it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::encode;
+use crate::error::BlackboxError;
+
+/// The WAV files to convert: `src` itself, or every `.wav` file directly in
+/// it if it's a directory, in name order.
+pub fn wav_files(src: &Path) -> Result<Vec<PathBuf>, BlackboxError> {
+    if !src.is_dir() {
+        return Ok(vec![src.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(src)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "wav") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Encodes one WAV file to `format` and returns the path written.
+pub fn convert_file(path: &Path, format: &str, config: &Config) -> Result<String, BlackboxError> {
+    let path = path.to_str()
+        .ok_or_else(|| BlackboxError::Config(format!("Unsupported file name: {}", path.display())))?;
+    // Reject anything that isn't a WAV before handing it to an encoder
+    hound::WavReader::open(path)?;
+    match format {
+        "wav" => Err(BlackboxError::Config("Recordings are already WAV".to_string())),
+        "wavpack" | "wv" => encode::encode_wavpack(path, &config.wavpack_command),
+        _ if format.is_empty() || !format.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Err(BlackboxError::Config(format!("Invalid format: {}", format)))
+        },
+        _ => encode::encode_proxy(path, format, config.proxy_bitrate, &config.proxy_command),
+    }
+}
+
+/// Converts `src` to `format`, printing each file's result and a summary.
+/// Returns how many files failed.
+pub fn run_convert(src: &Path, format: &str, config: &Config) -> Result<usize, BlackboxError> {
+    let files = wav_files(src)?;
+    let mut failed = 0;
+    for path in &files {
+        match convert_file(path, format, config) {
+            Ok(output) => println!("Converted {} to {}", path.display(), output),
+            Err(e) => {
+                eprintln!("Failed to convert {}: {}", path.display(), e);
+                failed += 1;
+            },
+        }
+    }
+    println!("{} of {} file(s) converted to {}", files.len() - failed, files.len(), format);
+    Ok(failed)
+}
+
+/// Decompresses `src`, or every `.gz` and `.zst` file directly in it if it's
+/// a directory, printing each file's result. Returns how many files failed.
+pub fn run_decompress(src: &Path, config: &Config) -> Result<usize, BlackboxError> {
+    let files = match src.is_dir() {
+        true => {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(src)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().is_some_and(|ext| ext == "gz" || ext == "zst") {
+                    files.push(path);
+                }
+            }
+            files.sort();
+            files
+        },
+        false => vec![src.to_path_buf()],
+    };
+    let mut failed = 0;
+    for path in &files {
+        let result = path.to_str()
+            .ok_or_else(|| BlackboxError::Config(format!("Unsupported file name: {}", path.display())))
+            .and_then(|path| encode::decompress_file(path, config.compress_command.as_deref()));
+        match result {
+            Ok(output) => println!("Decompressed {} to {}", path.display(), output),
+            Err(e) => {
+                eprintln!("Failed to decompress {}: {}", path.display(), e);
+                failed += 1;
+            },
+        }
+    }
+    println!("{} of {} file(s) decompressed", files.len() - failed, files.len());
+    Ok(failed)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_util::{env_from, wav_spec};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_convert_round_trips_through_wavpack() {
+        if !encode::installed("wavpack") || !encode::installed("wvunpack") {
+            eprintln!("Skipping: wavpack and wvunpack aren't installed");
+            return;
+        }
+        let temp_dir = tempdir().unwrap();
+        let config = Config::from_sources(&|_: &str| None, &[]).unwrap();
+        let spec = wav_spec(2, 44100);
+        let samples: Vec<i16> = (0..20000).map(|i| ((i * 37) % 65536 - 32768) as i16).collect();
+        let path = temp_dir.path().join("take.wav");
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let output = convert_file(&path, "wavpack", &config).unwrap();
+        assert!(output.ends_with("take.wv"));
+        let decoded = temp_dir.path().join("decoded.wav");
+        let status = std::process::Command::new("wvunpack")
+            .args(["-q", "-y", &output, "-o", decoded.to_str().unwrap()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut reader = hound::WavReader::open(&decoded).unwrap();
+        assert_eq!(reader.spec(), spec);
+        let unpacked: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(unpacked, samples);
+
+        // A WavPack file isn't taken as a source
+        assert!(convert_file(Path::new(&output), "wavpack", &config).is_err());
+    }
+
+    #[test]
+    fn test_convert_directory() {
+        // Stands in for wavpack: copies the input ($3) to the output ($5)
+        let temp_dir = tempdir().unwrap();
+        let encoder = temp_dir.path().join("fake-wavpack");
+        fs::write(&encoder, "#!/bin/sh\ncp \"$3\" \"$5\"\n").unwrap();
+        fs::set_permissions(&encoder, fs::Permissions::from_mode(0o755)).unwrap();
+        let command = encoder.to_str().unwrap().to_string();
+        let env = env_from(&[("WAVPACK_COMMAND", &command)]);
+        let config = Config::from_sources(&env, &[]).unwrap();
+
+        let recordings = temp_dir.path().join("recordings");
+        fs::create_dir(&recordings).unwrap();
+        let spec = wav_spec(2, 44100);
+        let samples: Vec<i16> = (0..200).map(|i| i * 100).collect();
+        let mut writer = hound::WavWriter::create(recordings.join("take.wav"), spec).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        fs::write(recordings.join("broken.wav"), b"not audio").unwrap();
+        fs::write(recordings.join("notes.txt"), b"").unwrap();
+
+        assert_eq!(run_convert(&recordings, "wavpack", &config).unwrap(), 1);
+        assert!(recordings.join("take.wav").exists());
+        assert!(!recordings.join("broken.wv").exists());
+        // The stand-in only copies, so this shows what the encoder was given
+        assert_eq!(fs::read(recordings.join("take.wv")).unwrap(), fs::read(recordings.join("take.wav")).unwrap());
+        assert!(!recordings.join("notes.wv").exists());
+    }
+}
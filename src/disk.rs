@@ -0,0 +1,256 @@
+//! Checks on the filesystem recordings are written to.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::BlackboxError;
+
+/// Gives the file at `path` the permissions `mode` and hands it to `group`,
+/// by name or number, where they are set.
+#[cfg(unix)]
+pub fn set_file_access(path: &Path, mode: Option<u32>, group: Option<&str>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(group) = group {
+        std::os::unix::fs::chown(path, None, Some(group_id(group)?))?;
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_file_access(_path: &Path, _mode: Option<u32>, _group: Option<&str>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file permissions are not supported on this platform"))
+}
+
+/// The ID of the group named `group`, or numbered by it.
+#[cfg(unix)]
+fn group_id(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No group named {}", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Free space available to this user on the filesystem holding `path`, in MiB.
+#[cfg(unix)]
+pub fn available_disk_space_mb(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64 / (1024 * 1024))
+}
+
+#[cfg(not(unix))]
+pub fn available_disk_space_mb(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is not available on this platform"))
+}
+
+/// Errors unless `dir` has at least `min_mb` MiB free, otherwise returns the free space.
+pub fn check_disk_space(dir: &Path, min_mb: u64) -> Result<u64, BlackboxError> {
+    let available = available_disk_space_mb(dir)?;
+    if available < min_mb {
+        return Err(BlackboxError::Config(format!("Only {} MB free in {}, need at least {} MB",
+                                                 available, dir.display(), min_mb)));
+    }
+    Ok(available)
+}
+
+/// Creates `dir` if needed, then creates and deletes a scratch file in it, so
+/// an output directory that can't be written to is reported before
+/// recording starts rather than when the first file is opened.
+pub fn check_output_writable(dir: &Path) -> Result<(), BlackboxError> {
+    let not_writable = |e: io::Error| {
+        BlackboxError::Config(format!("Output directory {} is not writable: {}", dir.display(), e))
+    };
+    fs::create_dir_all(dir).map_err(not_writable)?;
+    let path = dir.join(".blackbox-write-check");
+    fs::write(&path, b"").map_err(not_writable)?;
+    fs::remove_file(&path).map_err(not_writable)
+}
+
+/// Copies `path` into `backup_dir`, creating the directory if needed, and
+/// returns the path of the copy. The copy is written under a temporary name
+/// and renamed into place, so the backup directory never holds a partial
+/// file under a recording's name.
+pub fn copy_to_backup(path: &Path, backup_dir: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    fs::create_dir_all(backup_dir)?;
+    let backup = backup_dir.join(file_name);
+    let partial = backup_dir.join(format!(".{}.partial", file_name.to_string_lossy()));
+    fs::copy(path, &partial)?;
+    fs::rename(&partial, &backup).inspect_err(|_| { let _ = fs::remove_file(&partial); })?;
+    Ok(backup)
+}
+
+/// Writes a short WAV file to `dir`, reads it back, and deletes it.
+pub fn check_writable(dir: &Path) -> Result<(), BlackboxError> {
+    let path = dir.join(".blackbox-selftest.wav");
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let samples: Vec<i16> = (0..800).map(|i| (i * 40) as i16).collect();
+
+    let result = (|| {
+        let mut writer = hound::WavWriter::create(&path, spec)?;
+        for &sample in &samples {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+
+        let read_back = hound::WavReader::open(&path)?
+            .into_samples::<i16>()
+            .collect::<Result<Vec<_>, _>>()?;
+        if read_back != samples {
+            return Err(BlackboxError::Corrupt(format!("{} did not read back as written", path.display())));
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Calls `attempt` up to `attempts` times until it succeeds, waiting
+/// `initial_delay` after the first failure and twice as long after each one
+/// after that. Meant for creating files on storage with transient failures,
+/// such as a network filesystem. Returns the last error if every attempt fails.
+pub fn retry_with_backoff<T>(attempts: u32, initial_delay: Duration, what: &str,
+                             mut attempt: impl FnMut() -> Result<T, BlackboxError>) -> Result<T, BlackboxError> {
+    let mut delay = initial_delay;
+    let mut tries = 1;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) if tries < attempts => {
+                eprintln!("Failed to {} (attempt {} of {}): {}; retrying in {:?}", what, tries, attempts, e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+                tries += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_to_backup() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("take.wav");
+        fs::write(&path, b"RIFF audio").unwrap();
+        let backup_dir = temp_dir.path().join("backup/nested");
+
+        let backup = copy_to_backup(&path, &backup_dir).unwrap();
+        assert_eq!(backup, backup_dir.join("take.wav"));
+        assert_eq!(fs::read(&backup).unwrap(), b"RIFF audio");
+        assert_eq!(fs::read_dir(&backup_dir).unwrap().count(), 1);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let temp_dir = tempdir().unwrap();
+        check_writable(temp_dir.path()).unwrap();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        assert!(check_writable(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_check_output_writable() {
+        let temp_dir = tempdir().unwrap();
+        let nested = temp_dir.path().join("new").join("dir");
+        check_output_writable(&nested).unwrap();
+        assert_eq!(fs::read_dir(&nested).unwrap().count(), 0);
+
+        // A directory can't be made inside a file
+        let file = temp_dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        match check_output_writable(&file.join("dir")) {
+            Err(BlackboxError::Config(msg)) => assert!(msg.starts_with("Output directory ") && msg.contains("not writable")),
+            _ => panic!("expected a config error"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_output_dir_is_not_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permissions don't stop root, so there is nothing to check
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let temp_dir = tempdir().unwrap();
+        let read_only = temp_dir.path().join("read-only");
+        fs::create_dir(&read_only).unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let result = check_output_writable(&read_only);
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(result, Err(BlackboxError::Config(msg)) if msg.contains("Permission denied")));
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("retried.wav");
+        let spec = wav_spec(1, 8000);
+
+        // A create that fails twice, as a flaky mount might, then works
+        let mut calls = 0;
+        let writer = retry_with_backoff(3, Duration::from_millis(1), "create test file", || {
+            calls += 1;
+            if calls < 3 {
+                return Err(BlackboxError::Io(io::Error::other("transient")));
+            }
+            Ok(hound::WavWriter::create(&path, spec)?)
+        }).unwrap();
+        writer.finalize().unwrap();
+        assert_eq!(calls, 3);
+        assert!(path.exists());
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_backoff(2, Duration::from_millis(1), "fail", || {
+            calls += 1;
+            Err(BlackboxError::Stream("always".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_disk_space() {
+        let temp_dir = tempdir().unwrap();
+        let available = check_disk_space(temp_dir.path(), 0).unwrap();
+        assert_eq!(available, available_disk_space_mb(temp_dir.path()).unwrap());
+
+        assert!(matches!(check_disk_space(temp_dir.path(), u64::MAX), Err(BlackboxError::Config(_))));
+    }
+}
@@ -0,0 +1,76 @@
+//! Dither for reducing float input to integer samples.
+
+use crate::processor::full_scale;
+
+/// Adds triangular-PDF noise of up to ±1 LSB before rounding. Plain
+/// truncation leaves distortion that follows the signal, which is audible
+/// on quiet fades; dithering turns it into a constant, signal-independent
+/// noise floor instead. The noise comes from a small seeded generator, so
+/// the same seed always gives the same output.
+pub struct Dither {
+    state: u64,
+}
+
+impl Dither {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Dither { state: seed | 1 }
+    }
+
+    /// A uniform random number in [0, 1), from xorshift64*.
+    pub(crate) fn next_uniform(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Converts a sample in [-1.0, 1.0] to a `bits_per_sample` value widened
+    /// to `i32`.
+    pub fn quantize(&mut self, sample: f32, bits_per_sample: u16) -> i32 {
+        let max = full_scale(bits_per_sample) as f64;
+        let noise = self.next_uniform() - self.next_uniform();
+        (sample as f64 * max + noise).round().clamp(-max - 1.0, max) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB: f32 = 1.0 / i16::MAX as f32;
+
+    #[test]
+    fn test_dither_error_is_noise_like() {
+        // A ramp from 0 to 4 LSB, where truncation's error follows the signal
+        let ramp: Vec<f32> = (0..40000).map(|i| i as f32 / 10000.0 * LSB).collect();
+        let mut dither = Dither::new(7);
+        let errors: Vec<f64> = ramp.iter()
+            .map(|&s| dither.quantize(s, 16) as f64 - s as f64 * i16::MAX as f64)
+            .collect();
+        let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+        let variance = errors.iter().map(|e| (e - mean) * (e - mean)).sum::<f64>() / errors.len() as f64;
+        // Rounding plus TPDF noise averages out to no error, with a variance of 1/12 + 1/6 LSB²
+        assert!(mean.abs() < 0.02, "mean error {}", mean);
+        assert!((variance - 0.25).abs() < 0.03, "error variance {}", variance);
+
+        // Truncating a steady 0.3 LSB loses it entirely; dither keeps it on average
+        let truncated = (0.3 * LSB * i16::MAX as f32) as i16;
+        assert_eq!(truncated, 0);
+        let dithered = (0..10000).map(|_| dither.quantize(0.3 * LSB, 16) as f64).sum::<f64>() / 10000.0;
+        assert!((dithered - 0.3).abs() < 0.05, "dithered mean {}", dithered);
+    }
+
+    #[test]
+    fn test_same_seed_gives_same_output() {
+        let mut first = Dither::new(42);
+        let mut second = Dither::new(42);
+        for i in 0..1000 {
+            let sample = (i as f32 * 0.01).sin() * 0.001;
+            assert_eq!(first.quantize(sample, 16), second.quantize(sample, 16));
+        }
+        assert_eq!(Dither::new(1).quantize(1.0, 16), i16::MAX as i32);
+        assert_eq!(Dither::new(1).quantize(1.0, 24), (1 << 23) - 1);
+    }
+}
@@ -0,0 +1,232 @@
+//! Re-encoding finished WAV recordings into compressed archive and proxy
+//! formats.
+//!
+//! Encoding is done by the format's reference command-line encoder once a
+//! file is complete, so the live recording path is always plain WAV and
+//! everything that inspects the WAV (markers, tags, the silence check) runs
+//! before it is replaced.
+
+use std::fs::{self, File};
+use std::process::Command;
+
+use crate::config::FileCompression;
+use crate::error::BlackboxError;
+
+/// Losslessly compresses `path` to WavPack with `command` (normally
+/// `wavpack`), which keeps the WAV's extra chunks such as cue points and INFO
+/// tags. Deletes the WAV and returns the `.wv` path only once the encoder has
+/// succeeded.
+pub fn compress_wavpack(path: &str, command: &str) -> Result<String, BlackboxError> {
+    let output = encode_wavpack(path, command)?;
+    fs::remove_file(path)?;
+    Ok(output)
+}
+
+/// Like `compress_wavpack`, but keeps the WAV.
+pub fn encode_wavpack(path: &str, command: &str) -> Result<String, BlackboxError> {
+    let output = match path.strip_suffix(".wav") {
+        Some(stem) => format!("{}.wv", stem),
+        None => format!("{}.wv", path),
+    };
+    let status = Command::new(command)
+        .args(["-q", "-y", path, "-o", &output])
+        .status()
+        .map_err(|e| BlackboxError::ExternalTool(format!("Failed to run {}: {}", command, e)))?;
+    if !status.success() {
+        let _ = fs::remove_file(&output);
+        return Err(BlackboxError::ExternalTool(format!("{} failed on {} ({})", command, path, status)));
+    }
+    Ok(output)
+}
+
+/// Encodes a lossy proxy of `path` for quick review, e.g. `mp3` or `opus`,
+/// at `bitrate_kbps` with `command` (normally `ffmpeg`), which picks the
+/// codec from the extension. The WAV is kept. Returns the proxy's path.
+pub fn encode_proxy(path: &str, format: &str, bitrate_kbps: u32, command: &str) -> Result<String, BlackboxError> {
+    let output = match path.strip_suffix(".wav") {
+        Some(stem) => format!("{}.{}", stem, format),
+        None => format!("{}.{}", path, format),
+    };
+    let status = Command::new(command)
+        .args(["-loglevel", "error", "-y", "-i", path, "-b:a", &format!("{}k", bitrate_kbps), &output])
+        .status()
+        .map_err(|e| BlackboxError::ExternalTool(format!("Failed to run {}: {}", command, e)))?;
+    if !status.success() {
+        let _ = fs::remove_file(&output);
+        return Err(BlackboxError::ExternalTool(format!("{} failed on {} ({})", command, path, status)));
+    }
+    Ok(output)
+}
+
+/// Whether `command` can be run, for tests that need a real encoder.
+#[cfg(test)]
+pub(crate) fn installed(command: &str) -> bool {
+    Command::new(command).arg("--help").output().is_ok()
+}
+
+/// The name of the thread running `COMPRESS_FINALIZED`.
+pub const COMPRESS_THREAD_NAME: &str = "blackbox-compress";
+
+/// Compresses `path` whole with `gzip` or `zstd`, which must be installed,
+/// or with `command` in their place, to a file with the compression's
+/// extension added, e.g. `take.wav.zst`. `path` is kept. Returns the
+/// compressed file's path.
+pub fn compress_file(path: &str, compression: FileCompression, command: Option<&str>) -> Result<String, BlackboxError> {
+    let (extension, command) = match (compression.extension(), command.or(compression.command())) {
+        (Some(extension), Some(command)) => (extension, command),
+        _ => return Err(BlackboxError::Config("No compression chosen".to_string())),
+    };
+    let output = format!("{}.{}", path, extension);
+    run_compressor(command, &[], path, &output)?;
+    Ok(output)
+}
+
+/// Undoes `compress_file`, writing the original next to the compressed
+/// file at `path`, which is kept. Returns the original's path.
+pub fn decompress_file(path: &str, command: Option<&str>) -> Result<String, BlackboxError> {
+    let compression = FileCompression::from_path(path);
+    let output = match compression.extension().and_then(|extension| path.strip_suffix(&format!(".{}", extension))) {
+        Some(output) => output.to_string(),
+        None => return Err(BlackboxError::Config(format!("Not a .gz or .zst file: {}", path))),
+    };
+    let command = command.or(compression.command()).unwrap_or_default();
+    run_compressor(command, &["-d"], path, &output)?;
+    Ok(output)
+}
+
+/// Runs `command`, `gzip` or a tool taking the same arguments, on `input`
+/// with `args`, sending what it writes to standard output into `output`,
+/// which is deleted if it fails.
+fn run_compressor(command: &str, args: &[&str], input: &str, output: &str) -> Result<(), BlackboxError> {
+    let result = Command::new(command).arg("-q").args(args).arg("-c").arg(input).stdout(File::create(output)?).status();
+    let error = match result {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => BlackboxError::ExternalTool(format!("{} failed on {} ({})", command, input, status)),
+        Err(e) => BlackboxError::ExternalTool(format!("Failed to run {}: {}", command, e)),
+    };
+    let _ = fs::remove_file(output);
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_missing_encoder_keeps_wav() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("keep.wav");
+        fs::write(&path, b"RIFF").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(matches!(compress_wavpack(path, "/nonexistent/wavpack"), Err(BlackboxError::ExternalTool(_))));
+        assert!(Path::new(path).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_successful_encode_replaces_wav() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for wavpack: copies the input ($3) to the output ($5)
+        let temp_dir = tempdir().unwrap();
+        let encoder = temp_dir.path().join("fake-wavpack");
+        fs::write(&encoder, "#!/bin/sh\ncp \"$3\" \"$5\"\n").unwrap();
+        fs::set_permissions(&encoder, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = temp_dir.path().join("take.wav");
+        fs::write(&path, b"audio").unwrap();
+
+        let output = compress_wavpack(path.to_str().unwrap(), encoder.to_str().unwrap()).unwrap();
+        assert!(output.ends_with("take.wv"));
+        assert_eq!(fs::read(&output).unwrap(), b"audio");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wavpack_round_trip() {
+        if !installed("wavpack") || !installed("wvunpack") {
+            eprintln!("Skipping: wavpack and wvunpack aren't installed");
+            return;
+        }
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("take.wav");
+        let spec = wav_spec(1, 48000);
+        let samples: Vec<i16> = (0..48000).map(|i| ((i as f64 / 20.0).sin() * 20000.0) as i16).collect();
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let output = compress_wavpack(path.to_str().unwrap(), "wavpack").unwrap();
+        assert!(!path.exists());
+        assert!(fs::metadata(&output).unwrap().len() < 48000 * 2);
+        let status = Command::new("wvunpack").args(["-q", "-y", &output, "-o", path.to_str().unwrap()]).status().unwrap();
+        assert!(status.success());
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec(), spec);
+        let unpacked: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(unpacked, samples);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compressed_file_decompresses_to_the_same_bytes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for gzip: writes its last argument, the input, unchanged
+        // to standard output, whether compressing or decompressing
+        let temp_dir = tempdir().unwrap();
+        let compressor = temp_dir.path().join("fake-gzip");
+        fs::write(&compressor, "#!/bin/sh\nfor arg; do input=\"$arg\"; done\ncat \"$input\"\n").unwrap();
+        fs::set_permissions(&compressor, fs::Permissions::from_mode(0o755)).unwrap();
+        let compressor = Some(compressor.to_str().unwrap());
+
+        let path = temp_dir.path().join("take.wav");
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let path = path.to_str().unwrap();
+
+        let compressed = compress_file(path, FileCompression::Gzip, compressor).unwrap();
+        assert!(compressed.ends_with("take.wav.gz"));
+        assert!(Path::new(path).exists());
+
+        fs::remove_file(path).unwrap();
+        assert_eq!(decompress_file(&compressed, compressor).unwrap(), path);
+        assert_eq!(fs::read(path).unwrap(), contents);
+        assert!(Path::new(&compressed).exists());
+
+        assert!(decompress_file(path, compressor).is_err());
+        // A compressor that can't run leaves no partial output behind
+        assert!(compress_file(path, FileCompression::Zstd, Some("/nonexistent/zstd")).is_err());
+        assert!(!Path::new(&format!("{}.zst", path)).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_proxy_keeps_wav() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for ffmpeg: copies the input ($5) to the output ($8)
+        let temp_dir = tempdir().unwrap();
+        let encoder = temp_dir.path().join("fake-ffmpeg");
+        fs::write(&encoder, "#!/bin/sh\ncp \"$5\" \"$8\"\n").unwrap();
+        fs::set_permissions(&encoder, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = temp_dir.path().join("take.wav");
+        fs::write(&path, b"audio").unwrap();
+
+        let output = encode_proxy(path.to_str().unwrap(), "opus", 64, encoder.to_str().unwrap()).unwrap();
+        assert!(output.ends_with("take.opus"));
+        assert_eq!(fs::read(&output).unwrap(), b"audio");
+        assert!(path.exists());
+
+        assert!(matches!(encode_proxy(path.to_str().unwrap(), "mp3", 64, "/nonexistent/ffmpeg"), Err(BlackboxError::ExternalTool(_))));
+        assert!(!temp_dir.path().join("take.mp3").exists());
+    }
+}
@@ -0,0 +1,247 @@
+//! `LEVEL_ENVELOPE`: a coarse per-second level history written next to each
+//! recording, for drawing an overview of it without reading the audio.
+//!
+//! Each file gets `{file}.levels.json`, holding for each of its channels the
+//! peak and RMS level of every second, as fractions of full scale:
+//! `{"seconds_per_point":1,"channels":[{"peak":[0.5,...],"rms":[0.35,...]}]}`.
+//! With `DETECT_CLIPPING`, each channel also has its `clipped` sample count
+//! and `clipped_percent`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::clip::ClipCounter;
+use crate::error::BlackboxError;
+use crate::processor::{full_scale, AudioProcessor};
+
+/// Appended to a file's name for its level envelope.
+pub const ENVELOPE_SUFFIX: &str = ".levels.json";
+
+/// Where the level envelope of the file at `path` is kept.
+pub fn envelope_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(ENVELOPE_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Deletes the level envelope of the file at `path`, if it has one.
+pub fn remove_envelope(path: &Path) {
+    match fs::remove_file(envelope_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Failed to delete the level envelope of {}: {}", path.display(), e);
+        },
+        _ => {},
+    }
+}
+
+/// Renames the level envelope of the file at `from` to go with `to`, the
+/// same recording under a new name, such as after compression.
+pub fn move_envelope(from: &Path, to: &Path) {
+    match fs::rename(envelope_path(from), envelope_path(to)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Failed to move the level envelope of {}: {}", from.display(), e);
+        },
+        _ => {},
+    }
+}
+
+/// Pairs each of `files` with the positions of the channels it holds: every
+/// one of `channels` for a single file, or one each for split files.
+pub fn file_channels(files: &[String], channels: usize) -> Vec<(String, Vec<usize>)> {
+    match files {
+        [file] => vec![(file.clone(), (0..channels).collect())],
+        files => files.iter().enumerate().map(|(i, file)| (file.clone(), vec![i])).collect(),
+    }
+}
+
+/// The peak and RMS of each channel over each stretch of `frames_per_point`
+/// frames.
+#[derive(Debug, Clone)]
+pub struct LevelEnvelope {
+    channels: usize,
+    frames_per_point: u64,
+    /// Per channel, the finished points as (peak, rms).
+    points: Vec<Vec<(f64, f64)>>,
+    peaks: Vec<u32>,
+    sums_of_squares: Vec<f64>,
+    frames: u64,
+    bits_per_sample: u16,
+}
+
+impl LevelEnvelope {
+    pub fn new(channels: usize, frames_per_point: u64, bits_per_sample: u16) -> Self {
+        LevelEnvelope {
+            channels,
+            frames_per_point: frames_per_point.max(1),
+            points: vec![Vec::new(); channels],
+            peaks: vec![0; channels],
+            sums_of_squares: vec![0.0; channels],
+            frames: 0,
+            bits_per_sample,
+        }
+    }
+
+    /// Adds interleaved samples at the envelope's bit depth.
+    pub fn add(&mut self, samples: &[i32]) {
+        let scale = full_scale(self.bits_per_sample) as f64;
+        for frame in samples.chunks_exact(self.channels) {
+            for (i, &sample) in frame.iter().enumerate() {
+                self.peaks[i] = self.peaks[i].max(sample.unsigned_abs());
+                let value = sample as f64 / scale;
+                self.sums_of_squares[i] += value * value;
+            }
+            self.frames += 1;
+            if self.frames == self.frames_per_point {
+                self.finish_point();
+            }
+        }
+    }
+
+    /// Closes the partly filled point, if any.
+    fn finish_point(&mut self) {
+        if self.frames == 0 {
+            return;
+        }
+        let scale = full_scale(self.bits_per_sample) as f64;
+        for i in 0..self.channels {
+            let peak = (self.peaks[i] as f64 / scale).min(1.0);
+            let rms = (self.sums_of_squares[i] / self.frames as f64).sqrt();
+            self.points[i].push((peak, rms));
+        }
+        self.peaks.fill(0);
+        self.sums_of_squares.fill(0.0);
+        self.frames = 0;
+    }
+
+    /// The (peak, rms) points of channel `channel`, not counting a partly
+    /// filled one.
+    pub fn points(&self, channel: usize) -> &[(f64, f64)] {
+        &self.points[channel]
+    }
+
+    /// The envelope of the channels at `positions` as JSON, with their
+    /// counts from `clips` if given.
+    pub fn to_json(&self, positions: &[usize], seconds_per_point: f64, clips: Option<&ClipCounter>) -> String {
+        let channels: Vec<String> = positions.iter()
+            .map(|&i| {
+                let peak: Vec<String> = self.points[i].iter().map(|(peak, _)| format!("{:.4}", peak)).collect();
+                let rms: Vec<String> = self.points[i].iter().map(|(_, rms)| format!("{:.4}", rms)).collect();
+                let clipped = match clips {
+                    Some(clips) => format!(",\"clipped\":{},\"clipped_percent\":{:.3}", clips.count(i), clips.percent(i)),
+                    None => String::new(),
+                };
+                format!("{{\"peak\":[{}],\"rms\":[{}]{}}}", peak.join(","), rms.join(","), clipped)
+            })
+            .collect();
+        format!("{{\"seconds_per_point\":{},\"channels\":[{}]}}\n", seconds_per_point, channels.join(","))
+    }
+}
+
+/// Passes the audio on to `inner` while building its level envelope, and
+/// writes each file's envelope when `inner` is finalized. Wrapped around
+/// each file, or set of split files, it starts afresh at every rotation.
+pub struct EnvelopeProcessor {
+    inner: Box<dyn AudioProcessor>,
+    envelope: LevelEnvelope,
+    sample_rate: u32,
+    /// Each file's envelope path and the positions of the channels it holds.
+    outputs: Vec<(PathBuf, Vec<usize>)>,
+    clips: Option<ClipCounter>,
+}
+
+impl EnvelopeProcessor {
+    /// `files` are the files `inner` writes: one holding every channel, or
+    /// one per channel, in order.
+    pub fn new(inner: Box<dyn AudioProcessor>, channels: usize, sample_rate: u32, bits_per_sample: u16,
+               files: &[String]) -> Self {
+        let outputs = file_channels(files, channels).into_iter()
+            .map(|(file, positions)| (envelope_path(Path::new(&file)), positions))
+            .collect();
+        let envelope = LevelEnvelope::new(channels, sample_rate as u64, bits_per_sample);
+        EnvelopeProcessor { inner, envelope, sample_rate, outputs, clips: None }
+    }
+
+    /// Also counts each channel's clipped samples into the envelope.
+    pub fn with_clip_counts(mut self) -> Self {
+        self.clips = Some(ClipCounter::new(self.envelope.channels, self.envelope.bits_per_sample));
+        self
+    }
+}
+
+impl AudioProcessor for EnvelopeProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.envelope.add(samples);
+        if let Some(ref mut clips) = self.clips {
+            clips.add(samples);
+        }
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let files = self.inner.finalize()?;
+        self.envelope.finish_point();
+        let seconds_per_point = self.envelope.frames_per_point as f64 / self.sample_rate as f64;
+        for (path, positions) in &self.outputs {
+            if let Err(e) = fs::write(path, self.envelope.to_json(positions, seconds_per_point, self.clips.as_ref())) {
+                eprintln!("Failed to write the level envelope {}: {}", path.display(), e);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Discard;
+
+    #[test]
+    fn test_envelope_follows_each_second() {
+        // Stereo at 1000 Hz: the left channel gets louder each second, the
+        // right stays at a quarter, for three and a half seconds
+        let mut envelope = LevelEnvelope::new(2, 1000, 16);
+        for (second, level) in [4000, 8000, 16000, 32000].into_iter().enumerate() {
+            let frames = if second == 3 { 500 } else { 1000 };
+            let samples: Vec<i32> = (0..frames).flat_map(|i| {
+                let sign = if i % 2 == 0 { 1 } else { -1 };
+                [sign * level, sign * 8192]
+            }).collect();
+            envelope.add(&samples);
+        }
+        assert_eq!(envelope.points(0).len(), 3);
+        envelope.finish_point();
+
+        let peaks: Vec<f64> = envelope.points(0).iter().map(|&(peak, _)| (peak * 100.0).round() / 100.0).collect();
+        assert_eq!(peaks, [0.12, 0.24, 0.49, 0.98]);
+        for &(peak, rms) in envelope.points(0).iter().chain(envelope.points(1)) {
+            // A square wave's RMS is its peak
+            assert!((peak - rms).abs() < 1e-9);
+        }
+        assert!(envelope.points(1).iter().all(|&(peak, _)| (peak - 0.25).abs() < 0.001));
+    }
+
+    #[test]
+    fn test_envelope_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = ["a-ch0.wav", "a-ch1.wav"].iter()
+            .map(|name| temp_dir.path().join(name).to_str().unwrap().to_string())
+            .collect();
+        let mut processor = EnvelopeProcessor::new(Box::new(Discard), 2, 1000, 16, &files);
+        processor.write_samples(&[16384, 0].repeat(1500)).unwrap();
+        processor.finalize().unwrap();
+
+        let left = fs::read_to_string(envelope_path(Path::new(&files[0]))).unwrap();
+        assert_eq!(left, "{\"seconds_per_point\":1,\"channels\":[{\"peak\":[0.5000,0.5000],\"rms\":[0.5000,0.5000]}]}\n");
+        let right = fs::read_to_string(envelope_path(Path::new(&files[1]))).unwrap();
+        assert!(right.contains("\"peak\":[0.0000,0.0000]"));
+
+        // With clip counts, for a file holding both channels
+        let mut processor = EnvelopeProcessor::new(Box::new(Discard), 2, 1000, 16, &files[..1]).with_clip_counts();
+        processor.write_samples(&[32767, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        processor.finalize().unwrap();
+        let both = fs::read_to_string(envelope_path(Path::new(&files[0]))).unwrap();
+        assert!(both.contains("\"clipped\":1,\"clipped_percent\":25.000}"));
+        assert!(both.contains("\"clipped\":0,\"clipped_percent\":0.000}]"));
+    }
+}
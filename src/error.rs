@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while setting up or running a recording.
+#[derive(Debug)]
+pub enum BlackboxError {
+    /// No usable audio host, input device, or output device.
+    DeviceNotFound(String),
+    /// A requested channel doesn't exist on the device.
+    ChannelOutOfRange { requested: usize, available: usize },
+    /// An invalid setting.
+    Config(String),
+    /// The audio stream could not be built or started.
+    Stream(String),
+    /// An external program, such as `wavpack` or a post command, could not
+    /// be run or failed.
+    ExternalTool(String),
+    /// A file's contents aren't what they should be: not a WAV file, cut
+    /// short, or not reading back as written.
+    Corrupt(String),
+    Io(io::Error),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for BlackboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlackboxError::DeviceNotFound(msg) => write!(f, "{}", msg),
+            BlackboxError::ChannelOutOfRange { requested, available } => {
+                write!(f, "The audio device does not have channel {} (it has {} channels)", requested, available)
+            },
+            BlackboxError::Config(msg) => write!(f, "{}", msg),
+            BlackboxError::Stream(msg) => write!(f, "{}", msg),
+            BlackboxError::ExternalTool(msg) => write!(f, "{}", msg),
+            BlackboxError::Corrupt(msg) => write!(f, "{}", msg),
+            BlackboxError::Io(e) => write!(f, "I/O error: {}", e),
+            BlackboxError::Wav(e) => write!(f, "WAV error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlackboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlackboxError::Io(e) => Some(e),
+            BlackboxError::Wav(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlackboxError {
+    fn from(e: io::Error) -> Self {
+        BlackboxError::Io(e)
+    }
+}
+
+impl From<hound::Error> for BlackboxError {
+    fn from(e: hound::Error) -> Self {
+        match e {
+            hound::Error::IoError(e) => BlackboxError::Io(e),
+            e => BlackboxError::Wav(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_source_is_the_wrapped_error() {
+        let error = BlackboxError::from(io::Error::other("disk full"));
+        assert_eq!(error.source().unwrap().to_string(), "disk full");
+        let error = BlackboxError::from(hound::Error::FormatError("no RIFF tag found"));
+        assert!(error.source().unwrap().downcast_ref::<hound::Error>().is_some());
+        assert!(BlackboxError::Corrupt("Truncated audio".to_string()).source().is_none());
+    }
+}
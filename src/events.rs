@@ -0,0 +1,141 @@
+//! An append-only, machine-readable record of what happened to each file:
+//! created, rotated, finalized, deleted, or failed. One JSON object per line,
+//! `{"ts": ..., "event": ..., "path": ..., "detail": ...}`, separate from the
+//! human-readable messages on the console. Library users can also have the
+//! same events delivered to an `EventHook`.
+
+use chrono::Local;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::error::BlackboxError;
+
+/// Called as things happen to a recording's files, for library users who want
+/// to act on them, say by uploading each finished file, without watching the
+/// directory. Every method does nothing unless overridden.
+///
+/// A file is finalized once its markers and tags are written, when the
+/// recording stops; the silence and length checks run after that, so a
+/// finalized file may then be deleted. Methods are called from whichever
+/// thread the event happens on, including the audio callback for rotations,
+/// so they should return quickly.
+pub trait EventHook: Send + Sync {
+    fn on_file_finalized(&self, _path: &str) {}
+    fn on_file_deleted(&self, _path: &str) {}
+    /// A rotating recording has moved on to its next file.
+    fn on_rotation(&self) {}
+    fn on_error(&self, _error: &BlackboxError) {}
+}
+
+/// Where events are appended; a default `EventLog` records nothing.
+#[derive(Clone, Default)]
+pub struct EventLog {
+    path: Option<PathBuf>,
+    hook: Option<Arc<dyn EventHook>>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLog").field("path", &self.path).field("hook", &self.hook.is_some()).finish()
+    }
+}
+
+impl EventLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        EventLog { path: Some(path.into()), hook: None }
+    }
+
+    /// Also delivers events to `hook`.
+    pub fn with_hook(mut self, hook: Arc<dyn EventHook>) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// Records that `what` failed for `path` with `error`.
+    pub fn failed(&self, path: &str, what: &str, error: &BlackboxError) {
+        self.log("failed", path, &format!("{}: {}", what, error));
+        if let Some(ref hook) = self.hook {
+            hook.on_error(error);
+        }
+    }
+
+    /// Records the files a rotating recording finished as it moved on.
+    pub fn rotated(&self, files: &[String]) {
+        for file in files {
+            self.log("rotated", file, "");
+        }
+        if let Some(ref hook) = self.hook {
+            hook.on_rotation();
+        }
+    }
+
+    /// Appends one event. Failing to write the log is reported but never
+    /// interrupts the recording.
+    pub fn log(&self, event: &str, path: &str, detail: &str) {
+        if let Some(ref hook) = self.hook {
+            match event {
+                "finalized" => hook.on_file_finalized(path),
+                "deleted" => hook.on_file_deleted(path),
+                _ => {},
+            }
+        }
+        let log_path = match self.path {
+            Some(ref log_path) => log_path,
+            None => return,
+        };
+        let line = format!("{{\"ts\":{},\"event\":{},\"path\":{},\"detail\":{}}}\n",
+                           json_string(&Local::now().to_rfc3339()), json_string(event),
+                           json_string(path), json_string(detail));
+        let result = OpenOptions::new().create(true).append(true).open(log_path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            eprintln!("Failed to write event log {}: {}", log_path.display(), e);
+        }
+    }
+}
+
+/// Quotes and escapes `value` as a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_events_are_json_lines() {
+        let temp_dir = tempdir().unwrap();
+        let log_path = temp_dir.path().join("events.jsonl");
+        let events = EventLog::new(&log_path);
+        events.log("created", "a.wav", "");
+        events.log("failed", "b \"quoted\".wav", "disk full\n");
+        EventLog::default().log("created", "ignored.wav", "");
+
+        let contents = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"ts\":\""));
+        assert!(lines[0].ends_with(",\"event\":\"created\",\"path\":\"a.wav\",\"detail\":\"\"}"));
+        assert!(lines[1].contains("\"path\":\"b \\\"quoted\\\".wav\",\"detail\":\"disk full\\n\""));
+    }
+}
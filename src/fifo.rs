@@ -0,0 +1,223 @@
+//! `FIFO_PATH`: streaming the recording as a WAV to a named pipe, for tools
+//! like ffmpeg to read while it is recorded.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::BlackboxError;
+use crate::processor::AudioProcessor;
+
+/// The header of a PCM WAV stream of unknown length, with the RIFF and data
+/// sizes at their maximum as streaming readers expect.
+pub fn streaming_wav_header(channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+    let block_align = channels * bits_per_sample / 8;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+/// Passes the audio on to `inner` and also streams it to the FIFO at
+/// `path` whenever something is reading it. Each reader gets a fresh WAV
+/// header. Writes never block or wait: while nobody is reading, or the
+/// reader falls behind, the audio is only recorded to `inner`, and a reader
+/// that goes away is waited for again.
+pub struct FifoProcessor {
+    inner: Box<dyn AudioProcessor>,
+    path: PathBuf,
+    header: Vec<u8>,
+    /// Bytes of each sample in the stream.
+    sample_bytes: usize,
+    fifo: Option<File>,
+    /// What the pipe had no room for yet. A batch is only queued once the
+    /// last is out in full, so the stream stays whole frames.
+    pending: Vec<u8>,
+}
+
+impl FifoProcessor {
+    pub fn new(inner: Box<dyn AudioProcessor>, path: &Path, channels: u16, sample_rate: u32,
+               bits_per_sample: u16) -> Self {
+        FifoProcessor {
+            inner,
+            path: path.to_path_buf(),
+            header: streaming_wav_header(channels, sample_rate, bits_per_sample),
+            sample_bytes: bits_per_sample as usize / 8,
+            fifo: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Opens the FIFO if a reader has appeared, and starts its stream.
+    fn connect(&mut self) {
+        // Opening without blocking fails until there is a reader
+        let Ok(mut fifo) = OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(&self.path) else {
+            return;
+        };
+        self.pending.clear();
+        self.pending.extend_from_slice(&self.header);
+        if write_pending(&mut fifo, &mut self.pending).is_ok() {
+            status!("Streaming to {}", self.path.display());
+            self.fifo = Some(fifo);
+        }
+    }
+}
+
+/// Writes as much of `pending` as the pipe takes without blocking, and
+/// removes it from `pending`.
+fn write_pending(fifo: &mut File, pending: &mut Vec<u8>) -> io::Result<()> {
+    let mut written = 0;
+    while written < pending.len() {
+        match fifo.write(&pending[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    pending.drain(..written);
+    Ok(())
+}
+
+impl AudioProcessor for FifoProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        if self.fifo.is_none() {
+            self.connect();
+        }
+        if let Some(ref mut fifo) = self.fifo {
+            // The rest of an earlier batch goes first; while any of it is
+            // left, the reader is behind and this batch is skipped
+            let mut result = write_pending(fifo, &mut self.pending);
+            if result.is_ok() && self.pending.is_empty() {
+                // Little-endian, so the low bytes come first
+                let sample_bytes = self.sample_bytes;
+                self.pending.extend(samples.iter().flat_map(|&sample| {
+                    sample.to_le_bytes().into_iter().take(sample_bytes)
+                }));
+                result = write_pending(fifo, &mut self.pending);
+            }
+            if let Err(e) = result {
+                status!("Stopped streaming to {}: {}", self.path.display(), e);
+                self.fifo = None;
+                self.pending.clear();
+            }
+        }
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        self.fifo = None;
+        self.inner.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Discard;
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_stream_to_fifo() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.wav");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        // Nobody is reading yet, so this only goes to the inner processor
+        let mut processor = FifoProcessor::new(Box::new(Discard), &path, 2, 8000, 16);
+        processor.write_samples(&[1; 8]).unwrap();
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let reader_path = path.clone();
+        let reader = thread::spawn(move || {
+            let mut fifo = File::open(reader_path).unwrap();
+            let mut bytes = vec![0; 44 + 8];
+            fifo.read_exact(&mut bytes).unwrap();
+            done_tx.send(()).unwrap();
+            bytes
+        });
+        let mut batch = 2;
+        while done_rx.try_recv().is_err() {
+            processor.write_samples(&[batch; 4]).unwrap();
+            batch += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+        let bytes = reader.join().unwrap();
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[..44], &streaming_wav_header(2, 8000, 16)[..]);
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+        assert_eq!(u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]), 8000);
+        // The first batch after the header arrived whole
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert!(samples[0] >= 2 && samples.iter().all(|&s| s == samples[0]));
+
+        // The reader has gone, which pauses the stream without an error
+        processor.write_samples(&[0; 4]).unwrap();
+        processor.write_samples(&[0; 4]).unwrap();
+        assert!(processor.fifo.is_none());
+        processor.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_slow_reader_gets_whole_batches() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("slow.wav");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path).unwrap();
+        let mut read_available = |bytes: &mut Vec<u8>| {
+            let mut chunk = [0; 4096];
+            loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => bytes.extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        };
+
+        // Far more than the pipe holds, with the reader only catching up now
+        // and then: none of it waits, and batches that don't fit are skipped
+        let mut processor = FifoProcessor::new(Box::new(Discard), &path, 1, 8000, 16);
+        let mut bytes = Vec::new();
+        for batch in 1..=60 {
+            processor.write_samples(&[batch; 10000]).unwrap();
+            if batch % 10 == 0 {
+                read_available(&mut bytes);
+            }
+        }
+        processor.finalize().unwrap();
+        read_available(&mut bytes);
+
+        assert_eq!(&bytes[..44], &streaming_wav_header(1, 8000, 16)[..]);
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        let runs: Vec<(i16, usize)> = samples.chunk_by(|a, b| a == b).map(|run| (run[0], run.len())).collect();
+        assert!(runs.len() > 1 && runs.len() < 60);
+        // Every batch but the one cut off by finalize arrived whole, in order
+        for pair in runs.windows(2) {
+            assert_eq!(pair[0].1, 10000);
+            assert!(pair[0].0 < pair[1].0);
+        }
+    }
+}
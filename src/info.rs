@@ -0,0 +1,84 @@
+//! Reading back what's in a finished recording.
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::BlackboxError;
+use crate::silence;
+
+/// The format, length and levels of a WAV file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Length in frames, i.e. samples per channel.
+    pub frames: u32,
+    /// RMS level over every sample, as a fraction of full scale.
+    pub rms: f64,
+    /// Largest absolute sample, as a fraction of full scale.
+    pub peak: f64,
+}
+
+impl RecordingInfo {
+    /// Reads the header of the WAV file at `path` and measures its levels,
+    /// streaming through every sample once.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, BlackboxError> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let frames = reader.duration();
+        let levels = silence::reader_levels(&mut reader, None)?;
+        Ok(RecordingInfo {
+            sample_rate: spec.sample_rate,
+            channels: spec.channels,
+            bits_per_sample: spec.bits_per_sample,
+            frames,
+            rms: levels.rms(),
+            peak: levels.peak(),
+        })
+    }
+
+    /// How long the recording plays for.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recording_info() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("take.wav");
+        let spec = wav_spec(2, 8000);
+        // 1.5 s of a square wave at half scale on the left, silence on the right
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..12000 {
+            writer.write_sample(if i % 2 == 0 { 16384i16 } else { -16384 }).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let info = RecordingInfo::from_path(&path).unwrap();
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.sample_rate, 8000);
+        assert_eq!(info.bits_per_sample, 16);
+        assert_eq!(info.frames, 12000);
+        assert_eq!(info.duration(), Duration::from_millis(1500));
+        assert_eq!(info.peak, 0.5);
+        assert!((info.rms - 0.5 / 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_recording_info_rejects_non_wav() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("notes.wav");
+        std::fs::write(&path, b"not audio").unwrap();
+        assert!(RecordingInfo::from_path(&path).is_err());
+        assert!(RecordingInfo::from_path(temp_dir.path().join("missing.wav")).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Records selected channels of an audio input device to WAV files.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when audio is streamed to stdout, so status messages must go to stderr.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints a status message to stdout, or to stderr while stdout carries audio.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::print_status(format_args!($($arg)*))
+    };
+}
+
+mod backend;
+pub mod analyze;
+pub mod benchmark;
+mod capture;
+pub mod checksum;
+pub mod clip;
+pub mod clock;
+pub mod config;
+pub mod convert;
+pub mod disk;
+pub mod dither;
+pub mod encode;
+pub mod envelope;
+pub mod error;
+pub mod events;
+#[cfg(unix)]
+pub mod fifo;
+pub mod info;
+pub mod loudness;
+pub mod meter;
+pub mod metadata;
+pub mod monitor;
+pub mod post;
+pub mod processor;
+mod recorder;
+pub mod repair;
+pub mod retention;
+pub mod schedule;
+pub mod selftest;
+pub mod silence;
+pub mod status;
+pub mod sync;
+#[cfg(test)]
+mod test_util;
+pub mod throttle;
+pub mod timestamps;
+mod tone;
+
+pub use config::Config;
+pub use error::BlackboxError;
+pub use events::EventHook;
+pub use info::RecordingInfo;
+pub use recorder::{AudioRecorder, StartOutcome};
+
+/// The version line printed by `--version`, e.g.
+/// `audio_recorder 0.1.0 (3bd9796, features: jack)`.
+pub fn version_string() -> String {
+    let mut features = Vec::new();
+    if cfg!(feature = "jack") {
+        features.push("jack");
+    }
+    let features = if features.is_empty() { "none".to_string() } else { features.join(", ") };
+    match option_env!("GIT_HASH") {
+        Some(hash) => format!("{} {} ({}, features: {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), hash, features),
+        None => format!("{} {} (features: {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), features),
+    }
+}
+
+/// Prints a line for `status!`, wherever `set_status_to_stderr` sends them.
+#[doc(hidden)]
+pub fn print_status(args: std::fmt::Arguments) {
+    if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// Sends status messages to stderr instead of stdout, for when stdout
+/// carries audio.
+pub fn set_status_to_stderr(enabled: bool) {
+    STATUS_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_library_types_are_send() {
+        assert_send::<AudioRecorder>();
+        assert_send::<Config>();
+        assert_send::<BlackboxError>();
+        assert_send::<events::EventLog>();
+        assert_send::<status::RecordingStatus>();
+    }
+
+    #[test]
+    fn test_default_config_ignores_environment() {
+        let config = Config::default();
+        assert!(config.describe().lines().all(|line| line.ends_with("(default)")));
+    }
+
+    #[test]
+    fn test_version_string() {
+        let version = version_string();
+        assert!(version.starts_with(&format!("audio_recorder {}", env!("CARGO_PKG_VERSION"))));
+        assert!(version.contains("features: "));
+    }
+}
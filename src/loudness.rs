@@ -0,0 +1,192 @@
+//! Integrated loudness as defined by ITU-R BS.1770: K-weighting, 400 ms
+//! blocks overlapping by 75%, then an absolute gate at -70 LUFS and a
+//! relative gate 10 LU below the level of the blocks that pass it.
+//!
+//! All channels are weighted equally, since the recorder doesn't know which
+//! inputs are surround channels.
+
+use crate::error::BlackboxError;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// A second-order IIR filter in direct form I.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// The two K-weighting stages, a high shelf modelling the head and a high
+/// pass, designed for `sample_rate` so rates other than 48 kHz measure
+/// correctly too.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Measures integrated loudness one frame at a time. Only the energy of
+/// each 100 ms step is kept, so memory grows by one number per step.
+pub struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    frames_in_step: usize,
+    step_energy: f64,
+    steps: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        LoudnessMeter {
+            filters: vec![k_weighting(sample_rate); channels],
+            step_frames: (sample_rate as usize / 10).max(1),
+            frames_in_step: 0,
+            step_energy: 0.0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Feeds one frame of samples normalized to full scale.
+    pub fn push_frame(&mut self, frame: &[f64]) {
+        for ([shelf, high_pass], &sample) in self.filters.iter_mut().zip(frame) {
+            let weighted = high_pass.process(shelf.process(sample));
+            self.step_energy += weighted * weighted;
+        }
+        self.frames_in_step += 1;
+        if self.frames_in_step == self.step_frames {
+            self.steps.push(self.step_energy / self.step_frames as f64);
+            self.step_energy = 0.0;
+            self.frames_in_step = 0;
+        }
+    }
+
+    /// Integrated loudness in LUFS, or `None` if nothing was loud enough to
+    /// pass the absolute gate (including recordings shorter than 400 ms).
+    pub fn integrated(&self) -> Option<f64> {
+        let blocks: Vec<f64> = self.steps.windows(4)
+            .map(|steps| steps.iter().sum::<f64>() / 4.0)
+            .collect();
+
+        let above_absolute: Vec<f64> = blocks.iter().copied()
+            .filter(|&energy| block_loudness(energy) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if above_absolute.is_empty() {
+            return None;
+        }
+        let relative_gate = block_loudness(mean(&above_absolute)) + RELATIVE_GATE_LU;
+
+        let gated: Vec<f64> = above_absolute.into_iter()
+            .filter(|&energy| block_loudness(energy) > relative_gate)
+            .collect();
+        Some(block_loudness(mean(&gated)))
+    }
+}
+
+fn block_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Integrated loudness of a WAV file in LUFS. See `LoudnessMeter::integrated`.
+pub fn measure_file(path: &str) -> Result<Option<f64>, BlackboxError> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let mut meter = LoudnessMeter::new(channels, spec.sample_rate);
+    let mut frame = Vec::with_capacity(channels);
+
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            for sample in reader.samples::<f32>() {
+                frame.push(sample? as f64);
+                if frame.len() == channels {
+                    meter.push_frame(&frame);
+                    frame.clear();
+                }
+            }
+        },
+        hound::SampleFormat::Int => {
+            let full_scale = (1u64 << (spec.bits_per_sample - 1)) as f64;
+            for sample in reader.samples::<i32>() {
+                frame.push(sample? as f64 / full_scale);
+                if frame.len() == channels {
+                    meter.push_frame(&frame);
+                    frame.clear();
+                }
+            }
+        },
+    }
+    Ok(meter.integrated())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_calibration_tone() {
+        // A 1 kHz sine at -20 dBFS peak in one channel measures -23 LUFS
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("tone.wav");
+        let spec = wav_spec(1, 48000);
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..48000 * 5 {
+            let t = i as f64 / 48000.0;
+            let sample = 0.1 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin();
+            writer.write_sample((sample * 32767.0).round() as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let lufs = measure_file(path.to_str().unwrap()).unwrap().unwrap();
+        assert!((lufs + 23.0).abs() < 0.1, "measured {} LUFS", lufs);
+    }
+
+    #[test]
+    fn test_silence_is_gated_out() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        for _ in 0..48000 {
+            meter.push_frame(&[0.0, 0.0]);
+        }
+        assert_eq!(meter.integrated(), None);
+    }
+}
@@ -1,208 +1,247 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{SampleFormat};
-use hound;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use audio_recorder::config::OutputMode;
+use audio_recorder::schedule::ScheduleWindow;
+use audio_recorder::{status, AudioRecorder, BlackboxError, Config, StartOutcome};
+use chrono::Local;
 use std::env;
-use chrono::prelude::*;
-use tempfile::tempdir;
-
-const INTERMEDIATE_BUFFER_SIZE: usize = 512;
-const DEFAULT_CHANNELS: &str = "1,2";
-const DEFAULT_DEBUG: &str = "false";
-const DEFAULT_DURATION: &str = "10";
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn main() {
-    // Read environment variables
-    let channels: Vec<usize> = env::var("AUDIO_CHANNELS")
-        .unwrap_or_else(|_| DEFAULT_CHANNELS.to_string())
-        .split(',')
-        .map(|s| s.parse().expect("Invalid channel number"))
-        .collect();
-
-    let debug: bool = env::var("DEBUG")
-        .unwrap_or_else(|_| DEFAULT_DEBUG.to_string())
-        .parse()
-        .expect("Invalid debug flag");
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let record_duration: u64 = env::var("RECORD_DURATION")
-        .unwrap_or_else(|_| DEFAULT_DURATION.to_string())
-        .parse()
-        .expect("Invalid record duration");
+fn run() -> Result<(), BlackboxError> {
+    if env::args().any(|arg| arg == "--version") {
+        println!("{}", audio_recorder::version_string());
+        return Ok(());
+    }
 
-    // Generate the output file name
-    let now: DateTime<Local> = Local::now();
-    let file_name = format!("{}-{:02}-{:02}-{:02}-{:02}.wav", 
-                            now.year(), now.month(), now.day(), 
-                            now.hour(), now.minute());
+    let config = Config::load()?;
 
-    let host = cpal::default_host();
-    let device = host.default_input_device().expect("No input device available");
+    if env::args().any(|arg| arg == "--print-config") {
+        print!("{}", config.describe());
+        return Ok(());
+    }
 
-    println!("Using audio device: {}", device.name().unwrap());
+    if env::args().any(|arg| arg == "--selftest") {
+        if !audio_recorder::selftest::run_selftest(&config) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    let config = device.default_input_config().expect("Failed to get default input stream config");
+    // Repairs the directory given after --repair, or OUTPUT_DIR
+    let args: Vec<String> = env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--repair") {
+        let dir = args.get(i + 1).filter(|arg| !arg.starts_with("--")).map(PathBuf::from)
+            .unwrap_or_else(|| config.output_dir.clone());
+        let repaired = audio_recorder::repair::repair_directory(&dir)?;
+        for path in &repaired {
+            println!("Repaired {}", path.display());
+        }
+        println!("{} file(s) repaired in {}", repaired.len(), dir.display());
+        return Ok(());
+    }
 
-    println!("Default input stream config: {:?}", config);
+    // Converts the file or directory after --convert to the format after that
+    if let Some(i) = args.iter().position(|arg| arg == "--convert") {
+        let (src, format) = match (args.get(i + 1), args.get(i + 2)) {
+            (Some(src), Some(format)) => (PathBuf::from(src), format),
+            _ => return Err(BlackboxError::Config("Usage: --convert <file or directory> <format>".to_string())),
+        };
+        if audio_recorder::convert::run_convert(&src, format, &config)? > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    let sample_rate = config.sample_rate().0;
-    let total_channels = config.channels() as usize;
+    // Decompresses the file or directory after --decompress, as written with COMPRESS_FINALIZED
+    if let Some(i) = args.iter().position(|arg| arg == "--decompress") {
+        let src = args.get(i + 1).filter(|arg| !arg.starts_with("--")).map(PathBuf::from)
+            .ok_or_else(|| BlackboxError::Config("Usage: --decompress <file or directory>".to_string()))?;
+        if audio_recorder::convert::run_decompress(&src, &config)? > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    for &channel in &channels {
-        if channel >= total_channels {
-            panic!("The audio device does not have channel {}", channel);
+    // Checks the files in the directory after --verify, or OUTPUT_DIR, against their checksums
+    if let Some(i) = args.iter().position(|arg| arg == "--verify") {
+        let dir = args.get(i + 1).filter(|arg| !arg.starts_with("--")).map(PathBuf::from)
+            .unwrap_or_else(|| config.output_dir.clone());
+        if audio_recorder::checksum::run_verify(&dir)? > 0 {
+            std::process::exit(1);
         }
+        return Ok(());
     }
 
-    let spec = hound::WavSpec {
-        channels: 2,
-        sample_rate,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
-    };
+    // Summarizes the directory after --analyze, or OUTPUT_DIR, judging silence
+    // by the threshold after that, or SILENCE_THRESHOLD
+    if let Some(i) = args.iter().position(|arg| arg == "--analyze") {
+        let mut rest = args[i + 1..].iter().take_while(|arg| !arg.starts_with("--"));
+        let dir = rest.next().map(PathBuf::from).unwrap_or_else(|| config.output_dir.clone());
+        let threshold = match rest.next() {
+            Some(value) => value.parse().map_err(|_| BlackboxError::Config(format!("Invalid silence threshold: {}", value)))?,
+            None => config.silence_threshold,
+        };
+        return audio_recorder::analyze::run_analyze(&dir, threshold, &config);
+    }
 
-    let writer = Arc::new(Mutex::new(Some(hound::WavWriter::create(&file_name, spec).unwrap())));
-    let intermediate_buffer = Arc::new(Mutex::new(Vec::with_capacity(INTERMEDIATE_BUFFER_SIZE)));
+    if env::args().any(|arg| arg == "--list-channels") {
+        let recorder = AudioRecorder::new(config);
+        for (channel, label) in recorder.device_channel_labels()?.iter().enumerate() {
+            println!("{}: {}", channel, label);
+        }
+        return Ok(());
+    }
 
-    let err_fn = |err| eprintln!("An error occurred on the input audio stream: {}", err);
+    if env::args().any(|arg| arg == "--benchmark") {
+        return audio_recorder::benchmark::run_benchmark(&config);
+    }
 
-    let stream = match config.sample_format() {
-        SampleFormat::F32 => {
-            let writer_clone = Arc::clone(&writer);
-            let buffer_clone = Arc::clone(&intermediate_buffer);
-            device.build_input_stream(
-                &config.into(),
-                move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    if debug {
-                        println!("Received data with length: {}", data.len());
-                    }
-                    let mut writer_lock = writer_clone.lock().unwrap();
-                    let mut buffer_lock = buffer_clone.lock().unwrap();
-                    if let Some(ref mut writer) = *writer_lock {
-                        for frame in data.chunks(total_channels) {
-                            if frame.len() >= channels.len() {
-                                let sample_left = (frame[channels[0]] * std::i16::MAX as f32) as i16;
-                                let sample_right = (frame[channels[1]] * std::i16::MAX as f32) as i16;
-                                buffer_lock.push(sample_left as i32);
-                                buffer_lock.push(sample_right as i32);
-                                if buffer_lock.len() >= INTERMEDIATE_BUFFER_SIZE {
-                                    for &sample in &*buffer_lock {
-                                        if let Err(e) = writer.write_sample(sample) {
-                                            eprintln!("Failed to write sample: {:?}", e);
-                                        }
-                                    }
-                                    buffer_lock.clear();
-                                }
-                            } else {
-                                eprintln!("Buffer too small: expected at least {} channels, found {}", channels.len(), frame.len());
-                            }
-                        }
-                    }
-                },
-                err_fn,
-                None, // No specific latency requirement
-            ).expect("Failed to build input stream")
-        },
-        SampleFormat::I16 => {
-            let writer_clone = Arc::clone(&writer);
-            let buffer_clone = Arc::clone(&intermediate_buffer);
-            device.build_input_stream(
-                &config.into(),
-                move |data: &[i16], _: &cpal::InputCallbackInfo| {
-                    if debug {
-                        println!("Received data with length: {}", data.len());
-                    }
-                    let mut writer_lock = writer_clone.lock().unwrap();
-                    let mut buffer_lock = buffer_clone.lock().unwrap();
-                    if let Some(ref mut writer) = *writer_lock {
-                        for frame in data.chunks(total_channels) {
-                            if frame.len() >= channels.len() {
-                                let sample_left = frame[channels[0]] as i32;
-                                let sample_right = frame[channels[1]] as i32;
-                                buffer_lock.push(sample_left);
-                                buffer_lock.push(sample_right);
-                                if buffer_lock.len() >= INTERMEDIATE_BUFFER_SIZE {
-                                    for &sample in &*buffer_lock {
-                                        if let Err(e) = writer.write_sample(sample) {
-                                            eprintln!("Failed to write sample: {:?}", e);
-                                        }
-                                    }
-                                    buffer_lock.clear();
-                                }
-                            } else {
-                                eprintln!("Buffer too small: expected at least {} channels, found {}", channels.len(), frame.len());
-                            }
-                        }
-                    }
-                },
-                err_fn,
-                None, // No specific latency requirement
-            ).expect("Failed to build input stream")
-        },
-        SampleFormat::U16 => {
-            let writer_clone = Arc::clone(&writer);
-            let buffer_clone = Arc::clone(&intermediate_buffer);
-            device.build_input_stream(
-                &config.into(),
-                move |data: &[u16], _: &cpal::InputCallbackInfo| {
-                    if debug {
-                        println!("Received data with length: {}", data.len());
-                    }
-                    let mut writer_lock = writer_clone.lock().unwrap();
-                    let mut buffer_lock = buffer_clone.lock().unwrap();
-                    if let Some(ref mut writer) = *writer_lock {
-                        for frame in data.chunks(total_channels) {
-                            if frame.len() >= channels.len() {
-                                let sample_left = (frame[channels[0]] as i32) - 32768;
-                                let sample_right = (frame[channels[1]] as i32) - 32768;
-                                buffer_lock.push(sample_left);
-                                buffer_lock.push(sample_right);
-                                if buffer_lock.len() >= INTERMEDIATE_BUFFER_SIZE {
-                                    for &sample in &*buffer_lock {
-                                        if let Err(e) = writer.write_sample(sample) {
-                                            eprintln!("Failed to write sample: {:?}", e);
-                                        }
-                                    }
-                                    buffer_lock.clear();
-                                }
-                            } else {
-                                eprintln!("Buffer too small: expected at least {} channels, found {}", channels.len(), frame.len());
-                            }
-                        }
-                    }
-                },
-                err_fn,
-                None, // No specific latency requirement
-            ).expect("Failed to build input stream")
-        },
-        _ => panic!("Unsupported sample format"),
-    };
+    let stdout_mode = config.output_mode == OutputMode::Stdout;
+    audio_recorder::set_status_to_stderr(stdout_mode);
+    let record_duration = Duration::from_secs(config.record_duration);
+    let show_levels = config.show_levels;
+    let status_file = config.status_file.clone();
+    let start_armed = config.start_armed;
+    let schedule = config.schedule.clone();
 
-    stream.play().expect("Failed to play stream");
+    let mut recorder = AudioRecorder::new(config);
+    if !schedule.is_empty() {
+        return run_schedule(recorder, &schedule);
+    }
+    if start_armed {
+        recorder.arm()?;
+    } else {
+        recorder.start_recording()?;
+    }
 
-    thread::sleep(Duration::from_secs(record_duration));
+    // Each line on stdin drops a marker at the current position
+    let interactive = !stdout_mode && io::stdin().is_terminal();
+    if interactive {
+        status!("Press Enter to add a marker (type a label first to name it)");
+    }
+    // An armed recorder waits for its start key on stdin
+    if interactive || start_armed {
+        recorder.listen_for_markers(io::BufReader::new(io::stdin()));
+    }
 
-    let mut writer_lock = writer.lock().unwrap();
-    let buffer_lock = intermediate_buffer.lock().unwrap();
-    if let Some(ref mut writer) = *writer_lock {
-        for &sample in &*buffer_lock {
-            writer.write_sample(sample).unwrap();
+    if start_armed {
+        status!("Armed: waiting for the start key");
+        let armed = Instant::now();
+        let mut last_levels = armed;
+        while !recorder.take_start_request() {
+            if recorder.stop_requested() {
+                recorder.stop_recording()?;
+                return Ok(());
+            }
+            if show_levels && last_levels.elapsed() >= Duration::from_millis(500) {
+                last_levels = Instant::now();
+                let line = audio_recorder::meter::format_status(armed.elapsed(), &recorder.take_peak_levels());
+                status!("{}", line);
+            }
+            thread::sleep(Duration::from_millis(100));
         }
+        recorder.handle_start()?;
+        status!("Recording");
     }
+    recorder.set_planned_duration(record_duration);
 
-    if let Some(writer) = writer_lock.take() {
-        writer.finalize().unwrap();
+    // Stop early if new files can no longer be created
+    let mut started = Instant::now();
+    let mut last_levels = started;
+    let mut last_status: Option<Instant> = None;
+    while started.elapsed() < record_duration {
+        if recorder.write_failed() {
+            eprintln!("Stopping: a new file could not be created");
+            break;
+        }
+        if recorder.recording_aborted() {
+            eprintln!("Stopping: too many writes failed");
+            break;
+        }
+        if recorder.stop_requested() {
+            status!("Stopping");
+            break;
+        }
+        if recorder.take_start_request() {
+            if let StartOutcome::Restarted(files) = recorder.handle_start()? {
+                for file_name in files {
+                    status!("Recording saved to {}", file_name);
+                }
+                started = Instant::now();
+                recorder.set_planned_duration(record_duration);
+            }
+        }
+        if show_levels && last_levels.elapsed() >= Duration::from_millis(500) {
+            last_levels = Instant::now();
+            let line = audio_recorder::meter::format_status(started.elapsed(), &recorder.take_peak_levels());
+            status!("{}", line);
+        }
+        if let Some(ref path) = status_file {
+            if last_status.is_none_or(|last| last.elapsed() >= Duration::from_secs(1)) {
+                last_status = Some(Instant::now());
+                if let Some(status) = recorder.status() {
+                    if let Err(e) = audio_recorder::status::write_status_file(path, &status) {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100).min(record_duration.saturating_sub(started.elapsed())));
+    }
+
+    for file_name in recorder.stop_recording()? {
+        status!("Recording saved to {}", file_name);
     }
+    status!("Session: {}", recorder.session_stats().summary());
+    recorder.wait_for_post_commands();
+
+    if recorder.recording_aborted() {
+        return Err(BlackboxError::Stream("Recording aborted after too many write errors".to_string()));
+    }
+
+    Ok(())
+}
 
-    println!("Recording saved to {}", file_name);
+/// Records during each window of `schedule`, checking it every second, and
+/// waits in between, until the process is stopped.
+fn run_schedule(mut recorder: AudioRecorder, schedule: &[ScheduleWindow]) -> Result<(), BlackboxError> {
+    status!("Recording on a schedule");
+    loop {
+        let in_window = audio_recorder::schedule::in_schedule(schedule, Local::now().naive_local());
+        if in_window && !recorder.is_recording() {
+            recorder.start_recording()?;
+            status!("Schedule window opened: recording");
+        } else if !in_window && recorder.is_recording() {
+            for file_name in recorder.stop_recording()? {
+                status!("Recording saved to {}", file_name);
+            }
+            status!("Schedule window closed: waiting for the next one");
+        }
+        if recorder.is_recording() && (recorder.write_failed() || recorder.recording_aborted()) {
+            recorder.stop_recording()?;
+            status!("Session: {}", recorder.session_stats().summary());
+            recorder.wait_for_post_commands();
+            return Err(BlackboxError::Stream("Stopped the scheduled recording after a write failure".to_string()));
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
 }
 
 // Test modules
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::prelude::*;
     use std::fs;
+    use tempfile::tempdir;
 
     #[test]
     fn test_environment_variable_handling() {
@@ -210,25 +249,11 @@ mod tests {
         env::set_var("DEBUG", "true");
         env::set_var("RECORD_DURATION", "20");
 
-        let channels: Vec<usize> = env::var("AUDIO_CHANNELS")
-            .unwrap_or_else(|_| DEFAULT_CHANNELS.to_string())
-            .split(',')
-            .map(|s| s.parse().expect("Invalid channel number"))
-            .collect();
-
-        let debug: bool = env::var("DEBUG")
-            .unwrap_or_else(|_| DEFAULT_DEBUG.to_string())
-            .parse()
-            .expect("Invalid debug flag");
-
-        let record_duration: u64 = env::var("RECORD_DURATION")
-            .unwrap_or_else(|_| DEFAULT_DURATION.to_string())
-            .parse()
-            .expect("Invalid record duration");
+        let config = Config::load().unwrap();
 
-        assert_eq!(channels, vec![30, 31]);
-        assert_eq!(debug, true);
-        assert_eq!(record_duration, 20);
+        assert_eq!(config.channels, vec![30, 31]);
+        assert!(config.debug);
+        assert_eq!(config.record_duration, 20);
     }
 
     #[test]
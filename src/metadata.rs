@@ -0,0 +1,296 @@
+//! Extra RIFF chunks that `hound` doesn't write, appended to finalized WAV files.
+
+use chrono::{DateTime, Local, Timelike};
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// A labelled position in a recording, in frames from the start of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub position: u64,
+    pub label: String,
+}
+
+/// The markers that fall in the `len` frames starting at `start`, with their
+/// positions made relative to `start`. Used to give each file of a rotating
+/// recording its own markers.
+pub fn markers_in_range(markers: &[Marker], start: u64, len: u64) -> Vec<Marker> {
+    markers.iter()
+        .filter(|m| (start..start + len).contains(&m.position))
+        .map(|m| Marker { position: m.position - start, label: m.label.clone() })
+        .collect()
+}
+
+/// Appends a `cue ` chunk and a `LIST/adtl` chunk holding one `labl` per
+/// marker to a finalized WAV file, then patches the RIFF size. Editors that
+/// understand cue points show these as markers. Cue positions are 32-bit,
+/// like the length of a WAV file, so markers past that are left out.
+pub fn write_cue_chunks(path: &str, markers: &[Marker]) -> io::Result<()> {
+    let markers: Vec<(u32, &Marker)> = markers.iter()
+        .filter_map(|marker| u32::try_from(marker.position).ok().map(|position| (position, marker)))
+        .collect();
+    if markers.is_empty() {
+        return Ok(());
+    }
+
+    let mut cue = Vec::new();
+    cue.extend_from_slice(&(markers.len() as u32).to_le_bytes());
+    for (i, &(position, _)) in markers.iter().enumerate() {
+        let id = i as u32 + 1;
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&position.to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&0u32.to_le_bytes()); // chunk start
+        cue.extend_from_slice(&0u32.to_le_bytes()); // block start
+        cue.extend_from_slice(&position.to_le_bytes());
+    }
+
+    let mut adtl = Vec::new();
+    adtl.extend_from_slice(b"adtl");
+    for (i, (_, marker)) in markers.iter().enumerate() {
+        let mut labl = Vec::new();
+        labl.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+        labl.extend_from_slice(marker.label.as_bytes());
+        labl.push(0);
+        append_chunk(&mut adtl, b"labl", &labl);
+    }
+
+    let mut chunks = Vec::new();
+    append_chunk(&mut chunks, b"cue ", &cue);
+    append_chunk(&mut chunks, b"LIST", &adtl);
+    append_riff_chunks(path, &chunks)
+}
+
+/// Text written to a WAV file's `LIST/INFO` chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoTags {
+    /// `INAM`: the input device.
+    pub name: String,
+    /// `ICMT`: which channels the file holds.
+    pub comment: String,
+    /// `ISFT`: the program that wrote the file.
+    pub software: String,
+}
+
+/// Appends a `LIST/INFO` chunk to a finalized WAV file.
+pub fn write_info_chunk(path: &str, tags: &InfoTags) -> io::Result<()> {
+    let mut info = Vec::new();
+    info.extend_from_slice(b"INFO");
+    for (id, text) in [(b"INAM", &tags.name), (b"ICMT", &tags.comment), (b"ISFT", &tags.software)] {
+        let mut body = text.as_bytes().to_vec();
+        body.push(0);
+        append_chunk(&mut info, id, &body);
+    }
+
+    let mut chunks = Vec::new();
+    append_chunk(&mut chunks, b"LIST", &info);
+    append_riff_chunks(path, &chunks)
+}
+
+/// How many samples at `sample_rate` fall between the local midnight before
+/// `start` and `start`: the Broadcast Wave TimeReference of a file starting then.
+pub fn time_reference(start: DateTime<Local>, sample_rate: u32) -> u64 {
+    let nanos = start.num_seconds_from_midnight() as u64 * 1_000_000_000 + start.nanosecond().min(999_999_999) as u64;
+    (nanos as u128 * sample_rate as u128 / 1_000_000_000) as u64
+}
+
+/// Appends a Broadcast Wave `bext` chunk to a finalized WAV file, naming
+/// `originator` and placing the file's first sample, captured at `start`,
+/// by its local date, time and TimeReference.
+pub fn write_bext_chunk(path: &str, originator: &str, start: DateTime<Local>, sample_rate: u32) -> io::Result<()> {
+    /// Copies `text` into a fixed-width field, truncated or padded with NULs.
+    fn field(out: &mut Vec<u8>, text: &str, width: usize) {
+        let bytes = &text.as_bytes()[..text.len().min(width)];
+        out.extend_from_slice(bytes);
+        out.resize(out.len() + width - bytes.len(), 0);
+    }
+
+    let mut bext = Vec::with_capacity(602);
+    field(&mut bext, "", 256); // description
+    field(&mut bext, originator, 32);
+    field(&mut bext, "", 32); // originator reference
+    field(&mut bext, &start.format("%Y-%m-%d").to_string(), 10);
+    field(&mut bext, &start.format("%H:%M:%S").to_string(), 8);
+    bext.extend_from_slice(&time_reference(start, sample_rate).to_le_bytes());
+    bext.extend_from_slice(&1u16.to_le_bytes()); // version
+    bext.resize(602, 0); // UMID and reserved
+
+    let mut chunks = Vec::new();
+    append_chunk(&mut chunks, b"bext", &bext);
+    append_riff_chunks(path, &chunks)
+}
+
+/// Serializes one chunk, padding its body to an even length as RIFF requires.
+fn append_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Appends already-serialized chunks to the end of a RIFF file and updates
+/// the RIFF size in the header to match.
+fn append_riff_chunks(path: &str, chunks: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let end = file.seek(SeekFrom::End(0))?;
+    file.write_all(chunks)?;
+    let riff_size = (end + chunks.len() as u64 - 8) as u32;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&riff_size.to_le_bytes())?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Splits a RIFF file into its top-level chunks.
+    fn read_chunks(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = [bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]];
+            let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push((id, bytes[pos + 8..pos + 8 + len].to_vec()));
+            pos += 8 + len + len % 2;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_write_cue_chunks() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("markers.wav");
+        let path = path.to_str().unwrap();
+
+        let spec = wav_spec(2, 48000);
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for _ in 0..2000 {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let markers = vec![
+            Marker { position: 100, label: "intro".to_string() },
+            Marker { position: 750, label: "chorus".to_string() },
+        ];
+        write_cue_chunks(path, &markers).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        let riff_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_size, bytes.len() - 8);
+
+        let chunks = read_chunks(&bytes);
+        let cue = &chunks.iter().find(|(id, _)| id == b"cue ").unwrap().1;
+        assert_eq!(u32::from_le_bytes(cue[0..4].try_into().unwrap()), 2);
+        let offsets: Vec<u32> = cue[4..].chunks(24)
+            .map(|point| u32::from_le_bytes(point[20..24].try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, vec![100, 750]);
+
+        let list = &chunks.iter().find(|(id, _)| id == b"LIST").unwrap().1;
+        assert_eq!(&list[0..4], b"adtl");
+        let text = String::from_utf8_lossy(list);
+        assert!(text.contains("intro"));
+        assert!(text.contains("chorus"));
+
+        // The audio itself is untouched
+        let reader = hound::WavReader::open(path).unwrap();
+        assert_eq!(reader.len(), 2000);
+    }
+
+    #[test]
+    fn test_write_info_chunk() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("tagged.wav");
+        let path = path.to_str().unwrap();
+
+        let spec = wav_spec(1, 48000);
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        writer.write_sample(0i16).unwrap();
+        writer.finalize().unwrap();
+
+        let tags = InfoTags {
+            name: "Scarlett 18i20".to_string(),
+            comment: "ch3: Kick".to_string(),
+            software: "audio_blackbox v0.1.0".to_string(),
+        };
+        write_info_chunk(path, &tags).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+
+        let list = &read_chunks(&bytes).into_iter().find(|(id, _)| id == b"LIST").unwrap().1;
+        assert_eq!(&list[0..4], b"INFO");
+        let mut fields = Vec::new();
+        let mut pos = 4;
+        while pos + 8 <= list.len() {
+            let len = u32::from_le_bytes(list[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let text = String::from_utf8(list[pos + 8..pos + 8 + len - 1].to_vec()).unwrap();
+            fields.push((String::from_utf8(list[pos..pos + 4].to_vec()).unwrap(), text));
+            pos += 8 + len + len % 2;
+        }
+        assert_eq!(fields, vec![
+            ("INAM".to_string(), tags.name.clone()),
+            ("ICMT".to_string(), tags.comment.clone()),
+            ("ISFT".to_string(), tags.software.clone()),
+        ]);
+
+        assert_eq!(hound::WavReader::open(path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_write_bext_chunk() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("placed.wav");
+        let path = path.to_str().unwrap();
+
+        let spec = wav_spec(1, 48000);
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        writer.write_sample(0i16).unwrap();
+        writer.finalize().unwrap();
+
+        let start = DateTime::parse_from_rfc3339("2024-06-26T09:05:01.5+00:00").unwrap().with_timezone(&Local);
+        write_bext_chunk(path, "Scarlett 18i20", start, 48000).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let bext = &read_chunks(&bytes).into_iter().find(|(id, _)| id == b"bext").unwrap().1;
+        assert_eq!(bext.len(), 602);
+        assert_eq!(&bext[256..270], b"Scarlett 18i20");
+        assert_eq!(bext[270], 0);
+        let local = start.naive_local();
+        assert_eq!(&bext[320..330], local.format("%Y-%m-%d").to_string().as_bytes());
+        assert_eq!(&bext[330..338], local.format("%H:%M:%S").to_string().as_bytes());
+        let reference = u64::from_le_bytes(bext[338..346].try_into().unwrap());
+        assert_eq!(reference, time_reference(start, 48000));
+        assert_eq!(reference % 48000, 24000);
+        assert_eq!(u16::from_le_bytes(bext[346..348].try_into().unwrap()), 1);
+
+        assert_eq!(hound::WavReader::open(path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_markers_in_range() {
+        let markers = vec![
+            Marker { position: 10, label: "a".to_string() },
+            Marker { position: 1000, label: "b".to_string() },
+            Marker { position: 1999, label: "c".to_string() },
+        ];
+        let second = markers_in_range(&markers, 1000, 1000);
+        assert_eq!(second, vec![
+            Marker { position: 0, label: "b".to_string() },
+            Marker { position: 999, label: "c".to_string() },
+        ]);
+
+        // Past 2^32 frames, a circular recording's files still get theirs
+        let late = vec![Marker { position: u32::MAX as u64 + 500, label: "d".to_string() }];
+        assert_eq!(markers_in_range(&late, u32::MAX as u64, 1000),
+                   vec![Marker { position: 500, label: "d".to_string() }]);
+    }
+}
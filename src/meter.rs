@@ -0,0 +1,48 @@
+//! Peak level readouts shown while recording.
+
+use std::time::Duration;
+
+/// A level as a fraction of full scale in dBFS, rounded to the nearest
+/// decibel, or `-inf` for digital silence.
+pub fn format_dbfs(level: f64) -> String {
+    if level <= 0.0 {
+        return "-inf".to_string();
+    }
+    // Adding zero turns -0 into 0, so levels just under full scale don't show a sign
+    format!("{}", (20.0 * level.log10()).round() + 0.0)
+}
+
+/// A one-line status, e.g. `Rec 1:23 | 1:-12 2:-9 dB`, from the time
+/// recorded so far and each channel's name and peak level.
+pub fn format_status(elapsed: Duration, levels: &[(String, f64)]) -> String {
+    let seconds = elapsed.as_secs();
+    let time = format!("{}:{:02}", seconds / 60, seconds % 60);
+    if levels.is_empty() {
+        return format!("Rec {}", time);
+    }
+    let meters: Vec<String> = levels.iter()
+        .map(|(name, level)| format!("{}:{}", name, format_dbfs(*level)))
+        .collect();
+    format!("Rec {} | {} dB", time, meters.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_dbfs() {
+        assert_eq!(format_dbfs(1.0), "0");
+        assert_eq!(format_dbfs(0.5), "-6");
+        assert_eq!(format_dbfs(0.99), "0");
+        assert_eq!(format_dbfs(0.0), "-inf");
+        assert_eq!(format_dbfs(1.0 / 32768.0), "-90");
+    }
+
+    #[test]
+    fn test_format_status() {
+        let levels = vec![("L".to_string(), 0.25), ("R".to_string(), 0.0)];
+        assert_eq!(format_status(Duration::from_secs(83), &levels), "Rec 1:23 | L:-12 R:-inf dB");
+        assert_eq!(format_status(Duration::from_secs(5), &[]), "Rec 0:05");
+    }
+}
@@ -0,0 +1,262 @@
+//! Passes the recorded channels through to an output device while recording,
+//! e.g. for a PA or livestream feed.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::SampleFormat;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::error::BlackboxError;
+
+/// How much audio the monitor may lag behind the input before the oldest
+/// frames are dropped, in seconds.
+const MONITOR_MAX_LATENCY_SECS: f64 = 0.25;
+
+/// Frames captured from the input, waiting to be played on the monitor output.
+/// Frames are resampled to the output rate and downmixed to the output's
+/// channel count as they are read.
+pub struct MonitorBuffer {
+    samples: VecDeque<f32>,
+    input_channels: usize,
+    capacity_frames: usize,
+    /// Input frames consumed per output frame.
+    step: f64,
+    /// Fractional read position between the first and second queued frames.
+    position: f64,
+    /// For each output channel, the positions of the input channels summed
+    /// onto it, replacing the default `downmix`.
+    groups: Option<Vec<Vec<usize>>>,
+}
+
+impl MonitorBuffer {
+    pub fn new(input_channels: usize, input_rate: u32, output_rate: u32) -> Self {
+        let capacity_frames = ((input_rate as f64 * MONITOR_MAX_LATENCY_SECS) as usize).max(2);
+        MonitorBuffer {
+            samples: VecDeque::with_capacity(capacity_frames * input_channels),
+            input_channels,
+            capacity_frames,
+            step: input_rate as f64 / output_rate as f64,
+            position: 0.0,
+            groups: None,
+        }
+    }
+
+    /// Sums the input channels at each group's positions onto an output
+    /// channel instead of using the default downmix.
+    pub fn set_downmix(&mut self, groups: Vec<Vec<usize>>) {
+        self.groups = Some(groups);
+    }
+
+    /// Queues one input frame, dropping the oldest frame if the output has
+    /// fallen too far behind.
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        if self.len_frames() >= self.capacity_frames {
+            self.samples.drain(..self.input_channels);
+        }
+        self.samples.extend(frame.iter().take(self.input_channels));
+    }
+
+    pub fn len_frames(&self) -> usize {
+        self.samples.len() / self.input_channels
+    }
+
+    /// Fills an interleaved output buffer with `output_channels` channels.
+    /// Plays silence when not enough input has arrived yet.
+    pub fn fill(&mut self, output: &mut [f32], output_channels: usize) {
+        let mut frame = vec![0.0; self.input_channels];
+        for out_frame in output.chunks_mut(output_channels) {
+            if self.len_frames() < 2 {
+                out_frame.iter_mut().for_each(|s| *s = 0.0);
+                continue;
+            }
+            // Linear interpolation between the two oldest frames
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let current = self.samples[i];
+                let next = self.samples[self.input_channels + i];
+                *sample = current + (next - current) * self.position as f32;
+            }
+            match self.groups {
+                Some(ref groups) => sum_groups(&frame, groups, out_frame),
+                None => downmix(&frame, out_frame),
+            }
+
+            self.position += self.step;
+            while self.position >= 1.0 && self.len_frames() >= 2 {
+                self.samples.drain(..self.input_channels);
+                self.position -= 1.0;
+            }
+        }
+    }
+}
+
+/// Maps one frame onto an output frame with a possibly different channel count.
+/// Mono input is copied to every output channel; when the output has fewer
+/// channels, input channels are folded onto them and averaged; when it has
+/// more, the extra output channels are silent.
+fn downmix(input: &[f32], output: &mut [f32]) {
+    if input.len() == 1 {
+        output.iter_mut().for_each(|s| *s = input[0]);
+    } else if output.len() >= input.len() {
+        output[..input.len()].copy_from_slice(input);
+        output[input.len()..].iter_mut().for_each(|s| *s = 0.0);
+    } else {
+        let mut counts = vec![0; output.len()];
+        output.iter_mut().for_each(|s| *s = 0.0);
+        for (i, &sample) in input.iter().enumerate() {
+            let target = i % output.len();
+            output[target] += sample;
+            counts[target] += 1;
+        }
+        for (sample, count) in output.iter_mut().zip(counts) {
+            *sample /= count as f32;
+        }
+    }
+}
+
+/// Sums the input channels of each group onto one output channel, clamped
+/// to full scale. Should the output have fewer channels than there are
+/// groups, say after the device changed, the extra groups wrap around onto
+/// the first channels, so a mono output still hears everything.
+fn sum_groups(input: &[f32], groups: &[Vec<usize>], output: &mut [f32]) {
+    output.iter_mut().for_each(|s| *s = 0.0);
+    if output.is_empty() {
+        return;
+    }
+    for (g, positions) in groups.iter().enumerate() {
+        output[g % output.len()] += positions.iter().filter_map(|&i| input.get(i)).sum::<f32>();
+    }
+    output.iter_mut().for_each(|s| *s = s.clamp(-1.0, 1.0));
+}
+
+/// The output device of `host` called `device_name`.
+pub fn find_output_device(host: &cpal::Host, device_name: &str) -> Result<cpal::Device, BlackboxError> {
+    host.output_devices()
+        .map_err(|e| BlackboxError::DeviceNotFound(format!("Failed to list output devices: {}", e)))?
+        .find(|d| d.name().map(|n| n == device_name).unwrap_or(false))
+        .ok_or_else(|| BlackboxError::DeviceNotFound(format!("No output device named {}", device_name)))
+}
+
+/// Starts playing whatever is pushed into the returned buffer on `device`.
+/// The stream stops when it is dropped.
+pub fn start_monitor(device: cpal::Device, input_rate: u32, input_channels: usize)
+    -> Result<(cpal::Stream, Arc<Mutex<MonitorBuffer>>), BlackboxError> {
+    let config = device.default_output_config()
+        .map_err(|e| BlackboxError::Stream(format!("Failed to get default output stream config: {}", e)))?;
+    let output_rate = config.sample_rate().0;
+    let output_channels = config.channels() as usize;
+
+    if output_rate != input_rate {
+        status!("Resampling monitor output from {} Hz to {} Hz", input_rate, output_rate);
+    }
+
+    let buffer = Arc::new(Mutex::new(MonitorBuffer::new(input_channels, input_rate, output_rate)));
+    let err_fn = |err| eprintln!("An error occurred on the monitor output stream: {}", err);
+
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => {
+            let buffer_clone = Arc::clone(&buffer);
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    buffer_clone.lock().unwrap().fill(data, output_channels);
+                },
+                err_fn,
+                None,
+            )
+        },
+        SampleFormat::I16 => {
+            let buffer_clone = Arc::clone(&buffer);
+            let mut scratch = Vec::new();
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                    scratch.resize(data.len(), 0.0);
+                    buffer_clone.lock().unwrap().fill(&mut scratch, output_channels);
+                    for (out, &sample) in data.iter_mut().zip(&scratch) {
+                        *out = (sample * i16::MAX as f32) as i16;
+                    }
+                },
+                err_fn,
+                None,
+            )
+        },
+        SampleFormat::U16 => {
+            let buffer_clone = Arc::clone(&buffer);
+            let mut scratch = Vec::new();
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
+                    scratch.resize(data.len(), 0.0);
+                    buffer_clone.lock().unwrap().fill(&mut scratch, output_channels);
+                    for (out, &sample) in data.iter_mut().zip(&scratch) {
+                        *out = ((sample * i16::MAX as f32) as i32 + 32768) as u16;
+                    }
+                },
+                err_fn,
+                None,
+            )
+        },
+        format => return Err(BlackboxError::Stream(format!("Unsupported monitor sample format: {:?}", format))),
+    }.map_err(|e| BlackboxError::Stream(format!("Failed to build monitor output stream: {}", e)))?;
+
+    stream.play().map_err(|e| BlackboxError::Stream(format!("Failed to play monitor stream: {}", e)))?;
+
+    Ok((stream, buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downmix_stereo_to_mono() {
+        let mut output = [0.0];
+        downmix(&[0.5, 0.25], &mut output);
+        assert_eq!(output, [0.375]);
+    }
+
+    #[test]
+    fn test_downmix_groups_sum_onto_the_output() {
+        // Four recorded channels onto a stereo monitor: 0 and 1 left, 2 and
+        // 3 right
+        let mut buffer = MonitorBuffer::new(4, 8000, 8000);
+        buffer.set_downmix(vec![vec![0, 1], vec![2, 3]]);
+        for _ in 0..3 {
+            buffer.push_frame(&[0.1, 0.2, 0.3, 0.9]);
+        }
+        let mut output = [0.0; 2];
+        buffer.fill(&mut output, 2);
+        assert!((output[0] - 0.3).abs() < 1e-6);
+        assert_eq!(output[1], 1.0);
+
+        // The device now has a single channel
+        let mut output = [0.0; 1];
+        buffer.fill(&mut output, 1);
+        assert_eq!(output, [1.0]);
+        let mut output = [0.0];
+        sum_groups(&[0.1, 0.2, 0.3, 0.1], &[vec![0, 1], vec![2, 3]], &mut output);
+        assert!((output[0] - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fill_resamples_to_output_rate() {
+        // Output runs at twice the input rate, so each input frame is heard
+        // twice, with interpolated values in between.
+        let mut buffer = MonitorBuffer::new(1, 24000, 48000);
+        for sample in [0.0, 1.0, 0.0] {
+            buffer.push_frame(&[sample]);
+        }
+        let mut output = [0.0; 4];
+        buffer.fill(&mut output, 1);
+        assert_eq!(output, [0.0, 0.5, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest_frames_when_full() {
+        let mut buffer = MonitorBuffer::new(2, 8, 8);
+        for i in 0..10 {
+            buffer.push_frame(&[i as f32, i as f32]);
+        }
+        assert_eq!(buffer.len_frames(), 2);
+    }
+}
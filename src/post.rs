@@ -0,0 +1,94 @@
+//! `POST_COMMAND`: a command run on each finished recording, e.g. to upload
+//! or transcode it.
+
+use std::io;
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::BlackboxError;
+
+/// The program and arguments for `template` run on `path`. The template is
+/// split on whitespace and `{path}` replaced in each word, without going
+/// through a shell, so a path with spaces or quotes stays one argument.
+pub fn post_command_args(template: &str, path: &str) -> Vec<String> {
+    template.split_whitespace().map(|word| word.replace("{path}", path)).collect()
+}
+
+/// Runs `template` on `path` and waits for it to exit.
+pub fn run_post_command(template: &str, path: &str) -> Result<ExitStatus, BlackboxError> {
+    let args = post_command_args(template, path);
+    let (program, args) = args.split_first()
+        .ok_or_else(|| BlackboxError::Config("Empty post command".to_string()))?;
+    Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| BlackboxError::ExternalTool(format!("Failed to run {}: {}", program, e)))
+}
+
+/// The name of the thread running `POST_COMMAND`.
+pub const POST_THREAD_NAME: &str = "blackbox-post";
+
+/// Runs `job` on a new thread called `name` and returns its handle or, if no
+/// thread can be started, e.g. on a system at its thread limit, says so and
+/// runs it on this thread instead, so the work is held up but never lost.
+pub fn spawn_or_run<F: FnOnce() + Send + 'static>(name: &str, job: F) -> Option<thread::JoinHandle<()>> {
+    spawn_or_run_with(|job| thread::Builder::new().name(name.to_string()).spawn(job), job)
+}
+
+/// `spawn_or_run`, starting the thread with `spawn`.
+fn spawn_or_run_with<F, S>(spawn: S, job: F) -> Option<thread::JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+    S: FnOnce(Box<dyn FnOnce() + Send>) -> io::Result<thread::JoinHandle<()>>,
+{
+    // Shared, since a thread that fails to start drops what it was given
+    let job = Arc::new(Mutex::new(Some(job)));
+    let queued = Arc::clone(&job);
+    let result = spawn(Box::new(move || {
+        if let Some(job) = queued.lock().unwrap().take() {
+            job();
+        }
+    }));
+    match result {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            eprintln!("Warning: couldn't start a background thread ({}); running its work now instead", e);
+            if let Some(job) = job.lock().unwrap().take() {
+                job();
+            }
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_command_args() {
+        assert_eq!(post_command_args("rclone copy {path} remote:", "/rec/a b.wav"),
+                   ["rclone", "copy", "/rec/a b.wav", "remote:"]);
+        assert_eq!(post_command_args("notify --file={path}", "x.wav"), ["notify", "--file=x.wav"]);
+        assert!(post_command_args("  ", "x.wav").is_empty());
+    }
+
+    #[test]
+    fn test_work_runs_inline_when_no_thread_starts() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&ran);
+        let handle = spawn_or_run_with(|_| Err(io::Error::new(io::ErrorKind::WouldBlock, "thread limit")), move || {
+            log.lock().unwrap().push(thread::current().name().map(str::to_string));
+        });
+        assert!(handle.is_none());
+        assert_eq!(*ran.lock().unwrap(), vec![thread::current().name().map(str::to_string)]);
+
+        let log = Arc::clone(&ran);
+        let handle = spawn_or_run(POST_THREAD_NAME, move || {
+            log.lock().unwrap().push(thread::current().name().map(str::to_string));
+        });
+        handle.unwrap().join().unwrap();
+        assert_eq!(ran.lock().unwrap()[1].as_deref(), Some(POST_THREAD_NAME));
+    }
+}
@@ -0,0 +1,810 @@
+//! Destinations for the captured audio. `Capture` selects the recorded
+//! channels from each device buffer and hands whole frames to an
+//! `AudioProcessor`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use crate::error::BlackboxError;
+use crate::events::EventLog;
+
+pub type WavFileWriter = hound::WavWriter<BufWriter<SyncedFile>>;
+
+/// A file whose `flush` also asks the OS to write it to disk, so what a
+/// `WavWriter` flushes survives a power loss. That can block for a long
+/// time, so a recording's files are only written from `Capture`'s writer
+/// thread, never from the audio callback.
+pub struct SyncedFile(File);
+
+impl Write for SyncedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+impl Seek for SyncedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+pub trait AudioProcessor: Send {
+    /// Receives interleaved samples at the recording's bit depth, widened to
+    /// `i32`, always a whole number of frames.
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError>;
+
+    /// Flushes and closes the output, returning the paths of the files it
+    /// wrote. Nothing is written after this.
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError>;
+}
+
+/// Creates a WAV writer whose `BufWriter` holds `buffer_bytes` before each
+/// write to disk. Larger buffers mean fewer syscalls, which matters most on
+/// network filesystems.
+pub fn create_wav_writer(path: &str, spec: hound::WavSpec, buffer_bytes: usize) -> hound::Result<WavFileWriter> {
+    let file = SyncedFile(File::create(path)?);
+    hound::WavWriter::new(BufWriter::with_capacity(buffer_bytes, file), spec)
+}
+
+/// The largest sample value at `bits_per_sample`, e.g. 32767 at 16 bits.
+pub fn full_scale(bits_per_sample: u16) -> i32 {
+    (1 << (bits_per_sample - 1)) - 1
+}
+
+/// Writes a batch of whole frames, 16-bit ones in one call instead of one
+/// `write_sample` call per sample.
+pub fn write_buffered_samples(writer: &mut WavFileWriter, samples: &[i32]) -> hound::Result<()> {
+    if writer.spec().bits_per_sample != 16 {
+        for &sample in samples {
+            writer.write_sample(sample)?;
+        }
+        return Ok(());
+    }
+    let mut batch = writer.get_i16_writer(samples.len() as u32);
+    for &sample in samples {
+        batch.write_sample(sample);
+    }
+    batch.flush()
+}
+
+/// Records to a WAV file.
+pub struct WavProcessor {
+    path: String,
+    writer: Option<WavFileWriter>,
+    channels: usize,
+    /// Zero to only flush when finalizing.
+    flush_frames: u64,
+    frames_since_flush: u64,
+}
+
+impl WavProcessor {
+    pub fn create(path: &str, spec: hound::WavSpec, buffer_bytes: usize) -> Result<Self, BlackboxError> {
+        Ok(WavProcessor {
+            path: path.to_string(),
+            writer: Some(create_wav_writer(path, spec, buffer_bytes)?),
+            channels: spec.channels as usize,
+            flush_frames: 0,
+            frames_since_flush: 0,
+        })
+    }
+
+    /// Updates the header and flushes the file to disk every `frames`
+    /// frames, so a crash or power loss only loses the audio since the last
+    /// flush. The flush happens within `write_samples`, so this should only
+    /// be used behind a writer thread. Zero turns this off.
+    pub fn with_flush_interval(mut self, frames: u64) -> Self {
+        self.flush_frames = frames;
+        self
+    }
+}
+
+impl AudioProcessor for WavProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        if let Some(ref mut writer) = self.writer {
+            write_buffered_samples(writer, samples)?;
+            if self.flush_frames > 0 {
+                self.frames_since_flush += (samples.len() / self.channels) as u64;
+                if self.frames_since_flush >= self.flush_frames {
+                    writer.flush()?;
+                    self.frames_since_flush = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        match self.writer.take() {
+            Some(writer) => {
+                writer.finalize()?;
+                Ok(vec![self.path.clone()])
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Records each channel to its own mono WAV file.
+pub struct SplitProcessor {
+    files: Vec<WavProcessor>,
+    channel_buffer: Vec<i32>,
+}
+
+impl SplitProcessor {
+    /// Creates one mono file per entry in `paths`, in channel order.
+    pub fn create(paths: &[String], sample_rate: u32, bits_per_sample: u16, buffer_bytes: usize)
+        -> Result<Self, BlackboxError> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let files = paths.iter()
+            .map(|path| WavProcessor::create(path, spec, buffer_bytes))
+            .collect::<Result<_, _>>()?;
+        Ok(SplitProcessor { files, channel_buffer: Vec::new() })
+    }
+
+    /// Flushes every file on the same schedule. See `WavProcessor::with_flush_interval`.
+    pub fn with_flush_interval(mut self, frames: u64) -> Self {
+        self.files = self.files.into_iter().map(|file| file.with_flush_interval(frames)).collect();
+        self
+    }
+}
+
+impl AudioProcessor for SplitProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        let channels = self.files.len();
+        for (i, file) in self.files.iter_mut().enumerate() {
+            self.channel_buffer.clear();
+            self.channel_buffer.extend(samples.iter().skip(i).step_by(channels));
+            file.write_samples(&self.channel_buffer)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let mut paths = Vec::new();
+        for file in &mut self.files {
+            paths.extend(file.finalize()?);
+        }
+        Ok(paths)
+    }
+}
+
+/// Opens the processor for the next file of a rotating recording.
+pub type SegmentFactory = Box<dyn FnMut() -> Result<Box<dyn AudioProcessor>, BlackboxError> + Send>;
+
+/// Gives the length in frames of each file after the first, as it starts.
+pub type FileFrames = Box<dyn FnMut() -> u64 + Send>;
+
+/// Starts a new file (or set of split files) every `frames_per_file` frames.
+/// Rotation always falls between two frames, and a batch that straddles the
+/// boundary is split there, so consecutive files concatenate back into
+/// exactly the recorded stream.
+pub struct RotatingProcessor {
+    open_next: SegmentFactory,
+    current: Option<Box<dyn AudioProcessor>>,
+    channels: usize,
+    frames_per_file: u64,
+    /// The length of the current file, which is shorter than
+    /// `frames_per_file` for the first file of an aligned recording.
+    frames_this_file: u64,
+    /// Overrides `frames_per_file` for each file after the first.
+    file_frames: Option<FileFrames>,
+    frames_in_file: u64,
+    /// The files of each finished segment, oldest first.
+    finished: Vec<Vec<String>>,
+    /// Keep only this many segments, counting the one being written,
+    /// deleting the oldest at each rotation.
+    circular_segments: Option<usize>,
+    events: EventLog,
+}
+
+impl RotatingProcessor {
+    /// Opens the first file straight away.
+    pub fn new(channels: usize, frames_per_file: u64, mut open_next: SegmentFactory) -> Result<Self, BlackboxError> {
+        Ok(RotatingProcessor {
+            current: Some(open_next()?),
+            open_next,
+            channels,
+            frames_per_file,
+            frames_this_file: frames_per_file,
+            file_frames: None,
+            frames_in_file: 0,
+            finished: Vec::new(),
+            circular_segments: None,
+            events: EventLog::default(),
+        })
+    }
+
+    /// Keeps only the last `segments` segments on disk, including the one
+    /// being written, so a recording of any length takes the same space.
+    pub fn with_circular_segments(mut self, segments: usize) -> Self {
+        self.circular_segments = Some(segments.max(1));
+        self
+    }
+
+    /// Records each file finished at a rotation in `events`.
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Ends the first file after `frames` instead, e.g. so the rest start on
+    /// clock boundaries. Zero leaves it at `frames_per_file`.
+    pub fn with_first_file_frames(mut self, frames: u64) -> Self {
+        if frames > 0 {
+            self.frames_this_file = frames;
+        }
+        self
+    }
+
+    /// Asks `file_frames` for the length of each file after the first, for
+    /// files that aren't all the same length, such as one per calendar day.
+    pub fn with_file_frames(mut self, file_frames: FileFrames) -> Self {
+        self.file_frames = Some(file_frames);
+        self
+    }
+
+    fn rotate(&mut self) -> Result<(), BlackboxError> {
+        if let Some(mut current) = self.current.take() {
+            let files = current.finalize()?;
+            self.events.rotated(&files);
+            self.finished.push(files);
+        }
+        if let Some(keep) = self.circular_segments {
+            let excess = (self.finished.len() + 1).saturating_sub(keep);
+            for path in self.finished.drain(..excess).flatten() {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {
+                        crate::envelope::remove_envelope(std::path::Path::new(&path));
+                        crate::timestamps::remove_timestamps(std::path::Path::new(&path));
+                        self.events.log("deleted", &path, &format!("circular: oldest of {} files", keep))
+                    },
+                    Err(e) => {
+                        eprintln!("Failed to delete {}: {}", path, e);
+                        self.events.failed(&path, "delete the oldest file", &e.into());
+                    },
+                }
+            }
+        }
+        // A file that can't be created loses its stretch of the recording,
+        // and the next one is tried at the next boundary rather than on
+        // every write in between
+        self.frames_this_file = match self.file_frames {
+            Some(ref mut file_frames) => file_frames(),
+            None => self.frames_per_file,
+        };
+        self.frames_in_file = 0;
+        self.current = Some((self.open_next)()?);
+        Ok(())
+    }
+}
+
+impl AudioProcessor for RotatingProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        let mut rest = samples;
+        let mut result = Ok(());
+        while !rest.is_empty() {
+            // Rotate only once there is a frame for the new file, so a
+            // recording never ends with an empty one
+            if self.frames_in_file == self.frames_this_file {
+                if let Err(e) = self.rotate() {
+                    result = Err(e);
+                }
+            }
+            let room = (self.frames_this_file - self.frames_in_file) as usize * self.channels;
+            let (now, later) = rest.split_at(room.min(rest.len()));
+            match self.current {
+                Some(ref mut current) => current.write_samples(now)?,
+                None if result.is_ok() => {
+                    result = Err(BlackboxError::Stream("No file to write to until the next rotation".to_string()));
+                },
+                None => {},
+            }
+            self.frames_in_file += (now.len() / self.channels) as u64;
+            rest = later;
+        }
+        result
+    }
+
+    /// Returns every file written, in recording order.
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        if let Some(mut current) = self.current.take() {
+            self.finished.push(current.finalize()?);
+        }
+        Ok(std::mem::take(&mut self.finished).into_iter().flatten().collect())
+    }
+}
+
+/// Fades the audio in over its first `fade_frames` frames and out over its
+/// last, so a recording that starts or stops mid-signal doesn't click. The
+/// last `fade_frames` frames are held back until `finalize`, since only then
+/// is it known they are the last.
+pub struct FadeProcessor {
+    inner: Box<dyn AudioProcessor>,
+    channels: usize,
+    fade_frames: usize,
+    frames_in: usize,
+    tail: Vec<i32>,
+}
+
+impl FadeProcessor {
+    pub fn new(inner: Box<dyn AudioProcessor>, channels: usize, fade_frames: usize) -> Self {
+        FadeProcessor {
+            inner,
+            channels,
+            fade_frames,
+            frames_in: 0,
+            tail: Vec::with_capacity((fade_frames + 1) * channels),
+        }
+    }
+}
+
+fn apply_gain(sample: i32, gain: f64) -> i32 {
+    (sample as f64 * gain).round() as i32
+}
+
+impl AudioProcessor for FadeProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        for frame in samples.chunks(self.channels) {
+            let gain = (self.frames_in as f64 / self.fade_frames as f64).min(1.0);
+            self.tail.extend(frame.iter().map(|&s| apply_gain(s, gain)));
+            self.frames_in += 1;
+        }
+        let excess = self.tail.len().saturating_sub(self.fade_frames * self.channels);
+        if excess > 0 {
+            self.inner.write_samples(&self.tail[..excess])?;
+            self.tail.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let frames = self.tail.len() / self.channels;
+        for (i, frame) in self.tail.chunks_mut(self.channels).enumerate() {
+            let gain = (frames - 1 - i) as f64 / self.fade_frames as f64;
+            for sample in frame {
+                *sample = apply_gain(*sample, gain);
+            }
+        }
+        self.inner.write_samples(&self.tail)?;
+        self.tail.clear();
+        self.inner.finalize()
+    }
+}
+
+/// Passes the recording on to `inner` and also writes a stereo mix of it to
+/// `mix`, each side the average of its channels so the sum can't clip.
+pub struct MixProcessor {
+    inner: Box<dyn AudioProcessor>,
+    mix: Box<dyn AudioProcessor>,
+    channels: usize,
+    /// Positions within each frame of the channels mixed to each side.
+    left: Vec<usize>,
+    right: Vec<usize>,
+    stereo: Vec<i32>,
+}
+
+impl MixProcessor {
+    pub fn new(inner: Box<dyn AudioProcessor>, mix: Box<dyn AudioProcessor>, channels: usize,
+               left: Vec<usize>, right: Vec<usize>) -> Self {
+        MixProcessor { inner, mix, channels, left, right, stereo: Vec::new() }
+    }
+}
+
+/// The average of the samples at `positions` in `frame`, or silence if none.
+fn average(frame: &[i32], positions: &[usize]) -> i32 {
+    match positions.len() {
+        0 => 0,
+        n => positions.iter().map(|&i| frame[i] as i64).sum::<i64>() as i32 / n as i32,
+    }
+}
+
+impl AudioProcessor for MixProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.inner.write_samples(samples)?;
+        self.stereo.clear();
+        for frame in samples.chunks_exact(self.channels) {
+            self.stereo.push(average(frame, &self.left));
+            self.stereo.push(average(frame, &self.right));
+        }
+        self.mix.write_samples(&self.stereo)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let files = self.inner.finalize();
+        let mix = self.mix.finalize();
+        let mut files = files?;
+        files.extend(mix?);
+        Ok(files)
+    }
+}
+
+/// Writes each sample of a mono recording twice, as the left and right
+/// channels of a stereo file, for players that expect stereo.
+pub struct MonoToStereoProcessor {
+    inner: Box<dyn AudioProcessor>,
+    stereo: Vec<i32>,
+}
+
+impl MonoToStereoProcessor {
+    pub fn new(inner: Box<dyn AudioProcessor>) -> Self {
+        MonoToStereoProcessor { inner, stereo: Vec::new() }
+    }
+}
+
+impl AudioProcessor for MonoToStereoProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.stereo.clear();
+        self.stereo.extend(samples.iter().flat_map(|&sample| [sample, sample]));
+        self.inner.write_samples(&self.stereo)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        self.inner.finalize()
+    }
+}
+
+/// Passes the same audio to several processors, e.g. a file and a live
+/// meter, in the order given. Every one is called each time, even after
+/// another has failed, so one broken output doesn't starve the rest; the
+/// first error is returned and any later ones are printed. `finalize`
+/// returns the files of each in order, leaving out any output that failed to
+/// finalize, whose errors are printed; it only fails if every output did, so
+/// the files that were finished still get their markers, checks and the rest.
+pub struct TeeProcessor {
+    outputs: Vec<Box<dyn AudioProcessor>>,
+}
+
+impl TeeProcessor {
+    pub fn new(outputs: Vec<Box<dyn AudioProcessor>>) -> Self {
+        TeeProcessor { outputs }
+    }
+
+    /// Keeps the first error of `results`, printing the rest.
+    fn first_error<T>(results: impl Iterator<Item = Result<T, BlackboxError>>) -> Result<Vec<T>, BlackboxError> {
+        let mut values = Vec::new();
+        let mut first = None;
+        for result in results {
+            match result {
+                Ok(value) => values.push(value),
+                Err(e) if first.is_none() => first = Some(e),
+                Err(e) => eprintln!("Tee output also failed: {}", e),
+            }
+        }
+        match first {
+            Some(e) => Err(e),
+            None => Ok(values),
+        }
+    }
+}
+
+impl AudioProcessor for TeeProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        Self::first_error(self.outputs.iter_mut().map(|output| output.write_samples(samples))).map(|_| ())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let mut files = Vec::new();
+        let mut first = None;
+        for output in &mut self.outputs {
+            match output.finalize() {
+                Ok(output_files) => files.push(output_files),
+                Err(e) if first.is_none() => first = Some(e),
+                Err(e) => eprintln!("Tee output also failed to finalize: {}", e),
+            }
+        }
+        match first {
+            Some(e) if files.is_empty() => Err(e),
+            Some(e) => {
+                eprintln!("Tee output failed to finalize, keeping the other outputs' files: {}", e);
+                Ok(files.into_iter().flatten().collect())
+            },
+            None => Ok(files.into_iter().flatten().collect()),
+        }
+    }
+}
+
+/// Streams raw interleaved 16-bit little-endian PCM, typically to stdout so
+/// the audio can be piped into another tool.
+pub struct StdoutProcessor<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> StdoutProcessor<W> {
+    pub fn new(out: W) -> Self {
+        StdoutProcessor { out }
+    }
+}
+
+impl<W: Write + Send> AudioProcessor for StdoutProcessor<W> {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        for &sample in samples {
+            self.out.write_all(&(sample as i16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        self.out.flush()?;
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    /// A writer whose bytes can still be read after it is boxed away.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Adds the frames (mono) written to it to the last entry of a list.
+    struct CountFrames(Arc<Mutex<Vec<usize>>>);
+
+    impl AudioProcessor for CountFrames {
+        fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+            *self.0.lock().unwrap().last_mut().unwrap() += samples.len();
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Records every call made to it, failing writes once told to.
+    struct CallLog {
+        calls: Arc<Mutex<Vec<String>>>,
+        name: &'static str,
+        fail: bool,
+        fail_finalize: bool,
+    }
+
+    impl AudioProcessor for CallLog {
+        fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+            self.calls.lock().unwrap().push(format!("{} write {:?}", self.name, samples));
+            match self.fail {
+                true => Err(BlackboxError::Stream(format!("{} failed", self.name))),
+                false => Ok(()),
+            }
+        }
+
+        fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+            self.calls.lock().unwrap().push(format!("{} finalize", self.name));
+            match self.fail_finalize {
+                true => Err(BlackboxError::Stream(format!("{} failed to finalize", self.name))),
+                false => Ok(vec![format!("{}.wav", self.name)]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tee_forwards_to_each_output_in_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let output = |name, fail| -> Box<dyn AudioProcessor> { Box::new(CallLog { calls: Arc::clone(&calls), name, fail, fail_finalize: false }) };
+        let mut tee = TeeProcessor::new(vec![output("disk", false), output("meter", false)]);
+        tee.write_samples(&[1, 2]).unwrap();
+        tee.write_samples(&[3, 4]).unwrap();
+        assert_eq!(tee.finalize().unwrap(), ["disk.wav", "meter.wav"]);
+        assert_eq!(*calls.lock().unwrap(), [
+            "disk write [1, 2]", "meter write [1, 2]", "disk write [3, 4]", "meter write [3, 4]",
+            "disk finalize", "meter finalize",
+        ]);
+
+        // A failing output doesn't stop the others, and its error comes back
+        calls.lock().unwrap().clear();
+        let mut tee = TeeProcessor::new(vec![output("broken", true), output("disk", false), output("also", true)]);
+        assert!(matches!(tee.write_samples(&[5]), Err(BlackboxError::Stream(msg)) if msg == "broken failed"));
+        assert_eq!(calls.lock().unwrap().len(), 3);
+        assert_eq!(tee.finalize().unwrap(), ["broken.wav", "disk.wav", "also.wav"]);
+    }
+
+    #[test]
+    fn test_tee_keeps_files_of_outputs_that_finalize() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let output = |name, fail_finalize| -> Box<dyn AudioProcessor> {
+            Box::new(CallLog { calls: Arc::clone(&calls), name, fail: false, fail_finalize })
+        };
+        let mut tee = TeeProcessor::new(vec![output("broken", true), output("disk", false), output("meter", false)]);
+        tee.write_samples(&[1]).unwrap();
+        assert_eq!(tee.finalize().unwrap(), ["disk.wav", "meter.wav"]);
+        assert_eq!(calls.lock().unwrap().iter().filter(|call| call.ends_with("finalize")).count(), 3);
+
+        // With nothing finished, the first error comes back
+        let mut tee = TeeProcessor::new(vec![output("broken", true), output("also", true)]);
+        assert!(matches!(tee.finalize(), Err(BlackboxError::Stream(msg)) if msg == "broken failed to finalize"));
+    }
+
+    #[test]
+    fn test_large_write_buffer_integrity() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("buffered.wav");
+        let path = path.to_str().unwrap();
+
+        let spec = wav_spec(2, 48000);
+
+        let samples: Vec<i32> = (0..200_000).map(|i| (i % 65536) - 32768).collect();
+
+        let mut writer = create_wav_writer(path, spec, 1 << 20).unwrap();
+        for chunk in samples.chunks(512) {
+            write_buffered_samples(&mut writer, chunk).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let read_back: Vec<i32> = reader.samples::<i16>().map(|s| s.unwrap() as i32).collect();
+        assert_eq!(read_back, samples);
+    }
+
+    #[test]
+    fn test_stdout_processor_byte_layout() {
+        let mut processor = StdoutProcessor::new(Vec::new());
+        processor.write_samples(&[1, -1, 256, i16::MIN as i32]).unwrap();
+        assert!(processor.finalize().unwrap().is_empty());
+
+        assert_eq!(processor.out, vec![0x01, 0x00, 0xff, 0xff, 0x00, 0x01, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_rotation_is_gapless() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let spec = wav_spec(2, 48000);
+
+        let mut index = 0;
+        let open_next: SegmentFactory = Box::new(move || {
+            index += 1;
+            let path = dir.join(format!("segment-{}.wav", index));
+            Ok(Box::new(WavProcessor::create(path.to_str().unwrap(), spec, 8192)?) as Box<dyn AudioProcessor>)
+        });
+        let mut processor = RotatingProcessor::new(2, 1000, open_next).unwrap();
+
+        // 2500 frames in batches that don't line up with the rotation point
+        let input: Vec<i32> = (0..5000).map(|i| (i % 30000) - 15000).collect();
+        for batch in input.chunks(2 * 333) {
+            processor.write_samples(batch).unwrap();
+        }
+        let files = processor.finalize().unwrap();
+        assert_eq!(files.len(), 3);
+
+        let mut concatenated = Vec::new();
+        for (i, path) in files.iter().enumerate() {
+            let mut reader = hound::WavReader::open(path).unwrap();
+            assert_eq!(reader.duration(), if i < 2 { 1000 } else { 500 });
+            concatenated.extend(reader.samples::<i16>().map(|s| s.unwrap() as i32));
+        }
+        assert_eq!(concatenated, input);
+    }
+
+    #[test]
+    fn test_circular_segments_keep_the_newest() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let spec = wav_spec(1, 8000);
+
+        let mut index = 0;
+        let open_next: SegmentFactory = Box::new(move || {
+            index += 1;
+            let path = dir.join(format!("segment-{}.wav", index));
+            Ok(Box::new(WavProcessor::create(path.to_str().unwrap(), spec, 8192)?) as Box<dyn AudioProcessor>)
+        });
+        let mut processor = RotatingProcessor::new(1, 100, open_next).unwrap().with_circular_segments(3);
+
+        // Seven files of 100 frames, each holding its own number
+        for file in 1..=7 {
+            processor.write_samples(&[file; 100]).unwrap();
+            assert!(std::fs::read_dir(temp_dir.path()).unwrap().count() <= 3);
+        }
+        let files = processor.finalize().unwrap();
+        let names: Vec<_> = files.iter().map(|f| Path::new(f).file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["segment-5.wav", "segment-6.wav", "segment-7.wav"]);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+        for (file, expected) in files.iter().zip(5..) {
+            let samples: Vec<i16> = hound::WavReader::open(file).unwrap().samples().map(Result::unwrap).collect();
+            assert_eq!(samples, vec![expected; 100]);
+        }
+    }
+
+    #[test]
+    fn test_shortened_first_file() {
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let opened = Arc::clone(&lengths);
+        let open_next: SegmentFactory = Box::new(move || {
+            opened.lock().unwrap().push(0);
+            Ok(Box::new(CountFrames(Arc::clone(&opened))) as Box<dyn AudioProcessor>)
+        });
+        let mut processor = RotatingProcessor::new(1, 100, open_next).unwrap().with_first_file_frames(30);
+        for batch in vec![0; 250].chunks(40) {
+            processor.write_samples(batch).unwrap();
+        }
+        processor.finalize().unwrap();
+        assert_eq!(*lengths.lock().unwrap(), vec![30, 100, 100, 20]);
+    }
+
+    #[test]
+    fn test_fade_ramps_in_and_out() {
+        let out = SharedBuffer::default();
+        let mut processor = FadeProcessor::new(Box::new(StdoutProcessor::new(out.clone())), 2, 10);
+        for _ in 0..5 {
+            processor.write_samples(&[10000; 20]).unwrap();
+        }
+        processor.finalize().unwrap();
+        let samples: Vec<i16> = out.0.lock().unwrap().chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+        assert_eq!(samples.len(), 100);
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+        assert_eq!(left[0], 0);
+        assert_eq!(left[5], 5000);
+        assert!(left[..10].windows(2).all(|w| w[0] < w[1]));
+        assert!(left[10..40].iter().all(|&s| s == 10000));
+        assert!(left[40..].windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(left[49], 0);
+    }
+
+    #[test]
+    fn test_flushed_samples_are_readable_before_finalize() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("flushed.wav");
+        let spec = wav_spec(2, 48000);
+        let mut processor = WavProcessor::create(path.to_str().unwrap(), spec, 1 << 20).unwrap()
+            .with_flush_interval(100);
+
+        // 250 frames in batches of 50: flushed after 100 and 200
+        let input: Vec<i32> = (0..500).collect();
+        for batch in input.chunks(100) {
+            processor.write_samples(batch).unwrap();
+        }
+
+        // Read while still recording, as a crash would leave it
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), 200);
+        let on_disk: Vec<i32> = reader.samples::<i16>().map(|s| s.unwrap() as i32).collect();
+        assert_eq!(on_disk, input[..400]);
+
+        processor.finalize().unwrap();
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 250);
+    }
+
+    #[test]
+    fn test_split_processor_deinterleaves() {
+        let temp_dir = tempdir().unwrap();
+        let paths: Vec<String> = (0..3)
+            .map(|ch| temp_dir.path().join(format!("split-ch{}.wav", ch)).to_str().unwrap().to_string())
+            .collect();
+
+        let mut processor = SplitProcessor::create(&paths, 48000, 16, 8192).unwrap();
+        processor.write_samples(&[1, 2, 3, 11, 12, 13]).unwrap();
+        processor.write_samples(&[21, 22, 23]).unwrap();
+        assert_eq!(processor.finalize().unwrap(), paths);
+
+        for (ch, path) in paths.iter().enumerate() {
+            let mut reader = hound::WavReader::open(path).unwrap();
+            assert_eq!(reader.spec().channels, 1);
+            let samples: Vec<i32> = reader.samples::<i16>().map(|s| s.unwrap() as i32).collect();
+            let ch = ch as i32;
+            assert_eq!(samples, vec![ch + 1, ch + 11, ch + 21]);
+        }
+    }
+}
@@ -0,0 +1,2810 @@
+//! Setting up and running a recording from an input device.
+
+use chrono::prelude::*;
+use chrono::TimeDelta;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{ActiveStream, AudioBackend, CpalBackend, InputDevice, InputSelector};
+use crate::capture::{self, Capture, DeadChannelMonitor, DriftMonitor};
+use crate::checksum;
+use crate::clip::ClipProcessor;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, AudioSource, CaptureMode, ChannelMismatch, Config, ControlAction, FadeScope, FileCompression, Location, OutputFormat, OutputMode, SplitNaming, TimestampZone};
+use crate::disk;
+use crate::dither::Dither;
+use crate::encode;
+use crate::envelope::{self, EnvelopeProcessor};
+use crate::error::BlackboxError;
+use crate::events::{EventHook, EventLog};
+#[cfg(unix)]
+use crate::fifo::FifoProcessor;
+use crate::loudness;
+use crate::metadata;
+use crate::post;
+use crate::processor::{AudioProcessor, FadeProcessor, FileFrames, MixProcessor, MonoToStereoProcessor, RotatingProcessor, SegmentFactory, SplitProcessor, StdoutProcessor, WavProcessor};
+use crate::repair;
+use crate::retention;
+use crate::silence::{self, SilencePolicy};
+use crate::status::{RecordingStatus, SessionStats};
+use crate::sync::{self, SyncToneProcessor};
+use crate::timestamps::{self, TimestampProcessor};
+use crate::tone::ToneBackend;
+
+/// The longest `WAIT_FOR_DEVICE` waits between looking for the device.
+const MAX_DEVICE_POLL_DELAY: Duration = Duration::from_secs(30);
+
+/// Creates the files of one segment, as laid out by a `SegmentPlan`.
+type OpenSegment = Arc<dyn Fn(&SegmentPlan, &dyn Clock) -> Result<Box<dyn AudioProcessor>, BlackboxError> + Send + Sync>;
+
+/// Records the configured channels of the default input device, or of each
+/// device listed in `INPUT_DEVICES`.
+///
+/// A recording runs from `start_recording` until `stop_recording`, which
+/// returns the paths of the files written. `record_for` and `record_until`
+/// wrap the two for the common cases.
+pub struct AudioRecorder {
+    config: Config,
+    clock: Arc<dyn Clock>,
+    session: Option<Session>,
+    /// Set when a new file couldn't be created even after retrying.
+    write_failed: Arc<AtomicBool>,
+    /// Set when a key bound to `stop` is pressed.
+    stop_requested: Arc<AtomicBool>,
+    /// Set when a key bound to `start` is pressed.
+    start_requested: Arc<AtomicBool>,
+    /// The captures of the current session, which markers are added to.
+    marker_targets: Arc<Mutex<Vec<Weak<Mutex<Capture>>>>>,
+    /// Set when writes failed more than `MAX_WRITE_ERRORS` times.
+    recording_aborted: Arc<AtomicBool>,
+    events: EventLog,
+    /// `POST_COMMAND` runs still going in the background.
+    post_commands: Vec<thread::JoinHandle<()>>,
+    /// The last recording's `COMPRESS_FINALIZED` and what follows it, if
+    /// still going in the background.
+    compression: Option<thread::JoinHandle<()>>,
+    /// The default backend (see `default_backend`) unless set.
+    backend: Option<Box<dyn AudioBackend>>,
+    /// Totals over the recordings finished so far.
+    stats: SessionStats,
+    /// `SegmentPlan::open` unless set.
+    open_segment: OpenSegment,
+}
+
+/// The live parts of a recording in progress.
+struct Session {
+    // Declared first so the input streams stop before the monitor
+    devices: Vec<DeviceSession>,
+    _monitor_stream: Option<Box<dyn ActiveStream>>,
+    /// Where the recording is being made, for the INFO tags.
+    location: Option<Location>,
+    started: Instant,
+    /// How long the recording is due to last, if known.
+    duration: Option<Duration>,
+    /// The devices are open but nothing is being written yet.
+    armed: bool,
+}
+
+/// One input device's stream and the files it is writing.
+struct DeviceSession {
+    // Declared before `capture` so the stream stops before we finalize
+    stream: Box<dyn ActiveStream>,
+    capture: Arc<Mutex<Capture>>,
+    device_name: String,
+    /// Set when recording several devices, to tell their files apart.
+    tag: Option<String>,
+    sample_rate: u32,
+    channels: Vec<usize>,
+    /// The label of each of `channels`, or an empty string.
+    labels: Vec<String>,
+    /// Empty while armed.
+    output: DeviceOutput,
+}
+
+/// What a device is writing to, and how its files are laid out.
+#[derive(Default)]
+struct DeviceOutput {
+    /// Handed to the device's `Capture` once it is made.
+    processor: Option<Box<dyn AudioProcessor>>,
+    /// How many files each segment of a rotating recording is made of.
+    files_per_segment: usize,
+    /// The files being written now.
+    current_files: Arc<Mutex<Vec<String>>>,
+    /// How long each file is when rotating.
+    file_lengths: Option<FileLengths>,
+    /// The length of the `SYNC_TONE` written ahead of the audio in each file.
+    tone_frames: u64,
+}
+
+impl AudioRecorder {
+    /// Touches neither devices nor files: the output files are only created
+    /// by `start_recording`, once the devices' channels are known.
+    pub fn new(config: Config) -> Self {
+        AudioRecorder {
+            clock: Arc::new(SystemClock),
+            session: None,
+            write_failed: Arc::new(AtomicBool::new(false)),
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            start_requested: Arc::new(AtomicBool::new(false)),
+            marker_targets: Arc::new(Mutex::new(Vec::new())),
+            recording_aborted: Arc::new(AtomicBool::new(false)),
+            events: match config.event_log {
+                true => EventLog::new(config.output_dir.join("events.jsonl")),
+                false => EventLog::default(),
+            },
+            config,
+            post_commands: Vec::new(),
+            compression: None,
+            backend: None,
+            stats: SessionStats::default(),
+            open_segment: Arc::new(|plan: &SegmentPlan, clock: &dyn Clock| plan.open(clock)),
+        }
+    }
+
+    /// Records from `backend` instead of a cpal host.
+    #[cfg(test)]
+    fn with_backend(mut self, backend: Box<dyn AudioBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Names files and lines up rotations by `clock` instead of the system
+    /// time.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Creates each segment's files with `open_segment` instead, e.g. to
+    /// make some of them fail.
+    #[cfg(test)]
+    fn with_segment_opener(mut self, open_segment: OpenSegment) -> Self {
+        self.open_segment = open_segment;
+        self
+    }
+
+    /// Delivers file lifecycle events to `hook` as well as `EVENT_LOG`.
+    pub fn with_event_hook(mut self, hook: Arc<dyn EventHook>) -> Self {
+        self.events = self.events.with_hook(hook);
+        self
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| !session.armed)
+    }
+
+    /// Whether a file couldn't be created during the recording, so audio is
+    /// being lost.
+    pub fn write_failed(&self) -> bool {
+        self.write_failed.load(Ordering::Relaxed)
+    }
+
+    /// Whether writes to the output failed more than `MAX_WRITE_ERRORS` times,
+    /// so the recording was stopped.
+    pub fn recording_aborted(&self) -> bool {
+        self.recording_aborted.load(Ordering::Relaxed)
+    }
+
+    /// Whether a key bound to `stop` in `CONTROL_KEYS` has been pressed.
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::Relaxed)
+    }
+
+    /// Whether a key bound to `start` in `CONTROL_KEYS` has been pressed
+    /// since the recorder was armed.
+    pub fn start_requested(&self) -> bool {
+        self.start_requested.load(Ordering::Relaxed)
+    }
+
+    /// Whether a key bound to `start` has been pressed since the last call.
+    /// However many presses arrive in between, this returns true once.
+    pub fn take_start_request(&self) -> bool {
+        self.start_requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Waits for every `POST_COMMAND` started so far to finish, and for
+    /// `COMPRESS_FINALIZED` and everything after it to finish with the last
+    /// recording.
+    pub fn wait_for_post_commands(&mut self) {
+        for handle in self.post_commands.drain(..).chain(self.compression.take()) {
+            let _ = handle.join();
+        }
+    }
+
+    /// Opens the input devices and starts writing to new files (or stdout),
+    /// or starts writing from the devices already opened by `arm`.
+    pub fn start_recording(&mut self) -> Result<(), BlackboxError> {
+        match self.session {
+            Some(ref session) if session.armed => self.start_armed_session(),
+            Some(_) => Err(BlackboxError::Stream("Recording is already in progress".to_string())),
+            None => self.open_session(false),
+        }
+    }
+
+    /// Starts recording in response to a start command. Unlike
+    /// `start_recording`, a command while already recording isn't an error:
+    /// it is ignored or, with `RESTART_ON_START`, finishes the recording and
+    /// starts a new one. Commands reach the recorder through whoever owns it,
+    /// so however fast they arrive they are handled one at a time. If the new
+    /// recording can't be started, the finished files are reported (as event
+    /// hooks have already seen them) before the error is returned.
+    pub fn handle_start(&mut self) -> Result<StartOutcome, BlackboxError> {
+        match self.session {
+            Some(ref session) if !session.armed => {
+                if !self.config.restart_on_start {
+                    status!("Already recording; ignoring the start command");
+                    return Ok(StartOutcome::AlreadyRecording);
+                }
+                let files = self.stop_recording()?;
+                if let Err(e) = self.open_session(false) {
+                    for file_name in &files {
+                        status!("Recording saved to {}", file_name);
+                    }
+                    return Err(e);
+                }
+                status!("Restarted the recording");
+                Ok(StartOutcome::Restarted(files))
+            },
+            _ => {
+                self.start_recording()?;
+                Ok(StartOutcome::Started)
+            },
+        }
+    }
+
+    /// Opens the input devices and starts metering and monitoring them
+    /// without writing anything, until `start_recording` is called.
+    pub fn arm(&mut self) -> Result<(), BlackboxError> {
+        if self.session.is_some() {
+            return Err(BlackboxError::Stream("Recording is already in progress".to_string()));
+        }
+        self.open_session(true)
+    }
+
+    /// Whether the devices are open and metering, waiting for `start_recording`.
+    pub fn is_armed(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| session.armed)
+    }
+
+    /// Opens the outputs of an armed session and starts writing to them.
+    fn start_armed_session(&mut self) -> Result<(), BlackboxError> {
+        let mut session = self.session.take().expect("an armed session");
+        for device in &mut session.devices {
+            let output = self.open_output(&device.device_name, &device.channels, &device.labels, &device.tag,
+                                          device.sample_rate);
+            let mut output = match output {
+                Ok(output) => output,
+                Err(e) => {
+                    self.session = Some(session);
+                    return Err(e);
+                },
+            };
+            let started = match output.processor.take() {
+                Some(processor) => device.capture.lock().unwrap().start_writing(processor),
+                None => Ok(()),
+            };
+            device.output = output;
+            if let Err(e) = started {
+                self.session = Some(session);
+                return Err(e);
+            }
+        }
+        session.armed = false;
+        session.started = Instant::now();
+        self.session = Some(session);
+        Ok(())
+    }
+
+    fn open_session(&mut self, armed: bool) -> Result<(), BlackboxError> {
+        self.write_failed.store(false, Ordering::Relaxed);
+        self.stop_requested.store(false, Ordering::Relaxed);
+        self.start_requested.store(false, Ordering::Relaxed);
+        self.recording_aborted.store(false, Ordering::Relaxed);
+        if self.config.output_mode != OutputMode::Stdout {
+            disk::check_output_writable(&self.config.output_dir)?;
+            if self.config.auto_repair {
+                for path in repair::repair_directory(&self.config.output_dir)? {
+                    status!("Repaired unfinished recording {}", path.display());
+                    self.events.log("repaired", &path.to_string_lossy(), "");
+                }
+            }
+        }
+
+        let default_backend;
+        let backend: &dyn AudioBackend = match self.backend {
+            Some(ref backend) => backend.as_ref(),
+            None => {
+                default_backend = self.default_backend()?;
+                default_backend.as_ref()
+            },
+        };
+
+        if !self.config.input_devices.is_empty() {
+            if self.config.output_mode == OutputMode::Stdout {
+                return Err(BlackboxError::Config("Only one input device can be streamed to stdout".to_string()));
+            }
+            if self.config.fifo_path.is_some() && self.config.input_devices.len() > 1 {
+                return Err(BlackboxError::Config("Only one input device can be streamed to FIFO_PATH".to_string()));
+            }
+        }
+        let mut targets = Vec::new();
+        for (selector, channels) in input_selectors(&self.config) {
+            let input = self.open_input(backend, &selector)?;
+            let channels = channels.unwrap_or_else(|| {
+                if self.config.all_channels {
+                    status!("Recording all channels of {}: {}", input.name(), config::all_channels(input.channels()));
+                }
+                self.config.channels_for(input.channels())
+            });
+            // Only INPUT_DEVICES are named, and their files need telling apart
+            let tag = match selector {
+                InputSelector::Named(ref name) => Some(file_tag(name)),
+                _ => None,
+            };
+            targets.push((input, channels, tag));
+        }
+
+        let mut devices = Vec::new();
+        let mut monitor_stream = None;
+        for (i, (input, channels, tag)) in targets.into_iter().enumerate() {
+            // Only the first device is monitored
+            let (device_session, stream) = self.start_device(backend, input, channels, tag, i == 0, armed)?;
+            devices.push(device_session);
+            if stream.is_some() {
+                monitor_stream = stream;
+            }
+        }
+
+        *self.marker_targets.lock().unwrap() = devices.iter().map(|device| Arc::downgrade(&device.capture)).collect();
+        self.session = Some(Session {
+            devices,
+            _monitor_stream: monitor_stream,
+            location: self.current_location(),
+            started: Instant::now(),
+            duration: None,
+            armed,
+        });
+        Ok(())
+    }
+
+    /// The name of each channel of the input that would be recorded, or the
+    /// first of `INPUT_DEVICES`, for choosing `AUDIO_CHANNELS`. Channels the
+    /// platform doesn't name are called `Channel {n}`.
+    pub fn device_channel_labels(&self) -> Result<Vec<String>, BlackboxError> {
+        let (selector, _) = input_selectors(&self.config).swap_remove(0);
+        let input = match self.backend {
+            Some(ref backend) => backend.open_input(&selector)?,
+            None => self.default_backend()?.open_input(&selector)?,
+        };
+        Ok(input.channel_labels())
+    }
+
+    /// The cpal host named by `AUDIO_HOST`, or a test tone with `SOURCE=tone`.
+    fn default_backend(&self) -> Result<Box<dyn AudioBackend>, BlackboxError> {
+        default_backend(&self.config)
+    }
+
+    /// `LOCATION`, or the location read from `LOCATION_FILE`. A file that
+    /// can't be read or parsed is reported and the recording goes ahead
+    /// without a location.
+    fn current_location(&self) -> Option<Location> {
+        let path = match self.config.location_file {
+            Some(ref path) => path,
+            None => return self.config.location,
+        };
+        let location = std::fs::read_to_string(path).map_err(BlackboxError::from)
+            .and_then(|text| config::parse_location(text.trim()));
+        match location {
+            Ok(location) => Some(location),
+            Err(e) => {
+                eprintln!("Recording without a location: can't read {}: {}", path.display(), e);
+                None
+            },
+        }
+    }
+
+    /// Opens the input `selector` picks or, with `WAIT_FOR_DEVICE`, keeps
+    /// polling for it until it appears, backing off up to
+    /// `MAX_DEVICE_POLL_DELAY` between polls.
+    fn open_input(&self, backend: &dyn AudioBackend, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError> {
+        let mut delay = Duration::from_millis(self.config.device_poll_ms);
+        loop {
+            match backend.open_input(selector) {
+                Err(BlackboxError::DeviceNotFound(msg)) if self.config.wait_for_device => {
+                    status!("{}; checking again in {:?}", msg, delay);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_DEVICE_POLL_DELAY);
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Starts recording `channels` of `input`, or only metering them if
+    /// `armed`, returning its session and, if `monitor` is set and monitoring
+    /// is configured, the monitor stream.
+    fn start_device(&self, backend: &dyn AudioBackend, mut input: Box<dyn InputDevice>, channels: Vec<usize>,
+                    tag: Option<String>, monitor: bool, armed: bool)
+                    -> Result<(DeviceSession, Option<Box<dyn ActiveStream>>), BlackboxError> {
+        let device_name = input.name();
+        status!("Using audio device: {}", device_name);
+
+        if let Some(sample_rate) = self.config.sample_rate {
+            input.use_sample_rate(sample_rate)?;
+        }
+        input.cover_channels(channels.iter().max().map_or(0, |&channel| channel + 1));
+        if !self.config.preferred_formats.is_empty() {
+            input.prefer_formats(&self.config.preferred_formats);
+        }
+        let sample_rate = input.sample_rate();
+        let total_channels = input.channels();
+        check_has_inputs(&device_name, total_channels)?;
+
+        // Labels follow AUDIO_CHANNELS, so they only apply to a single device
+        let configured_labels: &[String] = if tag.is_none() { &self.config.channel_labels } else { &[] };
+        let kept = resolve_channels(&channels, total_channels, self.config.on_channel_mismatch, &device_name)?;
+        let labels: Vec<String> = kept.iter()
+            .map(|&channel| {
+                let position = channels.iter().position(|&c| c == channel);
+                position.and_then(|i| configured_labels.get(i)).cloned().unwrap_or_default()
+            })
+            .collect();
+        let channels = kept;
+
+        let mut output = match armed {
+            true => DeviceOutput::default(),
+            false => self.open_output(&device_name, &channels, &labels, &tag, sample_rate)?,
+        };
+        let mut capture = match output.processor.take() {
+            Some(processor) => Capture::new(&channels, total_channels, processor, self.config.debug),
+            None => Capture::armed(&channels, total_channels, self.config.debug),
+        }
+            .with_batch_samples(self.config.batch_samples)
+            .with_max_write_errors(self.config.max_write_errors, Arc::clone(&self.recording_aborted))
+            .with_warmup_discard(self.config.warmup_discard_ms * sample_rate as u64 / 1000)
+            .with_realtime_priority(self.config.realtime_priority)
+            .with_bits_per_sample(self.config.bits_per_sample())
+            .with_writer_thread(writer_queue_batches(sample_rate, channels.len(), self.config.batch_samples))?;
+        capture.dead_monitor = DeadChannelMonitor::new(&channels, self.config.dead_channel_timeout, sample_rate);
+        capture.drift_monitor = DriftMonitor::new(sample_rate, self.config.drift_threshold_ms, self.config.resync);
+        if self.config.dither {
+            let seed = self.clock.now().timestamp_nanos_opt().unwrap_or_default() as u64;
+            capture.dither = Some(Dither::new(seed));
+        }
+
+        // Keep the monitor stream alive for as long as we are recording
+        let monitor_stream = self.config.monitor_output.clone().filter(|_| monitor).and_then(|name| {
+            match backend.start_monitor(&name, sample_rate, channels.len()) {
+                Ok((stream, buffer)) => {
+                    status!("Monitoring through output device: {}", name);
+                    if let Some(ref downmix) = self.config.monitor_downmix {
+                        let groups = downmix.iter()
+                            .map(|group| group.iter()
+                                .filter_map(|&channel| {
+                                    let position = channels.iter().position(|&c| c == channel);
+                                    if position.is_none() {
+                                        eprintln!("Warning: monitor downmix channel {} isn't recorded from {}, leaving it out",
+                                                  channel, device_name);
+                                    }
+                                    position
+                                })
+                                .collect())
+                            .collect();
+                        buffer.lock().unwrap().set_downmix(groups);
+                    }
+                    capture.monitor = Some(buffer);
+                    Some(stream)
+                },
+                Err(e) => {
+                    eprintln!("Monitoring disabled: {}", e);
+                    None
+                },
+            }
+        });
+
+        let capture = Arc::new(Mutex::new(capture));
+
+        let stream = input.start(&capture)?;
+
+        let device_session = DeviceSession {
+            stream,
+            capture,
+            device_name,
+            tag,
+            sample_rate,
+            channels,
+            labels,
+            output,
+        };
+        Ok((device_session, monitor_stream))
+    }
+
+    /// Opens the output for `channels` of a device: the first file, or set of
+    /// split files, or stdout, behind whatever processing is configured.
+    fn open_output(&self, device_name: &str, channels: &[usize], labels: &[String], tag: &Option<String>,
+                   sample_rate: u32) -> Result<DeviceOutput, BlackboxError> {
+        let output_mode = self.config.output_mode;
+        let attempts = self.config.file_create_attempts.max(1);
+        let retry_delay = Duration::from_millis(self.config.file_create_retry_ms);
+        // DAILY_ROTATION cuts at midnight: on its own by rotating once a day,
+        // and with a ROTATE_SECONDS, which then divides a day, by aligning it
+        let file_lengths = match (self.config.daily_rotation, self.config.rotate_seconds) {
+            (true, 0) => Some(FileLengths::daily(self.clock.now(), self.config.timestamp_tz, sample_rate)),
+            (_, 0) => None,
+            (daily_rotation, seconds) => {
+                let every = seconds * sample_rate as u64;
+                let first = match self.config.rotate_align || daily_rotation {
+                    true => frames_to_boundary(self.clock.now(), self.config.timestamp_tz, seconds, sample_rate),
+                    false => every,
+                };
+                Some(FileLengths::Fixed { first, every })
+            },
+        };
+        let fade_frames = (self.config.fade_ms * sample_rate as u64 / 1000) as usize;
+        let fade_each_file = self.config.fade_scope == FadeScope::File && output_mode != OutputMode::Stdout;
+        let file_fade_frames = if fade_each_file { fade_frames } else { 0 };
+        let sync_tone = match self.config.sync_tone {
+            true => Some(Arc::new(sync::tone(sample_rate, self.config.sync_tone_hz, self.config.sync_tone_ms,
+                                               self.config.bits_per_sample()))),
+            false => None,
+        };
+        let sync_channel = self.config.sync_tone_channel.and_then(|channel| {
+            let position = channels.iter().position(|&c| c == channel);
+            if position.is_none() {
+                eprintln!("Warning: sync tone channel {} isn't recorded from {}, writing the tone on every channel",
+                          channel, device_name);
+            }
+            position
+        });
+        let monitor_mix = self.config.monitor_mix.as_ref().map(|mix| {
+            let positions = |side: &[usize]| -> Vec<usize> {
+                side.iter()
+                    .filter_map(|&channel| {
+                        let position = channels.iter().position(|&c| c == channel);
+                        if position.is_none() {
+                            eprintln!("Warning: monitor mix channel {} isn't recorded from {}, leaving it out",
+                                      channel, device_name);
+                        }
+                        position
+                    })
+                    .collect()
+            };
+            (positions(&mix.left), positions(&mix.right))
+        });
+        let plan = SegmentPlan {
+            dir: self.config.output_dir.clone(),
+            tag: tag.clone(),
+            output_mode,
+            channels: channels.to_vec(),
+            sample_rate,
+            bits_per_sample: self.config.bits_per_sample(),
+            buffer_bytes: self.config.write_buffer_bytes,
+            flush_frames: self.config.flush_interval_secs * sample_rate as u64,
+            timestamp_tz: self.config.timestamp_tz,
+            split_naming: self.config.split_naming,
+            split_index_width: self.config.split_index_width,
+            labels: labels.to_vec(),
+            mono_to_stereo: self.config.mono_to_stereo,
+            level_envelope: self.config.level_envelope,
+            clip_warn_percent: self.config.detect_clipping.then_some(self.config.clip_warn_percent),
+            timestamp_interval_frames: match self.config.timestamp_track {
+                true => self.config.timestamp_track_secs * sample_rate as u64,
+                false => 0,
+            },
+            monitor_mix,
+            current_files: Arc::new(Mutex::new(Vec::new())),
+            events: self.events.clone(),
+        };
+        let current_files = Arc::clone(&plan.current_files);
+        let files_per_segment = plan.files_per_segment();
+        let processor: Box<dyn AudioProcessor> = match (output_mode, file_lengths.clone()) {
+            (OutputMode::Stdout, _) => {
+                status!("Streaming {} Hz 16-bit {}-channel PCM to stdout", sample_rate, channels.len());
+                Box::new(StdoutProcessor::new(BufWriter::with_capacity(plan.buffer_bytes, io::stdout())))
+            },
+            (_, Some(lengths)) => {
+                let clock = Arc::clone(&self.clock);
+                let open_segment = Arc::clone(&self.open_segment);
+                let dir = self.config.output_dir.clone();
+                let max_recordings = self.config.max_recordings;
+                let write_failed = Arc::clone(&self.write_failed);
+                let events = self.events.clone();
+                let sync_tone = sync_tone.clone();
+                // Runs on the writer thread at each rotation, so the retries'
+                // sleeps and the pruning don't hold up the device
+                let open_next: SegmentFactory = Box::new(move || {
+                    // Make room for the files about to be created
+                    if let Some(max) = max_recordings {
+                        prune_old_recordings(&dir, max.saturating_sub(plan.files_per_segment()), &plan.events);
+                    }
+                    let segment = disk::retry_with_backoff(attempts, retry_delay, "create the next file", || {
+                        open_segment(&plan, clock.as_ref())
+                    });
+                    match segment {
+                        Ok(segment) => {
+                            let segment = with_sync_tone(segment, plan.channels.len(), &sync_tone, sync_channel);
+                            Ok(with_fade(segment, plan.channels.len(), file_fade_frames))
+                        },
+                        Err(e) => {
+                            write_failed.store(true, Ordering::Relaxed);
+                            plan.events.failed("", "create the next file", &e);
+                            Err(e)
+                        },
+                    }
+                });
+                let (_, first_file_frames) = lengths.segment(0);
+                let mut segment = 0;
+                let file_frames: FileFrames = Box::new(move || {
+                    segment += 1;
+                    lengths.segment(segment).1
+                });
+                let rotating = RotatingProcessor::new(channels.len(), first_file_frames, open_next)?
+                    .with_first_file_frames(first_file_frames)
+                    .with_file_frames(file_frames)
+                    .with_events(events);
+                match self.config.circular_files {
+                    Some(files) => Box::new(rotating.with_circular_segments(files)),
+                    None => Box::new(rotating),
+                }
+            },
+            _ => {
+                let segment = disk::retry_with_backoff(attempts, retry_delay, "create the output file", || {
+                    (self.open_segment)(&plan, self.clock.as_ref())
+                });
+                let segment = segment.inspect_err(|e| self.events.failed("", "create the output file", e))?;
+                let segment = with_sync_tone(segment, channels.len(), &sync_tone, sync_channel);
+                with_fade(segment, channels.len(), file_fade_frames)
+            },
+        };
+        let processor = if fade_each_file { processor } else { with_fade(processor, channels.len(), fade_frames) };
+        #[cfg(unix)]
+        let processor: Box<dyn AudioProcessor> = match self.config.fifo_path {
+            Some(ref path) => Box::new(FifoProcessor::new(processor, path, channels.len() as u16, sample_rate,
+                                                                 self.config.bits_per_sample())),
+            None => processor,
+        };
+        let tone_frames = match output_mode {
+            OutputMode::Stdout => 0,
+            _ => sync_tone.map_or(0, |tone| tone.len() as u64),
+        };
+        Ok(DeviceOutput {
+            processor: Some(processor),
+            files_per_segment,
+            current_files,
+            file_lengths,
+            tone_frames,
+        })
+    }
+
+    /// Stops the streams, finalizes the output, and returns the files written.
+    /// Does nothing if no recording is in progress. With `COMPRESS_FINALIZED`
+    /// the files are compressed, checksummed, backed up and so on in the
+    /// background, and the paths returned are the ones they'll end up at;
+    /// `wait_for_post_commands` waits for that to finish.
+    pub fn stop_recording(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(Vec::new()),
+        };
+
+        if !session.armed {
+            self.stats.recordings += 1;
+            self.stats.duration += session.started.elapsed();
+        }
+        let mut files = Vec::new();
+        let mut policy = SilencePolicy {
+            threshold: self.config.silence_threshold,
+            channel_thresholds: self.config.channel_thresholds.clone(),
+            window_secs: self.config.silence_check_seconds,
+            action: self.config.silence_action,
+            split_policy: self.config.split_silence_policy,
+            ..Default::default()
+        };
+        let mut stats = SessionStats::default();
+        for device in session.devices {
+            files.extend(self.finish_device(device, session.location, &mut policy, &mut stats)?);
+        }
+        self.stats.frames += stats.frames;
+        self.stats.write_errors += stats.write_errors;
+
+        if self.config.verify_on_finalize && self.config.output_mode != OutputMode::Stdout {
+            files = repair::verify_finalized_files(files, &self.events);
+        }
+
+        if self.config.measure_loudness {
+            for file_name in &files {
+                match loudness::measure_file(file_name) {
+                    Ok(Some(lufs)) => status!("Integrated loudness of {}: {:.1} LUFS", file_name, lufs),
+                    Ok(None) => status!("Integrated loudness of {}: below the -70 LUFS gate", file_name),
+                    Err(e) => eprintln!("Failed to measure loudness of {}: {}", file_name, e),
+                }
+            }
+        }
+
+        if let Some(min_secs) = self.config.min_recording_secs {
+            files = silence::delete_short_files(files, min_secs, &self.events);
+        }
+
+        let mut files = if policy.is_enabled() {
+            let checked = files.len();
+            let kept = silence::check_and_delete_silent_files(files, &policy, &self.events);
+            self.stats.deleted_silent += checked - kept.len();
+            kept
+        } else {
+            files
+        };
+
+        // Proxies are encoded from the WAV before it can be replaced by WavPack
+        let mut proxies = Vec::new();
+        if let Some(format) = &self.config.proxy_format {
+            for file in &files {
+                match encode::encode_proxy(file, format, self.config.proxy_bitrate, &self.config.proxy_command) {
+                    Ok(proxy) => {
+                        self.events.log("proxy", &proxy, &format!("from {}", file));
+                        proxies.push(proxy);
+                    },
+                    Err(e) => eprintln!("No {} proxy for {}: {}", format, file, e),
+                }
+            }
+        }
+
+        if self.config.output_format == OutputFormat::WavPack {
+            files = files.into_iter().map(|file| {
+                match encode::compress_wavpack(&file, &self.config.wavpack_command) {
+                    Ok(compressed) => {
+                        envelope::move_envelope(Path::new(&file), Path::new(&compressed));
+                        timestamps::move_timestamps(Path::new(&file), Path::new(&compressed));
+                        self.events.log("compressed", &compressed, &format!("from {}", file));
+                        compressed
+                    },
+                    Err(e) => {
+                        eprintln!("Keeping {} as WAV: {}", file, e);
+                        file
+                    },
+                }
+            }).collect();
+        }
+
+        // Compressing a long recording takes a while, so it's done in the
+        // background along with everything that has to follow it. Each
+        // recording's waits for the last's, so they never overlap.
+        let steps = FinishingSteps::from_config(&self.config);
+        if steps.compression != FileCompression::None {
+            let returned: Vec<String> = steps.compressed_names(&files).into_iter().chain(proxies.iter().cloned()).collect();
+            self.stats.files += returned.len();
+            self.stats.bytes += files.iter().chain(&proxies)
+                .filter_map(|file| std::fs::metadata(file).ok()).map(|m| m.len()).sum::<u64>();
+            let previous = self.compression.take();
+            let events = self.events.clone();
+            self.compression = post::spawn_or_run(encode::COMPRESS_THREAD_NAME, move || {
+                if let Some(previous) = previous {
+                    let _ = previous.join();
+                }
+                let mut files = steps.compress(files, &events);
+                files.extend(proxies);
+                steps.finish(&mut files, &events);
+                steps.run_post_command(&files, &events);
+            });
+            return Ok(returned);
+        }
+
+        files.extend(proxies);
+        steps.finish(&mut files, &self.events);
+        if steps.post_command.is_some() {
+            self.post_commands.retain(|handle| !handle.is_finished());
+            let post_files = files.clone();
+            let events = self.events.clone();
+            let handle = post::spawn_or_run(post::POST_THREAD_NAME, move || steps.run_post_command(&post_files, &events));
+            self.post_commands.extend(handle);
+        }
+
+        self.stats.files += files.len();
+        self.stats.bytes += files.iter().filter_map(|file| std::fs::metadata(file).ok()).map(|m| m.len()).sum::<u64>();
+        Ok(files)
+    }
+
+    /// Totals over every recording finished so far, for a summary on
+    /// shutdown.
+    pub fn session_stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// Stops one device's stream, finalizes its files, and adds their markers
+    /// and tags. The channel and segment of each split file are added to
+    /// `policy`, and its frames and write errors to `stats`.
+    fn finish_device(&self, device: DeviceSession, location: Option<Location>, policy: &mut SilencePolicy,
+                     stats: &mut SessionStats) -> Result<Vec<String>, BlackboxError> {
+        drop(device.stream);
+
+        let mut capture = device.capture.lock().unwrap();
+        let files = capture.finish().inspect_err(|e| self.events.failed("", "finalize", e))?;
+        stats.frames = stats.frames.max(capture.frames());
+        stats.write_errors += capture.write_errors();
+        // With CIRCULAR_FILES the earliest segments are gone, so count back
+        // from the last to find where the files start
+        let first_segment = match device.output.file_lengths {
+            None => 0,
+            Some(ref lengths) => {
+                let segments = lengths.segments(capture.frames());
+                segments.saturating_sub((files.len() / device.output.files_per_segment.max(1)) as u64)
+            },
+        };
+
+        for (i, file_name) in files.iter().enumerate() {
+            self.events.log("finalized", file_name, "");
+            // Each segment of a split recording holds one file per channel, in order
+            let split_channel = match self.config.output_mode {
+                OutputMode::Split => device.channels.get(i % device.output.files_per_segment).copied(),
+                _ => None,
+            };
+            if let Some(channel) = split_channel {
+                policy.file_channels.insert(file_name.clone(), channel);
+            }
+            if self.config.output_mode == OutputMode::Split {
+                let first = i - i % device.output.files_per_segment;
+                policy.file_groups.insert(file_name.clone(), files[first].clone());
+            }
+            let (start_frame, markers) = match device.output.file_lengths {
+                None => (0, capture.markers.clone()),
+                Some(ref lengths) => {
+                    let (start, len) = lengths.segment(first_segment + (i / device.output.files_per_segment) as u64);
+                    (start, metadata::markers_in_range(&capture.markers, start, len))
+                },
+            };
+            // The sync tone comes before the recorded audio in each file
+            let tone_frames = device.output.tone_frames;
+            let markers: Vec<metadata::Marker> = markers.into_iter()
+                .map(|marker| metadata::Marker { position: marker.position + tone_frames, ..marker })
+                .collect();
+            if let Err(e) = metadata::write_cue_chunks(file_name, &markers) {
+                eprintln!("Failed to write markers: {}", e);
+            }
+
+            // So the file starts that long before its first recorded frame
+            let file_start_frame = start_frame as i128 - tone_frames as i128;
+            let start_time = capture.start_time()
+                .filter(|_| self.config.sample_accurate_start || self.config.write_bext)
+                .map(|start| start + TimeDelta::nanoseconds((file_start_frame * 1_000_000_000
+                                                            / device.sample_rate.max(1) as i128) as i64));
+            if let Some(start) = start_time {
+                if self.config.sample_accurate_start {
+                    if let Err(e) = timestamps::write_start(Path::new(file_name), start, device.sample_rate) {
+                        eprintln!("Failed to write the start time of {}: {}", file_name, e);
+                    }
+                }
+                if self.config.write_bext {
+                    if let Err(e) = metadata::write_bext_chunk(file_name, &device.device_name, start, device.sample_rate) {
+                        eprintln!("Failed to write the bext chunk: {}", e);
+                    }
+                }
+            }
+
+            if self.config.write_info_tags || location.is_some() {
+                let channels = match split_channel {
+                    Some(channel) => vec![channel],
+                    None => device.channels.clone(),
+                };
+                let mut comment = describe_channels(&channels, &device.channels, &device.labels);
+                if let Some(location) = location {
+                    comment.push_str(&format!("; location {}", location));
+                }
+                let tags = metadata::InfoTags {
+                    name: device.device_name.clone(),
+                    comment,
+                    software: crate::version_string(),
+                };
+                if let Err(e) = metadata::write_info_chunk(file_name, &tags) {
+                    eprintln!("Failed to write INFO tags: {}", e);
+                }
+            }
+        }
+
+        let dead_channels = capture.dead_monitor.dead_channels();
+        if !dead_channels.is_empty() {
+            eprintln!("Warning: channels {:?} of {} had no signal at the end of the recording",
+                      dead_channels, device.device_name);
+        }
+
+        let write_errors = capture.write_errors();
+        if write_errors > 0 {
+            eprintln!("Warning: {} write(s) from {} failed, so audio is missing from the recording",
+                      write_errors, device.device_name);
+            self.events.log("write errors", files.first().map(String::as_str).unwrap_or_default(),
+                            &format!("{}: {} write(s)", device.device_name, write_errors));
+        }
+
+        let bad_samples = capture.bad_samples();
+        if bad_samples > 0 {
+            eprintln!("Warning: {} delivered {} NaN or infinite sample(s), recorded as silence",
+                      device.device_name, bad_samples);
+            self.events.log("bad samples", files.first().map(String::as_str).unwrap_or_default(),
+                            &format!("{}: {} sample(s)", device.device_name, bad_samples));
+        }
+
+        let xruns = capture.drift_monitor.xruns();
+        if xruns > 0 {
+            eprintln!("Warning: {} drifted from the clock {} time(s) during the recording{}", device.device_name, xruns,
+                      if self.config.resync { " and was resynced" } else { "" });
+            self.events.log("drift", files.first().map(String::as_str).unwrap_or_default(),
+                            &format!("{}: {} xrun(s)", device.device_name, xruns));
+        }
+
+        Ok(files)
+    }
+
+    /// Records for `duration` and returns the files written.
+    ///
+    /// ```no_run
+    /// use audio_recorder::{AudioRecorder, Config};
+    /// use std::time::Duration;
+    ///
+    /// let mut recorder = AudioRecorder::new(Config::load()?);
+    /// for path in recorder.record_for(Duration::from_secs(5))? {
+    ///     println!("Wrote {}", path);
+    /// }
+    /// # Ok::<(), audio_recorder::BlackboxError>(())
+    /// ```
+    pub fn record_for(&mut self, duration: Duration) -> Result<Vec<String>, BlackboxError> {
+        self.start_recording()?;
+        self.set_planned_duration(duration);
+        thread::sleep(duration);
+        self.stop_recording()
+    }
+
+    /// Records until `stop` is set, then returns the files written.
+    pub fn record_until(&mut self, stop: Arc<AtomicBool>) -> Result<Vec<String>, BlackboxError> {
+        self.start_recording()?;
+        while !stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        self.stop_recording()
+    }
+
+    /// Sets how long the current recording is due to last, for `status`.
+    pub fn set_planned_duration(&mut self, duration: Duration) {
+        if let Some(ref mut session) = self.session {
+            session.duration = Some(duration);
+        }
+    }
+
+    /// Where the current recording has got to, or `None` if there isn't one.
+    pub fn status(&self) -> Option<RecordingStatus> {
+        let session = self.session.as_ref().filter(|session| !session.armed)?;
+        let elapsed = session.started.elapsed();
+        let mut status = RecordingStatus {
+            output_mode: self.config.output_mode,
+            elapsed,
+            remaining: session.duration.map(|duration| duration.saturating_sub(elapsed)),
+            files: Vec::new(),
+            frames: 0,
+            bytes_written: 0,
+        };
+        for (i, device) in session.devices.iter().enumerate() {
+            let frames = device.capture.lock().unwrap().frames();
+            if i == 0 {
+                status.frames = frames;
+            }
+            status.bytes_written += frames * device.channels.len() as u64 * (self.config.bits_per_sample() / 8) as u64;
+            status.files.extend(device.output.current_files.lock().unwrap().iter().cloned());
+        }
+        Some(status)
+    }
+
+    /// Each recorded channel's label, or its number, and its peak level since
+    /// the last call as a fraction of full scale.
+    pub fn take_peak_levels(&self) -> Vec<(String, f64)> {
+        let devices = match self.session {
+            Some(ref session) => &session.devices,
+            None => return Vec::new(),
+        };
+        let mut levels = Vec::new();
+        for device in devices {
+            let peaks = device.capture.lock().unwrap().take_peaks();
+            for ((channel, label), peak) in device.channels.iter().zip(&device.labels).zip(peaks) {
+                let name = if label.is_empty() { channel.to_string() } else { label.clone() };
+                levels.push((name, peak));
+            }
+        }
+        levels
+    }
+
+    /// Drops a marker at the current position for each line read from
+    /// `input`, using the line as its label, unless the line is a key bound
+    /// in `CONTROL_KEYS`. Runs on a background thread until `input` ends or
+    /// a key bound to `stop` is pressed. Markers go to whichever recording is
+    /// running when they arrive.
+    pub fn listen_for_markers<R: BufRead + Send + 'static>(&self, input: R) {
+        if self.session.is_none() {
+            return;
+        }
+        let marker_targets = Arc::clone(&self.marker_targets);
+        let control_keys = self.config.control_keys.clone();
+        let stop_requested = Arc::clone(&self.stop_requested);
+        let start_requested = Arc::clone(&self.start_requested);
+        thread::spawn(move || {
+            for (i, line) in input.lines().map_while(Result::ok).enumerate() {
+                let label = match control_message(&line, &control_keys, i + 1) {
+                    ControlMessage::AddMarker(label) => label,
+                    ControlMessage::StopRecording => {
+                        stop_requested.store(true, Ordering::Relaxed);
+                        break;
+                    },
+                    ControlMessage::StartRecording => {
+                        start_requested.store(true, Ordering::Relaxed);
+                        continue;
+                    },
+                };
+                // Every device gets the marker, at its own frame position
+                let mut positions = Vec::new();
+                let captures = marker_targets.lock().unwrap().clone();
+                for capture in &captures {
+                    if let Some(capture) = capture.upgrade() {
+                        positions.push(capture.lock().unwrap().add_marker(&label));
+                    }
+                }
+                match positions.first() {
+                    Some(position) => status!("Added marker \"{}\" at frame {}", label, position),
+                    None => status!("No recording to add marker \"{}\" to", label),
+                }
+            }
+        });
+    }
+}
+
+/// What `AudioRecorder::handle_start` did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartOutcome {
+    /// A new recording started, or an armed one started writing.
+    Started,
+    /// A recording was already running, and carries on.
+    AlreadyRecording,
+    /// With `RESTART_ON_START`, the running recording was finished, leaving
+    /// these files, and a new one started.
+    Restarted(Vec<String>),
+}
+
+/// What a line of control input asks for.
+#[derive(Debug, Clone, PartialEq)]
+enum ControlMessage {
+    AddMarker(String),
+    StopRecording,
+    StartRecording,
+}
+
+/// The message for one line of control input: the action of a bound key, or
+/// otherwise a marker labelled with the line, numbered `number` if it's
+/// empty.
+fn control_message(line: &str, control_keys: &HashMap<String, ControlAction>, number: usize) -> ControlMessage {
+    let line = line.trim();
+    match control_keys.get(line) {
+        Some(ControlAction::Stop) => ControlMessage::StopRecording,
+        Some(ControlAction::Start) => ControlMessage::StartRecording,
+        Some(ControlAction::Marker) => ControlMessage::AddMarker(format!("Marker {}", number)),
+        None if line.is_empty() => ControlMessage::AddMarker(format!("Marker {}", number)),
+        None => ControlMessage::AddMarker(line.to_string()),
+    }
+}
+
+/// How many batches of `batch_samples` hold `WRITER_QUEUE_SECONDS` of audio
+/// with `channels` channels at `sample_rate`.
+fn writer_queue_batches(sample_rate: u32, channels: usize, batch_samples: usize) -> usize {
+    let samples = capture::WRITER_QUEUE_SECONDS * sample_rate as u64 * channels as u64;
+    (samples / batch_samples.max(1) as u64).max(1) as usize
+}
+
+/// Deletes the oldest recordings in `dir` beyond `keep`.
+fn prune_old_recordings(dir: &Path, keep: usize, events: &EventLog) {
+    match retention::prune_recordings(dir, keep) {
+        Ok(deleted) => {
+            for path in deleted {
+                status!("Deleted old recording {}", path.display());
+                events.log("deleted", &path.to_string_lossy(), "older than the newest MAX_RECORDINGS");
+            }
+        },
+        Err(e) => eprintln!("Failed to prune old recordings: {}", e),
+    }
+}
+
+/// What's done to a recording's files once they're in their final format:
+/// compression, checksums, permissions, backup, pruning and `POST_COMMAND`,
+/// in that order. It owns its settings so it can run on a background thread.
+struct FinishingSteps {
+    compression: FileCompression,
+    compress_keep_original: bool,
+    compress_command: Option<String>,
+    write_checksum: bool,
+    file_mode: Option<u32>,
+    file_group: Option<String>,
+    backup_dir: Option<PathBuf>,
+    /// `MAX_RECORDINGS` and the directory it applies to, unless writing to stdout.
+    max_recordings: Option<(PathBuf, usize)>,
+    post_command: Option<String>,
+}
+
+impl FinishingSteps {
+    fn from_config(config: &Config) -> Self {
+        FinishingSteps {
+            compression: config.compress_finalized,
+            compress_keep_original: config.compress_keep_original,
+            compress_command: config.compress_command.clone(),
+            write_checksum: config.write_checksum,
+            file_mode: config.file_mode,
+            file_group: config.file_group.clone(),
+            backup_dir: config.backup_dir.clone(),
+            max_recordings: config.max_recordings
+                .filter(|_| config.output_mode != OutputMode::Stdout)
+                .map(|max| (config.output_dir.clone(), max)),
+            post_command: config.post_command.clone(),
+        }
+    }
+
+    /// The paths `compress` gives `files` if it succeeds.
+    fn compressed_names(&self, files: &[String]) -> Vec<String> {
+        let Some(extension) = self.compression.extension() else {
+            return files.to_vec();
+        };
+        files.iter()
+            .flat_map(|file| {
+                let original = Some(file.clone()).filter(|_| self.compress_keep_original);
+                original.into_iter().chain([format!("{}.{}", file, extension)])
+            })
+            .collect()
+    }
+
+    /// Compresses each of `files`, deleting the original unless it's to be
+    /// kept, and returns the paths they end up at. A file that fails to
+    /// compress is kept as it was.
+    fn compress(&self, files: Vec<String>, events: &EventLog) -> Vec<String> {
+        if self.compression == FileCompression::None {
+            return files;
+        }
+        let mut compressed_files = Vec::new();
+        for file in files {
+            match encode::compress_file(&file, self.compression, self.compress_command.as_deref()) {
+                Ok(compressed) => {
+                    events.log("compressed", &compressed, &format!("from {}", file));
+                    if self.compress_keep_original {
+                        compressed_files.push(file);
+                    } else if let Err(e) = std::fs::remove_file(&file) {
+                        eprintln!("Failed to delete {} after compressing it: {}", file, e);
+                        compressed_files.push(file);
+                    } else {
+                        envelope::move_envelope(Path::new(&file), Path::new(&compressed));
+                        timestamps::move_timestamps(Path::new(&file), Path::new(&compressed));
+                    }
+                    compressed_files.push(compressed);
+                },
+                Err(e) => {
+                    eprintln!("Keeping {} uncompressed: {}", file, e);
+                    compressed_files.push(file);
+                },
+            }
+        }
+        compressed_files
+    }
+
+    /// Writes checksums, sets permissions, backs up and prunes, then drops
+    /// any of `files` pruned away.
+    fn finish(&self, files: &mut Vec<String>, events: &EventLog) {
+        if self.write_checksum {
+            for file in files.iter() {
+                match checksum::write_checksum(Path::new(file)) {
+                    Ok(path) => events.log("checksum", file, &path.display().to_string()),
+                    Err(e) => {
+                        eprintln!("Failed to write the checksum of {}: {}", file, e);
+                        events.failed(file, "write checksum", &e.into());
+                    },
+                }
+            }
+        }
+
+        if self.file_mode.is_some() || self.file_group.is_some() {
+            let group = self.file_group.as_deref();
+            for file in files.iter() {
+                let checksum = checksum::checksum_path(Path::new(file));
+                let result = disk::set_file_access(Path::new(file), self.file_mode, group)
+                    .and_then(|()| match self.write_checksum && checksum.exists() {
+                        true => disk::set_file_access(&checksum, self.file_mode, group),
+                        false => Ok(()),
+                    });
+                if let Err(e) = result {
+                    eprintln!("Failed to set the permissions of {}: {}", file, e);
+                    events.failed(file, "set permissions", &e.into());
+                }
+            }
+        }
+
+        if let Some(backup_dir) = &self.backup_dir {
+            for file in files.iter() {
+                match disk::copy_to_backup(Path::new(file), backup_dir) {
+                    Ok(backup) => events.log("backed up", file, &backup.display().to_string()),
+                    Err(e) => {
+                        eprintln!("Failed to back up {} to {}: {}", file, backup_dir.display(), e);
+                        events.failed(file, "back up", &e.into());
+                    },
+                }
+            }
+        }
+
+        if let Some((dir, max)) = &self.max_recordings {
+            prune_old_recordings(dir, *max, events);
+            files.retain(|file| Path::new(file).exists());
+        }
+    }
+
+    /// Runs `POST_COMMAND`, if set, on each of `files` in turn.
+    fn run_post_command(&self, files: &[String], events: &EventLog) {
+        let Some(template) = &self.post_command else {
+            return;
+        };
+        for file in files {
+            match post::run_post_command(template, file) {
+                Ok(status) if status.success() => events.log("post command", file, &status.to_string()),
+                Ok(status) => {
+                    eprintln!("Warning: post command for {} {}", file, status);
+                    events.log("post command", file, &status.to_string());
+                },
+                Err(e) => {
+                    eprintln!("Warning: post command for {} failed: {}", file, e);
+                    events.failed(file, "post command", &e);
+                },
+            }
+        }
+    }
+}
+
+/// Where the files for each part of a recording go and what they hold.
+#[derive(Clone)]
+struct SegmentPlan {
+    dir: PathBuf,
+    /// Set when recording several devices, to tell their files apart.
+    tag: Option<String>,
+    output_mode: OutputMode,
+    channels: Vec<usize>,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    buffer_bytes: usize,
+    /// Zero to only flush when a file is finalized.
+    flush_frames: u64,
+    timestamp_tz: TimestampZone,
+    split_naming: SplitNaming,
+    split_index_width: usize,
+    /// The label of each of `channels`, or an empty string.
+    labels: Vec<String>,
+    /// Write a single channel as a stereo file.
+    mono_to_stereo: bool,
+    /// Write a per-second level envelope next to each recorded file.
+    level_envelope: bool,
+    /// Count clipped samples, warning above this percentage, if set.
+    clip_warn_percent: Option<f64>,
+    /// Frames between the lines of each file's timestamp track; zero for none.
+    timestamp_interval_frames: u64,
+    /// The positions in `channels` of each side of the monitor mix, if one
+    /// is written after the recording's own files.
+    monitor_mix: Option<(Vec<usize>, Vec<usize>)>,
+    /// Set to the files of the part being written.
+    current_files: Arc<Mutex<Vec<String>>>,
+    events: EventLog,
+}
+
+impl SegmentPlan {
+    fn files_per_segment(&self) -> usize {
+        let files = if self.output_mode == OutputMode::Split { self.channels.len() } else { 1 };
+        files + self.monitor_mix.is_some() as usize
+    }
+
+    /// Creates the file, or the set of split files, for the next part of a
+    /// recording, named after the current time and the device tag if any.
+    fn open(&self, clock: &dyn Clock) -> Result<Box<dyn AudioProcessor>, BlackboxError> {
+        let suffixes: Vec<String> = match self.output_mode {
+            OutputMode::Split => self.channels.iter().enumerate()
+                .map(|(i, ch)| match self.split_naming {
+                    SplitNaming::Channel => format!("-ch{}.wav", ch),
+                    SplitNaming::Index => format!("_input{:0width$}.wav", i + 1, width = self.split_index_width),
+                    SplitNaming::Label => match self.labels.get(i).filter(|label| !label.is_empty()) {
+                        Some(label) => format!("-{}.wav", file_tag(label)),
+                        None => format!("-ch{}.wav", ch),
+                    },
+                })
+                .collect(),
+            _ => vec![".wav".to_string()],
+        };
+        let suffixes = match self.monitor_mix {
+            Some(_) => suffixes.into_iter().chain(["-mix.wav".to_string()]).collect(),
+            None => suffixes,
+        };
+        let stem = match self.tag {
+            Some(ref tag) => format!("{}-{}", timestamp_now(clock, self.timestamp_tz), tag),
+            None => timestamp_now(clock, self.timestamp_tz),
+        };
+        let stem = unique_stem(&self.dir, &stem, &suffixes);
+        let file_names: Vec<String> = suffixes.iter()
+            .map(|suffix| {
+                let path = self.dir.join(format!("{}{}", stem, suffix));
+                // Keep names in the current directory bare, as "name.wav" rather than "./name.wav"
+                path.strip_prefix(".").unwrap_or(&path).to_string_lossy().into_owned()
+            })
+            .collect();
+
+        let recorded = &file_names[..file_names.len() - self.monitor_mix.is_some() as usize];
+        let processor: Box<dyn AudioProcessor> = if self.output_mode == OutputMode::Split {
+            Box::new(SplitProcessor::create(recorded, self.sample_rate, self.bits_per_sample, self.buffer_bytes)?
+                .with_flush_interval(self.flush_frames))
+        } else {
+            let stereo = self.mono_to_stereo && self.channels.len() == 1;
+            let spec = hound::WavSpec {
+                channels: if stereo { 2 } else { self.channels.len() as u16 },
+                sample_rate: self.sample_rate,
+                bits_per_sample: self.bits_per_sample,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let processor = WavProcessor::create(&file_names[0], spec, self.buffer_bytes)?.with_flush_interval(self.flush_frames);
+            match stereo {
+                true => Box::new(MonoToStereoProcessor::new(Box::new(processor))),
+                false => Box::new(processor),
+            }
+        };
+        let processor: Box<dyn AudioProcessor> = match self.clip_warn_percent {
+            Some(warn_percent) => Box::new(ClipProcessor::new(processor, &self.channels, self.bits_per_sample, recorded,
+                                                              warn_percent, self.events.clone())),
+            None => processor,
+        };
+        let processor: Box<dyn AudioProcessor> = match self.level_envelope {
+            true => {
+                let envelope = EnvelopeProcessor::new(processor, self.channels.len(), self.sample_rate,
+                                                      self.bits_per_sample, recorded);
+                match self.clip_warn_percent {
+                    Some(_) => Box::new(envelope.with_clip_counts()),
+                    None => Box::new(envelope),
+                }
+            },
+            false => processor,
+        };
+        let processor: Box<dyn AudioProcessor> = match self.timestamp_interval_frames {
+            0 => processor,
+            frames => Box::new(TimestampProcessor::create(processor, self.channels.len(), recorded, frames)?),
+        };
+        let processor: Box<dyn AudioProcessor> = match self.monitor_mix {
+            Some((ref left, ref right)) => {
+                let spec = hound::WavSpec {
+                    channels: 2,
+                    sample_rate: self.sample_rate,
+                    bits_per_sample: self.bits_per_sample,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                let mix = WavProcessor::create(&file_names[file_names.len() - 1], spec, self.buffer_bytes)?
+                    .with_flush_interval(self.flush_frames);
+                Box::new(MixProcessor::new(processor, Box::new(mix), self.channels.len(), left.clone(), right.clone()))
+            },
+            None => processor,
+        };
+        for file_name in &file_names {
+            self.events.log("created", file_name, "");
+        }
+        *self.current_files.lock().unwrap() = file_names;
+        Ok(processor)
+    }
+}
+
+/// Wraps `processor` in a `FadeProcessor`, unless `fade_frames` is zero.
+fn with_fade(processor: Box<dyn AudioProcessor>, channels: usize, fade_frames: usize) -> Box<dyn AudioProcessor> {
+    match fade_frames {
+        0 => processor,
+        _ => Box::new(FadeProcessor::new(processor, channels, fade_frames)),
+    }
+}
+
+/// Writes `tone`, if set, at the start of `processor`'s output.
+fn with_sync_tone(processor: Box<dyn AudioProcessor>, channels: usize, tone: &Option<Arc<Vec<i32>>>,
+                  channel: Option<usize>) -> Box<dyn AudioProcessor> {
+    match tone {
+        Some(tone) => Box::new(SyncToneProcessor::new(processor, channels, Arc::clone(tone), channel)),
+        None => processor,
+    }
+}
+
+/// A file-name-safe version of a device name, used to tell apart the files
+/// of different devices. Anything but letters and digits becomes `_`, so the
+/// tag never contains the `-` that separates the parts of a file name.
+fn file_tag(device_name: &str) -> String {
+    device_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+/// Lists `channels` as "ch2: Kick, ch0", taking each label from the entry in
+/// `labels` at the channel's position in `configured`.
+fn describe_channels(channels: &[usize], configured: &[usize], labels: &[String]) -> String {
+    channels.iter()
+        .map(|&channel| {
+            let label = configured.iter().position(|&c| c == channel)
+                .and_then(|i| labels.get(i))
+                .filter(|label| !label.is_empty());
+            match label {
+                Some(label) => format!("ch{}: {}", channel, label),
+                None => format!("ch{}", channel),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The backend `config` records from: the cpal host named by `AUDIO_HOST`,
+/// or a test tone with `SOURCE=tone`.
+pub(crate) fn default_backend(config: &Config) -> Result<Box<dyn AudioBackend>, BlackboxError> {
+    Ok(match config.source {
+        AudioSource::Device => Box::new(CpalBackend::new(&config.audio_host)?),
+        AudioSource::Tone => Box::new(ToneBackend {
+            signal: config.tone_signal,
+            hz: config.tone_hz,
+            audio_host: config.audio_host.clone(),
+        }),
+    })
+}
+
+/// The inputs a recording with `config` is made from: each of
+/// `INPUT_DEVICES` with its channels, or else the default input, or the
+/// default output with `CAPTURE_MODE=loopback`, whose channels `None`
+/// leaves to `AUDIO_CHANNELS` once the device is open.
+pub(crate) fn input_selectors(config: &Config) -> Vec<(InputSelector, Option<Vec<usize>>)> {
+    if config.input_devices.is_empty() {
+        let selector = match config.capture_mode {
+            CaptureMode::Input => InputSelector::Default,
+            CaptureMode::Loopback => InputSelector::Loopback,
+        };
+        return vec![(selector, None)];
+    }
+    config.input_devices.iter()
+        .map(|spec| (InputSelector::Named(spec.name.clone()), Some(spec.channels.clone())))
+        .collect()
+}
+
+/// Checks a device has any input channels at all, before a stream is built
+/// for it.
+pub(crate) fn check_has_inputs(device_name: &str, total_channels: usize) -> Result<(), BlackboxError> {
+    if total_channels == 0 {
+        return Err(BlackboxError::DeviceNotFound(format!("{} has no input channels", device_name)));
+    }
+    Ok(())
+}
+
+/// Checks that every requested channel exists on a device with `total_channels` inputs.
+pub(crate) fn validate_channels(channels: &[usize], total_channels: usize) -> Result<(), BlackboxError> {
+    match channels.iter().find(|&&channel| channel >= total_channels) {
+        Some(&channel) => Err(BlackboxError::ChannelOutOfRange { requested: channel, available: total_channels }),
+        None => Ok(()),
+    }
+}
+
+/// Checks `channels` against a device with `total_channels` inputs and
+/// returns the ones to record. Channels the device doesn't have are an error
+/// under `ChannelMismatch::Error`, and are otherwise left out, with a warning
+/// under `ChannelMismatch::Warn`. It is always an error if none are left.
+fn resolve_channels(channels: &[usize], total_channels: usize, policy: ChannelMismatch,
+                    device_name: &str) -> Result<Vec<usize>, BlackboxError> {
+    if policy == ChannelMismatch::Error {
+        validate_channels(channels, total_channels)?;
+    }
+    let (kept, missing): (Vec<usize>, Vec<usize>) = channels.iter().partition(|&&channel| channel < total_channels);
+    if kept.is_empty() {
+        validate_channels(channels, total_channels)?;
+    }
+    if !missing.is_empty() && policy == ChannelMismatch::Warn {
+        eprintln!("Warning: {} has {} channels, so channels {:?} won't be recorded", device_name, total_channels, missing);
+    }
+    Ok(kept)
+}
+
+/// Formats the current time as YEAR-MONTH-DAY-HOUR-MINUTE for file names,
+/// followed by `Z` in UTC.
+fn timestamp_now(clock: &dyn Clock, tz: TimestampZone) -> String {
+    let now: DateTime<Local> = clock.now();
+    match tz {
+        TimestampZone::Local => now.format("%Y-%m-%d-%H-%M").to_string(),
+        TimestampZone::Utc => now.with_timezone(&Utc).format("%Y-%m-%d-%H-%MZ").to_string(),
+    }
+}
+
+/// How long each file of a rotating recording is, in frames.
+#[derive(Clone)]
+enum FileLengths {
+    /// `every` frames, after a first file of `first`.
+    Fixed { first: u64, every: u64 },
+    /// With `DAILY_ROTATION` on its own, a file from each midnight in `tz`
+    /// to the next, however long a clock change makes that day, after a
+    /// first file of `first` up to `first_midnight`.
+    Daily { first: u64, first_midnight: DateTime<Local>, tz: TimestampZone, sample_rate: u32 },
+}
+
+impl FileLengths {
+    /// A file a day, for a recording starting at `now`.
+    fn daily(now: DateTime<Local>, tz: TimestampZone, sample_rate: u32) -> Self {
+        let first_midnight = next_midnight(now, tz);
+        FileLengths::Daily { first: frames_between(now, first_midnight, sample_rate), first_midnight, tz, sample_rate }
+    }
+
+    /// The first frame and the length of file `segment`, counting from 0.
+    fn segment(&self, segment: u64) -> (u64, u64) {
+        match *self {
+            FileLengths::Fixed { first, .. } if segment == 0 => (0, first),
+            FileLengths::Fixed { first, every } => (first + (segment - 1) * every, every),
+            FileLengths::Daily { first, .. } if segment == 0 => (0, first),
+            FileLengths::Daily { first, first_midnight, tz, sample_rate } => {
+                let mut start = first;
+                let mut midnight = first_midnight;
+                for _ in 1..segment {
+                    let next = next_midnight(midnight, tz);
+                    start += frames_between(midnight, next, sample_rate);
+                    midnight = next;
+                }
+                (start, frames_between(midnight, next_midnight(midnight, tz), sample_rate))
+            },
+        }
+    }
+
+    /// How many files a recording of `frames` frames is spread over.
+    fn segments(&self, frames: u64) -> u64 {
+        match *self {
+            FileLengths::Fixed { first, every } => 1 + frames.saturating_sub(first).div_ceil(every),
+            FileLengths::Daily { .. } => {
+                let mut segments = 1;
+                let (mut start, mut len) = self.segment(0);
+                while start + len < frames {
+                    (start, len) = self.segment(segments);
+                    segments += 1;
+                }
+                segments
+            },
+        }
+    }
+}
+
+/// The start of the day after `time`'s in `tz`: midnight, or the first time
+/// after it where a clock change skips midnight.
+fn next_midnight(time: DateTime<Local>, tz: TimestampZone) -> DateTime<Local> {
+    let midnight = |date: NaiveDate| date.and_time(NaiveTime::MIN);
+    match tz {
+        TimestampZone::Utc => {
+            let date = time.with_timezone(&Utc).date_naive() + TimeDelta::days(1);
+            midnight(date).and_utc().with_timezone(&Local)
+        },
+        TimestampZone::Local => {
+            let midnight = midnight(time.date_naive() + TimeDelta::days(1));
+            (0..=24).map(|hours| midnight + TimeDelta::hours(hours))
+                .find_map(|local| Local.from_local_datetime(&local).earliest())
+                .unwrap_or(time + TimeDelta::days(1))
+        },
+    }
+}
+
+/// How many frames at `sample_rate` from `start` to `end`.
+fn frames_between(start: DateTime<Local>, end: DateTime<Local>, sample_rate: u32) -> u64 {
+    let nanoseconds = (end - start).num_nanoseconds().unwrap_or_default().max(0);
+    (nanoseconds as f64 / 1e9 * sample_rate as f64).round() as u64
+}
+
+/// How many frames at `sample_rate` from `now` to the next multiple of
+/// `rotate_seconds` since midnight in `tz`, or a whole `rotate_seconds` if
+/// `now` is on one.
+fn frames_to_boundary(now: DateTime<Local>, tz: TimestampZone, rotate_seconds: u64, sample_rate: u32) -> u64 {
+    let time = match tz {
+        TimestampZone::Local => now.time(),
+        TimestampZone::Utc => now.with_timezone(&Utc).time(),
+    };
+    let since_midnight = time.num_seconds_from_midnight() as f64 + time.nanosecond() as f64 / 1e9;
+    let remaining = rotate_seconds as f64 - since_midnight % rotate_seconds as f64;
+    match (remaining * sample_rate as f64).round() as u64 {
+        0 => rotate_seconds * sample_rate as u64,
+        frames => frames,
+    }
+}
+
+/// Picks `{stem}.wav` in `dir`, or `{stem}-1.wav`, `{stem}-2.wav`, ... if that
+/// is already taken, so recordings started within the same minute don't
+/// overwrite each other.
+#[cfg(test)]
+fn unique_file_name(dir: &Path, stem: &str) -> String {
+    format!("{}.wav", unique_stem(dir, stem, &[".wav".to_string()]))
+}
+
+/// Picks `stem`, or `{stem}-1`, `{stem}-2`, ... so that no file named the
+/// stem followed by any of `suffixes` exists in `dir` yet.
+fn unique_stem(dir: &Path, stem: &str, suffixes: &[String]) -> String {
+    // A recording already compressed to WavPack, or with COMPRESS_FINALIZED,
+    // still takes its name
+    let taken = |candidate: &str| suffixes.iter().any(|suffix| {
+        [format!("{}{}", candidate, suffix), format!("{}{}", candidate, suffix.replace(".wav", ".wv"))].iter()
+            .any(|name| ["", ".gz", ".zst"].iter().any(|extension| dir.join(format!("{}{}", name, extension)).exists()))
+    });
+    let mut unique = stem.to_string();
+    let mut index = 1;
+    while taken(&unique) {
+        unique = format!("{}-{}", stem, index);
+        index += 1;
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{env_from, wav_spec, ThreadNames};
+    use crate::backend::{MockBackend, MonitorOutput, MOCK_CAPTURE_START};
+    use crate::clock::MockClock;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_timestamp_with_mock_clock() {
+        let start = Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 0).unwrap();
+        let clock = MockClock::new(start);
+        let first = timestamp_now(&clock, TimestampZone::Local);
+        assert_eq!(first, "2024-06-26-09-05");
+
+        clock.advance(chrono::Duration::minutes(1));
+        let second = timestamp_now(&clock, TimestampZone::Local);
+        assert_eq!(second, "2024-06-26-09-06");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        let start = Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 0).unwrap();
+        let clock = MockClock::new(start);
+        let local = timestamp_now(&clock, TimestampZone::Local);
+        let utc = timestamp_now(&clock, TimestampZone::Utc);
+        assert!(utc.ends_with('Z'));
+        assert_eq!(utc.len(), local.len() + 1);
+
+        let parsed = NaiveDateTime::parse_from_str(&utc, "%Y-%m-%d-%H-%MZ").unwrap().and_utc();
+        assert_eq!(parsed, start.with_timezone(&Utc));
+        let parsed = NaiveDateTime::parse_from_str(&local, "%Y-%m-%d-%H-%M").unwrap();
+        assert_eq!(parsed, start.naive_local());
+    }
+
+    #[test]
+    fn test_first_rotation_lands_on_the_next_boundary() {
+        let clock = MockClock::new(Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 20).unwrap());
+        assert_eq!(frames_to_boundary(clock.now(), TimestampZone::Local, 60, 8000), 40 * 8000);
+        assert_eq!(frames_to_boundary(clock.now(), TimestampZone::Local, 3600, 8000), (55 * 60 - 20) * 8000);
+
+        clock.advance(chrono::Duration::milliseconds(39_500));
+        assert_eq!(frames_to_boundary(clock.now(), TimestampZone::Local, 60, 8000), 4000);
+        clock.advance(chrono::Duration::milliseconds(500));
+        assert_eq!(frames_to_boundary(clock.now(), TimestampZone::Local, 60, 8000), 60 * 8000);
+    }
+
+    #[test]
+    fn test_daily_rotation_cuts_at_midnight() {
+        // Half a second before midnight, with a second of audio at 8 kHz
+        let record = |rotate_seconds: &str| {
+            let temp_dir = tempdir().unwrap();
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let rotate_seconds = rotate_seconds.to_string();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("DAILY_ROTATION", "true"),
+                ("ROTATE_SECONDS", &rotate_seconds),
+            ]);
+            let clock = MockClock::new(Local.with_ymd_and_hms(2024, 6, 26, 23, 59, 59).unwrap() + chrono::Duration::milliseconds(500));
+            let backend = MockBackend::new(8000, 1, vec![100; 8000]);
+            let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+                .with_backend(Box::new(backend))
+                .with_clock(Arc::new(clock));
+            let files = recorder.record_for(Duration::ZERO).unwrap();
+            files.iter().map(|file| hound::WavReader::open(file).unwrap().duration()).collect::<Vec<u32>>()
+        };
+
+        // Once a day on its own, and on top of a cadence that divides a day
+        assert_eq!(record("0"), [4000, 4000]);
+        assert_eq!(record("3600"), [4000, 4000]);
+
+        let env = env_from(&[
+            ("DAILY_ROTATION", "true"),
+            ("ROTATE_SECONDS", "7000"),
+        ]);
+        assert!(matches!(Config::from_sources(&env, &[]), Err(BlackboxError::Config(_))));
+    }
+
+    #[test]
+    fn test_daily_rotation_follows_clock_changes() {
+        // The clocks only change in a local time zone that has them, so this
+        // runs again in a process of its own with TZ set to UK time
+        if std::env::var_os("BLACKBOX_CLOCK_CHANGE_TEST").is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "recorder::tests::test_daily_rotation_follows_clock_changes"])
+                .env("BLACKBOX_CLOCK_CHANGE_TEST", "1")
+                .env("TZ", "GMT0BST,M3.5.0/1,M10.5.0")
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+            return;
+        }
+
+        // Half a second before the midnight starting a day of `hours` hours,
+        // with audio at 2 Hz running 500 s into the day after
+        let record = |year, month, day, hours: u32| {
+            let temp_dir = tempdir().unwrap();
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("DAILY_ROTATION", "true"),
+            ]);
+            let clock = MockClock::new(Local.with_ymd_and_hms(year, month, day, 23, 59, 59).unwrap()
+                                       + chrono::Duration::milliseconds(500));
+            let frames = 1 + hours as usize * 3600 * 2 + 1000;
+            let backend = MockBackend::new(2, 1, vec![100; frames]);
+            let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+                .with_backend(Box::new(backend))
+                .with_clock(Arc::new(clock));
+            recorder.start_recording().unwrap();
+            let capture = Arc::clone(&recorder.session.as_ref().unwrap().devices[0].capture);
+            capture.lock().unwrap().add_marker("next morning");
+            let files = recorder.stop_recording().unwrap();
+            let durations: Vec<u32> = files.iter().map(|file| hound::WavReader::open(file).unwrap().duration()).collect();
+            // The marker lands in the last file, at its end
+            let bytes = std::fs::read(files.last().unwrap()).unwrap();
+            let cue = bytes.windows(4).position(|id| id == b"cue ").unwrap() + 8;
+            assert_eq!(u32::from_le_bytes(bytes[cue + 8..cue + 12].try_into().unwrap()), 1000);
+            durations
+        };
+
+        // The clocks go forward an hour on 31 March 2024, and back on 27 October
+        assert_eq!(record(2024, 3, 30, 23), [1, 23 * 3600 * 2, 1000]);
+        assert_eq!(record(2024, 10, 26, 25), [1, 25 * 3600 * 2, 1000]);
+        assert_eq!(record(2024, 6, 26, 24), [1, 24 * 3600 * 2, 1000]);
+    }
+
+    #[test]
+    fn test_same_minute_recordings_do_not_collide() {
+        let temp_dir = tempdir().unwrap();
+        let clock = MockClock::new(Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 0).unwrap());
+
+        let spec = wav_spec(2, 44100);
+
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            let name = unique_file_name(temp_dir.path(), &timestamp_now(&clock, TimestampZone::Local));
+            let path = temp_dir.path().join(&name);
+            WavProcessor::create(path.to_str().unwrap(), spec, 8192).unwrap().finalize().unwrap();
+            names.push(name);
+            clock.advance(chrono::Duration::seconds(10));
+        }
+
+        assert_eq!(names, vec!["2024-06-26-09-05.wav", "2024-06-26-09-05-1.wav"]);
+        for name in &names {
+            assert!(temp_dir.path().join(name).exists());
+        }
+
+        // Compressed recordings count too
+        std::fs::write(temp_dir.path().join("2024-06-26-09-05-2.wv"), b"").unwrap();
+        assert_eq!(unique_file_name(temp_dir.path(), &timestamp_now(&clock, TimestampZone::Local)), "2024-06-26-09-05-3.wav");
+    }
+
+    #[test]
+    fn test_recording_through_mock_backend() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "2,0"),
+        ]);
+        // Three channels carrying 0, 100 and 200
+        let backend = MockBackend::new(48000, 3, (0..3 * 4800).map(|i| (i % 3) as i16 * 100).collect());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].starts_with(temp_dir.path().to_str().unwrap()));
+
+        let mut reader = hound::WavReader::open(&files[0]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 48000);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, [200, 0].repeat(4800));
+    }
+
+    #[test]
+    fn test_split_bit_depth_overrides_the_default() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "2,0"),
+            ("OUTPUT_MODE", "split"),
+            ("BIT_DEPTH", "16"),
+            ("SPLIT_BIT_DEPTH", "24"),
+        ]);
+        let config = Config::from_sources(&env, &[]).unwrap();
+        assert_eq!((config.single_bit_depth, config.split_bit_depth), (16, 24));
+        let backend = MockBackend::new(48000, 3, (0..3 * 4800).map(|i| (i % 3) as i16 * 100).collect());
+
+        let mut recorder = AudioRecorder::new(config).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 2);
+        for (file, level) in files.iter().zip([200, 0]) {
+            let mut reader = hound::WavReader::open(file).unwrap();
+            assert_eq!(reader.spec().bits_per_sample, 24);
+            // The 16-bit device's samples, padded out to 24 bits
+            let samples: Vec<i32> = reader.samples::<i32>().map(|s| s.unwrap()).collect();
+            assert_eq!(samples, vec![level << 8; 4800]);
+        }
+
+        assert!(Config::from_sources(&env_from(&[("SPLIT_BIT_DEPTH", "20")]), &[]).is_err());
+    }
+
+    #[derive(Default)]
+    struct RecordingHook {
+        finalized: Mutex<Vec<String>>,
+    }
+
+    impl EventHook for RecordingHook {
+        fn on_file_finalized(&self, path: &str) {
+            self.finalized.lock().unwrap().push(path.to_string());
+        }
+    }
+
+    #[test]
+    fn test_event_hook_sees_finalized_files() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[("OUTPUT_DIR", &dir)]);
+        let backend = MockBackend::new(48000, 3, vec![100; 3 * 4800]);
+
+        let hook = Arc::new(RecordingHook::default());
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+            .with_backend(Box::new(backend))
+            .with_event_hook(hook.clone());
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(*hook.finalized.lock().unwrap(), files);
+        // EVENT_LOG is off, so nothing was written for it
+        assert!(!temp_dir.path().join("events.jsonl").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_command_runs_on_each_file() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("OUTPUT_MODE", "split"),
+            ("POST_COMMAND", "touch {path}.done"),
+        ]);
+        let backend = MockBackend::new(48000, 3, vec![100; 3 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        recorder.wait_for_post_commands();
+        assert_eq!(files.len(), 2);
+        for file in &files {
+            assert!(Path::new(&format!("{}.done", file)).exists());
+        }
+    }
+
+    #[test]
+    fn test_recordings_are_copied_to_backup_dir() {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = temp_dir.path().join("backup");
+        let dir = temp_dir.path().join("primary").to_str().unwrap().to_string();
+        let backup = backup_dir.to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("BACKUP_DIR", &backup),
+            ("AUDIO_CHANNELS", "0,2"),
+            ("OUTPUT_MODE", "split"),
+            ("SILENCE_THRESHOLD", "0.001"),
+        ]);
+        // Channel 0 is silent, channel 2 carries 200
+        let backend = MockBackend::new(48000, 3, (0..3 * 4800).map(|i| (i % 3) as i16 * 100).collect());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("-ch2.wav"));
+
+        // Only the file that was kept is backed up, byte for byte
+        let backups: Vec<_> = std::fs::read_dir(&backup_dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(backups, vec![backup_dir.join(Path::new(&files[0]).file_name().unwrap())]);
+        assert_eq!(std::fs::read(&backups[0]).unwrap(), std::fs::read(&files[0]).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_proxy_encoded_for_each_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for ffmpeg: copies the input ($5) to the output ($8)
+        let temp_dir = tempdir().unwrap();
+        let encoder = temp_dir.path().join("fake-ffmpeg");
+        std::fs::write(&encoder, "#!/bin/sh\ncp \"$5\" \"$8\"\n").unwrap();
+        std::fs::set_permissions(&encoder, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let dir = temp_dir.path().join("out").to_str().unwrap().to_string();
+        let command = encoder.to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("ROTATE_SECONDS", "1"),
+            ("PROXY_FORMAT", "mp3"),
+            ("PROXY_COMMAND", &command),
+        ]);
+        // 2.5 s, so three files
+        let backend = MockBackend::new(8000, 1, vec![100; 20000]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        let (wavs, proxies): (Vec<_>, Vec<_>) = files.iter().partition(|file| file.ends_with(".wav"));
+        assert_eq!(wavs.len(), 3);
+        assert_eq!(proxies.len(), 3);
+        for (wav, proxy) in wavs.iter().zip(&proxies) {
+            assert_eq!(proxy.strip_suffix(".mp3"), wav.strip_suffix(".wav"));
+            assert!(Path::new(wav).exists() && Path::new(proxy).exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compression_runs_in_the_background_before_checksums() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for gzip: waits, then writes the input, its last argument,
+        // unchanged to standard output
+        let temp_dir = tempdir().unwrap();
+        let compressor = temp_dir.path().join("fake-gzip");
+        std::fs::write(&compressor, "#!/bin/sh\nsleep 0.2\nfor arg; do input=\"$arg\"; done\ncat \"$input\"\n").unwrap();
+        std::fs::set_permissions(&compressor, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let dir = temp_dir.path().join("out").to_str().unwrap().to_string();
+        let command = compressor.to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("COMPRESS_FINALIZED", "gzip"),
+            ("COMPRESS_COMMAND", &command),
+            ("WRITE_CHECKSUM", "true"),
+        ]);
+        let backend = MockBackend::new(8000, 1, vec![100; 800]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 1);
+        let compressed = Path::new(&files[0]);
+        let original = temp_dir.path().join("out").join(compressed.file_stem().unwrap());
+        assert_eq!(compressed.extension().unwrap(), "gz");
+        // Still compressing when the recording has stopped
+        assert!(original.exists() && !checksum::checksum_path(compressed).exists());
+
+        recorder.wait_for_post_commands();
+        assert!(!original.exists());
+        assert!(checksum::checksum_path(compressed).exists());
+        assert!(!checksum::checksum_path(&original).exists());
+    }
+
+    #[test]
+    fn test_location_is_written_to_info_tags() {
+        let temp_dir = tempdir().unwrap();
+        let location_file = temp_dir.path().join("location.txt");
+        let record_with = |name: &'static str, value: String| {
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let env = env_from(&[("OUTPUT_DIR", &dir), ("AUDIO_CHANNELS", "0"), (name, &value)]);
+            let backend = MockBackend::new(8000, 1, vec![100; 800]);
+            let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+            let files = recorder.record_for(Duration::ZERO).unwrap();
+            String::from_utf8_lossy(&std::fs::read(&files[0]).unwrap()).into_owned()
+        };
+
+        let contents = record_with("LOCATION", "51.5007,-0.1246".to_string());
+        assert!(contents.contains("ch0; location 51.500700,-0.124600"));
+
+        std::fs::write(&location_file, "-33.8568, 151.2153\n").unwrap();
+        let contents = record_with("LOCATION_FILE", location_file.to_str().unwrap().to_string());
+        assert!(contents.contains("location -33.856800,151.215300"));
+
+        // An unreadable location file doesn't stop the recording
+        std::fs::write(&location_file, "somewhere").unwrap();
+        let contents = record_with("LOCATION_FILE", location_file.to_str().unwrap().to_string());
+        assert!(!contents.contains("location"));
+    }
+
+    #[test]
+    fn test_device_channel_labels_fall_back_to_generic_names() {
+        let backend = MockBackend::new(8000, 3, Vec::new());
+        let recorder = AudioRecorder::new(Config::default()).with_backend(Box::new(backend));
+        assert_eq!(recorder.device_channel_labels().unwrap(), vec!["Channel 0", "Channel 1", "Channel 2"]);
+    }
+
+    #[test]
+    fn test_input_selectors_follow_input_devices_and_capture_mode() {
+        let mut config = Config::default();
+        assert_eq!(input_selectors(&config), vec![(InputSelector::Default, None)]);
+        config.capture_mode = CaptureMode::Loopback;
+        assert_eq!(input_selectors(&config), vec![(InputSelector::Loopback, None)]);
+        config.capture_mode = CaptureMode::Input;
+        config.input_devices = config::parse_input_devices("MOTU=0,1;USB Mic=0").unwrap();
+        assert_eq!(input_selectors(&config), vec![
+            (InputSelector::Named("MOTU".to_string()), Some(vec![0, 1])),
+            (InputSelector::Named("USB Mic".to_string()), Some(vec![0])),
+        ]);
+    }
+
+    #[test]
+    fn test_armed_recorder_writes_nothing_until_started() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("CONTROL_KEYS", "s=start"),
+            ("START_ARMED", "true"),
+        ]);
+        let backend = MockBackend::new(8000, 1, vec![16384; 800]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.arm().unwrap();
+        assert!(recorder.is_armed() && !recorder.is_recording());
+        assert!(recorder.status().is_none());
+        // The input is metered while armed
+        assert_eq!(recorder.take_peak_levels(), vec![("0".to_string(), 0.5)]);
+
+        recorder.listen_for_markers(io::Cursor::new("s\n"));
+        for _ in 0..100 {
+            if recorder.start_requested() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(recorder.start_requested());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        recorder.start_recording().unwrap();
+        assert!(recorder.is_recording());
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(Path::new(&files[0]).exists());
+    }
+
+    #[test]
+    fn test_rapid_start_commands_start_one_recording() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let config = |restart: bool| {
+            let dir = dir.clone();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("CONTROL_KEYS", "s=start"),
+                ("START_ARMED", "true"),
+                ("RESTART_ON_START", &restart.to_string()),
+            ]);
+            Config::from_sources(&env, &[]).unwrap()
+        };
+        let backend = || Box::new(MockBackend::new(8000, 1, vec![1000; 800]));
+
+        let mut recorder = AudioRecorder::new(config(false)).with_backend(backend());
+        recorder.arm().unwrap();
+        recorder.listen_for_markers(io::Cursor::new("s\ns\n"));
+        let mut outcomes = Vec::new();
+        for _ in 0..20 {
+            if recorder.take_start_request() {
+                outcomes.push(recorder.handle_start().unwrap());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(outcomes.iter().filter(|&outcome| *outcome == StartOutcome::Started).count(), 1);
+        assert!(outcomes.iter().skip(1).all(|outcome| *outcome == StartOutcome::AlreadyRecording));
+        assert_eq!(recorder.stop_recording().unwrap().len(), 1);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        // With RESTART_ON_START a second start finishes the first recording
+        let mut recorder = AudioRecorder::new(config(true)).with_backend(backend());
+        recorder.arm().unwrap();
+        assert_eq!(recorder.handle_start().unwrap(), StartOutcome::Started);
+        let first = match recorder.handle_start().unwrap() {
+            StartOutcome::Restarted(files) => files,
+            outcome => panic!("expected a restart, got {:?}", outcome),
+        };
+        assert_eq!(first.len(), 1);
+        let second = recorder.stop_recording().unwrap();
+        assert_eq!(second.len(), 1);
+        assert_ne!(first, second);
+
+        // A restart that can't open its file still leaves the first recording
+        let opened = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = Arc::clone(&opened);
+        let open_segment: OpenSegment = Arc::new(move |plan: &SegmentPlan, clock: &dyn Clock| {
+            match counted.fetch_add(1, Ordering::Relaxed) {
+                0 => plan.open(clock),
+                _ => Err(BlackboxError::Io(io::Error::other("disk full"))),
+            }
+        });
+        let files_before = std::fs::read_dir(temp_dir.path()).unwrap().count();
+        let mut recorder = AudioRecorder::new(config(true)).with_backend(backend()).with_segment_opener(open_segment);
+        recorder.arm().unwrap();
+        assert_eq!(recorder.handle_start().unwrap(), StartOutcome::Started);
+        assert!(matches!(recorder.handle_start(), Err(BlackboxError::Io(_))));
+        assert!(!recorder.is_recording());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), files_before + 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode_and_group() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let gid = unsafe { libc::getgid() };
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("WRITE_CHECKSUM", "true"),
+            ("FILE_MODE", "640"),
+            ("FILE_GROUP", &gid.to_string()),
+        ]);
+        let backend = MockBackend::new(8000, 1, vec![1000; 800]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.start_recording().unwrap();
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(files.len(), 1);
+
+        for path in [PathBuf::from(&files[0]), checksum::checksum_path(Path::new(&files[0]))] {
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.mode() & 0o7777, 0o640, "{}", path.display());
+            assert_eq!(metadata.gid(), gid);
+        }
+    }
+
+    #[test]
+    fn test_warmup_is_discarded() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("WARMUP_DISCARD_MS", "100"),
+        ]);
+        // A 100 ms pop at 8000 Hz, then the signal
+        let mut samples = vec![30000; 800];
+        samples.extend((1..=1600).map(|i| (i % 100) as i16));
+        let backend = MockBackend::new(8000, 1, samples.clone());
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.start_recording().unwrap();
+        let files = recorder.stop_recording().unwrap();
+
+        let written: Vec<i16> = hound::WavReader::open(&files[0]).unwrap().samples().map(Result::unwrap).collect();
+        assert_eq!(written, samples[800..]);
+    }
+
+    #[test]
+    fn test_start_time_is_taken_from_the_device() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("WARMUP_DISCARD_MS", "100"),
+            ("ROTATE_SECONDS", "1"),
+            ("SAMPLE_ACCURATE_START", "true"),
+            ("WRITE_BEXT", "true"),
+        ]);
+        let backend = MockBackend::new(8000, 1, vec![100; 16800]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.start_recording().unwrap();
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(files.len(), 2);
+
+        // The first 800 frames are discarded, so recording starts 100 ms after
+        // the first buffer was captured, and the second file a second later
+        let start = DateTime::parse_from_rfc3339(MOCK_CAPTURE_START).unwrap().with_timezone(&Local)
+            + TimeDelta::milliseconds(100);
+        for (file, start) in files.iter().zip([start, start + TimeDelta::seconds(1)]) {
+            let sidecar = std::fs::read_to_string(timestamps::start_path(Path::new(file))).unwrap();
+            assert_eq!(sidecar, format!("{{\"start\":\"{}\",\"sample_rate\":8000,\"time_reference\":{}}}\n",
+                                        start.to_rfc3339_opts(SecondsFormat::Micros, false),
+                                        metadata::time_reference(start, 8000)));
+            let bytes = std::fs::read(file).unwrap();
+            let bext = bytes.windows(4).position(|id| id == b"bext").unwrap() + 8;
+            assert_eq!(u64::from_le_bytes(bytes[bext + 338..bext + 346].try_into().unwrap()),
+                       metadata::time_reference(start, 8000));
+        }
+    }
+
+    #[test]
+    fn test_failed_rotation_is_retried_on_the_writer_thread() {
+        // Fails the creations numbered in `failures`, counting the first
+        // file's as 0, and returns the files written and write_failed
+        let record = |failures: &'static [usize]| {
+            let temp_dir = tempdir().unwrap();
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("ROTATE_SECONDS", "1"),
+                ("FILE_CREATE_ATTEMPTS", "2"),
+                ("FILE_CREATE_RETRY_MS", "1"),
+            ]);
+            let opened = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let open_segment: OpenSegment = Arc::new(move |plan: &SegmentPlan, clock: &dyn Clock| {
+                let index = opened.fetch_add(1, Ordering::Relaxed);
+                // Only the first file is created before the device starts
+                if index > 0 {
+                    assert_eq!(thread::current().name(), Some(capture::WRITER_THREAD_NAME));
+                }
+                match failures.contains(&index) {
+                    true => Err(BlackboxError::Io(io::Error::other("share went away"))),
+                    false => plan.open(clock),
+                }
+            });
+            // 3.5 s, so four files
+            let samples: Vec<i16> = (0..28000).map(|i| (i / 8000) as i16).collect();
+            let backend = MockBackend::new(8000, 1, samples);
+            let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+                .with_backend(Box::new(backend))
+                .with_segment_opener(open_segment);
+            let files = recorder.record_for(Duration::ZERO).unwrap();
+            let contents: Vec<Vec<i16>> = files.iter()
+                .map(|file| hound::WavReader::open(file).unwrap().samples().map(Result::unwrap).collect())
+                .collect();
+            (contents, recorder.write_failed())
+        };
+
+        // A retry that succeeds loses nothing
+        let (files, write_failed) = record(&[1]);
+        assert!(!write_failed);
+        assert_eq!(files.len(), 4);
+        for (second, file) in files.iter().enumerate() {
+            assert!(file.iter().all(|&s| s == second as i16));
+        }
+
+        // One that doesn't loses that file's second, and the next file
+        // starts on time
+        let (files, write_failed) = record(&[1, 2]);
+        assert!(write_failed);
+        let firsts: Vec<i16> = files.iter().map(|file| file[0]).collect();
+        assert_eq!(firsts, vec![0, 2, 3]);
+        assert_eq!(files.iter().map(Vec::len).collect::<Vec<_>>(), vec![8000, 8000, 4000]);
+    }
+
+    #[test]
+    fn test_recording_is_written_on_the_writer_thread() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("REALTIME_PRIORITY", "true"),
+        ]);
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let noted = Arc::clone(&names);
+        let open_segment: OpenSegment = Arc::new(move |plan: &SegmentPlan, clock: &dyn Clock| {
+            Ok(Box::new(ThreadNames(plan.open(clock)?, Arc::clone(&noted))))
+        });
+        let backend = MockBackend::new(8000, 1, vec![100; 8000]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+            .with_backend(Box::new(backend))
+            .with_segment_opener(open_segment);
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(hound::WavReader::open(&files[0]).unwrap().duration(), 8000);
+
+        // Whether or not real-time priority was allowed here
+        let names = names.lock().unwrap();
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|name| name.as_deref() == Some(capture::WRITER_THREAD_NAME)));
+    }
+
+    #[test]
+    fn test_tone_source_records_in_real_time() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0,1"),
+            ("SAMPLE_RATE", "8000"),
+            ("SOURCE", "tone"),
+        ]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap());
+        let files = recorder.record_for(Duration::from_millis(300)).unwrap();
+        assert_eq!(files.len(), 1);
+
+        let reader = hound::WavReader::open(&files[0]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 8000);
+        let frames = reader.duration() as u64;
+        assert!((2000..=3600).contains(&frames), "{} frames in 300 ms", frames);
+        assert_eq!(std::fs::metadata(&files[0]).unwrap().len(), 44 + frames * 4);
+        assert!(crate::info::RecordingInfo::from_path(&files[0]).unwrap().peak > 0.4);
+    }
+
+    #[test]
+    fn test_control_message() {
+        let keys = HashMap::from([("q".to_string(), ControlAction::Stop), ("m".to_string(), ControlAction::Marker)]);
+        assert_eq!(control_message("q", &keys, 1), ControlMessage::StopRecording);
+        assert_eq!(control_message(" m ", &keys, 3), ControlMessage::AddMarker("Marker 3".to_string()));
+        assert_eq!(control_message("", &keys, 2), ControlMessage::AddMarker("Marker 2".to_string()));
+        assert_eq!(control_message("Chorus", &keys, 4), ControlMessage::AddMarker("Chorus".to_string()));
+        assert_eq!(control_message("q", &HashMap::new(), 1), ControlMessage::AddMarker("q".to_string()));
+        let keys = HashMap::from([("s".to_string(), ControlAction::Start)]);
+        assert_eq!(control_message("s", &keys, 1), ControlMessage::StartRecording);
+    }
+
+    #[test]
+    fn test_control_keys_stop_recording() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("CONTROL_KEYS", "q=stop,m=marker"),
+        ]);
+        let backend = MockBackend::new(8000, 1, vec![100; 800]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.start_recording().unwrap();
+
+        recorder.listen_for_markers(io::Cursor::new("Verse\nm\nq\nAfter stop\n"));
+        for _ in 0..100 {
+            if recorder.stop_requested() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(recorder.stop_requested());
+
+        let files = recorder.stop_recording().unwrap();
+        let contents = String::from_utf8_lossy(&std::fs::read(&files[0]).unwrap()).into_owned();
+        assert!(contents.contains("Verse") && contents.contains("Marker 2"));
+        assert!(!contents.contains("After stop"));
+    }
+
+    #[test]
+    fn test_sample_rate_override() {
+        let temp_dir = tempdir().unwrap();
+        let record_at = |rate: &'static str| {
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("SAMPLE_RATE", rate),
+            ]);
+            let backend = MockBackend { other_sample_rates: vec![48000, 96000], ..MockBackend::new(44100, 1, vec![100; 4800]) };
+            AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend))
+                .record_for(Duration::ZERO)
+        };
+
+        let files = record_at("48000").unwrap();
+        assert_eq!(hound::WavReader::open(&files[0]).unwrap().spec().sample_rate, 48000);
+
+        match record_at("22050") {
+            Err(BlackboxError::Config(msg)) => assert_eq!(msg, "Mock Input can't record at 22050 Hz"),
+            _ => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn test_describe_channels() {
+        let labels = vec!["Kick".to_string(), "".to_string()];
+        assert_eq!(describe_channels(&[2, 0, 1], &[2, 0, 1], &labels), "ch2: Kick, ch0, ch1");
+        assert_eq!(describe_channels(&[2], &[2, 0, 1], &labels), "ch2: Kick");
+    }
+
+    #[test]
+    fn test_devices_write_separate_tagged_files() {
+        let temp_dir = tempdir().unwrap();
+        let clock = MockClock::new(Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 0).unwrap());
+
+        // Two devices, each with its own capture, fed different audio
+        let mut files = Vec::new();
+        for (name, value) in [("Scarlett 18i20", 100i16), ("MOTU 828-es", -200)] {
+            let plan = SegmentPlan {
+                dir: temp_dir.path().to_path_buf(),
+                tag: Some(file_tag(name)),
+                output_mode: OutputMode::File,
+                channels: vec![0, 1],
+                sample_rate: 48000,
+                bits_per_sample: 16,
+                buffer_bytes: 8192,
+                flush_frames: 0,
+                timestamp_tz: TimestampZone::Local,
+                split_naming: SplitNaming::Channel,
+                split_index_width: 2,
+                labels: Vec::new(),
+                mono_to_stereo: false,
+                level_envelope: false,
+                clip_warn_percent: None,
+                timestamp_interval_frames: 0,
+                monitor_mix: None,
+                current_files: Default::default(),
+                events: EventLog::default(),
+            };
+            let processor = plan.open(&clock).unwrap();
+            let mut capture = Capture::new(&[0, 1], 2, processor, false);
+            capture.process(&[value; 64], |s| (s as i32) << 16);
+            files.extend(capture.finish().unwrap());
+        }
+
+        let names: Vec<String> = files.iter()
+            .map(|f| Path::new(f).file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["2024-06-26-09-05-Scarlett_18i20.wav", "2024-06-26-09-05-MOTU_828_es.wav"]);
+
+        for (file, expected) in files.iter().zip([100i16, -200]) {
+            let mut reader = hound::WavReader::open(file).unwrap();
+            let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+            assert_eq!(samples, vec![expected; 64]);
+        }
+    }
+
+    #[test]
+    fn test_event_log_records_silent_deletion() {
+        let temp_dir = tempdir().unwrap();
+        let log_path = temp_dir.path().join("events.jsonl");
+        let clock = MockClock::new(Local.with_ymd_and_hms(2024, 6, 26, 9, 5, 0).unwrap());
+        let plan = SegmentPlan {
+            dir: temp_dir.path().to_path_buf(),
+            tag: None,
+            output_mode: OutputMode::File,
+            channels: vec![0, 1],
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            buffer_bytes: 8192,
+            flush_frames: 0,
+            timestamp_tz: TimestampZone::Local,
+            split_naming: SplitNaming::Channel,
+            split_index_width: 2,
+            labels: Vec::new(),
+            mono_to_stereo: false,
+            level_envelope: false,
+            clip_warn_percent: None,
+            timestamp_interval_frames: 0,
+            monitor_mix: None,
+            current_files: Default::default(),
+            events: EventLog::new(&log_path),
+        };
+
+        let mut capture = Capture::new(&[0, 1], 2, plan.open(&clock).unwrap(), false);
+        capture.process(&[0i16; 4800], |s| (s as i32) << 16);
+        let files = capture.finish().unwrap();
+        let policy = SilencePolicy { threshold: 0.001, ..Default::default() };
+        assert!(silence::check_and_delete_silent_files(files.clone(), &policy, &plan.events).is_empty());
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let path = format!("\"path\":\"{}\"", files[0]);
+        let events: Vec<&str> = log.lines()
+            .filter(|line| line.contains(&path))
+            .map(|line| line.split("\"event\":\"").nth(1).unwrap().split('"').next().unwrap())
+            .collect();
+        assert_eq!(events, vec!["created", "deleted"]);
+    }
+
+    #[test]
+    fn test_nothing_is_written_before_recording_starts() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0,1"),
+            ("OUTPUT_MODE", "split"),
+        ]);
+        let backend = MockBackend::new(8000, 2, vec![100; 1600]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+
+        // Armed, the device is metered but nothing reaches the disk
+        recorder.arm().unwrap();
+        assert!(recorder.is_armed() && !recorder.is_recording());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        recorder.start_recording().unwrap();
+        assert!(recorder.is_recording());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| Path::new(file).exists()));
+    }
+
+    #[test]
+    fn test_channel_mismatch_policies() {
+        assert!(matches!(resolve_channels(&[0, 5, 1], 2, ChannelMismatch::Error, "Mic"),
+                         Err(BlackboxError::ChannelOutOfRange { requested: 5, available: 2 })));
+        assert_eq!(resolve_channels(&[0, 5, 1], 2, ChannelMismatch::Skip, "Mic").unwrap(), vec![0, 1]);
+        assert_eq!(resolve_channels(&[0, 5, 1], 2, ChannelMismatch::Warn, "Mic").unwrap(), vec![0, 1]);
+        // Nothing left to record is an error whatever the policy
+        assert!(resolve_channels(&[4, 5], 2, ChannelMismatch::Skip, "Mic").is_err());
+    }
+
+    #[test]
+    fn test_skipped_channel_gets_no_split_file() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("OUTPUT_MODE", "split"),
+            ("AUDIO_CHANNELS", "1,7"),
+            ("ON_CHANNEL_MISMATCH", "skip"),
+        ]);
+        let backend = MockBackend::new(48000, 2, vec![100; 2 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("-ch1.wav"));
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    /// A device that only appears after it has been looked for a few times.
+    struct AppearingBackend {
+        inner: MockBackend,
+        polls_left: Mutex<usize>,
+    }
+
+    impl AudioBackend for AppearingBackend {
+        fn open_input(&self, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError> {
+            let mut polls_left = self.polls_left.lock().unwrap();
+            if *polls_left > 0 {
+                *polls_left -= 1;
+                return Err(BlackboxError::DeviceNotFound("No input device available".to_string()));
+            }
+            self.inner.open_input(selector)
+        }
+
+        fn start_monitor(&self, name: &str, sample_rate: u32, channels: usize)
+            -> Result<MonitorOutput, BlackboxError> {
+            self.inner.start_monitor(name, sample_rate, channels)
+        }
+    }
+
+    #[test]
+    fn test_wait_for_device() {
+        let temp_dir = tempdir().unwrap();
+        let record = |wait: bool| {
+            let dir = temp_dir.path().to_str().unwrap().to_string();
+            let env = env_from(&[
+                ("OUTPUT_DIR", &dir),
+                ("AUDIO_CHANNELS", "0"),
+                ("WAIT_FOR_DEVICE", &wait.to_string()),
+                ("DEVICE_POLL_MS", "1"),
+            ]);
+            let backend = AppearingBackend {
+                inner: MockBackend::new(8000, 1, vec![100; 800]),
+                polls_left: Mutex::new(3),
+            };
+            AudioRecorder::new(Config::from_sources(&env, &[]).unwrap())
+                .with_backend(Box::new(backend))
+                .record_for(Duration::ZERO)
+        };
+
+        assert!(matches!(record(false), Err(BlackboxError::DeviceNotFound(_))));
+        let files = record(true).unwrap();
+        assert_eq!(hound::WavReader::open(&files[0]).unwrap().duration(), 800);
+    }
+
+    #[test]
+    fn test_status_tracks_the_recording() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[("OUTPUT_DIR", &dir)]);
+        let backend = MockBackend::new(48000, 3, vec![100; 3 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        assert!(recorder.status().is_none());
+        recorder.start_recording().unwrap();
+        recorder.set_planned_duration(Duration::from_secs(60));
+        let first = recorder.status().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let second = recorder.status().unwrap();
+        assert!(second.elapsed > first.elapsed);
+        assert!(second.remaining.unwrap() < first.remaining.unwrap());
+        assert_eq!(second.frames, 4800);
+        assert_eq!(second.bytes_written, 4800 * 2 * 2);
+
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(second.files, files);
+        assert!(recorder.status().is_none());
+    }
+
+    #[test]
+    fn test_session_stats_total_every_recording() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("OUTPUT_MODE", "split"),
+        ]);
+        let backend = MockBackend::new(48000, 3, vec![100; 3 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        assert_eq!(recorder.session_stats(), &SessionStats::default());
+        let mut files = recorder.record_for(Duration::ZERO).unwrap();
+        files.extend(recorder.record_for(Duration::ZERO).unwrap());
+
+        let stats = recorder.session_stats();
+        assert_eq!((stats.recordings, stats.files, stats.frames), (2, 4, 2 * 4800));
+        let bytes: u64 = files.iter().map(|file| std::fs::metadata(file).unwrap().len()).sum();
+        assert_eq!(stats.bytes, bytes);
+        assert_eq!((stats.deleted_silent, stats.write_errors), (0, 0));
+        assert!(stats.summary().starts_with("2 recordings, 4 files, 0:00:00, 0.0 MB"));
+    }
+
+    #[test]
+    fn test_mono_recorded_as_stereo() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "1"),
+            ("MONO_TO_STEREO", "true"),
+        ]);
+        let backend = MockBackend::new(48000, 2, (0..2 * 4800).map(|i| i as i16).collect());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        let mut reader = hound::WavReader::open(&files[0]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        let expected: Vec<i16> = (0..4800).flat_map(|frame| [2 * frame as i16 + 1; 2]).collect();
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn test_sync_tone_starts_each_file() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0,1"),
+            ("SYNC_TONE", "true"),
+            ("SYNC_TONE_MS", "50"),
+            ("SYNC_TONE_CHANNEL", "1"),
+        ]);
+        let backend = MockBackend::new(48000, 2, vec![100; 2 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        let mut reader = hound::WavReader::open(&files[0]).unwrap();
+        let samples: Vec<i32> = reader.samples::<i16>().map(|s| s.unwrap() as i32).collect();
+        let tone = sync::tone(48000, 1000, 50, 16);
+        assert_eq!(samples.len(), (tone.len() + 4800) * 2);
+        let (left, right): (Vec<i32>, Vec<i32>) = samples[..tone.len() * 2].chunks(2).map(|f| (f[0], f[1])).unzip();
+        assert!(left.iter().all(|&s| s == 0));
+        for (recorded, expected) in right.iter().zip(&tone) {
+            assert!((recorded - expected).abs() <= 1);
+        }
+        assert!(samples[tone.len() * 2..].iter().all(|&s| s == 100));
+    }
+
+    #[test]
+    fn test_sync_tone_shifts_markers_and_start_times() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0"),
+            ("ROTATE_SECONDS", "1"),
+            ("SYNC_TONE", "true"),
+            ("SYNC_TONE_MS", "100"),
+            ("SAMPLE_ACCURATE_START", "true"),
+            ("WRITE_BEXT", "true"),
+        ]);
+        // 1.5 s, so the second file holds half a second
+        let backend = MockBackend::new(8000, 1, vec![100; 12000]);
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        recorder.start_recording().unwrap();
+        let capture = Arc::clone(&recorder.session.as_ref().unwrap().devices[0].capture);
+        assert_eq!(capture.lock().unwrap().add_marker("clap"), 12000);
+        let files = recorder.stop_recording().unwrap();
+        assert_eq!(files.len(), 2);
+
+        // 800 frames of tone come first in each file, so the marker is that
+        // much further in, and each file starts 100 ms before its audio
+        let bytes = std::fs::read(&files[1]).unwrap();
+        let cue = bytes.windows(4).position(|id| id == b"cue ").unwrap() + 8;
+        assert_eq!(u32::from_le_bytes(bytes[cue + 8..cue + 12].try_into().unwrap()), 4000 + 800);
+        let start = DateTime::parse_from_rfc3339(MOCK_CAPTURE_START).unwrap().with_timezone(&Local)
+            - TimeDelta::milliseconds(100);
+        for (file, start) in files.iter().zip([start, start + TimeDelta::seconds(1)]) {
+            let sidecar = std::fs::read_to_string(timestamps::start_path(Path::new(file))).unwrap();
+            assert!(sidecar.contains(&start.to_rfc3339_opts(SecondsFormat::Micros, false)));
+            let bytes = std::fs::read(file).unwrap();
+            let bext = bytes.windows(4).position(|id| id == b"bext").unwrap() + 8;
+            assert_eq!(u64::from_le_bytes(bytes[bext + 338..bext + 346].try_into().unwrap()),
+                       metadata::time_reference(start, 8000));
+        }
+    }
+
+    #[test]
+    fn test_split_files_named_by_label() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("OUTPUT_MODE", "split"),
+            ("SPLIT_NAMING", "label"),
+            ("AUDIO_CHANNELS", "2,0"),
+            ("CHANNEL_LABELS", "Kick In,"),
+            ("WRITE_INFO_TAGS", "true"),
+        ]);
+        let backend = MockBackend::new(48000, 3, vec![100; 3 * 4800]);
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[0].ends_with("-Kick_In.wav"));
+        assert!(files[1].ends_with("-ch0.wav"));
+        // The INFO tags still record which device channel the label names
+        let contents = String::from_utf8_lossy(&std::fs::read(&files[0]).unwrap()).into_owned();
+        assert!(contents.contains("ch2: Kick In"));
+    }
+
+    #[test]
+    fn test_split_files_named_by_index() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("OUTPUT_MODE", "split"),
+            ("SPLIT_NAMING", "index"),
+            ("AUDIO_CHANNELS", "2,0,1"),
+            ("CHANNEL_THRESHOLDS", "1:0.005"),
+        ]);
+        // Three channels carrying 0, 100 and 200
+        let backend = MockBackend::new(48000, 3, (0..3 * 4800).map(|i| (i % 3) as i16 * 100).collect());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        // input03 holds channel 1, which is below its own threshold
+        let suffixes: Vec<&str> = files.iter().map(|file| &file[file.len() - 12..]).collect();
+        assert_eq!(suffixes, vec!["_input01.wav", "_input02.wav"]);
+        let first: Vec<i16> = hound::WavReader::open(&files[0]).unwrap().samples::<i16>().map(|s| s.unwrap()).collect();
+        assert!(first.iter().all(|&s| s == 200));
+    }
+
+    #[test]
+    fn test_monitor_mix_file() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("AUDIO_CHANNELS", "0-3"),
+            ("MONITOR_MIX", "0,1:2,3"),
+        ]);
+        // Four channels carrying 100, 300, -1000 and 30000
+        let backend = MockBackend::new(48000, 4, (0..4 * 4800).map(|i| [100, 300, -1000, 30000][i % 4]).collect());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        let files = recorder.record_for(Duration::ZERO).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[1].ends_with("-mix.wav"));
+        assert_eq!(hound::WavReader::open(&files[0]).unwrap().spec().channels, 4);
+        let mut reader = hound::WavReader::open(&files[1]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        let mix: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(mix.len(), 2 * 4800);
+        assert!(mix.chunks(2).all(|frame| frame == [200, 14500]));
+    }
+
+    #[test]
+    fn test_device_without_inputs() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        let env = env_from(&[
+            ("OUTPUT_DIR", &dir),
+            ("ON_CHANNEL_MISMATCH", "skip"),
+        ]);
+        let backend = MockBackend::new(48000, 0, Vec::new());
+
+        let mut recorder = AudioRecorder::new(Config::from_sources(&env, &[]).unwrap()).with_backend(Box::new(backend));
+        match recorder.start_recording() {
+            Err(BlackboxError::DeviceNotFound(msg)) => assert_eq!(msg, "Mock Input has no input channels"),
+            other => panic!("expected DeviceNotFound, got {:?}", other.map(|_| ())),
+        }
+        assert!(!recorder.is_recording());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_validate_channels() {
+        assert!(validate_channels(&[0, 1], 2).is_ok());
+        assert!(matches!(validate_channels(&[0, 5], 2),
+                         Err(BlackboxError::ChannelOutOfRange { requested: 5, available: 2 })));
+    }
+}
@@ -0,0 +1,253 @@
+//! Recovering recordings left unfinished by a crash or power loss.
+//!
+//! A WAV file's header gives the size of the file and of its audio, and is
+//! only brought up to date when the file is finalized (or flushed, see
+//! `FLUSH_INTERVAL_SECS`). A file cut off before then holds its audio but
+//! claims to hold less, often none at all. Repair takes the audio to run to
+//! the end of the file and rewrites the sizes to match.
+//!
+//! `VERIFY_ON_FINALIZE` checks the other way round: that a file which was
+//! finalized really holds all the audio its header claims.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::BlackboxError;
+use crate::events::EventLog;
+
+/// Appended to the name of a file that failed verification.
+const CORRUPT_SUFFIX: &str = ".corrupt";
+
+/// The suffix of a file still being recorded, for writers that record under
+/// a temporary name.
+const RECORDING_SUFFIX: &str = ".recording.wav";
+
+/// Where a WAV file's audio starts, and how big a frame is.
+struct DataChunk {
+    /// Offset of the data chunk's size field.
+    size_offset: u64,
+    block_align: u64,
+}
+
+/// Finds the data chunk of a RIFF WAVE file.
+fn find_data_chunk(file: &mut File, file_len: u64) -> Result<DataChunk, BlackboxError> {
+    let not_wav = |why: &str| BlackboxError::Corrupt(format!("Not a repairable WAV file: {}", why));
+    let mut header = [0u8; 12];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header).map_err(|_| not_wav("too short"))?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(not_wav("no RIFF WAVE header"));
+    }
+
+    let mut block_align = None;
+    let mut offset = 12;
+    while offset + 8 <= file_len {
+        let mut chunk_header = [0u8; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk_header)?;
+        let size = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as u64;
+        match &chunk_header[0..4] {
+            b"fmt " => {
+                // Format, channels, sample rate and byte rate come first
+                let mut fmt = [0u8; 14];
+                file.read_exact(&mut fmt).map_err(|_| not_wav("truncated fmt chunk"))?;
+                block_align = Some(u16::from_le_bytes([fmt[12], fmt[13]]) as u64);
+            },
+            b"data" => {
+                let block_align = block_align.filter(|&align| align > 0)
+                    .ok_or_else(|| not_wav("no fmt chunk before the data"))?;
+                return Ok(DataChunk { size_offset: offset + 4, block_align });
+            },
+            _ => {},
+        }
+        offset += 8 + size + size % 2;
+    }
+    Err(not_wav("no data chunk"))
+}
+
+/// Whether the header's RIFF size matches the length of the file, as it does
+/// for any file that was finalized.
+fn is_complete(file: &mut File, file_len: u64) -> Result<bool, BlackboxError> {
+    let mut riff_size = [0u8; 4];
+    file.seek(SeekFrom::Start(4))?;
+    file.read_exact(&mut riff_size)?;
+    Ok(u32::from_le_bytes(riff_size) as u64 + 8 == file_len)
+}
+
+/// Repairs the WAV file at `path` if its header doesn't match its length:
+/// everything after the start of the audio is taken to be audio, a partly
+/// written last frame is cut off, and the sizes in the header are rewritten.
+/// A file named `*.recording.wav` is then renamed to `*.wav`.
+///
+/// Returns the path of the repaired file, or `None` if it was already
+/// complete.
+pub fn repair_recording_file(path: &Path) -> Result<Option<PathBuf>, BlackboxError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+    if is_complete(&mut file, file_len)? {
+        return Ok(None);
+    }
+
+    let data = find_data_chunk(&mut file, file_len)?;
+    let data_start = data.size_offset + 4;
+    // RIFF sizes are 32-bit, so anything past 4 GiB can't be kept
+    let max_len = u32::MAX as u64 - (data_start - 8);
+    let data_len = file_len.saturating_sub(data_start).min(max_len) / data.block_align * data.block_align;
+    file.set_len(data_start + data_len)?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&((data_start + data_len - 8) as u32).to_le_bytes())?;
+    file.seek(SeekFrom::Start(data.size_offset))?;
+    file.write_all(&(data_len as u32).to_le_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    let name = path.to_string_lossy();
+    match name.strip_suffix(RECORDING_SUFFIX) {
+        Some(stem) => {
+            let repaired = PathBuf::from(format!("{}.wav", stem));
+            fs::rename(path, &repaired)?;
+            Ok(Some(repaired))
+        },
+        None => Ok(Some(path.to_path_buf())),
+    }
+}
+
+/// Checks that the finalized WAV file at `path` can be opened and holds as
+/// many frames as its header claims.
+pub fn verify_recording_file(path: &Path) -> Result<(), BlackboxError> {
+    let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let frame_bytes = spec.channels as u64 * spec.bits_per_sample.div_ceil(8) as u64;
+    let expected = reader.duration() as u64 * frame_bytes;
+    drop(reader);
+
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let data = find_data_chunk(&mut file, file_len)?;
+    let present = file_len.saturating_sub(data.size_offset + 4);
+    if present < expected {
+        return Err(BlackboxError::Corrupt(format!("Truncated audio: {} of {} bytes present", present, expected)));
+    }
+    Ok(())
+}
+
+/// Verifies each of `files`, renaming any that fail to `*.corrupt` so they
+/// are kept for inspection but not taken for a good recording. Returns the
+/// files that passed.
+pub fn verify_finalized_files(files: Vec<String>, events: &EventLog) -> Vec<String> {
+    files.into_iter()
+        .filter(|file| {
+            let error = match verify_recording_file(Path::new(file)) {
+                Ok(()) => return true,
+                Err(e) => e,
+            };
+            let corrupt = format!("{}{}", file, CORRUPT_SUFFIX);
+            eprintln!("Error: {} failed verification ({}), keeping it as {}", file, error, corrupt);
+            events.failed(file, "verify", &error);
+            if let Err(e) = fs::rename(file, &corrupt) {
+                eprintln!("Failed to rename {}: {}", file, e);
+            }
+            false
+        })
+        .collect()
+}
+
+/// Repairs every incomplete WAV file in `dir`, returning the repaired paths.
+/// A file that can't be repaired is reported and left alone.
+pub fn repair_directory(dir: &Path) -> Result<Vec<PathBuf>, BlackboxError> {
+    let mut repaired = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext != "wav").unwrap_or(true) || !path.is_file() {
+            continue;
+        }
+        match repair_recording_file(&path) {
+            Ok(Some(path)) => repaired.push(path),
+            Ok(None) => {},
+            Err(e) => eprintln!("Failed to repair {}: {}", path.display(), e),
+        }
+    }
+    repaired.sort();
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use tempfile::tempdir;
+
+    /// Writes a stereo WAV file the way a crash leaves it: samples on disk,
+    /// but the header never updated, plus half a frame.
+    fn write_unfinished(path: &Path, frames: i16) {
+        let spec = wav_spec(2, 48000);
+        let mut writer = hound::WavWriter::new(File::create(path).unwrap(), spec).unwrap();
+        for i in 0..frames * 2 {
+            writer.write_sample(i).unwrap();
+        }
+        std::mem::forget(writer);
+        OpenOptions::new().append(true).open(path).unwrap().write_all(&[1, 2]).unwrap();
+    }
+
+    #[test]
+    fn test_repair_unfinished_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("2024-06-26-09-05.recording.wav");
+        write_unfinished(&path, 100);
+        assert_eq!(hound::WavReader::open(&path).unwrap().duration(), 0);
+
+        let repaired = repair_recording_file(&path).unwrap().unwrap();
+        assert_eq!(repaired, temp_dir.path().join("2024-06-26-09-05.wav"));
+        assert!(!path.exists());
+
+        let mut reader = hound::WavReader::open(&repaired).unwrap();
+        assert_eq!(reader.duration(), 100);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, (0..200).collect::<Vec<i16>>());
+
+        // Once repaired, there is nothing left to do
+        assert_eq!(repair_recording_file(&repaired).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_finalized_files() {
+        let temp_dir = tempdir().unwrap();
+        let spec = wav_spec(2, 48000);
+        let paths: Vec<String> = ["good.wav", "bad-header.wav", "truncated.wav"].iter()
+            .map(|name| temp_dir.path().join(name).to_string_lossy().into_owned())
+            .collect();
+        for path in &paths {
+            let mut writer = hound::WavWriter::create(path, spec).unwrap();
+            for i in 0..200 {
+                writer.write_sample(i as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        let mut header = OpenOptions::new().write(true).open(&paths[1]).unwrap();
+        header.write_all(b"JUNK").unwrap();
+        let truncated = OpenOptions::new().write(true).open(&paths[2]).unwrap();
+        truncated.set_len(truncated.metadata().unwrap().len() - 40).unwrap();
+
+        let log_path = temp_dir.path().join("events.jsonl");
+        let verified = verify_finalized_files(paths.clone(), &EventLog::new(&log_path));
+        assert_eq!(verified, vec![paths[0].clone()]);
+        for path in &paths[1..] {
+            assert!(!Path::new(path).exists());
+            assert!(Path::new(&format!("{}.corrupt", path)).exists());
+        }
+        assert_eq!(fs::read_to_string(&log_path).unwrap().matches("\"failed\"").count(), 2);
+    }
+
+    #[test]
+    fn test_repair_directory_skips_complete_files() {
+        let temp_dir = tempdir().unwrap();
+        write_unfinished(&temp_dir.path().join("crashed.wav"), 10);
+        let spec = wav_spec(1, 8000);
+        hound::WavWriter::create(temp_dir.path().join("complete.wav"), spec).unwrap().finalize().unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), b"RIFF").unwrap();
+
+        let repaired = repair_directory(temp_dir.path()).unwrap();
+        assert_eq!(repaired, vec![temp_dir.path().join("crashed.wav")]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Keeping the number of recordings on disk bounded by deleting the oldest.
+
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::envelope;
+use crate::timestamps;
+
+/// Deletes the oldest recordings in `dir` so that at most `keep` remain, and
+/// returns the paths deleted. Only files named like our recordings
+/// (`YYYY-MM-DD-HH-MM.wav` or `.wv`, maybe compressed to `.gz` or `.zst`,
+/// with a `Z` after the time if in UTC,
+/// optionally with a device tag, a `-N` collision index and a `-ch{n}` or
+/// `_input{nn}` split-mode channel) are counted or touched; anything else in the
+/// directory is left alone. A file kept beside its compressed copy, as with
+/// `COMPRESS_KEEP_ORIGINAL`, counts as one recording with it.
+pub fn prune_recordings(dir: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let mut recordings: HashMap<String, (_, Vec<PathBuf>)> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if let Some(key) = recording_order(&file_name) {
+            let uncompressed = strip_compression(&file_name).to_string();
+            recordings.entry(uncompressed).or_insert_with(|| (key, Vec::new())).1.push(entry.path());
+        }
+    }
+    if recordings.len() <= keep {
+        return Ok(Vec::new());
+    }
+
+    let mut recordings: Vec<_> = recordings.into_iter().map(|(name, (key, paths))| (key, name, paths)).collect();
+    recordings.sort();
+    let excess = recordings.len() - keep;
+    let mut deleted = Vec::new();
+    for (_, _, paths) in recordings.into_iter().take(excess) {
+        for path in paths {
+            fs::remove_file(&path)?;
+            checksum::remove_checksum(&path);
+            envelope::remove_envelope(&path);
+            timestamps::remove_timestamps(&path);
+            deleted.push(path);
+        }
+    }
+    deleted.sort();
+    Ok(deleted)
+}
+
+/// `file_name` without a `.gz` or `.zst` extension.
+fn strip_compression(file_name: &str) -> &str {
+    file_name.strip_suffix(".gz").or_else(|| file_name.strip_suffix(".zst")).unwrap_or(file_name)
+}
+
+/// The start time, collision index and device tag of a recording, from its
+/// file name.
+fn recording_order(file_name: &str) -> Option<(NaiveDateTime, u32, String)> {
+    let file_name = strip_compression(file_name);
+    let mut stem = file_name.strip_suffix(".wav").or_else(|| file_name.strip_suffix(".wv"))?;
+    for separator in ["-ch", "_input"] {
+        if let Some((rest, channel)) = stem.rsplit_once(separator) {
+            if channel.parse::<usize>().is_ok() {
+                stem = rest;
+            }
+        }
+    }
+    let timestamp = NaiveDateTime::parse_from_str(stem.get(..16)?, "%Y-%m-%d-%H-%M").ok()?;
+    // UTC names mark the time with a Z
+    let mut rest = stem[16..].strip_prefix('Z').unwrap_or(&stem[16..]);
+    if rest.is_empty() {
+        return Some((timestamp, 0, String::new()));
+    }
+    rest = rest.strip_prefix('-')?;
+    let (tag, index) = match rest.rsplit_once('-') {
+        Some((tag, index)) if index.parse::<u32>().is_ok() => (tag, index.parse().ok()?),
+        _ => match rest.parse::<u32>() {
+            Ok(index) => ("", index),
+            Err(_) => (rest, 0),
+        },
+    };
+    Some((timestamp, index, tag.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_prune_keeps_newest_recordings() {
+        let temp_dir = tempdir().unwrap();
+        let names = [
+            "2024-06-26-09-05.wav",
+            "2024-06-26-09-05-1.wav",
+            "2024-06-26-09-05-MOTU-2.wav",
+            "2024-06-26-10-00.wav",
+            "2024-06-27-08-00-ch0.wv",
+        ];
+        for name in names.iter().chain(["notes.wav", "2024-06-01-00-00.txt"].iter()) {
+            fs::write(temp_dir.path().join(name), b"").unwrap();
+        }
+
+        let deleted = prune_recordings(temp_dir.path(), 2).unwrap();
+        assert_eq!(deleted.len(), 3);
+
+        let mut remaining: Vec<String> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["2024-06-01-00-00.txt", "2024-06-26-10-00.wav",
+                                   "2024-06-27-08-00-ch0.wv", "notes.wav"]);
+
+        // Already within the limit: nothing more to do
+        assert!(prune_recordings(temp_dir.path(), 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_kept_original_counts_with_its_compressed_copy() {
+        let temp_dir = tempdir().unwrap();
+        let names = [
+            "2024-06-26-09-05.wav",
+            "2024-06-26-09-05.wav.gz",
+            "2024-06-26-10-00.wav",
+            "2024-06-26-10-00.wav.gz",
+            "2024-06-26-11-00.wav.zst",
+        ];
+        for name in names {
+            fs::write(temp_dir.path().join(name), b"").unwrap();
+        }
+
+        // Three recordings, so keeping two deletes both files of the oldest
+        let deleted = prune_recordings(temp_dir.path(), 2).unwrap();
+        assert_eq!(deleted, vec![temp_dir.path().join(names[0]), temp_dir.path().join(names[1])]);
+        assert!(prune_recordings(temp_dir.path(), 2).unwrap().is_empty());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_utc_recordings_are_counted() {
+        assert_eq!(recording_order("2024-06-27-09-00Z-MOTU-1.wav").unwrap(),
+                   (NaiveDateTime::parse_from_str("2024-06-27-09-00", "%Y-%m-%d-%H-%M").unwrap(), 1, "MOTU".to_string()));
+        assert!(recording_order("2024-06-27-09-00Z.wav").is_some());
+        assert!(recording_order("2024-06-27-09-00Z_input01.wav").is_some());
+        assert!(recording_order("2024-06-27-09-00Y.wav").is_none());
+    }
+}
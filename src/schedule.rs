@@ -0,0 +1,124 @@
+//! `SCHEDULE`: the times of day, and days of the week, to record during,
+//! for a recorder left running that should only record at certain hours.
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Weekday};
+
+use crate::error::BlackboxError;
+
+/// A daily window to record during, e.g. 05:00 to 08:00 on weekdays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleWindow {
+    pub start: NaiveTime,
+    /// Earlier than `start` for a window that runs past midnight.
+    pub end: NaiveTime,
+    /// The days the window starts on; empty for every day.
+    pub days: Vec<Weekday>,
+}
+
+impl ScheduleWindow {
+    /// Whether `now` falls in the window. A window running past midnight
+    /// belongs to the day it starts on, so `sat 22:00-02:00` covers the
+    /// first two hours of Sunday.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            starts_on(now.weekday()) && self.start <= time && time < self.end
+        } else {
+            (starts_on(now.weekday()) && time >= self.start)
+                || (starts_on((now - Duration::days(1)).weekday()) && time < self.end)
+        }
+    }
+}
+
+/// Whether `now` falls in any of `windows`.
+pub fn in_schedule(windows: &[ScheduleWindow], now: NaiveDateTime) -> bool {
+    windows.iter().any(|window| window.contains(now))
+}
+
+/// Parses windows separated by `;`, each an optional list of days and a
+/// time range, e.g. `mon-fri 05:00-08:00; sat,sun 18:30-21:00; 22:00-02:00`.
+pub fn parse_schedule(value: &str) -> Result<Vec<ScheduleWindow>, BlackboxError> {
+    value.split(';').map(|window| {
+        let invalid = || BlackboxError::Config(format!("Invalid schedule window: {}", window.trim()));
+        let mut words = window.split_whitespace().rev();
+        let times = words.next().ok_or_else(invalid)?;
+        let days = match (words.next(), words.next()) {
+            (Some(days), None) => parse_days(days).ok_or_else(invalid)?,
+            (None, _) => Vec::new(),
+            _ => return Err(invalid()),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let time = |value: &str| NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| invalid());
+        let (start, end) = (time(start)?, time(end)?);
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(ScheduleWindow { start, end, days })
+    }).collect()
+}
+
+/// Parses days such as `mon,wed` or `mon-fri`, in any mix.
+fn parse_days(value: &str) -> Option<Vec<Weekday>> {
+    let mut days = Vec::new();
+    for part in value.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (first.parse::<Weekday>().ok()?, last.parse::<Weekday>().ok()?);
+                days.push(day);
+                while day != last {
+                    day = day.succ();
+                    days.push(day);
+                }
+            },
+            None => days.push(part.parse().ok()?),
+        }
+    }
+    Some(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// 2024-06-24 is a Monday.
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_weekday_windows() {
+        let schedule = parse_schedule("mon-fri 05:00-08:00; sat,sun 18:30-21:00").unwrap();
+        assert_eq!(schedule[0].days.len(), 5);
+        assert!(in_schedule(&schedule, at(24, 5, 0)));
+        assert!(in_schedule(&schedule, at(28, 7, 59)));
+        assert!(!in_schedule(&schedule, at(24, 8, 0)));
+        assert!(!in_schedule(&schedule, at(29, 6, 0)));
+        assert!(in_schedule(&schedule, at(29, 18, 30)));
+        assert!(!in_schedule(&schedule, at(24, 19, 0)));
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let schedule = parse_schedule("sat 22:00-02:00").unwrap();
+        assert!(in_schedule(&schedule, at(29, 23, 0)));
+        // Sunday morning belongs to Saturday's window
+        assert!(in_schedule(&schedule, at(30, 1, 59)));
+        assert!(!in_schedule(&schedule, at(30, 2, 0)));
+        assert!(!in_schedule(&schedule, at(30, 22, 30)));
+        assert!(!in_schedule(&schedule, at(29, 1, 0)));
+
+        // Every day, with the days left out
+        let schedule = parse_schedule("22:00-02:00").unwrap();
+        assert!(in_schedule(&schedule, at(24, 0, 30)) && in_schedule(&schedule, at(24, 22, 0)));
+        assert!(!in_schedule(&schedule, at(24, 12, 0)));
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        for value in ["", "05:00", "mon 5am-8am", "funday 05:00-08:00", "mon fri 05:00-08:00", "05:00-05:00"] {
+            assert!(matches!(parse_schedule(value), Err(BlackboxError::Config(_))), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! `--selftest`: checks that a unit is ready to record before it is left
+//! unattended.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::{AudioBackend, InputSelector};
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::disk;
+use crate::error::BlackboxError;
+use crate::processor::AudioProcessor;
+use crate::recorder;
+
+/// Runs every check, printing a PASS or FAIL line for each, and returns
+/// whether they all passed.
+pub fn run_selftest(config: &Config) -> bool {
+    let mut results: Vec<bool> = check_input_signals(config).into_iter()
+        .map(|result| report("input signal", result))
+        .collect();
+    results.push(report("output directory writable", disk::check_writable(&config.output_dir).map(|_| String::new())));
+    results.push(report("free disk space", disk::check_disk_space(&config.output_dir, config.min_disk_space_mb)
+        .map(|mb| format!("{} MB free", mb))));
+    results.iter().all(|&passed| passed)
+}
+
+fn report(check: &str, result: Result<String, BlackboxError>) -> bool {
+    match result {
+        Ok(detail) if detail.is_empty() => println!("PASS {}", check),
+        Ok(detail) => println!("PASS {}: {}", check, detail),
+        Err(e) => {
+            println!("FAIL {}: {}", check, e);
+            return false;
+        },
+    }
+    true
+}
+
+/// Checks each input a recording would be made from, as chosen by
+/// `INPUT_DEVICES` and `CAPTURE_MODE`, with one result per input.
+fn check_input_signals(config: &Config) -> Vec<Result<String, BlackboxError>> {
+    let backend = match recorder::default_backend(config) {
+        Ok(backend) => backend,
+        Err(e) => return vec![Err(e)],
+    };
+    recorder::input_selectors(config).into_iter()
+        .map(|(selector, channels)| check_input_signal(backend.as_ref(), config, &selector, channels))
+        .collect()
+}
+
+/// Opens an input for a second and checks every channel to be recorded from
+/// it, `channels` or else those `AUDIO_CHANNELS` picks, carries something
+/// other than digital silence.
+fn check_input_signal(backend: &dyn AudioBackend, config: &Config, selector: &InputSelector,
+                      channels: Option<Vec<usize>>) -> Result<String, BlackboxError> {
+    let mut input = backend.open_input(selector)?;
+    if let Some(sample_rate) = config.sample_rate {
+        input.use_sample_rate(sample_rate)?;
+    }
+    let channels = channels.unwrap_or_else(|| config.channels_for(input.channels()));
+    input.cover_channels(channels.iter().max().map_or(0, |&channel| channel + 1));
+    let device_name = input.name();
+    let total_channels = input.channels();
+    recorder::check_has_inputs(&device_name, total_channels)?;
+    recorder::validate_channels(&channels, total_channels)?;
+
+    let signal = Arc::new(Mutex::new(vec![false; channels.len()]));
+    let processor = SignalProcessor { signal: Arc::clone(&signal) };
+    let capture = Arc::new(Mutex::new(Capture::new(&channels, total_channels, Box::new(processor), false)));
+
+    let stream = input.start(&capture)?;
+    thread::sleep(Duration::from_secs(1));
+    drop(stream);
+    capture.lock().unwrap().finish()?;
+
+    let signal = signal.lock().unwrap();
+    let silent: Vec<usize> = channels.iter().zip(signal.iter())
+        .filter(|(_, &has_signal)| !has_signal)
+        .map(|(&channel, _)| channel)
+        .collect();
+    if !silent.is_empty() {
+        return Err(BlackboxError::Stream(format!("no signal on channels {:?} of {}", silent, device_name)));
+    }
+    Ok(format!("channels {:?} of {}", channels, device_name))
+}
+
+/// Notes which channels have received a non-zero sample.
+struct SignalProcessor {
+    signal: Arc<Mutex<Vec<bool>>>,
+}
+
+impl AudioProcessor for SignalProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        let mut signal = self.signal.lock().unwrap();
+        let channels = signal.len();
+        for frame in samples.chunks(channels) {
+            for (seen, &sample) in signal.iter_mut().zip(frame) {
+                *seen |= sample != 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        Ok(Vec::new())
+    }
+}
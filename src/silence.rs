@@ -0,0 +1,457 @@
+//! Finding recordings that contain nothing but silence, or are too short to
+//! be anything but an accidental start, so they can be thrown away instead of
+//! filling the disk.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use crate::checksum;
+use crate::config::{SilenceAction, SplitSilencePolicy};
+use crate::envelope;
+use crate::error::BlackboxError;
+use crate::events::EventLog;
+use crate::timestamps;
+
+/// RMS level of a WAV file, as a fraction of full scale (0.0 to 1.0).
+///
+/// With `window_secs` set, only the first and last `window_secs` seconds are
+/// read. That is a fast heuristic for long files; `None` reads every sample.
+/// Either way the samples are streamed, so memory use doesn't grow with the
+/// length of the file.
+pub fn file_rms(path: &str, window_secs: Option<f64>) -> Result<f64, BlackboxError> {
+    Ok(file_levels(path, window_secs)?.rms())
+}
+
+/// The RMS and peak levels of a WAV file, read as described for `file_rms`.
+pub fn file_levels(path: &str, window_secs: Option<f64>) -> Result<RmsAccumulator, BlackboxError> {
+    reader_levels(&mut hound::WavReader::open(path)?, window_secs)
+}
+
+/// The RMS and peak levels of the audio in `reader`, which must be at the
+/// start of its samples.
+pub(crate) fn reader_levels<R: Read + Seek>(reader: &mut hound::WavReader<R>,
+                                            window_secs: Option<f64>) -> Result<RmsAccumulator, BlackboxError> {
+    let spec = reader.spec();
+    let frames = reader.duration();
+    let window_frames = window_secs.map(|secs| (secs * spec.sample_rate as f64) as u32);
+
+    let mut rms = RmsAccumulator::default();
+    match window_frames {
+        Some(window) if window.saturating_mul(2) < frames => {
+            let samples = window as usize * spec.channels as usize;
+            accumulate(reader, samples, &mut rms)?;
+            reader.seek(frames - window)?;
+            accumulate(reader, samples, &mut rms)?;
+        },
+        _ => accumulate(reader, usize::MAX, &mut rms)?,
+    }
+    Ok(rms)
+}
+
+/// A running RMS and peak over samples normalized to full scale. Only the
+/// sum of squares is kept, in an `f64`, so it neither grows with the number
+/// of samples nor overflows the way an integer sum of squares can.
+#[derive(Debug, Default, Clone)]
+pub struct RmsAccumulator {
+    sum_of_squares: f64,
+    count: u64,
+    peak: f64,
+}
+
+impl RmsAccumulator {
+    /// Adds one sample, skipping NaN and infinite ones so a corrupt float
+    /// file can't make the whole level NaN.
+    pub fn add(&mut self, sample: f64) {
+        if !sample.is_finite() {
+            return;
+        }
+        self.sum_of_squares += sample * sample;
+        self.count += 1;
+        self.peak = self.peak.max(sample.abs());
+    }
+
+    /// The largest absolute sample added so far.
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+
+    /// The RMS of everything added so far, or 0.0 if nothing was.
+    pub fn rms(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.sum_of_squares / self.count as f64).sqrt()
+    }
+}
+
+/// Feeds up to `limit` normalized samples from `reader` into `rms`.
+fn accumulate<R: Read + Seek>(reader: &mut hound::WavReader<R>, limit: usize,
+                              rms: &mut RmsAccumulator) -> Result<(), BlackboxError> {
+    let spec = reader.spec();
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            for sample in reader.samples::<f32>().take(limit) {
+                rms.add(sample? as f64);
+            }
+        },
+        hound::SampleFormat::Int => {
+            let full_scale = (1u64 << (spec.bits_per_sample - 1)) as f64;
+            for sample in reader.samples::<i32>().take(limit) {
+                rms.add(sample? as f64 / full_scale);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Whether a file's RMS level is below `threshold`.
+pub fn is_silent(path: &str, threshold: f64, window_secs: Option<f64>) -> Result<bool, BlackboxError> {
+    Ok(file_rms(path, window_secs)? < threshold)
+}
+
+/// Which recordings count as silent.
+#[derive(Debug, Clone, Default)]
+pub struct SilencePolicy {
+    /// RMS below which a file is silent; zero disables the check.
+    pub threshold: f64,
+    /// Thresholds for single channels of a split recording, by channel index.
+    pub channel_thresholds: HashMap<usize, f64>,
+    /// Only check the first and last this many seconds.
+    pub window_secs: Option<f64>,
+    pub action: SilenceAction,
+    /// The channel each file of a split recording holds, for names that
+    /// don't say.
+    pub file_channels: HashMap<String, usize>,
+    pub split_policy: SplitSilencePolicy,
+    /// The group each file of a split recording belongs to, named by the
+    /// first file of its segment. With `SplitSilencePolicy::AllOrNothing` a
+    /// group's files are only deleted together.
+    pub file_groups: HashMap<String, String>,
+}
+
+impl SilencePolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0.0 || !self.channel_thresholds.is_empty()
+    }
+
+    /// The threshold for `path`: its channel's own for a file from a split
+    /// recording, otherwise the global one.
+    pub fn threshold_for(&self, path: &str) -> f64 {
+        self.file_channels.get(path).copied()
+            .or_else(|| split_channel(path))
+            .and_then(|channel| self.channel_thresholds.get(&channel))
+            .copied()
+            .unwrap_or(self.threshold)
+    }
+}
+
+/// The channel index of a `-ch{n}.wav` file written in split mode.
+pub fn split_channel(path: &str) -> Option<usize> {
+    let stem = path.strip_suffix(".wav")?;
+    let (_, channel) = stem.rsplit_once("-ch")?;
+    channel.parse().ok()
+}
+
+/// Deletes the files that are silent, recording each deletion in `events`,
+/// and returns the ones that are kept. A file that can't be checked is kept.
+/// With `SilenceAction::Report` the silent files are only reported, with
+/// their levels, and all of them are kept. With
+/// `SplitSilencePolicy::AllOrNothing` a silent file is kept if any other file
+/// of its group isn't silent.
+pub fn check_and_delete_silent_files(files: Vec<String>, policy: &SilencePolicy, events: &EventLog) -> Vec<String> {
+    let silent: Vec<Option<(RmsAccumulator, f64)>> = files.iter().map(|path| silent_levels(path, policy)).collect();
+    let kept_groups: HashSet<&str> = match policy.split_policy {
+        SplitSilencePolicy::PerChannel => HashSet::new(),
+        SplitSilencePolicy::AllOrNothing => files.iter().zip(&silent)
+            .filter(|(_, silent)| silent.is_none())
+            .filter_map(|(path, _)| policy.file_groups.get(path))
+            .map(String::as_str)
+            .collect(),
+    };
+
+    files.into_iter().zip(silent).filter(|(path, silent)| {
+        let Some((levels, threshold)) = silent else {
+            return true;
+        };
+        let detail = format!("rms {:.6}, peak {:.6}, threshold {}", levels.rms(), levels.peak(), threshold);
+        if let Some(group) = policy.file_groups.get(path).filter(|group| kept_groups.contains(group.as_str())) {
+            status!("Keeping silent recording {} with the rest of {}", path, group);
+            events.log("silent", path, &format!("{}, kept with {}", detail, group));
+            return true;
+        }
+        if policy.action == SilenceAction::Report {
+            status!("Silent recording {} would be deleted ({})", path, detail);
+            events.log("silent", path, &detail);
+            return true;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => {
+                checksum::remove_checksum(Path::new(path));
+                envelope::remove_envelope(Path::new(path));
+                timestamps::remove_timestamps(Path::new(path));
+                status!("Deleted silent recording {}", path);
+                events.log("deleted", path, &format!("silent: {}", detail));
+                false
+            },
+            Err(e) => {
+                eprintln!("Failed to delete silent recording {}: {}", path, e);
+                events.failed(path, "delete silent recording", &e.into());
+                true
+            },
+        }
+    }).map(|(path, _)| path).collect()
+}
+
+/// The levels of `path` and the threshold they fall below, if it is silent.
+fn silent_levels(path: &str, policy: &SilencePolicy) -> Option<(RmsAccumulator, f64)> {
+    let threshold = policy.threshold_for(path);
+    if threshold <= 0.0 {
+        return None;
+    }
+    match file_levels(path, policy.window_secs) {
+        Ok(levels) if levels.rms() < threshold => Some((levels, threshold)),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Failed to check {} for silence: {}", path, e);
+            None
+        },
+    }
+}
+
+/// Deletes the files shorter than `min_secs`, recording each deletion in
+/// `events`, and returns the ones that are kept. A file whose length can't
+/// be read is kept.
+pub fn delete_short_files(files: Vec<String>, min_secs: f64, events: &EventLog) -> Vec<String> {
+    files.into_iter().filter(|path| {
+        let secs = match hound::WavReader::open(path) {
+            Ok(reader) => reader.duration() as f64 / reader.spec().sample_rate as f64,
+            Err(e) => {
+                eprintln!("Failed to check the length of {}: {}", path, e);
+                return true;
+            },
+        };
+        if secs >= min_secs {
+            return true;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => {
+                envelope::remove_envelope(Path::new(path));
+                timestamps::remove_timestamps(Path::new(path));
+                status!("Deleted short recording {} ({:.2} s)", path, secs);
+                events.log("deleted", path, &format!("short: {:.3} s, minimum {} s", secs, min_secs));
+                false
+            },
+            Err(e) => {
+                eprintln!("Failed to delete short recording {}: {}", path, e);
+                events.failed(path, "delete short recording", &e.into());
+                true
+            },
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wav_spec;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_wav(path: &Path, sample_rate: u32, samples: &[i16]) -> String {
+        let spec = wav_spec(2, sample_rate);
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_streaming_rms_matches_collected_rms() {
+        let temp_dir = tempdir().unwrap();
+        let samples: Vec<i16> = (0..48000).map(|i| ((i * 37) % 20000 - 10000) as i16).collect();
+        let path = write_wav(&temp_dir.path().join("tone.wav"), 8000, &samples);
+
+        let collected: Vec<i32> = hound::WavReader::open(&path).unwrap()
+            .into_samples().collect::<Result<Vec<i32>, _>>().unwrap();
+        let expected = (collected.iter().map(|&s| (s as f64 / 32768.0).powi(2)).sum::<f64>()
+            / collected.len() as f64).sqrt();
+
+        assert!((file_rms(&path, None).unwrap() - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_large_file_rms_in_chunks() {
+        let temp_dir = tempdir().unwrap();
+        // 10 s of 48 kHz stereo
+        let samples: Vec<i16> = (0..960_000i64).map(|i| ((i * 7919) % 65536 - 32768) as i16).collect();
+        let path = write_wav(&temp_dir.path().join("long.wav"), 48000, &samples);
+
+        let naive = (samples.iter().map(|&s| (s as f64 / 32768.0).powi(2)).sum::<f64>()
+            / samples.len() as f64).sqrt();
+
+        // Pull the file through a fixed-size buffer, as a bounded-memory reader would
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let mut iter = reader.samples::<i16>();
+        let mut chunk = Vec::with_capacity(4096);
+        let mut rms = RmsAccumulator::default();
+        loop {
+            chunk.clear();
+            chunk.extend(iter.by_ref().take(4096).map(|s| s.unwrap()));
+            if chunk.is_empty() {
+                break;
+            }
+            assert!(chunk.capacity() <= 4096);
+            for &s in &chunk {
+                rms.add(s as f64 / 32768.0);
+            }
+        }
+
+        assert!((rms.rms() - naive).abs() < 1e-9);
+        assert!((file_rms(&path, None).unwrap() - naive).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_full_scale_32_bit_does_not_overflow() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("loud32.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Int,
+        };
+        // Each square is about 4.6e18, so an i64 sum overflows after two samples
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..10_000 {
+            writer.write_sample(if i % 2 == 0 { i32::MAX - 1 } else { i32::MIN + 1 }).unwrap();
+        }
+        writer.finalize().unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!((file_rms(path, None).unwrap() - 1.0).abs() < 1e-6);
+        assert!(!is_silent(path, 0.001, None).unwrap());
+    }
+
+    #[test]
+    fn test_non_finite_samples_are_skipped() {
+        let mut rms = RmsAccumulator::default();
+        for sample in [0.5, f64::NAN, -0.5, f64::INFINITY] {
+            rms.add(sample);
+        }
+        assert_eq!(rms.rms(), 0.5);
+        assert_eq!(rms.peak(), 0.5);
+    }
+
+    #[test]
+    fn test_window_only_reads_the_ends() {
+        let temp_dir = tempdir().unwrap();
+        // 1 s of silence, 3 s of signal, 1 s of silence at 1 kHz stereo
+        let mut samples = vec![0i16; 2000];
+        samples.extend(vec![10000i16; 6000]);
+        samples.extend(vec![0i16; 2000]);
+        let path = write_wav(&temp_dir.path().join("middle.wav"), 1000, &samples);
+
+        assert!(!is_silent(&path, 0.01, None).unwrap());
+        assert!(is_silent(&path, 0.01, Some(1.0)).unwrap());
+        // A window covering the whole file falls back to reading all of it
+        assert!(!is_silent(&path, 0.01, Some(3.0)).unwrap());
+    }
+
+    #[test]
+    fn test_check_and_delete_silent_files() {
+        let temp_dir = tempdir().unwrap();
+        let silent = write_wav(&temp_dir.path().join("silent.wav"), 1000, &[0; 2000]);
+        let loud = write_wav(&temp_dir.path().join("loud.wav"), 1000, &[8000; 2000]);
+
+        let policy = SilencePolicy { threshold: 0.001, ..Default::default() };
+        let kept = check_and_delete_silent_files(vec![silent.clone(), loud.clone()], &policy, &EventLog::default());
+        assert_eq!(kept, vec![loud.clone()]);
+        assert!(!Path::new(&silent).exists());
+        assert!(Path::new(&loud).exists());
+    }
+
+    #[test]
+    fn test_short_files_are_deleted() {
+        let temp_dir = tempdir().unwrap();
+        // Stereo at 1000 Hz: half a second and three seconds
+        let short = write_wav(&temp_dir.path().join("short.wav"), 1000, &[8000; 1000]);
+        let long = write_wav(&temp_dir.path().join("long.wav"), 1000, &[8000; 6000]);
+        let log_path = temp_dir.path().join("events.jsonl");
+
+        let kept = delete_short_files(vec![short.clone(), long.clone()], 2.0, &EventLog::new(&log_path));
+        assert_eq!(kept, vec![long.clone()]);
+        assert!(!Path::new(&short).exists());
+        assert!(Path::new(&long).exists());
+        assert!(fs::read_to_string(&log_path).unwrap().contains("short: 0.500 s, minimum 2 s"));
+    }
+
+    #[test]
+    fn test_report_mode_keeps_silent_files() {
+        let temp_dir = tempdir().unwrap();
+        let silent = write_wav(&temp_dir.path().join("silent.wav"), 1000, &[3; 2000]);
+        let log_path = temp_dir.path().join("events.jsonl");
+
+        let policy = SilencePolicy { threshold: 0.001, action: SilenceAction::Report, ..Default::default() };
+        let kept = check_and_delete_silent_files(vec![silent.clone()], &policy, &EventLog::new(&log_path));
+        assert_eq!(kept, vec![silent.clone()]);
+        assert!(Path::new(&silent).exists());
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains("\"event\":\"silent\""));
+        assert!(log.contains("peak 0.000092"));
+    }
+
+    #[test]
+    fn test_per_channel_thresholds_in_split_mode() {
+        let temp_dir = tempdir().unwrap();
+        // Both channels carry the same quiet signal, about 0.003 of full scale
+        let ch0 = write_wav(&temp_dir.path().join("2024-06-26-09-05-ch0.wav"), 1000, &[100; 2000]);
+        let ch1 = write_wav(&temp_dir.path().join("2024-06-26-09-05-ch1.wav"), 1000, &[100; 2000]);
+
+        let policy = SilencePolicy {
+            threshold: 0.01,
+            channel_thresholds: HashMap::from([(0, 0.001)]),
+            window_secs: None,
+            action: SilenceAction::Delete,
+            ..Default::default()
+        };
+        assert_eq!(policy.threshold_for(&ch0), 0.001);
+        assert_eq!(policy.threshold_for(&ch1), 0.01);
+
+        let kept = check_and_delete_silent_files(vec![ch0.clone(), ch1.clone()], &policy, &EventLog::default());
+        assert_eq!(kept, vec![ch0]);
+        assert!(!Path::new(&ch1).exists());
+    }
+
+    #[test]
+    fn test_all_or_nothing_keeps_a_group_with_one_loud_channel() {
+        let temp_dir = tempdir().unwrap();
+        let files: Vec<String> = [0, 8000, 0].iter().enumerate()
+            .map(|(i, &level)| write_wav(&temp_dir.path().join(format!("2024-06-26-09-05-ch{}.wav", i)), 1000, &[level; 2000]))
+            .collect();
+        let group = |files: &[String]| files.iter().map(|file| (file.clone(), files[0].clone())).collect();
+
+        let policy = SilencePolicy {
+            threshold: 0.001,
+            split_policy: SplitSilencePolicy::AllOrNothing,
+            file_groups: group(&files),
+            ..Default::default()
+        };
+        let kept = check_and_delete_silent_files(files.clone(), &policy, &EventLog::default());
+        assert_eq!(kept, files);
+        assert!(files.iter().all(|file| Path::new(file).exists()));
+
+        // A group that is silent throughout is deleted whole
+        let quiet: Vec<String> = (0..3)
+            .map(|i| write_wav(&temp_dir.path().join(format!("2024-06-26-09-10-ch{}.wav", i)), 1000, &[0; 2000]))
+            .collect();
+        let policy = SilencePolicy { file_groups: group(&quiet), ..policy };
+        assert!(check_and_delete_silent_files(quiet.clone(), &policy, &EventLog::default()).is_empty());
+        assert!(quiet.iter().all(|file| !Path::new(file).exists()));
+    }
+}
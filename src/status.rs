@@ -0,0 +1,110 @@
+//! A snapshot of the recording in progress, for `STATUS_FILE` and for
+//! anything else that wants to show it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::OutputMode;
+use crate::events::json_string;
+
+/// Where the current recording has got to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingStatus {
+    pub output_mode: OutputMode,
+    pub elapsed: Duration,
+    /// Until the recording is due to stop, if it has a set length.
+    pub remaining: Option<Duration>,
+    /// The files being written now.
+    pub files: Vec<String>,
+    /// Frames recorded so far, from the first device.
+    pub frames: u64,
+    /// Bytes of audio recorded so far, across every device.
+    pub bytes_written: u64,
+}
+
+impl RecordingStatus {
+    /// The status as one JSON object, with times in seconds.
+    pub fn to_json(&self) -> String {
+        let mode = match self.output_mode {
+            OutputMode::File => "file",
+            OutputMode::Split => "split",
+            OutputMode::Stdout => "stdout",
+        };
+        let remaining = match self.remaining {
+            Some(remaining) => format!("{:.1}", remaining.as_secs_f64()),
+            None => "null".to_string(),
+        };
+        let files: Vec<String> = self.files.iter().map(|file| json_string(file)).collect();
+        format!("{{\"mode\":{},\"elapsed\":{:.1},\"remaining\":{},\"files\":[{}],\"frames\":{},\"bytes_written\":{}}}",
+                json_string(mode), self.elapsed.as_secs_f64(), remaining, files.join(","), self.frames, self.bytes_written)
+    }
+}
+
+/// Totals over every recording a recorder has finished, for a summary when
+/// it shuts down.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionStats {
+    pub recordings: usize,
+    /// Files kept, including proxies, once silent and short ones are gone.
+    pub files: usize,
+    /// Frames recorded, from the device that recorded the most each time.
+    pub frames: u64,
+    /// How long the recordings ran for.
+    pub duration: Duration,
+    /// Size on disk of the files kept.
+    pub bytes: u64,
+    pub deleted_silent: usize,
+    pub write_errors: u64,
+}
+
+impl SessionStats {
+    /// The totals on one line, e.g. `2 recordings, 4 files, 0:10:00, 115.2 MB,
+    /// 1 deleted as silent, 0 write errors`.
+    pub fn summary(&self) -> String {
+        let secs = self.duration.as_secs();
+        format!("{} recording{}, {} file{}, {}:{:02}:{:02}, {:.1} MB, {} deleted as silent, {} write error{}",
+                self.recordings, plural(self.recordings as u64), self.files, plural(self.files as u64),
+                secs / 3600, secs / 60 % 60, secs % 60, self.bytes as f64 / 1_000_000.0,
+                self.deleted_silent, self.write_errors, plural(self.write_errors))
+    }
+}
+
+fn plural(count: u64) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}
+
+/// Replaces the file at `path` with `status`, through a temporary file so a
+/// reader never sees it half written.
+pub fn write_status_file(path: &Path, status: &RecordingStatus) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, status.to_json() + "\n")?;
+    fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_status_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("status.json");
+        let status = RecordingStatus {
+            output_mode: OutputMode::Split,
+            elapsed: Duration::from_millis(1500),
+            remaining: None,
+            files: vec!["a-ch0.wav".to_string(), "a-ch1.wav".to_string()],
+            frames: 72000,
+            bytes_written: 288000,
+        };
+        write_status_file(&path, &status).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "{\"mode\":\"split\",\"elapsed\":1.5,\"remaining\":null,\"files\":[\"a-ch0.wav\",\"a-ch1.wav\"],\
+                    \"frames\":72000,\"bytes_written\":288000}\n");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+}
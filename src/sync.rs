@@ -0,0 +1,102 @@
+//! A sync tone written at the start of each file, for lining recordings up
+//! with video or other recorders by ear or by waveform.
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::error::BlackboxError;
+use crate::processor::{full_scale, AudioProcessor};
+
+/// Peak level of the tone as a fraction of full scale, -6 dBFS.
+const TONE_LEVEL: f64 = 0.5;
+
+/// How long the tone ramps in and out, so it starts and stops without a click.
+const TONE_RAMP_MS: u64 = 5;
+
+/// `ms` milliseconds of a `hz` sine at `sample_rate` and `bits_per_sample`,
+/// ramped in and out.
+pub fn tone(sample_rate: u32, hz: u32, ms: u64, bits_per_sample: u16) -> Vec<i32> {
+    let amplitude = TONE_LEVEL * full_scale(bits_per_sample) as f64;
+    let frames = (sample_rate as u64 * ms / 1000) as usize;
+    let ramp = ((sample_rate as u64 * TONE_RAMP_MS / 1000) as usize).clamp(1, frames.max(2) / 2);
+    (0..frames)
+        .map(|i| {
+            let gain = (i.min(frames - 1 - i) as f64 / ramp as f64).min(1.0);
+            let phase = 2.0 * PI * hz as f64 * i as f64 / sample_rate as f64;
+            (amplitude * gain * phase.sin()).round() as i32
+        })
+        .collect()
+}
+
+/// Writes `tone` ahead of the audio, before the first frame, on every
+/// channel or only the one at position `channel`, with the others silent.
+pub struct SyncToneProcessor {
+    inner: Box<dyn AudioProcessor>,
+    channels: usize,
+    tone: Arc<Vec<i32>>,
+    channel: Option<usize>,
+    written: bool,
+}
+
+impl SyncToneProcessor {
+    pub fn new(inner: Box<dyn AudioProcessor>, channels: usize, tone: Arc<Vec<i32>>, channel: Option<usize>) -> Self {
+        SyncToneProcessor { inner, channels, tone, channel, written: false }
+    }
+}
+
+impl AudioProcessor for SyncToneProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        if !self.written {
+            self.written = true;
+            let frames: Vec<i32> = self.tone.iter()
+                .flat_map(|&sample| (0..self.channels).map(move |position| (position, sample)))
+                .map(|(position, sample)| match self.channel {
+                    Some(channel) if channel != position => 0,
+                    _ => sample,
+                })
+                .collect();
+            self.inner.write_samples(&frames)?;
+        }
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        self.inner.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Collect;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_tone() {
+        let samples = tone(48000, 1000, 100, 16);
+        assert_eq!(samples.len(), 4800);
+        // Ramped from silence, and a sine at full level in the middle
+        assert_eq!(samples[0], 0);
+        assert!(samples[..240].iter().all(|s| s.abs() <= 16384));
+        for (i, &sample) in samples.iter().enumerate().take(2400).skip(240) {
+            let expected = TONE_LEVEL * i16::MAX as f64 * (2.0 * PI * 1000.0 * i as f64 / 48000.0).sin();
+            assert!((sample as f64 - expected).abs() <= 1.0, "sample {} is {}", i, sample);
+        }
+    }
+
+    #[test]
+    fn test_tone_comes_before_the_audio() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let tone = Arc::new(tone(8000, 1000, 10, 16));
+        let mut processor = SyncToneProcessor::new(Box::new(Collect(Arc::clone(&out))), 2, Arc::clone(&tone), Some(1));
+        processor.write_samples(&[7, 7]).unwrap();
+        processor.write_samples(&[8, 8]).unwrap();
+
+        let out = out.lock().unwrap();
+        assert_eq!(out.len(), tone.len() * 2 + 4);
+        let (left, right): (Vec<i32>, Vec<i32>) = out[..tone.len() * 2].chunks(2).map(|f| (f[0], f[1])).unzip();
+        assert!(left.iter().all(|&s| s == 0));
+        assert_eq!(right, *tone);
+        assert_eq!(out[tone.len() * 2..], [7, 7, 8, 8]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Fixtures shared by the unit tests: stand-in processors, settings and WAV
+//! specs.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::BlackboxError;
+use crate::processor::AudioProcessor;
+
+/// Settings for `Config::from_sources` with just these variables set.
+pub fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars.iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name: &str| vars.get(name).cloned()
+}
+
+/// A 16-bit integer WAV format.
+pub fn wav_spec(channels: u16, sample_rate: u32) -> hound::WavSpec {
+    hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+/// Throws away everything it receives.
+pub struct Discard;
+
+impl AudioProcessor for Discard {
+    fn write_samples(&mut self, _samples: &[i32]) -> Result<(), BlackboxError> {
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Keeps every sample it receives.
+pub struct Collect(pub Arc<Mutex<Vec<i32>>>);
+
+impl AudioProcessor for Collect {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.0.lock().unwrap().extend_from_slice(samples);
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Notes the size of each write it receives.
+pub struct WriteSizes(pub Arc<Mutex<Vec<usize>>>);
+
+impl AudioProcessor for WriteSizes {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.0.lock().unwrap().push(samples.len());
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Fails every write, and names the file it would have written.
+pub struct FailingWriter;
+
+impl AudioProcessor for FailingWriter {
+    fn write_samples(&mut self, _samples: &[i32]) -> Result<(), BlackboxError> {
+        Err(BlackboxError::Io(std::io::Error::other("disk full")))
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        Ok(vec!["failing.wav".to_string()])
+    }
+}
+
+/// Passes everything on to another processor, noting the thread each call
+/// is made on.
+pub struct ThreadNames(pub Box<dyn AudioProcessor>, pub Arc<Mutex<Vec<Option<String>>>>);
+
+impl ThreadNames {
+    fn note(&self) {
+        self.1.lock().unwrap().push(thread::current().name().map(str::to_string));
+    }
+}
+
+impl AudioProcessor for ThreadNames {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.note();
+        self.0.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        self.note();
+        self.0.finalize()
+    }
+}
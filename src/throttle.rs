@@ -0,0 +1,88 @@
+//! Keeping a warning that repeats on every callback or write, such as write
+//! errors on a disk gone read-only, from flooding the log.
+
+use std::time::{Duration, Instant};
+
+/// How often a repeating warning is printed at most.
+pub const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prints the first of a run of identical warnings, then at most one summary
+/// per interval counting the ones since, e.g. "Failed to write samples: ...
+/// (1203 write errors in the last 10 s)".
+#[derive(Debug)]
+pub struct ThrottledLogger {
+    /// What's being counted, e.g. "write errors".
+    what: &'static str,
+    interval: Duration,
+    last_logged: Option<Instant>,
+    /// Warnings since the last line printed.
+    suppressed: u64,
+}
+
+impl ThrottledLogger {
+    pub fn new(what: &'static str, interval: Duration) -> Self {
+        ThrottledLogger { what, interval, last_logged: None, suppressed: 0 }
+    }
+
+    /// Prints the warning `message` describes to stderr, unless one was
+    /// printed less than the interval ago.
+    pub fn warn(&mut self, message: impl FnOnce() -> String) {
+        if let Some(line) = self.event(Instant::now(), message) {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Counts a warning at `now`, returning the line to print for it, if
+    /// any. `message` is only called when a line is due.
+    pub fn event(&mut self, now: Instant, message: impl FnOnce() -> String) -> Option<String> {
+        self.suppressed += 1;
+        match self.last_logged {
+            None => {
+                self.last_logged = Some(now);
+                self.suppressed = 0;
+                Some(message())
+            },
+            Some(last) if now.duration_since(last) >= self.interval => {
+                let line = format!("{} ({} {} in the last {} s)", message(), self.suppressed, self.what,
+                                   now.duration_since(last).as_secs());
+                self.last_logged = Some(now);
+                self.suppressed = 0;
+                Some(line)
+            },
+            Some(_) => None,
+        }
+    }
+
+    /// The summary of warnings not yet printed, if there are any, for when
+    /// no more are coming.
+    pub fn flush(&mut self) -> Option<String> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        let line = format!("{} more {} not shown", self.suppressed, self.what);
+        self.suppressed = 0;
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_warnings_are_summarized() {
+        let mut log = ThrottledLogger::new("write errors", WARNING_INTERVAL);
+        let start = Instant::now();
+        // 1000 errors a second for 25 seconds
+        let lines: Vec<String> = (0..25_000u64)
+            .filter_map(|i| log.event(start + Duration::from_millis(i), || "Failed to write samples".to_string()))
+            .collect();
+        assert_eq!(lines, vec![
+            "Failed to write samples".to_string(),
+            "Failed to write samples (10000 write errors in the last 10 s)".to_string(),
+            "Failed to write samples (10000 write errors in the last 10 s)".to_string(),
+        ]);
+        assert_eq!(log.flush(), Some("4999 more write errors not shown".to_string()));
+        assert_eq!(log.flush(), None);
+    }
+}
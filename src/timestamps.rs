@@ -0,0 +1,190 @@
+//! `TIMESTAMP_TRACK`: a record of when each stretch of a recording was
+//! captured, so any sample can be placed in wall-clock time even as the
+//! device's clock drifts from the system's.
+//!
+//! Each file gets `{file}.timestamps.jsonl`, one line every
+//! `TIMESTAMP_TRACK_SECS` of audio:
+//! `{"sample_offset":48000,"monotonic_ns":...,"wallclock":"2024-06-26T09:05:01.000123+02:00"}`.
+//! `sample_offset` counts frames from the start of the file, and the times
+//! are read as the batch holding that frame is written, so within a batch of
+//! it.
+//!
+//! `SAMPLE_ACCURATE_START` adds `{file}.start.json`, when the file's first
+//! sample was captured, by the device's timestamp rather than the clock as
+//! the audio is written.
+
+use chrono::{DateTime, Local, SecondsFormat};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::BlackboxError;
+use crate::metadata;
+use crate::processor::AudioProcessor;
+
+/// Appended to a file's name for its timestamp track.
+pub const TIMESTAMPS_SUFFIX: &str = ".timestamps.jsonl";
+
+/// Where the timestamp track of the file at `path` is kept.
+pub fn timestamps_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(TIMESTAMPS_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Appended to a file's name for the time its first sample was captured.
+pub const START_SUFFIX: &str = ".start.json";
+
+/// Where the start time of the file at `path` is kept.
+pub fn start_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(START_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Writes the start time of the file at `path`, whose first sample at
+/// `sample_rate` was captured at `start`.
+pub fn write_start(path: &Path, start: DateTime<Local>, sample_rate: u32) -> io::Result<()> {
+    fs::write(start_path(path), format!("{{\"start\":\"{}\",\"sample_rate\":{},\"time_reference\":{}}}\n",
+                                        start.to_rfc3339_opts(SecondsFormat::Micros, false), sample_rate,
+                                        metadata::time_reference(start, sample_rate)))
+}
+
+/// Deletes the timestamp track and start time of the file at `path`, if it
+/// has them.
+pub fn remove_timestamps(path: &Path) {
+    for sidecar in [timestamps_path(path), start_path(path)] {
+        match fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("Failed to delete {}: {}", sidecar.display(), e);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Renames the timestamp track and start time of the file at `from` to go
+/// with `to`, the same recording under a new name, such as after compression.
+pub fn move_timestamps(from: &Path, to: &Path) {
+    for (sidecar, moved) in [(timestamps_path(from), timestamps_path(to)), (start_path(from), start_path(to))] {
+        match fs::rename(&sidecar, moved) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("Failed to move {}: {}", sidecar.display(), e);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Nanoseconds on the system's monotonic clock, which never jumps when the
+/// wall clock is set, and is shared by every process on the machine.
+#[cfg(unix)]
+pub fn monotonic_ns() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// Nanoseconds since this process first asked.
+#[cfg(not(unix))]
+pub fn monotonic_ns() -> u64 {
+    static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    EPOCH.get_or_init(std::time::Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Passes the audio on to `inner` while writing a timestamp track for each
+/// of its files. Wrapped around each file, or set of split files, it counts
+/// from the start of each one.
+pub struct TimestampProcessor {
+    inner: Box<dyn AudioProcessor>,
+    channels: usize,
+    interval_frames: u64,
+    frames: u64,
+    /// The frame the next line is due at.
+    next_entry: u64,
+    tracks: Vec<(PathBuf, BufWriter<File>)>,
+}
+
+impl TimestampProcessor {
+    /// Creates a track for each of `files`, the files `inner` writes, with a
+    /// line every `interval_frames` frames.
+    pub fn create(inner: Box<dyn AudioProcessor>, channels: usize, files: &[String], interval_frames: u64)
+        -> Result<Self, BlackboxError> {
+        let mut tracks = Vec::with_capacity(files.len());
+        for file in files {
+            let path = timestamps_path(Path::new(file));
+            let track = BufWriter::new(File::create(&path)?);
+            tracks.push((path, track));
+        }
+        Ok(TimestampProcessor { inner, channels, interval_frames: interval_frames.max(1), frames: 0, next_entry: 0, tracks })
+    }
+}
+
+impl AudioProcessor for TimestampProcessor {
+    fn write_samples(&mut self, samples: &[i32]) -> Result<(), BlackboxError> {
+        self.frames += (samples.len() / self.channels) as u64;
+        if self.next_entry < self.frames {
+            let (monotonic, wallclock) = (monotonic_ns(), Local::now().to_rfc3339_opts(SecondsFormat::Micros, false));
+            while self.next_entry < self.frames {
+                let line = format!("{{\"sample_offset\":{},\"monotonic_ns\":{},\"wallclock\":\"{}\"}}\n",
+                                   self.next_entry, monotonic, wallclock);
+                for (path, track) in &mut self.tracks {
+                    if let Err(e) = track.write_all(line.as_bytes()) {
+                        eprintln!("Failed to write the timestamp track {}: {}", path.display(), e);
+                    }
+                }
+                self.next_entry += self.interval_frames;
+            }
+        }
+        self.inner.write_samples(samples)
+    }
+
+    fn finalize(&mut self) -> Result<Vec<String>, BlackboxError> {
+        let files = self.inner.finalize()?;
+        for (path, track) in &mut self.tracks {
+            if let Err(e) = track.flush() {
+                eprintln!("Failed to write the timestamp track {}: {}", path.display(), e);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Discard;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The numbers after `"sample_offset":` and `"monotonic_ns":` in a line.
+    fn fields(line: &str) -> (u64, u64) {
+        let number = |key: &str| -> u64 {
+            let rest = &line[line.find(key).unwrap() + key.len()..];
+            rest[..rest.find(',').unwrap()].parse().unwrap()
+        };
+        (number("\"sample_offset\":"), number("\"monotonic_ns\":"))
+    }
+
+    #[test]
+    fn test_entries_at_each_interval() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("take.wav").to_str().unwrap().to_string();
+        // A line every 100 frames of stereo, fed 64 frames at a time
+        let mut processor = TimestampProcessor::create(Box::new(Discard), 2, std::slice::from_ref(&file), 100).unwrap();
+        for _ in 0..10 {
+            processor.write_samples(&[0; 128]).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        processor.finalize().unwrap();
+
+        let track = fs::read_to_string(timestamps_path(Path::new(&file))).unwrap();
+        let entries: Vec<(u64, u64)> = track.lines().map(fields).collect();
+        let offsets: Vec<u64> = entries.iter().map(|&(offset, _)| offset).collect();
+        assert_eq!(offsets, [0, 100, 200, 300, 400, 500, 600]);
+        assert!(entries.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        // Entries from different batches were read at different times
+        assert!(entries[0].1 < entries[6].1);
+        assert!(track.lines().all(|line| line.contains("\"wallclock\":\"20")));
+    }
+}
@@ -0,0 +1,198 @@
+//! A generated test signal standing in for an audio device, for checking
+//! the rest of the pipeline (disk, rotation, silence handling, encoding)
+//! on a machine with no input to record.
+
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{ActiveStream, AudioBackend, CpalBackend, InputDevice, InputSelector, MonitorOutput, INPUT_THREAD_NAME};
+use crate::capture::Capture;
+use crate::config::ToneSignal;
+use crate::dither::Dither;
+use crate::error::BlackboxError;
+
+/// Peak level of the signal, -6 dBFS.
+const TONE_AMPLITUDE: f64 = i16::MAX as f64 / 2.0;
+
+/// The rate the tone is generated at unless `SAMPLE_RATE` says otherwise.
+const DEFAULT_TONE_SAMPLE_RATE: u32 = 48000;
+
+/// How many channels the tone has until more are needed.
+const DEFAULT_TONE_CHANNELS: usize = 2;
+
+/// Where a sweep starts, and how long it takes to reach half the sample rate.
+const SWEEP_START_HZ: f64 = 20.0;
+const SWEEP_SECS: f64 = 10.0;
+
+/// How much audio is delivered at a time, like a device's buffer.
+const TONE_BLOCK: Duration = Duration::from_millis(10);
+
+/// Generates one channel's worth of a test signal, a frame at a time.
+pub struct ToneGenerator {
+    signal: ToneSignal,
+    hz: u32,
+    sample_rate: u32,
+    /// Position in the current cycle, in radians.
+    phase: f64,
+    frame: u64,
+    noise: Dither,
+}
+
+impl ToneGenerator {
+    pub fn new(signal: ToneSignal, hz: u32, sample_rate: u32) -> Self {
+        ToneGenerator { signal, hz, sample_rate, phase: 0.0, frame: 0, noise: Dither::new(0x5eed) }
+    }
+
+    /// The next sample, as a 16-bit value.
+    pub fn next_sample(&mut self) -> i16 {
+        let rate = self.sample_rate as f64;
+        let value = match self.signal {
+            ToneSignal::Sine => self.advance(self.hz as f64),
+            ToneSignal::Sweep => {
+                // Exponential, so each octave takes as long as the last
+                let position = (self.frame as f64 / rate) % SWEEP_SECS / SWEEP_SECS;
+                self.advance(SWEEP_START_HZ * (rate / 2.0 / SWEEP_START_HZ).powf(position))
+            },
+            ToneSignal::Noise => self.noise.next_uniform() * 2.0 - 1.0,
+        };
+        self.frame += 1;
+        (TONE_AMPLITUDE * value).round() as i16
+    }
+
+    /// The sine at the current phase, then steps the phase on at `hz`.
+    fn advance(&mut self, hz: f64) -> f64 {
+        let value = self.phase.sin();
+        self.phase = (self.phase + 2.0 * PI * hz / self.sample_rate as f64) % (2.0 * PI);
+        value
+    }
+}
+
+/// Records a test signal in place of every input. Monitoring still plays
+/// through a real output device on `audio_host`.
+pub struct ToneBackend {
+    pub signal: ToneSignal,
+    pub hz: u32,
+    pub audio_host: String,
+}
+
+impl AudioBackend for ToneBackend {
+    fn open_input(&self, selector: &InputSelector) -> Result<Box<dyn InputDevice>, BlackboxError> {
+        let name = match selector {
+            InputSelector::Named(name) => name.clone(),
+            _ => "Test Tone".to_string(),
+        };
+        Ok(Box::new(ToneInput {
+            name,
+            signal: self.signal,
+            hz: self.hz,
+            sample_rate: DEFAULT_TONE_SAMPLE_RATE,
+            channels: DEFAULT_TONE_CHANNELS,
+        }))
+    }
+
+    fn start_monitor(&self, name: &str, sample_rate: u32, channels: usize)
+        -> Result<MonitorOutput, BlackboxError> {
+        CpalBackend::new(&self.audio_host)?.start_monitor(name, sample_rate, channels)
+    }
+}
+
+struct ToneInput {
+    name: String,
+    signal: ToneSignal,
+    hz: u32,
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl InputDevice for ToneInput {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn use_sample_rate(&mut self, sample_rate: u32) -> Result<(), BlackboxError> {
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    fn cover_channels(&mut self, needed: usize) {
+        self.channels = self.channels.max(needed);
+    }
+
+    fn prefer_formats(&mut self, _formats: &[String]) {}
+
+    fn start(self: Box<Self>, capture: &Arc<Mutex<Capture>>) -> Result<Box<dyn ActiveStream>, BlackboxError> {
+        status!("Recording a test tone at {} Hz on {} channels", self.sample_rate, self.channels);
+        let capture = Arc::clone(capture);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new().name(INPUT_THREAD_NAME.to_string()).spawn(move || {
+            let mut generator = ToneGenerator::new(self.signal, self.hz, self.sample_rate);
+            let block_frames = (self.sample_rate as f64 * TONE_BLOCK.as_secs_f64()).ceil() as usize;
+            let mut block = Vec::with_capacity(block_frames * self.channels);
+            let started = Instant::now();
+            let mut frames = 0u64;
+            while !stopped.load(Ordering::Relaxed) {
+                block.clear();
+                for _ in 0..block_frames {
+                    let sample = generator.next_sample();
+                    block.extend(std::iter::repeat_n(sample, self.channels));
+                }
+                capture.lock().unwrap().process(&block, |s| (s as i32) << 16);
+                // Deliver in real time, as a device would
+                frames += block_frames as u64;
+                let due = started + Duration::from_secs_f64(frames as f64 / self.sample_rate as f64);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+        })?;
+        Ok(Box::new(ToneStream { stop, thread: Some(thread) }))
+    }
+}
+
+/// Stops the tone thread when dropped.
+struct ToneStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for ToneStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ActiveStream for ToneStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tone_signals() {
+        let mut sine = ToneGenerator::new(ToneSignal::Sine, 1000, 8000);
+        let samples: Vec<i16> = (0..8).map(|_| sine.next_sample()).collect();
+        assert_eq!(samples[0], 0);
+        assert_eq!(samples[2], TONE_AMPLITUDE.round() as i16);
+        assert_eq!(samples[6], -TONE_AMPLITUDE.round() as i16);
+
+        for signal in [ToneSignal::Sweep, ToneSignal::Noise] {
+            let mut generator = ToneGenerator::new(signal, 1000, 8000);
+            let samples: Vec<i16> = (0..8000).map(|_| generator.next_sample()).collect();
+            let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+            assert!(peak > i16::MAX as u16 / 4 && peak <= TONE_AMPLITUDE.round() as u16 + 1, "{:?} peak {}", signal, peak);
+        }
+    }
+}